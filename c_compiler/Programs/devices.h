@@ -0,0 +1,27 @@
+// IO device map shared by the example programs.
+
+#pragma once
+
+#include <bepl.h>
+
+// Numbers written here are printed in decimal
+#define CONSOLE 0
+// Shows a single summary value, such as a count
+#define RESULT 1
+// Shows the characters written here as text
+#define DISPLAY 2
+
+// Lamp screen of 32 by 16 pixels, driven with the commands below
+#define SCREEN 3
+
+#define print(value) __out(CONSOLE, value)
+
+// Lights the pixel at x, y of the screen, or turns it off if on is 0
+#define plot(x, y, on)                 \
+    do {                               \
+        __out(SCREEN, (x));            \
+        __out(SCREEN, 0x1000 | (y));   \
+        __out(SCREEN, 0x2000 | (on));  \
+    } while (0)
+// Turns all pixels of the screen off
+#define clear_screen() __out(SCREEN, 0x3000)
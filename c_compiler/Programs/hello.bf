@@ -0,0 +1,4 @@
+Prints "Hello World!" and a newline to the character display
+
+++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]
+>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.
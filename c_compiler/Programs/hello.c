@@ -0,0 +1,8 @@
+// Greets on the character display.
+
+#include "devices.h"
+
+int main() {
+    __puts(DISPLAY, "Hello, world!\n");
+    return 0;
+}
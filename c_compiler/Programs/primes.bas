@@ -0,0 +1,14 @@
+10 REM Prints the primes below the number read from the console
+20 INPUT N
+30 IF N < 3 THEN N = 100
+40 FOR P = 2 TO N - 1
+50 D = 2
+60 IF D * D > P THEN GOTO 100
+70 IF P - P / D * D = 0 THEN GOTO 120
+80 D = D + 1
+90 GOTO 60
+100 PRINT P;
+110 PRINT " ";
+120 NEXT P
+130 PRINT
+140 END
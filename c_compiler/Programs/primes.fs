@@ -0,0 +1,11 @@
+\ Prints the primes below 100 to the console
+
+: PRIME? ( n -- flag )  \ for n > 2
+  -1 SWAP DUP 2 DO        ( flag n )
+    DUP I MOD 0= IF SWAP DROP 0 SWAP THEN
+  LOOP DROP ;
+
+: PRIMES ( limit -- )
+  2 . 3 DO I PRIME? IF I . THEN LOOP ;
+
+100 PRIMES
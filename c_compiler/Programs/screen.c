@@ -0,0 +1,36 @@
+// Draws a frame around the lamp screen with a diagonal line in it, then
+// moves the line over in a second frame.
+//
+// Run with --device 3=screen to see the frames, or with
+// --device 3=screen:32x16:frame.png to also get them as images.
+
+#include "devices.h"
+
+#define WIDTH 32
+#define HEIGHT 16
+
+void border() {
+    for (int x = 0; x < WIDTH; x++) {
+        plot(x, 0, 1);
+        plot(x, HEIGHT - 1, 1);
+    }
+    for (int y = 0; y < HEIGHT; y++) {
+        plot(0, y, 1);
+        plot(WIDTH - 1, y, 1);
+    }
+}
+
+void diagonal(int start) {
+    for (int i = 1; i < HEIGHT - 1; i++) {
+        plot(start + i, i, 1);
+    }
+}
+
+int main() {
+    border();
+    diagonal(0);
+    clear_screen();
+    border();
+    diagonal(WIDTH - HEIGHT);
+    return 0;
+}
@@ -0,0 +1,24 @@
+// Prints all primes below LIMIT to the console using the sieve of
+// Eratosthenes, followed by their count on the result device.
+
+#include "devices.h"
+
+#define LIMIT 500
+
+int is_composite[LIMIT];
+
+int main() {
+    int count = 0;
+    for (int i = 2; i < LIMIT; i++) {
+        if (is_composite[i]) {
+            continue;
+        }
+        print(i);
+        count++;
+        for (int j = i + i; j < LIMIT; j += i) {
+            is_composite[j] = 1;
+        }
+    }
+    __out(RESULT, count);
+    return 0;
+}
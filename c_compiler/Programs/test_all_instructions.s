@@ -4,6 +4,21 @@ set x1 1
 store x1 x0
 load x2 x0
 out x2 0
+set x3 20
+set x1 2
+store x1 x3
+set x5 10
+load x2 x5
+out x2 0
+load x2 x3
+out x2 0
+set x6 -1
+set x1 3
+store x1 x6
+load x2 x6
+out x2 0
+load x2 x0
+out x2 0
 
 # add
 set x1 2
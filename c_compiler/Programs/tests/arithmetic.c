@@ -0,0 +1,42 @@
+// Wrapping arithmetic, signed and unsigned division and shifts.
+
+#include <bepl.h>
+
+int values[] = {0, 1, -1, 7, -7, 100, -100, 32767, -32768, 12345};
+
+int main() {
+    int checksum = 0;
+    for (int i = 0; i < 10; i++) {
+        for (int j = 0; j < 10; j++) {
+            int a = values[i];
+            int b = values[j];
+            unsigned ua = a;
+            unsigned ub = b;
+            __out(0, a + b);
+            __out(0, a - b);
+            __out(0, a * b);
+            __out(0, a / b);
+            __out(0, a % b);
+            __out(0, ua / ub);
+            __out(0, ua % ub);
+            __out(0, a < b);
+            __out(0, ua < ub);
+            checksum = checksum * 31 + (a ^ b) + (a & b) - (a | b);
+        }
+        int a = values[i];
+        unsigned ua = a;
+        for (int s = 0; s < 16; s += 3) {
+            __out(1, a << s);
+            __out(1, a >> s);
+            __out(1, ua >> s);
+        }
+        __out(1, a / 4);
+        __out(1, ua / 4);
+        __out(1, a % 8);
+        __out(1, ua % 8);
+        __out(1, -a);
+        __out(1, ~a);
+        __out(1, !a);
+    }
+    return checksum;
+}
@@ -0,0 +1,27 @@
+// Conversions between char, short, int and unsigned.
+
+int main() {
+    char c = 0;
+    unsigned char u = 0;
+    int sum = 0;
+    for (int i = 0; i < 300; i += 7) {
+        c = i;
+        u = i;
+        c += 100;
+        u -= 3;
+        __out(0, c);
+        __out(0, u);
+        sum += c * u;
+    }
+    short s = -5;
+    unsigned short us = s;
+    __out(1, s);
+    __out(1, us > 100);
+    __out(1, (char)300);
+    __out(1, (unsigned char)-1);
+    char text[] = "Hello";
+    for (char *p = text; *p; p++) {
+        __out(2, *p);
+    }
+    return sum;
+}
@@ -0,0 +1,19 @@
+// Block and line comments, which must not change the code around them.
+
+#include <bepl.h>
+
+/* A block comment
+ * over several lines, with * and / inside: a*b/c */
+int value = 6 /* before */ * /**/ 7; /***/
+
+int main() {
+    int a = 2;  // line comment /* not a block comment
+    int b = 3;  /* block comment // not a line comment */
+    __out(0, value);
+    __out(0, a /* between */ * b);
+    /*
+    __out(0, 99);
+    */
+    __out(0, a/**//b);
+    return 0;
+}
@@ -0,0 +1,58 @@
+// Loops, switch fallthrough, break and continue.
+
+int classify(int n) {
+    switch (n % 7) {
+    case 0:
+        return 100;
+    case 1:
+    case 2:
+        n += 10;
+    case 3:
+        n *= 2;
+        break;
+    case 5:
+        for (int i = 0; i < 3; i++) {
+            n += i;
+        }
+        break;
+    default:
+        n = -n;
+    }
+    return n;
+}
+
+int collatz(int n) {
+    int steps = 0;
+    while (n != 1) {
+        n = n % 2 ? 3 * n + 1 : n / 2;
+        steps++;
+    }
+    return steps;
+}
+
+int main() {
+    int total = 0;
+    for (int i = -10; i < 30; i++) {
+        __out(0, classify(i));
+        if (i % 3 == 0) {
+            continue;
+        }
+        total += classify(i);
+        if (total > 1000) {
+            break;
+        }
+    }
+    int n = 0;
+    do {
+        n++;
+        __out(1, collatz(n));
+    } while (n < 27);
+
+    switch (total & 3) {
+    case 0:
+        total++;
+    case 1:
+        total++;
+    }
+    return total;
+}
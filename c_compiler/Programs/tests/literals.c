@@ -0,0 +1,25 @@
+// Types of number literals and constant expressions folded in them.
+
+#include <bepl.h>
+
+int main() {
+    int x = -1;
+    // Decimal numbers from 32768 on are long, hex ones unsigned
+    __out(0, x / (-32768));
+    __out(0, x % (-32768));
+    __out(0, 40000 / -3);
+    __out(0, -1 < 40000);
+    __out(0, -1 < 0xFFFF);
+    __out(0, 0x8000 >> 15);
+    __out(0, 65535 == -1);
+    __out(0, 0xFFFF == -1);
+    __out(0, sizeof(40000));
+    // Folded in the type of the operands
+    __out(0, -32768 / -1);
+    __out(0, 32767 + 1 > 0);
+    __out(0, (unsigned)-1 / 2);
+    __out(0, -7 % 3);
+    __out(0, (char)300);
+    __out(0, 1 ? 2u : -1);
+    return 0;
+}
@@ -0,0 +1,44 @@
+// 32 bit arithmetic on long and unsigned long.
+
+long factorial(int n) {
+    long result = 1;
+    for (int i = 2; i <= n; i++) {
+        result *= i;
+    }
+    return result;
+}
+
+void print_long(long value) {
+    __out(0, value);
+    __out(0, value >> 16);
+}
+
+int main() {
+    long values[] = {0, 1, -1, 65535, 65536, -65536, 2147483647L, 100000, -123456};
+    for (int i = 0; i < 9; i++) {
+        for (int j = 0; j < 9; j++) {
+            long a = values[i];
+            long b = values[j];
+            unsigned long ua = a;
+            unsigned long ub = b;
+            print_long(a + b);
+            print_long(a - b);
+            print_long(a * b);
+            print_long(a / b);
+            print_long(a % b);
+            print_long(ua / ub);
+            __out(1, a < b);
+            __out(1, ua < ub);
+        }
+        long a = values[i];
+        for (int s = 0; s < 40; s += 5) {
+            print_long(a << s);
+            print_long(a >> s);
+            print_long((unsigned long)a >> s);
+        }
+    }
+    for (int n = 0; n < 14; n++) {
+        print_long(factorial(n));
+    }
+    return factorial(10) % 1000;
+}
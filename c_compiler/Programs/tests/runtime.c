@@ -0,0 +1,13 @@
+// Runtime library functions called through the prototypes of bepl.h.
+
+#include <bepl.h>
+
+int main() {
+    int device = 2;
+    __puts(device, "Hello, world!\n");
+    __puts(0, "");
+    __putn(device, -1234);
+    __putn(3, 0);
+    __putn(3, -32768);
+    return 7;
+}
@@ -0,0 +1,20 @@
+// Shift counts of 16 or more and negative ones, which the hardware reads
+// as unsigned.
+
+#include <bepl.h>
+
+int main() {
+    int n = 17;
+    int m = -1;
+    int x = -300;
+    unsigned u = 40000;
+    __out(0, 1 << n);
+    __out(0, x >> n);
+    __out(0, u >> n);
+    __out(0, x << m);
+    __out(0, x >> m);
+    __out(0, 5 >> m);
+    __out(0, x >> 15);
+    __out(0, u >> 15);
+    return 0;
+}
@@ -0,0 +1,40 @@
+// Static locals, const tables and strings in the data image.
+
+const int squares[] = {0, 1, 4, 9, 16, 25};
+const char *const names[] = {"zero", "one", "two"};
+static int calls;
+
+int next_id(void) {
+    static int id = 100;
+    calls++;
+    return id++;
+}
+
+int counter(int reset) {
+    static int count;
+    if (reset) {
+        count = 0;
+    }
+    return ++count;
+}
+
+int main() {
+    for (int i = 0; i < 3; i++) {
+        __out(0, next_id());
+        __puts(2, names[i]);
+        __puts(i & 1, "!");
+    }
+    counter(0);
+    counter(0);
+    __out(1, counter(0));
+    __out(1, counter(1));
+    int sum = 0;
+    for (int i = 0; i < 6; i++) {
+        sum += squares[i];
+    }
+    // Identical literals may share their storage
+    const char *a = "same";
+    const char *b = "same";
+    __out(1, a == b);
+    return sum * 100 + calls;
+}
@@ -0,0 +1,64 @@
+// Structs, pointers, arrays of structs, function pointers and recursion.
+
+struct point {
+    int x;
+    int y;
+};
+
+struct shape {
+    struct point corners[3];
+    char name;
+};
+
+struct shape shapes[2] = {{{{0, 0}, {4, 0}, {0, 3}}, 'a'}, {{{1, 1}, {5, 2}, {2, 7}}, 'b'}};
+
+int twice_area(struct shape *s) {
+    struct point *p = s->corners;
+    int area = (p[1].x - p[0].x) * (p[2].y - p[0].y) - (p[2].x - p[0].x) * (p[1].y - p[0].y);
+    return area < 0 ? -area : area;
+}
+
+int add(int a, int b) {
+    return a + b;
+}
+
+int sub(int a, int b) {
+    return a - b;
+}
+
+int (*operations[])(int, int) = {add, sub};
+
+int fib(int n) {
+    return n < 2 ? n : fib(n - 1) + fib(n - 2);
+}
+
+void swap(int *a, int *b) {
+    int t = *a;
+    *a = *b;
+    *b = t;
+}
+
+int main() {
+    for (int i = 0; i < 2; i++) {
+        __out(0, twice_area(&shapes[i]));
+        __out(0, shapes[i].name);
+    }
+    struct point copy = shapes[1].corners[2];
+    copy.x += 10;
+    __out(1, copy.x);
+    __out(1, shapes[1].corners[2].x);
+
+    int a = 3;
+    int b = 8;
+    swap(&a, &b);
+    __out(2, a);
+    __out(2, b);
+    for (int i = 0; i < 2; i++) {
+        __out(2, operations[i](a, b));
+    }
+    int numbers[5] = {5, 3, 9};
+    int *end = numbers + 5;
+    __out(3, end - numbers);
+    __out(3, sizeof(struct shape));
+    return fib(12);
+}
@@ -0,0 +1,24 @@
+// Intrinsics and runtime functions of the BEPL-T3X16 C compiler.
+//
+// __in and __out compile to a single IN or OUT instruction. The device
+// number is part of the instruction, so it has to be a constant from 0 to 7.
+// The compiler knows both intrinsics without this header; it documents them
+// and keeps prototypes around for editors. `#include <bepl.h>` finds it even
+// without include paths.
+
+#pragma once
+
+// Reads the current value of IO device `device`.
+int __in(int device);
+
+// Writes `value` to IO device `device`.
+void __out(int device, int value);
+
+// Writes the zero terminated string `text` to IO device `device` one
+// character at a time, as character displays take it. Unlike with the
+// intrinsics the device may be a variable.
+void __puts(int device, const char *text);
+
+// Writes `value` in decimal to IO device `device` the way `__puts` writes
+// text.
+void __putn(int device, int value);
@@ -3,6 +3,15 @@ use std::collections::HashMap;
 pub struct AssemblyCompiler;
 
 impl super::Compiler for AssemblyCompiler {
+    /// Assembly is left as it is.
+    fn compile_to_assembly(&self, raw_code: &str) -> String {
+        raw_code.to_string()
+    }
+
+    fn file_name(self, _file_name: &str) -> AssemblyCompiler {
+        self
+    }
+
     fn compile(&self, raw_code: &str) -> Vec<u16> {
         let mut instructions: Vec<_> = raw_code.split("\n").enumerate().collect();
         let mut line = 0;
@@ -53,7 +62,8 @@ impl super::Compiler for AssemblyCompiler {
                 "ADDI" => {
                     hex = (4 << 12)
                         | (get_reg(&words, 1, line) << 9)
-                        | get_immediate(&words, 2, line);
+                        | get_label_half(&words, 2, line, &labels)
+                            .unwrap_or_else(|| get_immediate(&words, 2, line));
                 }
                 "SUB" => {
                     hex = (5 << 12)
@@ -142,7 +152,9 @@ fn get_imm_or_label(
     line: usize,
     labels: &HashMap<String, usize>,
 ) -> u16 {
-    if get_arg(words, argument, line).parse::<i32>().is_ok() {
+    if let Some(half) = get_label_half(words, argument, line, labels) {
+        half
+    } else if get_arg(words, argument, line).parse::<i32>().is_ok() {
         get_immediate(words, argument, line)
     } else {
         get_label(words, argument, line, labels)
@@ -171,6 +183,39 @@ fn get_label(
     address as u16
 }
 
+/// Labels beyond 127 are loaded in two halves: `set xN %hi(label)`, a shift
+/// left by 8 and `addi xN %lo(label)`. The low byte is sign extended by
+/// `addi`, which the high byte makes up for.
+fn get_label_half(
+    words: &Vec<&str>,
+    argument: usize,
+    line: usize,
+    labels: &HashMap<String, usize>,
+) -> Option<u16> {
+    let arg = get_arg(words, argument, line);
+    let (high, rest) = if let Some(rest) = arg.strip_prefix("%hi(") {
+        (true, rest)
+    } else if let Some(rest) = arg.strip_prefix("%lo(") {
+        (false, rest)
+    } else {
+        return None;
+    };
+    let label = rest
+        .strip_suffix(')')
+        .unwrap_or_else(|| panic!("{}", unknown_argument_error(&arg, line)));
+    let address = *labels
+        .get(label)
+        .unwrap_or_else(|| panic!("Undefined label: {} in line {}", label, line))
+        as u16 as i16;
+
+    let low = address as i8;
+    let half = match high {
+        true => (address.wrapping_sub(low as i16) >> 8) as i8,
+        false => low,
+    };
+    Some(half as u8 as u16)
+}
+
 fn get_sft_op(words: &Vec<&str>, argument: usize, line: usize) -> u16 {
     let arg = get_arg(words, argument, line);
     match arg.as_str() {
@@ -0,0 +1,827 @@
+//! Compiles an integer Tiny BASIC to BEPL machine code.
+//!
+//! A program is a list of numbered lines with one statement each:
+//!
+//! ```text
+//! 10 REM Counts down from the number read
+//! 20 INPUT N
+//! 30 FOR I = N TO 1 STEP -1
+//! 40 PRINT "T MINUS "; I
+//! 50 NEXT I
+//! 60 END
+//! ```
+//!
+//! The statements are `LET` (which may be left out), `PRINT`, `INPUT`,
+//! `IF .. THEN`, `GOTO`, `GOSUB`, `RETURN`, `FOR .. TO .. STEP`, `NEXT`,
+//! `END` and `REM`. Variables are 16 bit integers that start at 0, and
+//! expressions have `+`, `-`, `*`, `/` and parentheses. `PRINT` writes its
+//! strings and numbers as text to the character display, separated by a
+//! space after `,` and nothing after `;`, and ends with a newline unless it
+//! ends in one of them. `INPUT` reads numbers from the console.
+//!
+//! The program becomes a C `main` around a `switch` over the line number to
+//! go on at, which the C compiler optimizes and generates code for like any
+//! other. `GOTO` and `GOSUB` take any expression, and going to a line that
+//! does not exist ends the program. `NEXT` belongs to the closest `FOR` of
+//! its variable before it, and the loop body runs at least once.
+
+use std::collections::BTreeSet;
+
+use super::c_compiler::asm;
+use super::c_compiler::ast::{
+    BinaryOp, Declaration, Expr, ExprKind, Function, Initializer, Program, Stmt, Type, UnaryOp,
+};
+use super::{CCompiler, Compiler, OptLevel, CONSOLE, DISPLAY};
+
+/// `PRINT` writes to the display and `INPUT` reads from the console.
+const OUTPUT_DEVICE: i64 = DISPLAY as i64;
+const INPUT_DEVICE: i64 = CONSOLE as i64;
+
+/// How deep `GOSUB` nests. Going deeper, or returning without a `GOSUB`,
+/// ends the program with -1.
+const RETURN_STACK_SIZE: usize = 16;
+
+/// The line number to go on at, and the return stack of `GOSUB`. BASIC
+/// names cannot start with an underscore.
+const NEXT_LINE: &str = "__line";
+const RETURNS: &str = "__returns";
+const DEPTH: &str = "__depth";
+
+pub struct BasicCompiler {
+    c_compiler: CCompiler,
+    file_name: String,
+}
+
+impl BasicCompiler {
+    pub fn new(opt_level: OptLevel) -> BasicCompiler {
+        BasicCompiler {
+            c_compiler: CCompiler::new(opt_level),
+            file_name: "main.bas".to_string(),
+        }
+    }
+
+    fn generate(&self, raw_code: &str) -> (Vec<asm::Item>, Vec<(String, String)>) {
+        let program = Translator::new().translate(&parse(raw_code));
+        let files = [(self.file_name.clone(), raw_code.to_string())];
+        self.c_compiler.generate_program(program, &files)
+    }
+}
+
+impl Compiler for BasicCompiler {
+    fn compile(&self, raw_code: &str) -> Vec<u16> {
+        asm::assemble(&self.generate(raw_code).0)
+    }
+
+    fn compile_to_assembly(&self, raw_code: &str) -> String {
+        let (items, files) = self.generate(raw_code);
+        asm::to_text(&items, &files)
+    }
+
+    fn file_name(mut self, file_name: &str) -> BasicCompiler {
+        self.file_name = file_name.to_string();
+        self
+    }
+}
+
+// Parsing
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Str(String),
+    /// A keyword or variable, in upper case.
+    Word(String),
+    Symbol(&'static str),
+}
+
+#[derive(Debug)]
+enum Statement {
+    Let(String, Expr),
+    Print(Vec<PrintItem>, bool),
+    Input(Vec<String>),
+    If(Expr, Box<Statement>),
+    Goto(Expr),
+    Gosub(Expr),
+    Return,
+    For {
+        variable: String,
+        from: Expr,
+        to: Expr,
+        step: Option<Expr>,
+    },
+    Next(Option<String>),
+    End,
+    Rem,
+}
+
+/// What `PRINT` writes: a string, a number, or the space of a `,`.
+#[derive(Debug)]
+enum PrintItem {
+    Str(String),
+    Number(Expr),
+    Space,
+}
+
+/// A numbered line of the program.
+#[derive(Debug)]
+struct Line {
+    number: i64,
+    statement: Statement,
+    /// Line in the source file.
+    line: usize,
+}
+
+const KEYWORDS: [&str; 14] = [
+    "LET", "PRINT", "INPUT", "IF", "THEN", "GOTO", "GOSUB", "RETURN", "FOR", "TO", "STEP", "NEXT",
+    "END", "REM",
+];
+
+const SYMBOLS: [&str; 15] = [
+    "<=", ">=", "<>", "><", "<", ">", "=", "+", "-", "*", "/", "(", ")", ",", ";",
+];
+
+/// Parses the numbered lines and sorts them by their number.
+fn parse(raw_code: &str) -> Vec<Line> {
+    let mut lines: Vec<Line> = Vec::new();
+    for (i, text) in raw_code.lines().enumerate() {
+        let line = i + 1;
+        let tokens = tokenize(text, line);
+        if tokens.is_empty() {
+            continue;
+        }
+        let mut parser = Parser {
+            tokens,
+            position: 0,
+            line,
+        };
+        let number = match parser.next() {
+            Some(Token::Number(number)) if number > 0 => number,
+            _ => panic!("Expected a line number in line {}", line),
+        };
+        let statement = parser.statement();
+        if let Some(token) = parser.peek() {
+            panic!("Unexpected {:?} in line {}", token, line);
+        }
+        lines.push(Line {
+            number,
+            statement,
+            line,
+        });
+    }
+    lines.sort_by_key(|line| line.number);
+    for pair in lines.windows(2) {
+        if pair[0].number == pair[1].number {
+            panic!("Duplicate line {} in line {}", pair[1].number, pair[1].line);
+        }
+    }
+    lines
+}
+
+fn tokenize(text: &str, line: usize) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while !rest.is_empty() {
+        let c = rest.chars().next().unwrap();
+        let length = if c.is_ascii_digit() {
+            let length = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            let number = rest[..length]
+                .parse()
+                .ok()
+                .filter(|number| *number <= i16::MAX as i64)
+                .unwrap_or_else(|| {
+                    panic!("Number {} is too large in line {}", &rest[..length], line)
+                });
+            tokens.push(Token::Number(number));
+            length
+        } else if c.is_ascii_alphabetic() {
+            let length = rest
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(rest.len());
+            let word = rest[..length].to_ascii_uppercase();
+            // The rest of a REM line is a comment
+            let comment = word == "REM";
+            tokens.push(Token::Word(word));
+            if comment {
+                break;
+            }
+            length
+        } else if c == '"' {
+            let Some(end) = rest[1..].find('"') else {
+                panic!("Unterminated string in line {}", line);
+            };
+            tokens.push(Token::Str(rest[1..end + 1].to_string()));
+            end + 2
+        } else {
+            match SYMBOLS.iter().find(|symbol| rest.starts_with(*symbol)) {
+                Some(symbol) => {
+                    tokens.push(Token::Symbol(symbol));
+                    symbol.len()
+                }
+                None => panic!("Unexpected character {} in line {}", c, line),
+            }
+        };
+        rest = rest[length..].trim_start();
+    }
+    tokens
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    line: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    /// Takes the next token if it is this keyword or symbol.
+    fn accept(&mut self, text: &str) -> bool {
+        let found = match self.peek() {
+            Some(Token::Word(word)) => word == text,
+            Some(Token::Symbol(symbol)) => *symbol == text,
+            _ => false,
+        };
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, text: &str) {
+        if !self.accept(text) {
+            panic!("Expected {} in line {}", text, self.line);
+        }
+    }
+
+    fn variable(&mut self) -> String {
+        match self.next() {
+            Some(Token::Word(word)) if !KEYWORDS.contains(&word.as_str()) => word,
+            _ => panic!("Expected a variable in line {}", self.line),
+        }
+    }
+
+    fn statement(&mut self) -> Statement {
+        let keyword = match self.peek() {
+            Some(Token::Word(word)) if KEYWORDS.contains(&word.as_str()) => word.clone(),
+            Some(Token::Word(_)) => "LET".to_string(),
+            _ => panic!("Expected a statement in line {}", self.line),
+        };
+        self.accept(&keyword);
+        match keyword.as_str() {
+            "LET" => {
+                let variable = self.variable();
+                self.expect("=");
+                Statement::Let(variable, self.expr())
+            }
+            "PRINT" => self.print(),
+            "INPUT" => {
+                let mut variables = vec![self.variable()];
+                while self.accept(",") {
+                    variables.push(self.variable());
+                }
+                Statement::Input(variables)
+            }
+            "IF" => {
+                let condition = self.condition();
+                self.expect("THEN");
+                let then = match self.peek() {
+                    Some(Token::Number(_)) => Statement::Goto(self.expr()),
+                    _ => self.statement(),
+                };
+                Statement::If(condition, Box::new(then))
+            }
+            "GOTO" => Statement::Goto(self.expr()),
+            "GOSUB" => Statement::Gosub(self.expr()),
+            "RETURN" => Statement::Return,
+            "FOR" => {
+                let variable = self.variable();
+                self.expect("=");
+                let from = self.expr();
+                self.expect("TO");
+                let to = self.expr();
+                let step = self.accept("STEP").then(|| self.expr());
+                Statement::For {
+                    variable,
+                    from,
+                    to,
+                    step,
+                }
+            }
+            "NEXT" => match self.peek() {
+                Some(Token::Word(_)) => Statement::Next(Some(self.variable())),
+                _ => Statement::Next(None),
+            },
+            "END" => Statement::End,
+            "REM" => Statement::Rem,
+            _ => panic!("Unexpected {} in line {}", keyword, self.line),
+        }
+    }
+
+    fn print(&mut self) -> Statement {
+        let mut items = Vec::new();
+        let mut newline = true;
+        while self.peek().is_some() {
+            items.push(match self.peek() {
+                Some(Token::Str(text)) => {
+                    let text = text.clone();
+                    self.position += 1;
+                    PrintItem::Str(text)
+                }
+                _ => PrintItem::Number(self.expr()),
+            });
+            newline = true;
+            if self.accept(",") {
+                items.push(PrintItem::Space);
+            } else if !self.accept(";") {
+                break;
+            }
+            newline = false;
+        }
+        Statement::Print(items, newline)
+    }
+
+    /// An expression or a comparison of two, as `IF` takes it.
+    fn condition(&mut self) -> Expr {
+        let left = self.expr();
+        let op = match self.peek() {
+            Some(Token::Symbol("=")) => BinaryOp::Eq,
+            Some(Token::Symbol("<>" | "><")) => BinaryOp::Ne,
+            Some(Token::Symbol("<")) => BinaryOp::Lt,
+            Some(Token::Symbol("<=")) => BinaryOp::Le,
+            Some(Token::Symbol(">")) => BinaryOp::Gt,
+            Some(Token::Symbol(">=")) => BinaryOp::Ge,
+            _ => return left,
+        };
+        self.position += 1;
+        let right = self.expr();
+        binary(op, left, right, self.line)
+    }
+
+    fn expr(&mut self) -> Expr {
+        let mut left = self.term();
+        loop {
+            let op = if self.accept("+") {
+                BinaryOp::Add
+            } else if self.accept("-") {
+                BinaryOp::Sub
+            } else {
+                return left;
+            };
+            let right = self.term();
+            left = binary(op, left, right, self.line);
+        }
+    }
+
+    fn term(&mut self) -> Expr {
+        let mut left = self.factor();
+        loop {
+            let op = if self.accept("*") {
+                BinaryOp::Mul
+            } else if self.accept("/") {
+                BinaryOp::Div
+            } else {
+                return left;
+            };
+            let right = self.factor();
+            left = binary(op, left, right, self.line);
+        }
+    }
+
+    fn factor(&mut self) -> Expr {
+        if self.accept("-") {
+            let operand = self.factor();
+            return Expr::new(ExprKind::Unary(UnaryOp::Neg, Box::new(operand)), self.line);
+        }
+        self.accept("+");
+        match self.next() {
+            Some(Token::Number(value)) => Expr::new(ExprKind::Number(value), self.line),
+            Some(Token::Word(word)) if !KEYWORDS.contains(&word.as_str()) => {
+                Expr::new(ExprKind::Ident(word), self.line)
+            }
+            Some(Token::Symbol("(")) => {
+                let expr = self.expr();
+                self.expect(")");
+                expr
+            }
+            _ => panic!("Expected an expression in line {}", self.line),
+        }
+    }
+}
+
+// Translation
+
+/// A `FOR` that no `NEXT` has closed yet.
+struct OpenFor {
+    variable: String,
+    /// Where the loop body starts.
+    body: i64,
+    /// The limit and step, as constants or hidden variables.
+    to: Expr,
+    step: Expr,
+}
+
+/// Turns the lines into a C `main` function.
+struct Translator {
+    variables: BTreeSet<String>,
+    /// Hidden variables for the limits and steps of `FOR` loops.
+    hidden: Vec<String>,
+    open_fors: Vec<OpenFor>,
+    /// Lines that are gone to, and whether any `GOTO` or `GOSUB` computes
+    /// its line so that any may be.
+    targets: BTreeSet<i64>,
+    computed: bool,
+}
+
+impl Translator {
+    fn new() -> Translator {
+        Translator {
+            variables: BTreeSet::new(),
+            hidden: Vec::new(),
+            open_fors: Vec::new(),
+            targets: BTreeSet::new(),
+            computed: false,
+        }
+    }
+
+    fn translate(mut self, lines: &[Line]) -> Program {
+        let numbers: Vec<i64> = lines.iter().map(|line| line.number).collect();
+
+        let first = numbers.first().copied().unwrap_or(-1);
+        self.targets.insert(first);
+
+        let mut stmts = Vec::new();
+        for (i, line) in lines.iter().enumerate() {
+            // `GOSUB` returns to the next line, or ends the program after the last
+            let next = numbers.get(i + 1).copied().unwrap_or(-1);
+            stmts.push(self.statement(&line.statement, next, &numbers, line.line));
+        }
+        // Lines nothing goes to are only reached from the line before
+        let cases = lines
+            .iter()
+            .zip(stmts)
+            .map(
+                |(line, stmt)| match self.computed || self.targets.contains(&line.number) {
+                    true => Stmt::Case(number(line.number, line.line), Box::new(stmt)),
+                    false => stmt,
+                },
+            )
+            .collect();
+
+        // Globals start at 0 and take no registers across the dispatch loop
+        let mut globals: Vec<Declaration> = self
+            .variables
+            .iter()
+            .chain(&self.hidden)
+            .map(|name| declare(name, Type::INT, None))
+            .collect();
+        globals.push(declare(DEPTH, Type::INT, None));
+        let stack_type = Type::Array(Box::new(Type::INT), Some(RETURN_STACK_SIZE));
+        globals.push(declare(RETURNS, stack_type, None));
+
+        let dispatch = Stmt::Block(vec![
+            Stmt::Switch(ident(NEXT_LINE, 1), Box::new(Stmt::Block(cases))),
+            Stmt::Return(Some(number(0, 1))),
+        ]);
+        let body = vec![
+            Stmt::Decl(declare(NEXT_LINE, Type::INT, Some(number(first, 1)))),
+            Stmt::While(number(1, 1), Box::new(dispatch)),
+        ];
+
+        Program {
+            globals,
+            functions: vec![Function {
+                name: "main".to_string(),
+                return_type: Type::INT,
+                params: Vec::new(),
+                body: Some(body),
+                line: 1,
+                file: 0,
+            }],
+            structs: Default::default(),
+        }
+    }
+
+    /// Translates a statement, where `next` is the number of the line after
+    /// it and `line` the source line.
+    fn statement(
+        &mut self,
+        statement: &Statement,
+        next: i64,
+        numbers: &[i64],
+        line: usize,
+    ) -> Stmt {
+        match statement {
+            Statement::Let(variable, value) => {
+                let value = self.expr(value);
+                self.assign(variable, value, line)
+            }
+            Statement::Print(items, newline) => {
+                let mut stmts: Vec<Stmt> = items
+                    .iter()
+                    .map(|item| match item {
+                        PrintItem::Str(text) => {
+                            let text = Expr::new(ExprKind::Str(text.as_bytes().to_vec()), line);
+                            call("__puts", vec![number(OUTPUT_DEVICE, line), text], line)
+                        }
+                        PrintItem::Number(value) => {
+                            let value = self.expr(value);
+                            call("__putn", vec![number(OUTPUT_DEVICE, line), value], line)
+                        }
+                        PrintItem::Space => put_char(b' ', line),
+                    })
+                    .collect();
+                if *newline {
+                    stmts.push(put_char(b'\n', line));
+                }
+                Stmt::Block(stmts)
+            }
+            Statement::Input(variables) => Stmt::Block(
+                variables
+                    .iter()
+                    .map(|variable| {
+                        let value = call_expr("__in", vec![number(INPUT_DEVICE, line)], line);
+                        self.assign(variable, value, line)
+                    })
+                    .collect(),
+            ),
+            Statement::If(condition, then) => {
+                let condition = self.expr(condition);
+                let then = self.statement(then, next, numbers, line);
+                Stmt::If(condition, Box::new(then), None)
+            }
+            Statement::Goto(target) => {
+                self.target(target, numbers, line);
+                go_to(self.expr(target), line)
+            }
+            Statement::Gosub(target) => {
+                self.target(target, numbers, line);
+                self.targets.insert(next);
+                let depth = ident(DEPTH, line);
+                let full = binary(
+                    BinaryOp::Ge,
+                    depth.clone(),
+                    number(RETURN_STACK_SIZE as i64, line),
+                    line,
+                );
+                let slot = Expr::new(
+                    ExprKind::Index(Box::new(ident(RETURNS, line)), Box::new(depth.clone())),
+                    line,
+                );
+                Stmt::Block(vec![
+                    Stmt::If(full, Box::new(Stmt::Return(Some(number(-1, line)))), None),
+                    Stmt::Expr(assign(slot, number(next, line), line)),
+                    Stmt::Expr(assign(
+                        depth.clone(),
+                        binary(BinaryOp::Add, depth, number(1, line), line),
+                        line,
+                    )),
+                    go_to(self.expr(target), line),
+                ])
+            }
+            Statement::Return => {
+                let depth = ident(DEPTH, line);
+                let empty = binary(BinaryOp::Le, depth.clone(), number(0, line), line);
+                let slot = Expr::new(
+                    ExprKind::Index(Box::new(ident(RETURNS, line)), Box::new(depth.clone())),
+                    line,
+                );
+                Stmt::Block(vec![
+                    Stmt::If(empty, Box::new(Stmt::Return(Some(number(-1, line)))), None),
+                    Stmt::Expr(assign(
+                        depth.clone(),
+                        binary(BinaryOp::Sub, depth, number(1, line), line),
+                        line,
+                    )),
+                    go_to(slot, line),
+                ])
+            }
+            Statement::For {
+                variable,
+                from,
+                to,
+                step,
+            } => {
+                let from = self.expr(from);
+                let mut stmts = vec![self.assign(variable, from, line)];
+                let to = self.loop_value(to, &mut stmts, line);
+                let step = match step {
+                    Some(step) => self.loop_value(step, &mut stmts, line),
+                    None => number(1, line),
+                };
+                self.targets.insert(next);
+                self.open_fors.push(OpenFor {
+                    variable: variable.clone(),
+                    body: next,
+                    to,
+                    step,
+                });
+                Stmt::Block(stmts)
+            }
+            Statement::Next(variable) => {
+                let position = match variable {
+                    Some(variable) => self.open_fors.iter().rposition(|f| f.variable == *variable),
+                    None => self.open_fors.len().checked_sub(1),
+                };
+                let Some(position) = position else {
+                    panic!("NEXT without FOR in line {}", line);
+                };
+                let open = self.open_fors.remove(position);
+                self.open_fors.truncate(position);
+
+                let counter = ident(&open.variable, line);
+                let stepped = binary(BinaryOp::Add, counter.clone(), open.step.clone(), line);
+                let up = binary(BinaryOp::Le, counter.clone(), open.to.clone(), line);
+                let down = binary(BinaryOp::Ge, counter, open.to, line);
+                let again = match open.step.constant_value() {
+                    Some(step) if step >= 0 => up,
+                    Some(_) => down,
+                    None => {
+                        let rising = binary(BinaryOp::Ge, open.step, number(0, line), line);
+                        Expr::new(
+                            ExprKind::Conditional(Box::new(rising), Box::new(up), Box::new(down)),
+                            line,
+                        )
+                    }
+                };
+                Stmt::Block(vec![
+                    self.assign(&open.variable, stepped, line),
+                    Stmt::If(again, Box::new(go_to(number(open.body, line), line)), None),
+                ])
+            }
+            Statement::End => Stmt::Return(Some(number(0, line))),
+            Statement::Rem => Stmt::Block(Vec::new()),
+        }
+    }
+
+    /// The limit or step of a `FOR` loop, which is kept in a hidden variable
+    /// unless it is a constant.
+    fn loop_value(&mut self, value: &Expr, stmts: &mut Vec<Stmt>, line: usize) -> Expr {
+        let value = self.expr(value);
+        if let Some(constant) = value.constant_value() {
+            return number(constant, line);
+        }
+        let name = format!("__for{}", self.hidden.len());
+        self.hidden.push(name.clone());
+        stmts.push(Stmt::Expr(assign(ident(&name, line), value, line)));
+        ident(&name, line)
+    }
+
+    /// Notes a line that `GOTO` or `GOSUB` goes to, which has to exist if it
+    /// is a constant.
+    fn target(&mut self, target: &Expr, numbers: &[i64], line: usize) {
+        match target.constant_value() {
+            Some(target) if !numbers.contains(&target) => {
+                panic!("No line {} to go to in line {}", target, line)
+            }
+            Some(target) => {
+                self.targets.insert(target);
+            }
+            None => self.computed = true,
+        }
+    }
+
+    /// Notes the variables an expression uses, which all are declared.
+    fn expr(&mut self, expr: &Expr) -> Expr {
+        expr.walk(&mut |e| {
+            if let ExprKind::Ident(name) = &e.kind {
+                self.variables.insert(name.clone());
+            }
+        });
+        expr.clone()
+    }
+
+    fn assign(&mut self, variable: &str, value: Expr, line: usize) -> Stmt {
+        self.variables.insert(variable.to_string());
+        Stmt::Expr(assign(ident(variable, line), value, line))
+    }
+}
+
+/// Goes on at the line with the number `target` computes.
+fn go_to(target: Expr, line: usize) -> Stmt {
+    Stmt::Block(vec![
+        Stmt::Expr(assign(ident(NEXT_LINE, line), target, line)),
+        Stmt::Continue(line),
+    ])
+}
+
+fn put_char(c: u8, line: usize) -> Stmt {
+    call(
+        "__out",
+        vec![number(OUTPUT_DEVICE, line), number(c as i64, line)],
+        line,
+    )
+}
+
+fn call(name: &str, args: Vec<Expr>, line: usize) -> Stmt {
+    Stmt::Expr(call_expr(name, args, line))
+}
+
+fn call_expr(name: &str, args: Vec<Expr>, line: usize) -> Expr {
+    Expr::new(ExprKind::Call(Box::new(ident(name, line)), args), line)
+}
+
+fn declare(name: &str, var_type: Type, init: Option<Expr>) -> Declaration {
+    Declaration {
+        name: name.to_string(),
+        var_type,
+        init: init.map(Initializer::Expr),
+        line: 1,
+        is_static: false,
+        constant: false,
+    }
+}
+
+fn assign(target: Expr, value: Expr, line: usize) -> Expr {
+    Expr::new(
+        ExprKind::Assign(None, Box::new(target), Box::new(value)),
+        line,
+    )
+}
+
+fn binary(op: BinaryOp, left: Expr, right: Expr, line: usize) -> Expr {
+    Expr::new(ExprKind::Binary(op, Box::new(left), Box::new(right)), line)
+}
+
+fn ident(name: &str, line: usize) -> Expr {
+    Expr::new(ExprKind::Ident(name.to_string()), line)
+}
+
+fn number(value: i64, line: usize) -> Expr {
+    Expr::new(ExprKind::Number(value), line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::{Bus, InputQueue, OnEmpty, Source};
+    use crate::simulator::{Cpu, Status};
+
+    /// The text the program prints and the value it ends with, reading
+    /// `input`.
+    fn run(code: &str, input: Vec<i16>) -> (String, i16) {
+        let console = InputQueue::new(CONSOLE as usize, Source::Values(input), OnEmpty::Fault);
+        let bus = Bus::default().attach(CONSOLE as usize, Box::new(console));
+        let hex_code = BasicCompiler::new(OptLevel::O1).compile(code);
+        let mut cpu = Cpu::with_bus(&hex_code, bus);
+        assert_eq!(cpu.run(1_000_000), Ok(Status::Halted));
+        let text = cpu
+            .outputs()
+            .iter()
+            .map(|&(device, value)| {
+                assert_eq!(device, DISPLAY);
+                value as u8 as char
+            })
+            .collect();
+        (text, cpu.reg(0))
+    }
+
+    #[test]
+    fn gosub_returns_after_the_call() {
+        let code = "\
+10 A = 3
+20 GOSUB 100
+30 IF A < 20 THEN GOTO 20
+40 PRINT \"A=\"; A
+50 END
+100 A = A * 2
+110 RETURN
+";
+        assert_eq!(run(code, vec![]), ("A=24\n".to_string(), 0));
+        assert_eq!(
+            run("10 PRINT 1\n20 RETURN\n", vec![]),
+            ("1\n".to_string(), -1)
+        );
+    }
+
+    #[test]
+    fn if_skips_the_statement_when_false() {
+        let code = "\
+10 INPUT N
+20 FOR I = N TO 1 STEP -1
+30 IF I <> 2 THEN PRINT I,
+40 NEXT I
+50 PRINT
+60 IF N >= 4 THEN GOTO 80
+70 PRINT \"SMALL\"
+80 END
+";
+        assert_eq!(run(code, vec![4]), ("4 3 1 \n".to_string(), 0));
+        assert_eq!(run(code, vec![3]), ("3 1 \nSMALL\n".to_string(), 0));
+    }
+
+    #[test]
+    fn expressions_have_precedence_and_truncate() {
+        let code = "10 PRINT 2 + 3 * 4; \" \"; (2 + 3) * 4, -7 / 2\n";
+        assert_eq!(run(code, vec![]), ("14 20 -3\n".to_string(), 0));
+    }
+}
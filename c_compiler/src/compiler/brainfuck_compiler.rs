@@ -0,0 +1,248 @@
+//! Compiles Brainfuck to BEPL machine code.
+//!
+//! The tape lives in data memory from address 0 on, one 8 bit cell per
+//! word, and x1 points at the current cell. `.` writes the cell to the
+//! character display and `,` reads it from the console. Runs of `+`, `-`,
+//! `<` and `>` fold into one instruction, and `[-]` clears the cell without
+//! looping.
+//!
+//! Code is generated through the assembler of the C compiler, so labels
+//! beyond the reach of `set` are loaded the same way.
+
+use super::c_compiler::asm::{self, load_constant, Flag, Inst, Item, Reg};
+use super::{Compiler, CONSOLE, DISPLAY};
+
+/// Always 0, to compare cells against.
+const ZERO: Reg = 0;
+/// Address of the current cell.
+const POINTER: Reg = 1;
+/// Value of the current cell.
+const CELL: Reg = 2;
+/// Holds 255 to keep cells to 8 bits.
+const MASK: Reg = 3;
+/// Holds jump targets.
+const ADDRESS: Reg = 6;
+
+/// `.` writes to the display and `,` reads from the console.
+const OUTPUT_DEVICE: u8 = DISPLAY;
+const INPUT_DEVICE: u8 = CONSOLE;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    /// Adds to the current cell, modulo 256.
+    Add(i32),
+    /// Moves the cell pointer.
+    Move(i32),
+    Output,
+    Input,
+    /// `[-]` or `[+]`, which leave the cell at 0.
+    Clear,
+    /// Start and end of the loop with this number.
+    LoopStart(usize),
+    LoopEnd(usize),
+}
+
+pub struct BrainfuckCompiler {
+    file_name: String,
+}
+
+impl BrainfuckCompiler {
+    pub fn new() -> BrainfuckCompiler {
+        BrainfuckCompiler {
+            file_name: "main.bf".to_string(),
+        }
+    }
+}
+
+impl Compiler for BrainfuckCompiler {
+    fn compile(&self, raw_code: &str) -> Vec<u16> {
+        asm::assemble(&generate(&parse(raw_code)))
+    }
+
+    fn compile_to_assembly(&self, raw_code: &str) -> String {
+        let files = [(self.file_name.clone(), raw_code.to_string())];
+        asm::to_text(&generate(&parse(raw_code)), &files)
+    }
+
+    fn file_name(mut self, file_name: &str) -> BrainfuckCompiler {
+        self.file_name = file_name.to_string();
+        self
+    }
+}
+
+/// Turns the source into operations with their line. Characters other than
+/// the eight commands are comments.
+fn parse(raw_code: &str) -> Vec<(Op, usize)> {
+    let mut ops = Vec::new();
+    let mut loops = Vec::new();
+    let mut loop_count = 0;
+    for (i, text) in raw_code.lines().enumerate() {
+        let line = i + 1;
+        for c in text.chars() {
+            let op = match c {
+                '+' => Op::Add(1),
+                '-' => Op::Add(-1),
+                '>' => Op::Move(1),
+                '<' => Op::Move(-1),
+                '.' => Op::Output,
+                ',' => Op::Input,
+                '[' => {
+                    loops.push((loop_count, line));
+                    loop_count += 1;
+                    Op::LoopStart(loop_count - 1)
+                }
+                ']' => match loops.pop() {
+                    Some((number, _)) => Op::LoopEnd(number),
+                    None => panic!("Unmatched ] in line {}", line),
+                },
+                _ => continue,
+            };
+            push(&mut ops, op, line);
+        }
+    }
+    if let Some((_, line)) = loops.pop() {
+        panic!("Unmatched [ in line {}", line);
+    }
+    ops
+}
+
+/// Appends an operation, folding it into the ones before where possible.
+fn push(ops: &mut Vec<(Op, usize)>, op: Op, line: usize) {
+    match (ops.last_mut(), op) {
+        (Some((Op::Add(sum), _)), Op::Add(value)) | (Some((Op::Move(sum), _)), Op::Move(value)) => {
+            *sum += value;
+            if *sum == 0 {
+                ops.pop();
+            }
+        }
+        (_, Op::LoopEnd(number)) => {
+            let clear = ops.len() >= 2
+                && ops[ops.len() - 2].0 == Op::LoopStart(number)
+                && matches!(ops[ops.len() - 1].0, Op::Add(1 | -1));
+            if clear {
+                let (_, start_line) = ops[ops.len() - 2];
+                ops.truncate(ops.len() - 2);
+                ops.push((Op::Clear, start_line));
+            } else {
+                ops.push((op, line));
+            }
+        }
+        _ => ops.push((op, line)),
+    }
+}
+
+fn generate(ops: &[(Op, usize)]) -> Vec<Item> {
+    let mut items: Vec<Item> = vec![
+        Item::Inst(Inst::Set(ZERO, 0)),
+        Item::Inst(Inst::Set(POINTER, 0)),
+    ];
+    items.extend(load_constant(MASK, 255).into_iter().map(Item::Inst));
+
+    // Whether `CELL` holds the current cell. Both ways into a loop body and
+    // out of a loop have just loaded it.
+    let mut loaded = false;
+    let mut last_line = None;
+    for &(op, line) in ops {
+        if last_line != Some(line) {
+            items.push(Item::Source { file: 0, line });
+            last_line = Some(line);
+        }
+        let mut emit = |inst: Inst| items.push(Item::Inst(inst));
+        if matches!(
+            op,
+            Op::Add(_) | Op::Output | Op::LoopStart(_) | Op::LoopEnd(_)
+        ) && !loaded
+        {
+            emit(Inst::Load(CELL, POINTER));
+        }
+        match op {
+            Op::Add(value) => {
+                emit(Inst::Addi(CELL, value.rem_euclid(256) as u8 as i8));
+                emit(Inst::And(CELL, CELL, MASK));
+                emit(Inst::Store(CELL, POINTER));
+                loaded = true;
+            }
+            Op::Move(mut distance) => {
+                while distance != 0 {
+                    let step = distance.clamp(-128, 127);
+                    emit(Inst::Addi(POINTER, step as i8));
+                    distance -= step;
+                }
+                loaded = false;
+            }
+            Op::Output => {
+                emit(Inst::Out(CELL, OUTPUT_DEVICE));
+                loaded = true;
+            }
+            Op::Input => {
+                emit(Inst::In(CELL, INPUT_DEVICE));
+                emit(Inst::And(CELL, CELL, MASK));
+                emit(Inst::Store(CELL, POINTER));
+                loaded = true;
+            }
+            Op::Clear => {
+                emit(Inst::Store(ZERO, POINTER));
+                loaded = false;
+            }
+            Op::LoopStart(number) => {
+                items.push(Item::SetLabel(ADDRESS, format!("end{}", number)));
+                items.push(Item::Inst(Inst::J(
+                    ADDRESS,
+                    Some((CELL, Flag::Equal, ZERO)),
+                )));
+                items.push(Item::Label(format!("loop{}", number)));
+                loaded = true;
+            }
+            // Cells never go below 0, so any other value is greater
+            Op::LoopEnd(number) => {
+                items.push(Item::SetLabel(ADDRESS, format!("loop{}", number)));
+                items.push(Item::Inst(Inst::J(
+                    ADDRESS,
+                    Some((CELL, Flag::Greater, ZERO)),
+                )));
+                items.push(Item::Label(format!("end{}", number)));
+                loaded = true;
+            }
+        }
+    }
+    items.push(Item::Inst(Inst::Halt));
+    items
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::{Bus, InputQueue, OnEmpty, Source};
+    use crate::simulator::{Cpu, Status};
+
+    /// The values the program writes to the display, reading `input`.
+    fn run(code: &str, input: Vec<i16>) -> Vec<i16> {
+        let console = InputQueue::new(CONSOLE as usize, Source::Values(input), OnEmpty::Fault);
+        let bus = Bus::default().attach(CONSOLE as usize, Box::new(console));
+        let mut cpu = Cpu::with_bus(&BrainfuckCompiler::new().compile(code), bus);
+        assert_eq!(cpu.run(100_000), Ok(Status::Halted));
+        assert!(cpu.outputs().iter().all(|&(device, _)| device == DISPLAY));
+        cpu.outputs().iter().map(|&(_, value)| value).collect()
+    }
+
+    #[test]
+    fn cells_wrap_around_at_256() {
+        assert_eq!(run("-.", vec![]), [255]);
+        assert_eq!(run(&format!("{}.", "+".repeat(257)), vec![]), [1]);
+        assert_eq!(run("+-.>--<.>.", vec![]), [0, 0, 254]);
+    }
+
+    #[test]
+    fn loops_run_until_the_cell_is_0() {
+        assert_eq!(run("++++++[>++++++++<-]>.", vec![]), [48]);
+        assert_eq!(run("++[>++[>+++<-]<-]>>.<<.", vec![]), [12, 0]);
+        assert_eq!(run("+++[-].[+].", vec![]), [0, 0]);
+        assert_eq!(run("[.]+.", vec![]), [1]);
+    }
+
+    #[test]
+    fn input_is_kept_to_8_bits() {
+        assert_eq!(run(",[.,]", vec![72, 105, 0]), [72, 105]);
+        assert_eq!(run(",.,.", vec![300, -1]), [44, 255]);
+    }
+}
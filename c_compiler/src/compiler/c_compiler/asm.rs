@@ -0,0 +1,516 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use super::debug::Variable;
+
+pub type Reg = u8;
+
+/// Register used to build values that do not fit into an 8 bit immediate.
+pub const SCRATCH: Reg = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Flag {
+    Less,
+    Equal,
+    Greater,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShiftOp {
+    Left,
+    LogicalRight,
+    ArithmeticRight,
+}
+
+/// The complete instruction set, not all of which the compiler emits.
+#[allow(dead_code)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Inst {
+    Nop,
+    Load(Reg, Reg),
+    Store(Reg, Reg),
+    Add(Reg, Reg, Reg),
+    Addi(Reg, i8),
+    Sub(Reg, Reg, Reg),
+    And(Reg, Reg, Reg),
+    Xor(Reg, Reg, Reg),
+    J(Reg, Option<(Reg, Flag, Reg)>),
+    Jal(Reg, Option<(Reg, Flag, Reg)>),
+    Ssp(Reg),
+    Set(Reg, i8),
+    Ret,
+    Sft(Reg, Reg, ShiftOp, Reg),
+    In(Reg, u8),
+    Out(Reg, u8),
+    Halt,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Item {
+    Inst(Inst),
+    Label(String),
+    /// Loads the ROM address of a label into a register. Addresses above 127
+    /// do not fit into `set` and are built with the `SCRATCH` register.
+    SetLabel(Reg, String),
+    /// The following instructions were generated from this source line.
+    Source {
+        file: usize,
+        line: usize,
+    },
+    /// Where the source variables are kept from here on.
+    Variables(Vec<Variable>),
+}
+
+impl Inst {
+    pub fn encode(&self) -> u16 {
+        let r = |reg: &Reg| *reg as u16;
+        match self {
+            Inst::Nop => 0,
+            Inst::Load(target, address) => (1 << 12) | (r(target) << 9) | (r(address) << 3),
+            Inst::Store(source, address) => (2 << 12) | (r(source) << 6) | (r(address) << 3),
+            Inst::Add(target, a, b) => (3 << 12) | (r(target) << 9) | (r(a) << 6) | (r(b) << 3),
+            Inst::Addi(target, imm) => (4 << 12) | (r(target) << 9) | (*imm as u8 as u16),
+            Inst::Sub(target, a, b) => (5 << 12) | (r(target) << 9) | (r(a) << 6) | (r(b) << 3),
+            Inst::And(target, a, b) => (6 << 12) | (r(target) << 9) | (r(a) << 6) | (r(b) << 3),
+            Inst::Xor(target, a, b) => (7 << 12) | (r(target) << 9) | (r(a) << 6) | (r(b) << 3),
+            Inst::J(target, condition) => (8 << 12) | encode_jump(*target, condition),
+            Inst::Jal(target, condition) => (9 << 12) | encode_jump(*target, condition),
+            Inst::Ssp(source) => (10 << 12) | (r(source) << 6),
+            Inst::Set(target, imm) => (11 << 12) | (r(target) << 9) | (*imm as u8 as u16),
+            Inst::Ret => 12 << 12,
+            Inst::Sft(target, a, op, steps) => {
+                let op = match op {
+                    ShiftOp::Left => 0,
+                    ShiftOp::LogicalRight => 1,
+                    ShiftOp::ArithmeticRight => 2,
+                };
+                (13 << 12) | (r(target) << 9) | (r(a) << 6) | (r(steps) << 3) | (op << 1)
+            }
+            Inst::In(target, device) => (14 << 12) | (r(target) << 9) | ((*device as u16) << 6),
+            Inst::Out(source, device) => {
+                (14 << 12) | (r(source) << 9) | ((*device as u16) << 6) | (1 << 5)
+            }
+            Inst::Halt => 15 << 12,
+        }
+    }
+}
+
+impl Inst {
+    /// Replaces every register the instruction names.
+    pub fn map_regs(&mut self, f: impl Fn(Reg) -> Reg) {
+        match self {
+            Inst::Nop | Inst::Ret | Inst::Halt => (),
+            Inst::Load(a, b) | Inst::Store(a, b) => {
+                *a = f(*a);
+                *b = f(*b);
+            }
+            Inst::Add(a, b, c) | Inst::Sub(a, b, c) | Inst::And(a, b, c) | Inst::Xor(a, b, c) => {
+                *a = f(*a);
+                *b = f(*b);
+                *c = f(*c);
+            }
+            Inst::Sft(a, b, _, c) => {
+                *a = f(*a);
+                *b = f(*b);
+                *c = f(*c);
+            }
+            Inst::J(target, condition) | Inst::Jal(target, condition) => {
+                *target = f(*target);
+                if let Some((a, _, b)) = condition {
+                    *a = f(*a);
+                    *b = f(*b);
+                }
+            }
+            Inst::Addi(a, _)
+            | Inst::Ssp(a)
+            | Inst::Set(a, _)
+            | Inst::In(a, _)
+            | Inst::Out(a, _) => *a = f(*a),
+        }
+    }
+}
+
+impl Item {
+    pub fn map_regs(&mut self, f: impl Fn(Reg) -> Reg) {
+        match self {
+            Item::Inst(inst) => inst.map_regs(f),
+            Item::SetLabel(target, _) => *target = f(*target),
+            Item::Label(_) | Item::Source { .. } | Item::Variables(_) => (),
+        }
+    }
+}
+
+impl fmt::Display for Inst {
+    /// Formats the instruction in the syntax of `AssemblyCompiler`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Inst::Nop => write!(f, "nop"),
+            Inst::Load(target, address) => write!(f, "load x{} x{}", target, address),
+            Inst::Store(source, address) => write!(f, "store x{} x{}", source, address),
+            Inst::Add(target, a, b) => write!(f, "add x{} x{} x{}", target, a, b),
+            Inst::Addi(target, imm) => write!(f, "addi x{} {}", target, imm),
+            Inst::Sub(target, a, b) => write!(f, "sub x{} x{} x{}", target, a, b),
+            Inst::And(target, a, b) => write!(f, "and x{} x{} x{}", target, a, b),
+            Inst::Xor(target, a, b) => write!(f, "xor x{} x{} x{}", target, a, b),
+            Inst::J(target, condition) => write_jump(f, "j", *target, condition),
+            Inst::Jal(target, condition) => write_jump(f, "jal", *target, condition),
+            Inst::Ssp(source) => write!(f, "ssp x{}", source),
+            Inst::Set(target, imm) => write!(f, "set x{} {}", target, imm),
+            Inst::Ret => write!(f, "ret"),
+            Inst::Sft(target, a, op, steps) => {
+                let op = match op {
+                    ShiftOp::Left => "<<",
+                    ShiftOp::LogicalRight => ">>>",
+                    ShiftOp::ArithmeticRight => ">>",
+                };
+                write!(f, "sft x{} x{} {} x{}", target, a, op, steps)
+            }
+            Inst::In(target, device) => write!(f, "in x{} {}", target, device),
+            Inst::Out(source, device) => write!(f, "out x{} {}", source, device),
+            Inst::Halt => write!(f, "halt"),
+        }
+    }
+}
+
+fn write_jump(
+    f: &mut fmt::Formatter<'_>,
+    name: &str,
+    target: Reg,
+    condition: &Option<(Reg, Flag, Reg)>,
+) -> fmt::Result {
+    write!(f, "{} x{}", name, target)?;
+    if let Some((a, flag, b)) = condition {
+        let flag = match flag {
+            Flag::Less => "<",
+            Flag::Equal => "=",
+            Flag::Greater => ">",
+        };
+        write!(f, " x{} {} x{}", a, flag, b)?;
+    }
+    Ok(())
+}
+
+fn encode_jump(target: Reg, condition: &Option<(Reg, Flag, Reg)>) -> u16 {
+    let mut hex = (target as u16) << 9;
+    if let Some((a, flag, b)) = condition {
+        let flag = match flag {
+            Flag::Less => 1,
+            Flag::Equal => 2,
+            Flag::Greater => 3,
+        };
+        hex |= ((*a as u16) << 6) | ((*b as u16) << 3) | (flag << 1);
+    }
+    hex
+}
+
+/// Instructions that load an arbitrary 16 bit constant into `target`.
+pub fn load_constant(target: Reg, value: i16) -> Vec<Inst> {
+    assert!(
+        target != SCRATCH,
+        "Cannot load a wide constant into the scratch register"
+    );
+
+    if (-128..=127).contains(&value) {
+        return vec![Inst::Set(target, value as i8)];
+    }
+
+    let low = value as i8;
+    let high = (value.wrapping_sub(low as i16) >> 8) as i8;
+    let mut insts = vec![
+        Inst::Set(target, high),
+        Inst::Set(SCRATCH, 8),
+        Inst::Sft(target, target, ShiftOp::Left, SCRATCH),
+    ];
+    if low != 0 {
+        insts.push(Inst::Addi(target, low));
+    }
+    insts
+}
+
+/// Resolves labels and encodes the instructions into ROM words.
+pub fn assemble(items: &[Item]) -> Vec<u16> {
+    let (labels, far) = layout(items);
+
+    let mut hex_code = Vec::new();
+    for (i, item) in items.iter().enumerate() {
+        match item {
+            Item::Inst(inst) => hex_code.push(inst.encode()),
+            Item::Label(_) | Item::Source { .. } | Item::Variables(_) => (),
+            Item::SetLabel(target, label) => {
+                let address = resolve(&labels, label);
+                let insts = if far[i] {
+                    far_label_load(*target, address)
+                } else {
+                    vec![Inst::Set(*target, address as i8)]
+                };
+                hex_code.extend(insts.iter().map(Inst::encode));
+            }
+        }
+    }
+
+    hex_code
+}
+
+/// Renders the items as assembly text that `AssemblyCompiler` turns into
+/// the same words as `assemble`. Source markers become comments quoting the
+/// line they refer to, `files` holds the name and contents of each source.
+pub fn to_text(items: &[Item], files: &[(String, String)]) -> String {
+    let (labels, far) = layout(items);
+
+    let mut text = String::new();
+    for (i, item) in items.iter().enumerate() {
+        match item {
+            Item::Inst(inst) => text += &format!("    {}\n", inst),
+            Item::Label(name) => text += &format!(":{}\n", name),
+            Item::SetLabel(target, label) if far[i] => {
+                let address = resolve(&labels, label);
+                text += &format!("    # {} = {}\n", label, address);
+                text += &format!("    set x{} %hi({})\n", target, label);
+                text += &format!("    set x{} 8\n", SCRATCH);
+                text += &format!("    sft x{} x{} << x{}\n", target, target, SCRATCH);
+                text += &format!("    addi x{} %lo({})\n", target, label);
+            }
+            Item::SetLabel(target, label) => text += &format!("    set x{} {}\n", target, label),
+            Item::Source { file, line } => {
+                let (name, source) = &files[*file];
+                let code = source.lines().nth(line.wrapping_sub(1)).unwrap_or("");
+                text += &format!("# {}:{}: {}\n", name, line, code.trim());
+            }
+            Item::Variables(_) => (),
+        }
+    }
+    text
+}
+
+/// Parses one line in the syntax of `AssemblyCompiler`. Besides `x0` to
+/// `x7`, registers may be written as `%0` up to `%{operands - 1}`, which
+/// become register numbers from 8 on for the caller to replace. Comments
+/// run from `#` to the end of the line. Returns `None` for empty lines.
+pub fn parse_line(text: &str, operands: usize) -> Result<Option<Item>, String> {
+    let text = text.split('#').next().unwrap().trim();
+    if text.is_empty() {
+        return Ok(None);
+    }
+    if let Some(label) = text.strip_prefix(':') {
+        return Ok(Some(Item::Label(label.trim().to_string())));
+    }
+
+    let words: Vec<&str> = text.split_ascii_whitespace().collect();
+    let mnemonic = words[0].to_lowercase();
+    let arguments = |counts: &[usize]| {
+        let count = words.len() - 1;
+        match counts.contains(&count) {
+            true => Ok(count),
+            false => Err(format!(
+                "Wrong number of arguments for {}: expected {}, found {}",
+                words[0], counts[0], count
+            )),
+        }
+    };
+    let reg = |i: usize| parse_reg(words[i], operands);
+    let imm = |i: usize| {
+        let value: i32 = words[i]
+            .parse()
+            .map_err(|_| format!("Unknown argument: {}", words[i]))?;
+        match (-128..=127).contains(&value) {
+            true => Ok(value as i8),
+            false => Err(format!(
+                "Immediate out of range: {}. Must be -128 <= imm <= 127",
+                words[i]
+            )),
+        }
+    };
+    let device = |i: usize| match words[i].parse::<u8>() {
+        Ok(device) if device < 8 => Ok(device),
+        _ => Err(format!("Unknown device: {}", words[i])),
+    };
+    let jump = || -> Result<(Reg, Condition), String> {
+        if arguments(&[1, 4])? == 1 {
+            return Ok((reg(1)?, None));
+        }
+        let flag = match words[3] {
+            "<" => Flag::Less,
+            "=" => Flag::Equal,
+            ">" => Flag::Greater,
+            flag => return Err(format!("Unknown flag: {}", flag)),
+        };
+        Ok((reg(1)?, Some((reg(2)?, flag, reg(4)?))))
+    };
+
+    let inst = match mnemonic.as_str() {
+        "nop" | "ret" | "halt" => {
+            arguments(&[0])?;
+            match mnemonic.as_str() {
+                "nop" => Inst::Nop,
+                "ret" => Inst::Ret,
+                _ => Inst::Halt,
+            }
+        }
+        "load" | "store" => {
+            arguments(&[2])?;
+            match mnemonic.as_str() {
+                "load" => Inst::Load(reg(1)?, reg(2)?),
+                _ => Inst::Store(reg(1)?, reg(2)?),
+            }
+        }
+        "add" | "sub" | "and" | "xor" => {
+            arguments(&[3])?;
+            let (target, a, b) = (reg(1)?, reg(2)?, reg(3)?);
+            match mnemonic.as_str() {
+                "add" => Inst::Add(target, a, b),
+                "sub" => Inst::Sub(target, a, b),
+                "and" => Inst::And(target, a, b),
+                _ => Inst::Xor(target, a, b),
+            }
+        }
+        "addi" => {
+            arguments(&[2])?;
+            Inst::Addi(reg(1)?, imm(2)?)
+        }
+        "j" => {
+            let (target, condition) = jump()?;
+            Inst::J(target, condition)
+        }
+        "jal" => {
+            let (target, condition) = jump()?;
+            Inst::Jal(target, condition)
+        }
+        "ssp" => {
+            arguments(&[1])?;
+            Inst::Ssp(reg(1)?)
+        }
+        "set" => {
+            arguments(&[2])?;
+            let target = reg(1)?;
+            if words[2].parse::<i32>().is_err() {
+                if target == SCRATCH {
+                    return Err(format!("Cannot load a label into x{}", SCRATCH));
+                }
+                return Ok(Some(Item::SetLabel(target, words[2].to_string())));
+            }
+            Inst::Set(target, imm(2)?)
+        }
+        "sft" => {
+            arguments(&[4])?;
+            let op = match words[3] {
+                "<<" | "<<<" => ShiftOp::Left,
+                ">>>" => ShiftOp::LogicalRight,
+                ">>" => ShiftOp::ArithmeticRight,
+                op => return Err(format!("Unknown shift operation: {}", op)),
+            };
+            Inst::Sft(reg(1)?, reg(2)?, op, reg(4)?)
+        }
+        "in" | "out" => {
+            arguments(&[2])?;
+            match mnemonic.as_str() {
+                "in" => Inst::In(reg(1)?, device(2)?),
+                _ => Inst::Out(reg(1)?, device(2)?),
+            }
+        }
+        _ => return Err(format!("Unknown instruction: {}", words[0])),
+    };
+    Ok(Some(Item::Inst(inst)))
+}
+
+type Condition = Option<(Reg, Flag, Reg)>;
+
+fn parse_reg(word: &str, operands: usize) -> Result<Reg, String> {
+    let (number, first) = match (word.strip_prefix('x'), word.strip_prefix('%')) {
+        (Some(number), _) => (number, 0),
+        (_, Some(number)) => (number, 8),
+        _ => return Err(format!("Unknown argument: {}", word)),
+    };
+    let limit = if first == 0 { 8 } else { operands };
+    match number.parse::<usize>() {
+        Ok(n) if n < limit => Ok((first + n) as Reg),
+        Ok(_) if first == 0 => Err(format!("Register index too large: {}", word)),
+        Ok(_) => Err(format!("Unknown operand: {}", word)),
+        Err(_) => Err(format!("Unknown argument: {}", word)),
+    }
+}
+
+/// Finds the address of every label. Label loads start short and grow
+/// until every address fits. Growing only moves labels further back, so
+/// this terminates.
+fn layout(items: &[Item]) -> (HashMap<String, usize>, Vec<bool>) {
+    let mut far: Vec<bool> = vec![false; items.len()];
+    loop {
+        let labels = label_addresses(items, &far);
+        let mut changed = false;
+        for (i, item) in items.iter().enumerate() {
+            if let Item::SetLabel(_, label) = item {
+                if !far[i] && resolve(&labels, label) > 127 {
+                    far[i] = true;
+                    changed = true;
+                }
+            }
+        }
+        if !changed {
+            return (labels, far);
+        }
+    }
+}
+
+/// Same as `load_constant`, but always four instructions so the layout does
+/// not depend on the address. Matches `%hi` and `%lo` of the assembler.
+fn far_label_load(target: Reg, address: usize) -> Vec<Inst> {
+    let address = address as u16 as i16;
+    let low = address as i8;
+    let high = (address.wrapping_sub(low as i16) >> 8) as i8;
+    vec![
+        Inst::Set(target, high),
+        Inst::Set(SCRATCH, 8),
+        Inst::Sft(target, target, ShiftOp::Left, SCRATCH),
+        Inst::Addi(target, low),
+    ]
+}
+
+/// The ROM address of every item.
+pub fn addresses(items: &[Item]) -> Vec<usize> {
+    let mut address = 0;
+    let mut addresses = Vec::with_capacity(items.len());
+    for size in sizes(items) {
+        addresses.push(address);
+        address += size;
+    }
+    addresses
+}
+
+/// The number of ROM words every item takes.
+pub fn sizes(items: &[Item]) -> Vec<usize> {
+    let (_, far) = layout(items);
+    items
+        .iter()
+        .zip(far)
+        .map(|(item, far)| size(item, far))
+        .collect()
+}
+
+fn label_addresses(items: &[Item], far: &[bool]) -> HashMap<String, usize> {
+    let mut labels = HashMap::new();
+    let mut address = 0;
+    for (item, &far) in items.iter().zip(far) {
+        if let Item::Label(name) = item {
+            labels.insert(name.clone(), address);
+        }
+        address += size(item, far);
+    }
+    labels
+}
+
+/// Number of ROM words an item takes.
+fn size(item: &Item, far: bool) -> usize {
+    match item {
+        Item::Inst(_) => 1,
+        Item::SetLabel(..) if far => 4,
+        Item::SetLabel(..) => 1,
+        Item::Label(_) | Item::Source { .. } | Item::Variables(_) => 0,
+    }
+}
+
+fn resolve(labels: &HashMap<String, usize>, label: &str) -> usize {
+    *labels
+        .get(label)
+        .unwrap_or_else(|| panic!("Undefined label: {}", label))
+}
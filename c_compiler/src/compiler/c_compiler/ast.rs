@@ -0,0 +1,529 @@
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+    Void,
+    /// Integers up to `int` take a 16 bit word. A `char` holds 8 bits that
+    /// are sign or zero extended to the whole word, `short` is the same as
+    /// `int`. Values are converted when they are stored, so loads need no
+    /// extension. A 32 bit `long` takes two words, the low word first.
+    Integer {
+        bits: u8,
+        signed: bool,
+    },
+    Pointer(Box<Type>),
+    Array(Box<Type>, Option<usize>),
+    /// A struct by its tag, whose layout is in `Structs`.
+    Struct(String),
+    /// Return type and parameter types. Functions are only used through
+    /// pointers to them.
+    Function(Box<Type>, Vec<Type>),
+}
+
+/// Layouts of the structs of a program by their tag.
+pub type Structs = HashMap<String, Struct>;
+
+#[derive(Debug, Clone)]
+pub struct Struct {
+    pub members: Vec<Member>,
+    /// Size in words.
+    pub size: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub name: String,
+    pub member_type: Type,
+    /// Offset in words from the start of the struct.
+    pub offset: usize,
+}
+
+impl Struct {
+    pub fn member(&self, name: &str) -> Option<&Member> {
+        self.members.iter().find(|m| m.name == name)
+    }
+}
+
+impl Type {
+    pub const INT: Type = Type::Integer {
+        bits: 16,
+        signed: true,
+    };
+    pub const UNSIGNED: Type = Type::Integer {
+        bits: 16,
+        signed: false,
+    };
+    pub const CHAR: Type = Type::Integer {
+        bits: 8,
+        signed: true,
+    };
+    pub const LONG: Type = Type::Integer {
+        bits: 32,
+        signed: true,
+    };
+    pub const ULONG: Type = Type::Integer {
+        bits: 32,
+        signed: false,
+    };
+
+    /// Size in 16 bit memory words.
+    pub fn size(&self, structs: &Structs, line: usize) -> usize {
+        match self {
+            Type::Void => panic!("Void has no size in line {}", line),
+            Type::Integer { bits: 32, .. } => 2,
+            Type::Integer { .. } | Type::Pointer(_) => 1,
+            Type::Array(element, Some(length)) => element.size(structs, line) * length,
+            Type::Array(_, None) => panic!("Array has unknown size in line {}", line),
+            Type::Function(..) => panic!("Function has no size in line {}", line),
+            Type::Struct(tag) => match structs.get(tag) {
+                Some(layout) => layout.size,
+                None => panic!("Struct {} is incomplete in line {}", tag, line),
+            },
+        }
+    }
+
+    pub fn is_integer(&self) -> bool {
+        matches!(self, Type::Integer { .. })
+    }
+
+    /// Whether values of the type take a pair of registers.
+    pub fn is_long(&self) -> bool {
+        matches!(self, Type::Integer { bits: 32, .. })
+    }
+
+    /// Arrays and structs, which are used through their address.
+    pub fn is_aggregate(&self) -> bool {
+        matches!(self, Type::Array(..) | Type::Struct(_))
+    }
+
+    /// Integers narrower than `int` are computed with as `int`.
+    pub fn promote(&self) -> Type {
+        match self {
+            Type::Integer { bits, .. } if *bits < 16 => Type::INT,
+            other => other.clone(),
+        }
+    }
+
+    /// The type arithmetic on two integers is done in: `long` if either
+    /// operand is, and unsigned if an operand of that size is.
+    pub fn common(&self, other: &Type) -> Type {
+        if self.is_long() || other.is_long() {
+            match *self == Type::ULONG || *other == Type::ULONG {
+                true => Type::ULONG,
+                false => Type::LONG,
+            }
+        } else if self.promote() == Type::UNSIGNED || other.promote() == Type::UNSIGNED {
+            Type::UNSIGNED
+        } else {
+            Type::INT
+        }
+    }
+
+    pub fn is_unsigned(&self) -> bool {
+        matches!(self, Type::Integer { signed: false, .. })
+    }
+
+    /// Converts a constant to this type the way storing it would.
+    pub fn convert_constant(&self, value: i64) -> i64 {
+        match self {
+            Type::Integer { bits: 8, signed } => match signed {
+                true => value as i8 as i64,
+                false => value & 0xff,
+            },
+            Type::Integer { bits: 32, signed } => match signed {
+                true => value as i32 as i64,
+                false => value as u32 as i64,
+            },
+            Type::Integer { signed: true, .. } => value as i16 as i64,
+            Type::Integer { signed: false, .. } => value as u16 as i64,
+            _ => value,
+        }
+    }
+
+    /// The type pointed to by a pointer or the element type of an array.
+    pub fn target(&self) -> Option<&Type> {
+        match self {
+            Type::Pointer(target) | Type::Array(target, _) => Some(target),
+            _ => None,
+        }
+    }
+
+    pub fn is_pointer_like(&self) -> bool {
+        self.target().is_some()
+    }
+
+    /// Arrays are used as pointers to their first element in expressions,
+    /// functions as pointers to them.
+    pub fn decay(&self) -> Type {
+        match self {
+            Type::Array(element, _) => Type::Pointer(element.clone()),
+            Type::Function(..) => Type::Pointer(Box::new(self.clone())),
+            other => other.clone(),
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Void => write!(f, "void"),
+            Type::Integer { bits, signed } => {
+                let sign = if *signed { "" } else { "unsigned " };
+                let name = match bits {
+                    8 => "char",
+                    32 => "long",
+                    _ => "int",
+                };
+                write!(f, "{}{}", sign, name)
+            }
+            Type::Pointer(target) => write!(f, "{}*", target),
+            Type::Array(element, Some(length)) => write!(f, "{}[{}]", element, length),
+            Type::Array(element, None) => write!(f, "{}[]", element),
+            Type::Struct(tag) => write!(f, "struct {}", tag),
+            Type::Function(return_type, params) => {
+                let params: Vec<String> = params.iter().map(|p| p.to_string()).collect();
+                write!(f, "{}({})", return_type, params.join(", "))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Program {
+    pub globals: Vec<Declaration>,
+    pub functions: Vec<Function>,
+    pub structs: Structs,
+}
+
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub name: String,
+    pub return_type: Type,
+    pub params: Vec<(Type, String)>,
+    pub body: Option<Vec<Stmt>>,
+    pub line: usize,
+    /// Index of the source file the function comes from.
+    pub file: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Declaration {
+    pub name: String,
+    pub var_type: Type,
+    pub init: Option<Initializer>,
+    pub line: usize,
+    /// Whether a local keeps its value between calls, in the data image
+    /// like a global.
+    pub is_static: bool,
+    /// Whether the variable itself is `const`, which forbids assigning it
+    /// after its initializer.
+    pub constant: bool,
+}
+
+#[derive(Debug, Clone)]
+pub enum Initializer {
+    Expr(Expr),
+    /// A braced list, whose items may be braced lists again.
+    List(Vec<Initializer>),
+}
+
+impl Initializer {
+    pub fn walk(&self, f: &mut dyn FnMut(&Expr)) {
+        match self {
+            Initializer::Expr(expr) => expr.walk(f),
+            Initializer::List(items) => {
+                for item in items {
+                    item.walk(f);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Stmt {
+    Decl(Declaration),
+    Expr(Expr),
+    If(Expr, Box<Stmt>, Option<Box<Stmt>>),
+    While(Expr, Box<Stmt>),
+    DoWhile(Box<Stmt>, Expr),
+    For(Option<Box<Stmt>>, Option<Expr>, Option<Expr>, Box<Stmt>),
+    Switch(Expr, Box<Stmt>),
+    /// `case value: stmt`, where the value has to be a constant.
+    Case(Expr, Box<Stmt>),
+    /// `default: stmt`, with the line of the label.
+    Default(usize, Box<Stmt>),
+    Return(Option<Expr>),
+    Break(usize),
+    Continue(usize),
+    Block(Vec<Stmt>),
+    Asm(Asm),
+}
+
+/// `asm("template" : outputs : inputs : clobbers);` with the operands
+/// numbered from 0 in that order.
+#[derive(Debug, Clone)]
+pub struct Asm {
+    pub template: String,
+    pub outputs: Vec<AsmOperand>,
+    pub inputs: Vec<AsmOperand>,
+    pub clobbers: Vec<String>,
+    pub line: usize,
+}
+
+/// `[name] "constraint" (expr)`, the name being optional.
+#[derive(Debug, Clone)]
+pub struct AsmOperand {
+    pub name: Option<String>,
+    pub constraint: String,
+    pub expr: Expr,
+}
+
+#[derive(Debug, Clone)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub line: usize,
+}
+
+#[derive(Debug, Clone)]
+pub enum ExprKind {
+    Number(i64),
+    Str(Vec<u8>),
+    Ident(String),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    Assign(Option<BinaryOp>, Box<Expr>, Box<Expr>),
+    IncDec {
+        increment: bool,
+        prefix: bool,
+        target: Box<Expr>,
+    },
+    Conditional(Box<Expr>, Box<Expr>, Box<Expr>),
+    /// Call of a function by name or through a pointer.
+    Call(Box<Expr>, Vec<Expr>),
+    Index(Box<Expr>, Box<Expr>),
+    Deref(Box<Expr>),
+    AddressOf(Box<Expr>),
+    /// `a.b`, and `a->b` as the member of `*a`.
+    Member(Box<Expr>, String),
+    Cast(Type, Box<Expr>),
+    /// `sizeof` of an expression. The size of a type is a `Number` already.
+    SizeOf(Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+    BitNot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    And,
+    Or,
+    Xor,
+    Shl,
+    Shr,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    LogicAnd,
+    LogicOr,
+}
+
+impl BinaryOp {
+    pub fn is_comparison(&self) -> bool {
+        matches!(
+            self,
+            BinaryOp::Eq | BinaryOp::Ne | BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge
+        )
+    }
+}
+
+impl Expr {
+    pub fn new(kind: ExprKind, line: usize) -> Expr {
+        Expr { kind, line }
+    }
+
+    /// Evaluates integer constant expressions such as array sizes.
+    pub fn constant_value(&self) -> Option<i64> {
+        match &self.kind {
+            ExprKind::Number(value) => Some(*value),
+            ExprKind::Unary(op, operand) => {
+                let value = operand.constant_value()?;
+                Some(match op {
+                    UnaryOp::Neg => -value,
+                    UnaryOp::Not => (value == 0) as i64,
+                    UnaryOp::BitNot => !value,
+                })
+            }
+            ExprKind::Binary(op, left, right) => {
+                let a = left.constant_value()?;
+                let b = right.constant_value()?;
+                Some(match op {
+                    BinaryOp::Add => a.wrapping_add(b),
+                    BinaryOp::Sub => a.wrapping_sub(b),
+                    BinaryOp::Mul => a.wrapping_mul(b),
+                    BinaryOp::Div => a.checked_div(b)?,
+                    BinaryOp::Mod => a.checked_rem(b)?,
+                    BinaryOp::And => a & b,
+                    BinaryOp::Or => a | b,
+                    BinaryOp::Xor => a ^ b,
+                    BinaryOp::Shl => a.checked_shl(b as u32)?,
+                    BinaryOp::Shr => a.checked_shr(b as u32)?,
+                    BinaryOp::Eq => (a == b) as i64,
+                    BinaryOp::Ne => (a != b) as i64,
+                    BinaryOp::Lt => (a < b) as i64,
+                    BinaryOp::Le => (a <= b) as i64,
+                    BinaryOp::Gt => (a > b) as i64,
+                    BinaryOp::Ge => (a >= b) as i64,
+                    BinaryOp::LogicAnd => (a != 0 && b != 0) as i64,
+                    BinaryOp::LogicOr => (a != 0 || b != 0) as i64,
+                })
+            }
+            ExprKind::Conditional(condition, then, otherwise) => {
+                if condition.constant_value()? != 0 {
+                    then.constant_value()
+                } else {
+                    otherwise.constant_value()
+                }
+            }
+            ExprKind::Cast(target, operand) if target.is_integer() => {
+                Some(target.convert_constant(operand.constant_value()?))
+            }
+            ExprKind::Cast(Type::Pointer(_), operand) => operand.constant_value(),
+            _ => None,
+        }
+    }
+}
+
+impl Expr {
+    /// Calls `f` on this expression and all of its subexpressions.
+    pub fn walk(&self, f: &mut dyn FnMut(&Expr)) {
+        f(self);
+        match &self.kind {
+            ExprKind::Number(_) | ExprKind::Str(_) | ExprKind::Ident(_) => (),
+            ExprKind::Unary(_, operand)
+            | ExprKind::Deref(operand)
+            | ExprKind::AddressOf(operand)
+            | ExprKind::Member(operand, _)
+            | ExprKind::Cast(_, operand)
+            | ExprKind::SizeOf(operand)
+            | ExprKind::IncDec {
+                target: operand, ..
+            } => operand.walk(f),
+            ExprKind::Binary(_, left, right)
+            | ExprKind::Assign(_, left, right)
+            | ExprKind::Index(left, right) => {
+                left.walk(f);
+                right.walk(f);
+            }
+            ExprKind::Conditional(condition, then, otherwise) => {
+                condition.walk(f);
+                then.walk(f);
+                otherwise.walk(f);
+            }
+            ExprKind::Call(function, args) => {
+                function.walk(f);
+                for arg in args {
+                    arg.walk(f);
+                }
+            }
+        }
+    }
+}
+
+impl Stmt {
+    /// The `case` and `default` labels of a switch statement with this
+    /// body, with `None` for `default`. Nested switches have their own.
+    pub fn case_labels(&self) -> Vec<(Option<&Expr>, usize)> {
+        let mut labels = Vec::new();
+        self.collect_case_labels(&mut labels);
+        labels
+    }
+
+    fn collect_case_labels<'a>(&'a self, labels: &mut Vec<(Option<&'a Expr>, usize)>) {
+        match self {
+            Stmt::Case(value, body) => {
+                labels.push((Some(value), value.line));
+                body.collect_case_labels(labels);
+            }
+            Stmt::Default(line, body) => {
+                labels.push((None, *line));
+                body.collect_case_labels(labels);
+            }
+            Stmt::If(_, then, otherwise) => {
+                then.collect_case_labels(labels);
+                if let Some(otherwise) = otherwise {
+                    otherwise.collect_case_labels(labels);
+                }
+            }
+            Stmt::While(_, body) | Stmt::DoWhile(body, _) | Stmt::For(_, _, _, body) => {
+                body.collect_case_labels(labels);
+            }
+            Stmt::Block(stmts) => {
+                for stmt in stmts {
+                    stmt.collect_case_labels(labels);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Calls `f` on every expression in this statement and nested statements.
+    pub fn walk_exprs(&self, f: &mut dyn FnMut(&Expr)) {
+        match self {
+            Stmt::Decl(decl) => {
+                if let Some(init) = &decl.init {
+                    init.walk(f);
+                }
+            }
+            Stmt::Expr(expr) | Stmt::Return(Some(expr)) => expr.walk(f),
+            Stmt::If(condition, then, otherwise) => {
+                condition.walk(f);
+                then.walk_exprs(f);
+                if let Some(otherwise) = otherwise {
+                    otherwise.walk_exprs(f);
+                }
+            }
+            Stmt::While(condition, body)
+            | Stmt::DoWhile(body, condition)
+            | Stmt::Switch(condition, body)
+            | Stmt::Case(condition, body) => {
+                condition.walk(f);
+                body.walk_exprs(f);
+            }
+            Stmt::Default(_, body) => body.walk_exprs(f),
+            Stmt::For(init, condition, step, body) => {
+                if let Some(init) = init {
+                    init.walk_exprs(f);
+                }
+                for expr in [condition, step].into_iter().flatten() {
+                    expr.walk(f);
+                }
+                body.walk_exprs(f);
+            }
+            Stmt::Block(stmts) => {
+                for stmt in stmts {
+                    stmt.walk_exprs(f);
+                }
+            }
+            Stmt::Asm(asm) => {
+                for operand in asm.outputs.iter().chain(&asm.inputs) {
+                    operand.expr.walk(f);
+                }
+            }
+            Stmt::Return(None) | Stmt::Break(_) | Stmt::Continue(_) => (),
+        }
+    }
+}
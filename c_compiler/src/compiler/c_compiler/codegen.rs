@@ -0,0 +1,436 @@
+//! Turns register allocated IR into BEPL instructions.
+
+use std::collections::HashMap;
+
+use super::asm::{load_constant, Flag, Inst, Item, Reg, ShiftOp, SCRATCH};
+use super::debug::{self, Variable};
+use super::ir::{BinOp, BlockId, Cond, Function, Op, Terminator};
+
+/// Holds jump targets and addresses of stores.
+pub const ADDRESS: Reg = 6;
+/// Software stack pointer for locals, arguments and spilled registers.
+const SP: Reg = 7;
+
+/// Stack frame of a function, from the stack pointer upwards:
+///
+/// - arguments of the calls the function makes
+/// - frame slots of the IR function: arrays, variables whose address is
+///   taken and spilled registers
+/// - the arguments passed by the caller
+#[derive(Default)]
+struct Frame {
+    outgoing: usize,
+    size: usize,
+}
+
+pub struct CodeGen {
+    items: Vec<Item>,
+}
+
+impl CodeGen {
+    pub fn new() -> CodeGen {
+        CodeGen { items: Vec::new() }
+    }
+
+    /// Generates the startup code followed by the given functions with the
+    /// registers assigned to their virtual registers. `function_addresses`
+    /// are the data words that hold the address of a function.
+    pub fn generate(
+        mut self,
+        functions: &[(Function, Vec<Reg>)],
+        data: &[i16],
+        function_addresses: &[(usize, String)],
+    ) -> Vec<Item> {
+        // Jump tables go behind the data image. Their entries are ROM
+        // addresses, which only the assembler knows, as are the addresses
+        // of functions.
+        let mut tables: Vec<(usize, Vec<String>)> = function_addresses
+            .iter()
+            .map(|(address, name)| (*address, vec![name.clone()]))
+            .collect();
+        let mut addresses = Vec::new();
+        let mut next = data.len();
+        for (function, _) in functions {
+            let mut function_tables = HashMap::new();
+            for (id, block) in function.blocks.iter().enumerate() {
+                if let Terminator::Table(_, targets) = &block.terminator {
+                    let labels = targets.iter().map(|t| block_label(function, *t));
+                    tables.push((next, labels.collect::<Vec<_>>()));
+                    function_tables.insert(id, next);
+                    next += targets.len();
+                }
+            }
+            addresses.push(function_tables);
+        }
+
+        self.gen_startup(data, &tables);
+        for ((function, registers), tables) in functions.iter().zip(&addresses) {
+            FunctionGen {
+                items: &mut self.items,
+                function,
+                registers,
+                tables,
+                frame: Frame::default(),
+                line: None,
+                variables: Vec::new(),
+            }
+            .generate();
+        }
+        self.items
+    }
+
+    /// Sets up both stacks, writes the data image and the label addresses
+    /// into memory and calls main. The return value of main is left in x0
+    /// when the CPU halts.
+    fn gen_startup(&mut self, data: &[i16], labels: &[(usize, Vec<String>)]) {
+        let data_end = labels
+            .iter()
+            .map(|(address, labels)| address + labels.len())
+            .fold(data.len(), usize::max);
+        self.load_constant(0, data_end as i16);
+        self.emit(Inst::Ssp(0));
+        self.emit(Inst::Set(SP, 0));
+
+        let mut last: Option<(usize, i16)> = None;
+        for (address, &value) in data.iter().enumerate() {
+            if value == 0 {
+                continue;
+            }
+            if last.map(|(_, v)| v) != Some(value) {
+                self.load_constant(0, value);
+            }
+            match last {
+                Some((a, _)) if address - a <= 127 => self.emit(Inst::Addi(1, (address - a) as i8)),
+                _ => self.load_constant(1, address as i16),
+            }
+            self.emit(Inst::Store(0, 1));
+            last = Some((address, value));
+        }
+        let mut last = None;
+        for (start, entries) in labels {
+            for (i, label) in entries.iter().enumerate() {
+                let address = start + i;
+                match last {
+                    Some(a) if a < address && address - a <= 127 => {
+                        self.emit(Inst::Addi(1, (address - a) as i8))
+                    }
+                    _ => self.load_constant(1, address as i16),
+                }
+                self.items.push(Item::SetLabel(0, label.clone()));
+                self.emit(Inst::Store(0, 1));
+                last = Some(address);
+            }
+        }
+
+        self.items.push(Item::SetLabel(ADDRESS, "main".to_string()));
+        self.emit(Inst::Jal(ADDRESS, None));
+        self.emit(Inst::Halt);
+    }
+
+    fn emit(&mut self, inst: Inst) {
+        self.items.push(Item::Inst(inst));
+    }
+
+    fn load_constant(&mut self, target: Reg, value: i16) {
+        for inst in load_constant(target, value) {
+            self.emit(inst);
+        }
+    }
+}
+
+fn block_label(function: &Function, block: BlockId) -> String {
+    format!("{}.{}", function.name, block)
+}
+
+struct FunctionGen<'a> {
+    items: &'a mut Vec<Item>,
+    function: &'a Function,
+    registers: &'a [Reg],
+    /// Data addresses of the jump tables of blocks ending in one.
+    tables: &'a HashMap<BlockId, usize>,
+    frame: Frame,
+    /// Source line of the instructions generated last.
+    line: Option<usize>,
+    /// Source variables where the last `Item::Variables` put them.
+    variables: Vec<Variable>,
+}
+
+impl FunctionGen<'_> {
+    fn generate(&mut self) {
+        let outgoing = self
+            .function
+            .blocks
+            .iter()
+            .flat_map(|b| &b.insts)
+            .filter_map(|inst| match inst.op {
+                Op::Call(_, _, args) | Op::CallIndirect(_, _, args) => Some(args),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0);
+        self.frame = Frame {
+            outgoing,
+            size: outgoing + self.function.frame_size,
+        };
+
+        self.items.push(Item::Label(self.function.name.clone()));
+        self.source_line(self.function.line);
+        self.add_sp(-(self.frame.size as i64));
+        let live = debug::live_variables(self.function, self.registers, self.frame.outgoing);
+        for ((id, block), live) in self.function.blocks.iter().enumerate().zip(live) {
+            self.items.push(Item::Label(self.block_label(id)));
+            for (inst, variables) in block.insts.iter().zip(&live) {
+                self.source_line(inst.line);
+                self.mark_variables(variables.clone());
+                self.gen_op(&inst.op);
+            }
+            self.source_line(block.line);
+            self.mark_variables(live.last().cloned().unwrap_or_default());
+            self.gen_terminator(&block.terminator, id);
+        }
+        self.mark_variables(Vec::new());
+    }
+
+    /// Records where the variables are kept from here on, if that changed.
+    fn mark_variables(&mut self, variables: Vec<Variable>) {
+        if self.variables != variables {
+            self.variables = variables.clone();
+            self.items.push(Item::Variables(variables));
+        }
+    }
+
+    /// Marks where the code for a new source line starts.
+    fn source_line(&mut self, line: usize) {
+        if self.line != Some(line) {
+            self.line = Some(line);
+            self.items.push(Item::Source {
+                file: self.function.file,
+                line,
+            });
+        }
+    }
+
+    fn gen_op(&mut self, op: &Op) {
+        let r = |reg: &usize| self.registers[*reg];
+        match op {
+            Op::Const(target, value) => self.load_constant(r(target), *value),
+            Op::Copy(target, source) => self.mov(r(target), r(source)),
+            Op::Binary(op, target, a, b) => {
+                let (target, a, b) = (r(target), r(a), r(b));
+                match op {
+                    BinOp::Add => self.emit(Inst::Add(target, a, b)),
+                    BinOp::Sub => self.emit(Inst::Sub(target, a, b)),
+                    BinOp::And => self.emit(Inst::And(target, a, b)),
+                    BinOp::Xor => self.emit(Inst::Xor(target, a, b)),
+                    BinOp::Or => {
+                        // a | b == (a ^ b) ^ (a & b)
+                        self.emit(Inst::And(SCRATCH, a, b));
+                        self.emit(Inst::Xor(target, a, b));
+                        self.emit(Inst::Xor(target, target, SCRATCH));
+                    }
+                    BinOp::Shl => self.emit(Inst::Sft(target, a, ShiftOp::Left, b)),
+                    BinOp::Shr => self.emit(Inst::Sft(target, a, ShiftOp::ArithmeticRight, b)),
+                    BinOp::UShr => self.emit(Inst::Sft(target, a, ShiftOp::LogicalRight, b)),
+                    BinOp::Mul | BinOp::Div | BinOp::Mod | BinOp::UDiv | BinOp::UMod => {
+                        unreachable!("{:?} is a call to the runtime library", op)
+                    }
+                }
+            }
+            Op::Load(target, address) => self.emit(Inst::Load(r(target), r(address))),
+            Op::Store(value, address) => self.emit(Inst::Store(r(value), r(address))),
+            Op::FrameAddr(target, slot) => {
+                self.stack_address(r(target), self.frame.outgoing + slot);
+            }
+            Op::Param(target, index) => {
+                let target = r(target);
+                self.stack_address(target, self.frame.size + index);
+                self.emit(Inst::Load(target, target));
+            }
+            Op::Arg(value, index) => self.store_stack(r(value), *index),
+            Op::Call(results, name, _) => {
+                self.items.push(Item::SetLabel(ADDRESS, name.clone()));
+                self.emit(Inst::Jal(ADDRESS, None));
+                let moves = results.iter().enumerate().map(|(i, v)| (r(v), i as Reg));
+                self.parallel_move(moves.collect());
+            }
+            Op::CallIndirect(results, function, _) => {
+                self.mov(ADDRESS, r(function));
+                self.emit(Inst::Jal(ADDRESS, None));
+                let moves = results.iter().enumerate().map(|(i, v)| (r(v), i as Reg));
+                self.parallel_move(moves.collect());
+            }
+            Op::FuncAddr(target, name) => self.items.push(Item::SetLabel(r(target), name.clone())),
+            Op::In(target, device) => self.emit(Inst::In(r(target), *device)),
+            Op::Out(value, device) => self.emit(Inst::Out(r(value), *device)),
+            Op::SpillLoad(target, slot) => {
+                let target = r(target);
+                self.stack_address(target, self.frame.outgoing + slot);
+                self.emit(Inst::Load(target, target));
+            }
+            Op::SpillStore(value, slot) => {
+                self.store_stack(r(value), self.frame.outgoing + slot);
+            }
+            Op::Asm(asm) => {
+                for (output, tied) in asm.outputs.iter().zip(&asm.tied) {
+                    if let Some(input) = tied {
+                        self.mov(r(output), r(&asm.inputs[*input]));
+                    }
+                }
+                let operands: Vec<Reg> = asm.outputs.iter().chain(&asm.inputs).map(r).collect();
+                for item in &asm.body {
+                    let mut item = item.clone();
+                    item.map_regs(|reg| match reg {
+                        0..=7 => reg,
+                        _ => operands[reg as usize - 8],
+                    });
+                    self.items.push(item);
+                }
+            }
+            Op::Phi(..) => unreachable!("Phi instructions are removed before code generation"),
+        }
+    }
+
+    /// Ends a block. Jumps to the block placed right after it are left out.
+    fn gen_terminator(&mut self, terminator: &Terminator, id: BlockId) {
+        let r = |reg: &usize| self.registers[*reg];
+        let next = id + 1;
+        match terminator {
+            Terminator::Jump(target) => {
+                if *target != next {
+                    self.jump(*target);
+                }
+            }
+            Terminator::Branch(a, cond, b, then, otherwise) => {
+                let (a, b) = (r(a), r(b));
+                if *then == next {
+                    self.branch(a, cond.negate(), b, *otherwise);
+                } else {
+                    self.branch(a, *cond, b, *then);
+                    if *otherwise != next {
+                        self.jump(*otherwise);
+                    }
+                }
+            }
+            Terminator::Table(index, _) => {
+                self.load_constant(ADDRESS, self.tables[&id] as i16);
+                self.emit(Inst::Add(ADDRESS, ADDRESS, r(index)));
+                self.emit(Inst::Load(ADDRESS, ADDRESS));
+                self.emit(Inst::J(ADDRESS, None));
+            }
+            Terminator::Return(values) => {
+                // The frame and the registers are given up from here on
+                self.mark_variables(Vec::new());
+                let moves = values.iter().enumerate().map(|(i, v)| (i as Reg, r(v)));
+                self.parallel_move(moves.collect());
+                self.add_sp(self.frame.size as i64);
+                self.emit(Inst::Ret);
+            }
+        }
+    }
+
+    fn branch(&mut self, a: Reg, cond: Cond, b: Reg, target: BlockId) {
+        let flags: &[Flag] = match cond {
+            Cond::Eq => &[Flag::Equal],
+            Cond::Ne => &[Flag::Less, Flag::Greater],
+            Cond::Lt => &[Flag::Less],
+            Cond::Le => &[Flag::Less, Flag::Equal],
+            Cond::Gt => &[Flag::Greater],
+            Cond::Ge => &[Flag::Greater, Flag::Equal],
+        };
+        let label = self.block_label(target);
+        self.items.push(Item::SetLabel(ADDRESS, label));
+        for flag in flags {
+            self.emit(Inst::J(ADDRESS, Some((a, *flag, b))));
+        }
+    }
+
+    fn jump(&mut self, target: BlockId) {
+        let label = self.block_label(target);
+        self.items.push(Item::SetLabel(ADDRESS, label));
+        self.emit(Inst::J(ADDRESS, None));
+    }
+
+    fn block_label(&self, block: BlockId) -> String {
+        block_label(self.function, block)
+    }
+
+    // Instruction helpers
+
+    fn emit(&mut self, inst: Inst) {
+        self.items.push(Item::Inst(inst));
+    }
+
+    fn load_constant(&mut self, target: Reg, value: i16) {
+        for inst in load_constant(target, value) {
+            self.emit(inst);
+        }
+    }
+
+    /// Adds a constant to `target`. Clobbers `ADDRESS` for large values.
+    fn add_constant(&mut self, target: Reg, value: i64) {
+        if value == 0 {
+            return;
+        }
+        if (-128..=127).contains(&value) {
+            self.emit(Inst::Addi(target, value as i8));
+            return;
+        }
+        self.load_constant(ADDRESS, value as i16);
+        self.emit(Inst::Add(target, target, ADDRESS));
+    }
+
+    fn add_sp(&mut self, value: i64) {
+        self.add_constant(SP, value);
+    }
+
+    fn mov(&mut self, target: Reg, source: Reg) {
+        if target != source {
+            self.emit(Inst::And(target, source, source));
+        }
+    }
+
+    /// Copies registers as if all were read before the first is written.
+    /// Cycles are broken up through `SCRATCH`.
+    fn parallel_move(&mut self, mut moves: Vec<(Reg, Reg)>) {
+        moves.retain(|(target, source)| target != source);
+        while !moves.is_empty() {
+            let free = moves
+                .iter()
+                .position(|(target, _)| moves.iter().all(|(_, source)| source != target));
+            match free {
+                Some(i) => {
+                    let (target, source) = moves.remove(i);
+                    self.mov(target, source);
+                }
+                None => {
+                    let (_, source) = moves[0];
+                    self.mov(SCRATCH, source);
+                    for (_, s) in &mut moves {
+                        if *s == source {
+                            *s = SCRATCH;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Loads the address of the stack word at `offset` into `target`.
+    fn stack_address(&mut self, target: Reg, offset: usize) {
+        if offset == 0 {
+            self.mov(target, SP);
+        } else {
+            self.load_constant(target, offset as i16);
+            self.emit(Inst::Add(target, target, SP));
+        }
+    }
+
+    fn store_stack(&mut self, source: Reg, offset: usize) {
+        if offset == 0 {
+            self.emit(Inst::Store(source, SP));
+        } else {
+            self.stack_address(ADDRESS, offset);
+            self.emit(Inst::Store(source, ADDRESS));
+        }
+    }
+}
@@ -0,0 +1,264 @@
+//! Debug information that maps ROM addresses back to the C source: the
+//! file and line every instruction comes from and where each variable is
+//! kept while it is live.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::ops::Range;
+
+use super::asm::{self, Item, Reg};
+use super::ir::{Function, Op, VReg};
+
+/// Where one word of a variable is kept.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Location {
+    Register(Reg),
+    /// Memory at this many words above the software stack pointer in x7.
+    Stack(usize),
+}
+
+/// A source variable or a word of a `long`, the low word being word 0.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variable {
+    pub name: String,
+    pub word: Option<usize>,
+    pub location: Location,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DebugInfo {
+    files: Vec<String>,
+    /// Address, file and line, sorted by address. Each entry holds up to
+    /// the next one.
+    lines: Vec<(usize, usize, usize)>,
+    variables: Vec<(Range<usize>, Variable)>,
+}
+
+impl DebugInfo {
+    /// Collects the source and variable markers of laid out code.
+    pub fn new(items: &[Item], files: &[(String, String)]) -> DebugInfo {
+        let mut info = DebugInfo {
+            files: files.iter().map(|(name, _)| name.clone()).collect(),
+            ..DebugInfo::default()
+        };
+        let addresses = asm::addresses(items);
+        let mut open: Vec<(usize, Variable)> = Vec::new();
+        for (item, &address) in items.iter().zip(&addresses) {
+            match item {
+                Item::Source { file, line } => {
+                    if info.lines.last().is_some_and(|(a, _, _)| *a == address) {
+                        info.lines.pop();
+                    }
+                    info.lines.push((address, *file, *line));
+                }
+                Item::Variables(variables) => {
+                    let (kept, closed) = open.into_iter().partition(|(_, v)| variables.contains(v));
+                    open = kept;
+                    info.close(closed, address);
+                    for variable in variables {
+                        if !open.iter().any(|(_, v)| v == variable) {
+                            open.push((address, variable.clone()));
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+        let end = addresses.last().map_or(0, |a| a + 1);
+        info.close(open, end);
+        info
+    }
+
+    fn close(&mut self, variables: Vec<(usize, Variable)>, end: usize) {
+        for (start, variable) in variables {
+            if start < end {
+                self.variables.push((start..end, variable));
+            }
+        }
+    }
+
+    /// The file and line the instruction at `address` was generated from.
+    pub fn line(&self, address: usize) -> Option<(&str, usize)> {
+        let index = self.lines.partition_point(|(a, _, _)| *a <= address);
+        let (_, file, line) = self.lines.get(index.checked_sub(1)?)?;
+        Some((&self.files[*file], *line))
+    }
+
+    /// The names and values of the variables live at `address`, with `read`
+    /// giving the word at a location. A `long` is put together from its two
+    /// words and shown as `?` while one of them is dead.
+    pub fn variable_values(
+        &self,
+        address: usize,
+        read: impl Fn(Location) -> i16,
+    ) -> Vec<(String, String)> {
+        let mut variables: Vec<(&str, HashMap<Option<usize>, i16>)> = Vec::new();
+        for (range, variable) in &self.variables {
+            if !range.contains(&address) {
+                continue;
+            }
+            let index = match variables.iter().position(|(n, _)| *n == variable.name) {
+                Some(index) => index,
+                None => {
+                    variables.push((&variable.name, HashMap::new()));
+                    variables.len() - 1
+                }
+            };
+            let words = &mut variables[index].1;
+            words
+                .entry(variable.word)
+                .or_insert_with(|| read(variable.location));
+        }
+
+        variables
+            .into_iter()
+            .map(|(name, words)| {
+                let value = match (words.get(&None), words.get(&Some(0)), words.get(&Some(1))) {
+                    (Some(value), _, _) => value.to_string(),
+                    (None, Some(low), Some(high)) => {
+                        (((*high as i32) << 16) | *low as u16 as i32).to_string()
+                    }
+                    _ => "?".to_string(),
+                };
+                (name.to_string(), value)
+            })
+            .collect()
+    }
+}
+
+/// The text form stored next to the ROM image: a line per source file,
+/// per address where the source line changes and per variable location,
+/// with `-` as the word of variables that are not a `long`.
+impl fmt::Display for DebugInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, name) in self.files.iter().enumerate() {
+            writeln!(f, "file {} {}", i, name)?;
+        }
+        for (address, file, line) in &self.lines {
+            writeln!(f, "line {} {} {}", address, file, line)?;
+        }
+        for (range, variable) in &self.variables {
+            let word = variable.word.map_or("-".to_string(), |w| w.to_string());
+            writeln!(
+                f,
+                "var {} {} {} {} {}",
+                range.start, range.end, variable.name, word, variable.location
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Location::Register(reg) => write!(f, "x{}", reg),
+            Location::Stack(offset) => write!(f, "sp+{}", offset),
+        }
+    }
+}
+
+/// A value the liveness of variables is tracked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Value {
+    Reg(VReg),
+    Slot(usize),
+}
+
+/// The variables live before every instruction of every block, with the
+/// terminator as the last entry of a block. `registers` are the allocated
+/// registers and `frame_offset` is where the frame slots start above the
+/// stack pointer. Variables in the frame are there in the whole function.
+pub fn live_variables(
+    function: &Function,
+    registers: &[Reg],
+    frame_offset: usize,
+) -> Vec<Vec<Vec<Variable>>> {
+    // Spill slots are live from their store to their last load
+    let uses = |op: &Op| {
+        let mut uses: Vec<Value> = op.uses().into_iter().map(Value::Reg).collect();
+        if let Op::SpillLoad(_, slot) = op {
+            uses.push(Value::Slot(*slot));
+        }
+        uses
+    };
+    let defs = |op: &Op| {
+        let mut defs: Vec<Value> = op.defs().into_iter().map(Value::Reg).collect();
+        if let Op::SpillStore(_, slot) = op {
+            defs.push(Value::Slot(*slot));
+        }
+        defs
+    };
+
+    let mut live_in: Vec<BTreeSet<Value>> = vec![BTreeSet::new(); function.blocks.len()];
+    let mut live_before = vec![Vec::new(); function.blocks.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (id, block) in function.blocks.iter().enumerate().rev() {
+            let mut live: BTreeSet<Value> = block
+                .terminator
+                .successors()
+                .iter()
+                .flat_map(|s| live_in[*s].iter().copied())
+                .collect();
+            live.extend(block.terminator.uses().into_iter().map(Value::Reg));
+            let mut before = vec![live.clone()];
+            for inst in block.insts.iter().rev() {
+                for def in defs(&inst.op) {
+                    live.remove(&def);
+                }
+                live.extend(uses(&inst.op));
+                before.push(live.clone());
+            }
+            before.reverse();
+            if live != live_in[id] {
+                live_in[id] = live;
+                changed = true;
+            }
+            live_before[id] = before;
+        }
+    }
+
+    let frame: Vec<Variable> = function
+        .frame_variables
+        .iter()
+        .map(|(name, word, slot)| Variable {
+            name: name.clone(),
+            word: *word,
+            location: Location::Stack(frame_offset + slot),
+        })
+        .collect();
+    let named = |value: &Value| {
+        let (variable, location) = match value {
+            Value::Reg(reg) => (
+                function.variables.get(reg),
+                Location::Register(registers[*reg]),
+            ),
+            Value::Slot(slot) => (
+                function.spilled_variables.get(slot),
+                Location::Stack(frame_offset + slot),
+            ),
+        };
+        variable.map(|(name, word)| Variable {
+            name: name.clone(),
+            word: *word,
+            location,
+        })
+    };
+    live_before
+        .iter()
+        .map(|block| {
+            block
+                .iter()
+                .map(|live| {
+                    frame
+                        .iter()
+                        .cloned()
+                        .chain(live.iter().filter_map(named))
+                        .collect()
+                })
+                .collect()
+        })
+        .collect()
+}
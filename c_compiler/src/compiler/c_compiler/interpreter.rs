@@ -0,0 +1,1141 @@
+//! Reference interpreter that runs the AST directly, to check the compiled
+//! code against.
+//!
+//! It only shares the AST with the compiler and lays out memory on its
+//! own: globals in order from address 0, followed by static locals and
+//! strings once they are reached. Values behave like on the target:
+//! integers wrap at their width, memory is 64K of 16 bit words holding
+//! `long`s low word first, and division by 0 gives what the runtime library
+//! computes. A function pointer is the position of the function in the
+//! order of first use plus one, as ROM addresses do not exist here.
+
+use std::collections::HashMap;
+use std::iter::Peekable;
+use std::slice;
+
+use super::ast::*;
+
+const MEMORY_SIZE: usize = 1 << 16;
+const IO_DEVICES: usize = 8;
+
+/// What a program did until main returned.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outcome {
+    /// Device and value of every `__out`, in order.
+    pub outputs: Vec<(u8, i16)>,
+    /// The low word of main's return value, which the hardware leaves in x0.
+    pub result: i16,
+}
+
+/// How a statement ends, with the value of a `return`.
+enum Flow {
+    Next,
+    Break,
+    Continue,
+    Return(i64),
+}
+
+pub struct Interpreter<'a> {
+    /// Functions by name, with their definition if there is one.
+    functions: HashMap<&'a str, &'a Function>,
+    structs: &'a Structs,
+    /// Addresses and types of the globals.
+    globals: HashMap<String, (usize, Type)>,
+    /// Addresses of the strings in memory, which identical literals share.
+    strings: HashMap<Vec<u8>, usize>,
+    /// End of the globals, static locals and strings.
+    data_end: usize,
+    memory: Vec<i16>,
+    io: [i16; IO_DEVICES],
+    outputs: Vec<(u8, i16)>,
+    /// Variables of the running function by name, with their address.
+    scopes: Vec<HashMap<String, (usize, Type)>>,
+    /// Locals take memory from the top down, like on the software stack.
+    stack_pointer: usize,
+    /// Addresses and types of the static locals by their declaration.
+    statics: HashMap<*const Declaration, (usize, Type)>,
+    function_pointers: Vec<String>,
+    return_type: Type,
+}
+
+impl<'a> Interpreter<'a> {
+    pub fn new(program: &'a Program) -> Interpreter<'a> {
+        let mut functions: HashMap<&str, &Function> = HashMap::new();
+        for function in &program.functions {
+            match functions.get(function.name.as_str()) {
+                Some(defined) if defined.body.is_some() => assert!(
+                    function.body.is_none(),
+                    "Function {} defined twice in line {}",
+                    function.name,
+                    function.line
+                ),
+                _ => {
+                    functions.insert(&function.name, function);
+                }
+            }
+        }
+
+        let mut interpreter = Interpreter {
+            functions,
+            structs: &program.structs,
+            globals: HashMap::new(),
+            strings: HashMap::new(),
+            data_end: 0,
+            memory: vec![0; MEMORY_SIZE],
+            io: [0; IO_DEVICES],
+            outputs: Vec::new(),
+            scopes: Vec::new(),
+            stack_pointer: MEMORY_SIZE,
+            statics: HashMap::new(),
+            function_pointers: Vec::new(),
+            return_type: Type::Void,
+        };
+        for global in &program.globals {
+            interpreter.add_global(global);
+        }
+        interpreter
+    }
+
+    /// Calls main and collects what the program writes to the IO devices.
+    pub fn run(mut self) -> Outcome {
+        let result = self.call_function("main", Vec::new(), 0);
+        Outcome {
+            outputs: self.outputs,
+            result: result as i16,
+        }
+    }
+
+    fn call_function(&mut self, name: &str, args: Vec<i64>, line: usize) -> i64 {
+        let function = self
+            .function(name)
+            .unwrap_or_else(|| panic!("Undefined function: {} in line {}", name, line));
+        let body = function.body.as_ref().unwrap_or_else(|| {
+            panic!(
+                "Function {} is declared but never defined in line {}",
+                name, line
+            )
+        });
+
+        let scopes = std::mem::replace(&mut self.scopes, vec![HashMap::new()]);
+        let return_type = std::mem::replace(&mut self.return_type, function.return_type.clone());
+        let stack_pointer = self.stack_pointer;
+        // main gets 0 for any parameters
+        let args = args.into_iter().chain(std::iter::repeat(0));
+        for ((param_type, param_name), value) in function.params.iter().zip(args) {
+            let address = self.declare(param_name, param_type.clone(), function.line);
+            self.store(address, param_type, value);
+        }
+        let mut result = 0;
+        for stmt in body {
+            if let Flow::Return(value) = self.exec(stmt, None) {
+                result = value;
+                break;
+            }
+        }
+        self.stack_pointer = stack_pointer;
+        self.return_type = return_type;
+        self.scopes = scopes;
+        result
+    }
+
+    // Statements
+
+    /// Runs a statement. With a `label` the statement is entered at that
+    /// `case` or `default` label, skipping everything before it.
+    fn exec(&mut self, stmt: &Stmt, label: Option<&Stmt>) -> Flow {
+        match stmt {
+            Stmt::Decl(decl) => self.local(decl),
+            Stmt::Expr(expr) => {
+                self.eval(expr);
+            }
+            Stmt::If(condition, then, otherwise) => {
+                let take_then = match label {
+                    Some(label) => contains_label(then, label),
+                    None => self.truth(condition),
+                };
+                if take_then {
+                    return self.exec(then, label);
+                }
+                if let Some(otherwise) = otherwise {
+                    return self.exec(otherwise, label);
+                }
+            }
+            Stmt::While(condition, body) => {
+                let mut label = label;
+                while label.is_some() || self.truth(condition) {
+                    match self.exec(body, label.take()) {
+                        Flow::Break => break,
+                        Flow::Return(value) => return Flow::Return(value),
+                        Flow::Next | Flow::Continue => (),
+                    }
+                }
+            }
+            Stmt::DoWhile(body, condition) => {
+                let mut label = label;
+                loop {
+                    match self.exec(body, label.take()) {
+                        Flow::Break => break,
+                        Flow::Return(value) => return Flow::Return(value),
+                        Flow::Next | Flow::Continue => (),
+                    }
+                    if !self.truth(condition) {
+                        break;
+                    }
+                }
+            }
+            Stmt::For(init, condition, step, body) => {
+                self.scopes.push(HashMap::new());
+                let stack_pointer = self.stack_pointer;
+                match (init.as_deref(), label) {
+                    (Some(Stmt::Decl(decl)), Some(_)) => {
+                        self.declare_local(decl);
+                    }
+                    (Some(init), None) => {
+                        self.exec(init, None);
+                    }
+                    _ => (),
+                }
+                let mut label = label;
+                let mut flow = Flow::Next;
+                while label.is_some() || condition.as_ref().is_none_or(|c| self.truth(c)) {
+                    match self.exec(body, label.take()) {
+                        Flow::Break => break,
+                        Flow::Return(value) => {
+                            flow = Flow::Return(value);
+                            break;
+                        }
+                        Flow::Next | Flow::Continue => (),
+                    }
+                    if let Some(step) = step {
+                        self.eval(step);
+                    }
+                }
+                self.stack_pointer = stack_pointer;
+                self.scopes.pop();
+                return flow;
+            }
+            Stmt::Switch(value, body) => {
+                let value_type = self.type_of(value).promote();
+                assert!(
+                    value_type.is_integer() && !value_type.is_long(),
+                    "Cannot switch on {} in line {}",
+                    value_type,
+                    value.line
+                );
+                let value = self.eval(value) as i16;
+                let Some(target) = self.find_label(body, value, &value_type) else {
+                    return Flow::Next;
+                };
+                return match self.exec(body, Some(target)) {
+                    Flow::Break => Flow::Next,
+                    flow => flow,
+                };
+            }
+            Stmt::Case(_, body) | Stmt::Default(_, body) => {
+                let label = label.filter(|label| !std::ptr::eq(*label, stmt));
+                return self.exec(body, label);
+            }
+            Stmt::Return(value) => {
+                let return_type = self.return_type.clone();
+                let value = match value {
+                    Some(value) => self.converted(value, &return_type),
+                    None => 0,
+                };
+                return Flow::Return(value);
+            }
+            Stmt::Break(_) => return Flow::Break,
+            Stmt::Continue(_) => return Flow::Continue,
+            Stmt::Block(stmts) => {
+                self.scopes.push(HashMap::new());
+                let stack_pointer = self.stack_pointer;
+                let mut label = label;
+                let mut flow = Flow::Next;
+                for stmt in stmts {
+                    match label {
+                        // Jumping over a declaration still brings the
+                        // variable into scope
+                        Some(target) if !contains_label(stmt, target) => {
+                            if let Stmt::Decl(decl) = stmt {
+                                self.declare_local(decl);
+                            }
+                            continue;
+                        }
+                        _ => (),
+                    }
+                    flow = self.exec(stmt, label.take());
+                    if !matches!(flow, Flow::Next) {
+                        break;
+                    }
+                }
+                self.stack_pointer = stack_pointer;
+                self.scopes.pop();
+                return flow;
+            }
+            Stmt::Asm(asm) => panic!("Cannot interpret asm in line {}", asm.line),
+        }
+        Flow::Next
+    }
+
+    /// The `case` label of a switch body for `value`, or else its `default`.
+    fn find_label<'s>(
+        &mut self,
+        body: &'s Stmt,
+        value: i16,
+        value_type: &Type,
+    ) -> Option<&'s Stmt> {
+        let mut default = None;
+        let mut found = None;
+        let mut labels = vec![body];
+        while let Some(stmt) = labels.pop() {
+            match stmt {
+                Stmt::Case(label, body) => {
+                    if found.is_none() && self.converted(label, value_type) as i16 == value {
+                        found = Some(stmt);
+                    }
+                    labels.push(body);
+                }
+                Stmt::Default(_, body) => {
+                    default = default.or(Some(stmt));
+                    labels.push(body);
+                }
+                Stmt::If(_, then, otherwise) => {
+                    labels.push(then);
+                    labels.extend(otherwise.as_deref());
+                }
+                Stmt::While(_, body) | Stmt::DoWhile(body, _) | Stmt::For(_, _, _, body) => {
+                    labels.push(body);
+                }
+                Stmt::Block(stmts) => labels.extend(stmts),
+                _ => (),
+            }
+        }
+        found.or(default)
+    }
+
+    fn local(&mut self, decl: &Declaration) {
+        let (address, var_type) = self.declare_local(decl);
+        // Static locals are initialized once, when they are first reached
+        if let Some(init) = decl.init.as_ref().filter(|_| !decl.is_static) {
+            self.initialize(address, &var_type, init, decl.line);
+        }
+    }
+
+    fn declare_local(&mut self, decl: &Declaration) -> (usize, Type) {
+        if decl.is_static {
+            let (address, var_type) = match self.statics.get(&(decl as *const Declaration)) {
+                Some(var) => var.clone(),
+                None => {
+                    let var = self.add_static(decl);
+                    self.statics.insert(decl, var.clone());
+                    var
+                }
+            };
+            self.scopes
+                .last_mut()
+                .unwrap()
+                .insert(decl.name.clone(), (address, var_type.clone()));
+            return (address, var_type);
+        }
+        let var_type = self.complete_type(decl);
+        (
+            self.declare(&decl.name, var_type.clone(), decl.line),
+            var_type,
+        )
+    }
+
+    /// Takes zeroed stack memory for a variable.
+    fn declare(&mut self, name: &str, var_type: Type, line: usize) -> usize {
+        let size = var_type.size(self.structs, line);
+        self.stack_pointer = match self.stack_pointer.checked_sub(size) {
+            Some(address) if address >= self.data_end => address,
+            _ => panic!("Stack overflow in line {}", line),
+        };
+        let address = self.stack_pointer;
+        self.memory[address..address + size].fill(0);
+        self.scopes
+            .last_mut()
+            .unwrap()
+            .insert(name.to_string(), (address, var_type));
+        address
+    }
+
+    // Globals and initializers
+
+    fn add_global(&mut self, global: &Declaration) {
+        assert!(
+            !self.globals.contains_key(&global.name),
+            "Global {} defined twice in line {}",
+            global.name,
+            global.line
+        );
+        let var = self.add_static(global);
+        self.globals.insert(global.name.clone(), var);
+    }
+
+    /// Takes memory after the data for a global or a static local and
+    /// initializes it.
+    fn add_static(&mut self, decl: &Declaration) -> (usize, Type) {
+        let var_type = self.complete_type(decl);
+        let address = self.data_end;
+        self.data_end += var_type.size(self.structs, decl.line);
+        if let Some(init) = &decl.init {
+            self.initialize(address, &var_type, init, decl.line);
+        }
+        (address, var_type)
+    }
+
+    /// The type of a variable, with the length of an array without one
+    /// counted from its initializer.
+    fn complete_type(&self, decl: &Declaration) -> Type {
+        match (&decl.var_type, &decl.init) {
+            (Type::Array(element, None), Some(Initializer::List(items))) => {
+                let mut items = items.iter().peekable();
+                let mut length = 0;
+                while items.peek().is_some() {
+                    self.skip_items(element, &mut items, decl.line);
+                    length += 1;
+                }
+                Type::Array(element.clone(), Some(length))
+            }
+            (
+                Type::Array(element, None),
+                Some(Initializer::Expr(Expr {
+                    kind: ExprKind::Str(bytes),
+                    ..
+                })),
+            ) => Type::Array(element.clone(), Some(bytes.len() + 1)),
+            (var_type, _) => var_type.clone(),
+        }
+    }
+
+    /// Stores an initializer into the variable of the type at `address`.
+    /// What it leaves out stays 0.
+    fn initialize(&mut self, address: usize, var_type: &Type, init: &Initializer, line: usize) {
+        match init {
+            Initializer::Expr(Expr {
+                kind: ExprKind::Str(bytes),
+                line,
+            }) if is_char_array(var_type) => {
+                let Type::Array(element, Some(length)) = var_type else {
+                    panic!("Array has unknown size in line {}", line);
+                };
+                assert!(
+                    bytes.len() <= *length,
+                    "String does not fit into array in line {}",
+                    line
+                );
+                for (i, byte) in bytes.iter().chain([&0]).take(*length).enumerate() {
+                    let value = element.convert_constant(*byte as i64);
+                    self.store(address + i, element, value);
+                }
+            }
+            Initializer::Expr(expr) if matches!(var_type, Type::Struct(_)) => {
+                let source = self.eval(expr) as usize;
+                self.copy(address, source, var_type.size(self.structs, line));
+            }
+            Initializer::Expr(expr) if var_type.is_aggregate() => {
+                panic!("Expected initializer list in line {}", expr.line)
+            }
+            Initializer::Expr(expr) => {
+                let value = self.converted(expr, var_type);
+                self.store(address, var_type, value);
+            }
+            Initializer::List(items) => {
+                let mut items = items.iter().peekable();
+                self.initialize_fields(address, var_type, &mut items, line);
+                assert!(
+                    items.peek().is_none(),
+                    "Too many initializers in line {}",
+                    line
+                );
+            }
+        }
+    }
+
+    /// Initializes the elements or members of an aggregate from a list.
+    /// Nested aggregates without braces of their own take as many items as
+    /// they need.
+    fn initialize_fields(
+        &mut self,
+        address: usize,
+        var_type: &Type,
+        items: &mut Peekable<slice::Iter<Initializer>>,
+        line: usize,
+    ) {
+        for (offset, field_type) in self.fields(var_type, line) {
+            let whole = match items.peek() {
+                Some(item) => initializes_whole(item, &field_type),
+                None => break,
+            };
+            if field_type.is_aggregate() && !whole {
+                self.initialize_fields(address + offset, &field_type, items, line);
+            } else {
+                let item = items.next().unwrap();
+                self.initialize(address + offset, &field_type, item, line);
+            }
+        }
+    }
+
+    /// Takes the items that initialize a value of the type off a list.
+    fn skip_items(
+        &self,
+        var_type: &Type,
+        items: &mut Peekable<slice::Iter<Initializer>>,
+        line: usize,
+    ) {
+        let whole = match items.peek() {
+            Some(item) => initializes_whole(item, var_type),
+            None => return,
+        };
+        if var_type.is_aggregate() && !whole {
+            for (_, field_type) in self.fields(var_type, line) {
+                self.skip_items(&field_type, items, line);
+            }
+        } else {
+            items.next();
+        }
+    }
+
+    /// Offsets and types of the elements or members of an aggregate, or
+    /// just the type itself for a scalar.
+    fn fields(&self, var_type: &Type, line: usize) -> Vec<(usize, Type)> {
+        match var_type {
+            Type::Array(element, Some(length)) => {
+                let size = element.size(self.structs, line);
+                (0..*length)
+                    .map(|i| (i * size, (**element).clone()))
+                    .collect()
+            }
+            Type::Struct(tag) => self
+                .layout(tag, line)
+                .members
+                .iter()
+                .map(|m| (m.offset, m.member_type.clone()))
+                .collect(),
+            scalar => vec![(0, scalar.clone())],
+        }
+    }
+
+    // Expressions
+
+    /// Evaluates an expression to a value of its type: integers in the
+    /// range of their type, pointers from 0 to 65535 and aggregates as
+    /// their address.
+    fn eval(&mut self, expr: &Expr) -> i64 {
+        let value = self.eval_raw(expr);
+        normalize(value, &self.type_of(expr).decay())
+    }
+
+    fn eval_raw(&mut self, expr: &Expr) -> i64 {
+        let line = expr.line;
+        match &expr.kind {
+            ExprKind::Number(value) => *value,
+            ExprKind::Str(_) => self.string(expr) as i64,
+            ExprKind::Ident(name) if self.function_name(expr).is_some() => {
+                self.function_pointer(name)
+            }
+            ExprKind::Ident(_) | ExprKind::Index(..) | ExprKind::Member(..) => {
+                let address = self.address(expr);
+                self.value_at(address, &self.type_of(expr))
+            }
+            ExprKind::Deref(pointer) => match self.type_of(expr) {
+                Type::Function(..) => self.eval(pointer),
+                value_type => {
+                    let address = self.eval(pointer) as usize;
+                    self.value_at(address, &value_type)
+                }
+            },
+            ExprKind::Cast(target_type, operand) => {
+                assert!(
+                    !matches!(target_type, Type::Struct(_)),
+                    "Cannot cast to a struct in line {}",
+                    line
+                );
+                self.converted(operand, target_type)
+            }
+            ExprKind::SizeOf(operand) => self.type_of(operand).size(self.structs, line) as i64,
+            ExprKind::AddressOf(inner) => self.address(inner) as i64,
+            ExprKind::Unary(UnaryOp::Neg, operand) => -self.eval(operand),
+            ExprKind::Unary(UnaryOp::BitNot, operand) => !self.eval(operand),
+            ExprKind::Unary(UnaryOp::Not, operand) => !self.truth(operand) as i64,
+            ExprKind::Binary(BinaryOp::LogicAnd, left, right) => {
+                (self.truth(left) && self.truth(right)) as i64
+            }
+            ExprKind::Binary(BinaryOp::LogicOr, left, right) => {
+                (self.truth(left) || self.truth(right)) as i64
+            }
+            ExprKind::Binary(op, left, right) if op.is_comparison() => {
+                self.compare(*op, left, right) as i64
+            }
+            ExprKind::Binary(op, left, right) => {
+                let left_type = self.type_of(left).decay();
+                let right_type = self.type_of(right).decay();
+                let a = self.eval(left);
+                let b = self.eval(right);
+                match (op, left_type.target(), right_type.target()) {
+                    (BinaryOp::Add, Some(pointee), None) => a + b * self.size(pointee, line),
+                    (BinaryOp::Sub, Some(pointee), None) => a - b * self.size(pointee, line),
+                    (BinaryOp::Add, None, Some(pointee)) => a * self.size(pointee, line) + b,
+                    (BinaryOp::Sub, Some(pointee), Some(_)) => {
+                        let difference = (a - b) as i16 as i64;
+                        match self.size(pointee, line) {
+                            size if (size as u64).is_power_of_two() => {
+                                difference >> size.trailing_zeros()
+                            }
+                            size => divide(difference, size, 16, true).0,
+                        }
+                    }
+                    _ => arithmetic(*op, &left_type, &right_type, a, b),
+                }
+            }
+            ExprKind::Assign(None, target, value) => {
+                let target_type = self.type_of(target);
+                if let Type::Struct(_) = target_type {
+                    let source = self.eval(value) as usize;
+                    let address = self.address(target);
+                    self.copy(address, source, target_type.size(self.structs, line));
+                    return address as i64;
+                }
+                let value = self.converted(value, &target_type);
+                self.assign(target, value);
+                value
+            }
+            ExprKind::Assign(Some(op), target, value) => {
+                let target_type = self.type_of(target);
+                let value_type = self.type_of(value).decay();
+                let address = self.address(target);
+                let old = self.value_at(address, &target_type);
+                let value = self.eval(value);
+                let new = match target_type.target() {
+                    Some(pointee) if matches!(op, BinaryOp::Add | BinaryOp::Sub) => {
+                        let step = value * self.size(pointee, line);
+                        if *op == BinaryOp::Add {
+                            old + step
+                        } else {
+                            old - step
+                        }
+                    }
+                    // Arithmetic happens in a word unless the target is a
+                    // `long`, signed or not as in the common type
+                    _ if !target_type.is_long()
+                        && value_type.is_long()
+                        && !matches!(op, BinaryOp::Shl | BinaryOp::Shr) =>
+                    {
+                        let word = match target_type.common(&value_type).is_unsigned() {
+                            true => Type::UNSIGNED,
+                            false => Type::INT,
+                        };
+                        arithmetic(*op, &word, &word, old, value)
+                    }
+                    _ => arithmetic(*op, &target_type, &value_type, old, value),
+                };
+                let new = normalize(new, &target_type);
+                self.store(address, &target_type, new);
+                new
+            }
+            ExprKind::IncDec {
+                increment,
+                prefix,
+                target,
+            } => {
+                let target_type = self.type_of(target);
+                let step = match &target_type {
+                    Type::Pointer(pointee) => self.size(pointee, line),
+                    _ => 1,
+                };
+                let address = self.address(target);
+                let old = self.value_at(address, &target_type);
+                let new = match increment {
+                    true => old + step,
+                    false => old - step,
+                };
+                let new = normalize(new, &target_type);
+                self.store(address, &target_type, new);
+                if *prefix {
+                    new
+                } else {
+                    old
+                }
+            }
+            ExprKind::Conditional(condition, then, otherwise) => {
+                let value_type = self.type_of(expr);
+                match self.truth(condition) {
+                    true => self.converted(then, &value_type),
+                    false => self.converted(otherwise, &value_type),
+                }
+            }
+            ExprKind::Call(function, args) => match self.function_name(function) {
+                Some("__in") => {
+                    let device = self.io_device("__in", args, 1, line);
+                    self.io[device] as i64
+                }
+                Some("__out") => {
+                    let device = self.io_device("__out", args, 2, line);
+                    let value = self.converted(&args[1], &Type::INT) as i16;
+                    self.io[device] = value;
+                    self.outputs.push((device as u8, value));
+                    value as i64
+                }
+                Some(name) => {
+                    let function = self
+                        .function(name)
+                        .unwrap_or_else(|| panic!("Undefined function: {} in line {}", name, line));
+                    let params: Vec<Type> = function.params.iter().map(|p| p.0.clone()).collect();
+                    let args = self.args(&format!("Function {}", name), &params, args, line);
+                    self.call_function(name, args, line)
+                }
+                None => {
+                    let params = match self.type_of(function).decay().target() {
+                        Some(Type::Function(_, params)) => params.clone(),
+                        _ => panic!("Called object is not a function in line {}", line),
+                    };
+                    let pointer = self.eval(function);
+                    let args = self.args("Function pointer", &params, args, line);
+                    let name = usize::try_from(pointer - 1)
+                        .ok()
+                        .and_then(|i| self.function_pointers.get(i))
+                        .cloned()
+                        .unwrap_or_else(|| {
+                            panic!("Call through invalid pointer {} in line {}", pointer, line)
+                        });
+                    self.call_function(&name, args, line)
+                }
+            },
+        }
+    }
+
+    /// The device of an `__in` or `__out`, which has to be in range.
+    fn io_device(&mut self, name: &str, args: &[Expr], arg_count: usize, line: usize) -> usize {
+        assert!(
+            args.len() == arg_count,
+            "Function {} expects {} arguments but got {} in line {}",
+            name,
+            arg_count,
+            args.len(),
+            line
+        );
+        let device = self.eval(&args[0]);
+        assert!(
+            (0..IO_DEVICES as i64).contains(&device),
+            "Device out of range: {} in line {}. Must be 0 <= device <= 7",
+            device,
+            line
+        );
+        device as usize
+    }
+
+    /// Evaluates the arguments of a call, converted to the parameter types.
+    fn args(&mut self, callee: &str, params: &[Type], args: &[Expr], line: usize) -> Vec<i64> {
+        assert!(
+            params.len() == args.len(),
+            "{} expects {} arguments but got {} in line {}",
+            callee,
+            params.len(),
+            args.len(),
+            line
+        );
+        args.iter()
+            .zip(params)
+            .map(|(arg, param_type)| self.converted(arg, param_type))
+            .collect()
+    }
+
+    /// Compares like the hardware does after lowering: unsigned if both are
+    /// integers of an unsigned common type, pointers as signed words.
+    fn compare(&mut self, op: BinaryOp, left: &Expr, right: &Expr) -> bool {
+        let left_type = self.type_of(left).decay();
+        let right_type = self.type_of(right).decay();
+        let common = match left_type.is_integer() && right_type.is_integer() {
+            true => left_type.common(&right_type),
+            false => Type::INT,
+        };
+        let a = self.converted(left, &common);
+        let b = self.converted(right, &common);
+        match op {
+            BinaryOp::Eq => a == b,
+            BinaryOp::Ne => a != b,
+            BinaryOp::Lt => a < b,
+            BinaryOp::Le => a <= b,
+            BinaryOp::Gt => a > b,
+            _ => a >= b,
+        }
+    }
+
+    fn truth(&mut self, expr: &Expr) -> bool {
+        self.eval(expr) != 0
+    }
+
+    /// Evaluates `expr` and converts it to the type it is stored as.
+    fn converted(&mut self, expr: &Expr, to: &Type) -> i64 {
+        let value = self.eval(expr);
+        normalize(value, to)
+    }
+
+    fn assign(&mut self, target: &Expr, value: i64) {
+        if let ExprKind::Ident(name) = &target.kind {
+            assert!(
+                !matches!(self.lookup(name, target.line).1, Type::Array(..)),
+                "Cannot assign to array {} in line {}",
+                name,
+                target.line
+            );
+        }
+        let address = self.address(target);
+        let target_type = self.type_of(target);
+        self.store(address, &target_type, value);
+    }
+
+    /// Computes the address of an lvalue.
+    fn address(&mut self, expr: &Expr) -> usize {
+        let line = expr.line;
+        match &expr.kind {
+            ExprKind::Ident(name) if self.function_name(expr).is_some() => {
+                self.function_pointer(name) as usize
+            }
+            ExprKind::Ident(name) => self.lookup(name, line).0,
+            ExprKind::Deref(pointer) => self.eval(pointer) as usize,
+            ExprKind::Index(array, index) => {
+                let array_type = self.type_of(array).decay();
+                let index_type = self.type_of(index).decay();
+                let a = self.eval(array);
+                let b = self.eval(index);
+                let (pointer, index, pointee) = match (array_type.target(), index_type.target()) {
+                    (Some(pointee), _) => (a, b, pointee.clone()),
+                    (None, Some(pointee)) => (b, a, pointee.clone()),
+                    _ => panic!("Subscripted value is not an array in line {}", line),
+                };
+                (pointer + index * self.size(&pointee, line)) as u16 as usize
+            }
+            ExprKind::Member(base, name) => {
+                let offset = self.member(base, name, line).offset;
+                (self.address(base) + offset) % MEMORY_SIZE
+            }
+            ExprKind::Str(_) => self.string(expr),
+            _ => panic!("Expression is not assignable in line {}", line),
+        }
+    }
+
+    fn string(&mut self, expr: &Expr) -> usize {
+        let ExprKind::Str(bytes) = &expr.kind else {
+            unreachable!("{:?} is no string", expr.kind);
+        };
+        if let Some(address) = self.strings.get(bytes) {
+            return *address;
+        }
+        let address = self.data_end;
+        for (i, byte) in bytes.iter().chain([&0]).enumerate() {
+            self.memory[address + i] = *byte as i8 as i16;
+        }
+        self.data_end += bytes.len() + 1;
+        self.strings.insert(bytes.clone(), address);
+        address
+    }
+
+    fn function_pointer(&mut self, name: &str) -> i64 {
+        let index = match self.function_pointers.iter().position(|f| f == name) {
+            Some(index) => index,
+            None => {
+                self.function_pointers.push(name.to_string());
+                self.function_pointers.len() - 1
+            }
+        };
+        index as i64 + 1
+    }
+
+    // Memory
+
+    /// The value of a variable of the type at `address`. Aggregates are
+    /// used through their address.
+    fn value_at(&self, address: usize, value_type: &Type) -> i64 {
+        if value_type.is_aggregate() {
+            return address as i64;
+        }
+        let word = |offset: usize| self.memory[(address + offset) % MEMORY_SIZE];
+        match value_type {
+            Type::Integer { bits: 32, signed } => {
+                let value = (word(1) as i32) << 16 | word(0) as u16 as i32;
+                match signed {
+                    true => value as i64,
+                    false => value as u32 as i64,
+                }
+            }
+            Type::Integer { signed: true, .. } => word(0) as i64,
+            _ => word(0) as u16 as i64,
+        }
+    }
+
+    fn store(&mut self, address: usize, value_type: &Type, value: i64) {
+        self.memory[address % MEMORY_SIZE] = value as i16;
+        if value_type.is_long() {
+            self.memory[(address + 1) % MEMORY_SIZE] = (value >> 16) as i16;
+        }
+    }
+
+    fn copy(&mut self, target: usize, source: usize, size: usize) {
+        for i in 0..size {
+            self.memory[(target + i) % MEMORY_SIZE] = self.memory[(source + i) % MEMORY_SIZE];
+        }
+    }
+
+    // Variables and types
+
+    fn lookup(&self, name: &str, line: usize) -> (usize, Type) {
+        self.find_var(name)
+            .unwrap_or_else(|| panic!("Undefined variable: {} in line {}", name, line))
+    }
+
+    fn find_var(&self, name: &str) -> Option<(usize, Type)> {
+        for scope in self.scopes.iter().rev() {
+            if let Some((address, var_type)) = scope.get(name) {
+                return Some((*address, var_type.clone()));
+            }
+        }
+        self.globals.get(name).cloned()
+    }
+
+    /// The definition of a function, or its declaration if there is none.
+    fn function(&self, name: &str) -> Option<&'a Function> {
+        self.functions.get(name).copied()
+    }
+
+    /// The type of a function used by name.
+    fn function_type(&self, name: &str, line: usize) -> Type {
+        match self.function(name) {
+            Some(function) => Type::Function(
+                Box::new(function.return_type.clone()),
+                function.params.iter().map(|p| p.0.clone()).collect(),
+            ),
+            None => panic!("Undefined variable: {} in line {}", name, line),
+        }
+    }
+
+    fn layout(&self, tag: &str, line: usize) -> &'a Struct {
+        self.structs
+            .get(tag)
+            .unwrap_or_else(|| panic!("Struct {} is incomplete in line {}", tag, line))
+    }
+
+    /// The function an expression names, unless a variable hides it.
+    fn function_name<'e>(&self, expr: &'e Expr) -> Option<&'e str> {
+        match &expr.kind {
+            ExprKind::Ident(name) if self.find_var(name).is_none() => Some(name),
+            _ => None,
+        }
+    }
+
+    fn member(&self, base: &Expr, name: &str, line: usize) -> Member {
+        match self.type_of(base) {
+            Type::Struct(tag) => self
+                .layout(&tag, line)
+                .member(name)
+                .cloned()
+                .unwrap_or_else(|| {
+                    panic!("Struct {} has no member {} in line {}", tag, name, line)
+                }),
+            other => panic!(
+                "Member {} of {}, which is no struct, in line {}",
+                name, other, line
+            ),
+        }
+    }
+
+    fn size(&self, value_type: &Type, line: usize) -> i64 {
+        value_type.size(self.structs, line) as i64
+    }
+
+    /// The type of an expression by the rules of the compiler.
+    fn type_of(&self, expr: &Expr) -> Type {
+        match &expr.kind {
+            // Hex numbers that only fit `unsigned` come cast to it
+            ExprKind::Number(value) => match *value {
+                value if i16::try_from(value).is_ok() => Type::INT,
+                value if i32::try_from(value).is_ok() => Type::LONG,
+                _ => Type::ULONG,
+            },
+            ExprKind::SizeOf(_) => Type::INT,
+            ExprKind::Str(bytes) => Type::Array(Box::new(Type::CHAR), Some(bytes.len() + 1)),
+            ExprKind::Ident(name) => match self.find_var(name) {
+                Some((_, var_type)) => var_type,
+                None => self.function_type(name, expr.line),
+            },
+            ExprKind::Unary(UnaryOp::Not, _) => Type::INT,
+            ExprKind::Unary(_, operand) => self.type_of(operand).promote(),
+            ExprKind::Binary(op, left, right) => {
+                let left = self.type_of(left).decay();
+                let right = self.type_of(right).decay();
+                match op {
+                    BinaryOp::Add if right.is_pointer_like() => right,
+                    BinaryOp::Add | BinaryOp::Sub
+                        if left.is_pointer_like() && !right.is_pointer_like() =>
+                    {
+                        left
+                    }
+                    BinaryOp::Shl | BinaryOp::Shr => left.promote(),
+                    _ if op.is_comparison() => Type::INT,
+                    BinaryOp::LogicAnd | BinaryOp::LogicOr => Type::INT,
+                    _ if left.is_integer() && right.is_integer() => left.common(&right),
+                    _ => Type::INT,
+                }
+            }
+            ExprKind::Assign(_, target, _) | ExprKind::IncDec { target, .. } => {
+                self.type_of(target)
+            }
+            ExprKind::Conditional(_, then, otherwise) => {
+                let then = self.type_of(then).decay();
+                let otherwise = self.type_of(otherwise).decay();
+                match then.is_integer() && otherwise.is_integer() {
+                    true => then.common(&otherwise),
+                    false => then,
+                }
+            }
+            ExprKind::Call(function, _) => match self.function_name(function) {
+                Some(name) => self
+                    .function(name)
+                    .map_or(Type::INT, |f| f.return_type.clone()),
+                None => match self.type_of(function).decay().target() {
+                    Some(Type::Function(return_type, _)) => *return_type.clone(),
+                    _ => panic!("Called object is not a function in line {}", expr.line),
+                },
+            },
+            ExprKind::Member(base, name) => self.member(base, name, expr.line).member_type,
+            ExprKind::Cast(target_type, _) => target_type.clone(),
+            ExprKind::Index(array, index) => {
+                let array_type = self.type_of(array);
+                let pointer = match array_type.target() {
+                    Some(_) => array_type,
+                    None => self.type_of(index),
+                };
+                pointer.target().cloned().unwrap_or_else(|| {
+                    panic!("Subscripted value is not an array in line {}", expr.line)
+                })
+            }
+            ExprKind::Deref(pointer) => {
+                self.type_of(pointer).target().cloned().unwrap_or_else(|| {
+                    panic!("Dereferenced value is not a pointer in line {}", expr.line)
+                })
+            }
+            ExprKind::AddressOf(inner) => Type::Pointer(Box::new(self.type_of(inner))),
+        }
+    }
+}
+
+/// Brings a value into the range of its type. Everything that is not an
+/// integer is a word holding an address.
+fn normalize(value: i64, value_type: &Type) -> i64 {
+    match value_type {
+        Type::Integer { .. } => value_type.convert_constant(value),
+        Type::Void => value,
+        _ => value as u16 as i64,
+    }
+}
+
+/// Applies an arithmetic operator to two integers, in a `long` if either
+/// is one and in a word otherwise. Shifts are done in the promoted type of
+/// the left operand, and like `sft` a word shifted by 16 or more, or by a
+/// negative count that the hardware reads as unsigned, keeps only zeros or
+/// sign bits.
+fn arithmetic(op: BinaryOp, left: &Type, right: &Type, a: i64, b: i64) -> i64 {
+    let value_type = match op {
+        BinaryOp::Shl | BinaryOp::Shr => left.promote(),
+        _ if left.is_integer() && right.is_integer() => left.common(right),
+        _ => Type::INT,
+    };
+    let bits = if value_type.is_long() { 32 } else { 16 };
+    let signed = !value_type.is_unsigned();
+    let a = normalize(a, &value_type);
+    let result = match op {
+        BinaryOp::Shl | BinaryOp::Shr => {
+            let count = b as i16 as i64;
+            match (bits, op) {
+                (16, BinaryOp::Shl) if !(0..16).contains(&count) => 0,
+                (16, _) if !(0..16).contains(&count) => a.min(0).signum(),
+                (_, _) if count <= 0 => a,
+                (_, BinaryOp::Shl) if count >= 32 => 0,
+                (_, _) if count >= 32 => a.min(0).signum(),
+                (_, BinaryOp::Shl) => a << count,
+                _ => a >> count,
+            }
+        }
+        _ => {
+            let b = normalize(b, &value_type);
+            match op {
+                BinaryOp::Add => a.wrapping_add(b),
+                BinaryOp::Sub => a.wrapping_sub(b),
+                BinaryOp::Mul => a.wrapping_mul(b),
+                BinaryOp::Div => divide(a, b, bits, signed).0,
+                BinaryOp::Mod => divide(a, b, bits, signed).1,
+                BinaryOp::And => a & b,
+                BinaryOp::Or => a | b,
+                BinaryOp::Xor => a ^ b,
+                _ => unreachable!("{:?} is no arithmetic operator", op),
+            }
+        }
+    };
+    normalize(result, &value_type)
+}
+
+/// Quotient and remainder the way the runtime library divides: on the
+/// magnitudes, with the quotient negated if the signs differ and the
+/// remainder taking the sign of the dividend. Dividing by 0 gives a
+/// quotient of all ones and the dividend as remainder.
+fn divide(n: i64, d: i64, bits: u32, signed: bool) -> (i64, i64) {
+    let mask = (1u64 << bits) - 1;
+    let magnitude = |value: i64| match signed && value < 0 {
+        true => value.unsigned_abs() & mask,
+        false => value as u64 & mask,
+    };
+    let (n_magnitude, d_magnitude) = (magnitude(n), magnitude(d));
+    let (quotient, remainder) = match d_magnitude {
+        0 => (mask, n_magnitude),
+        _ => (n_magnitude / d_magnitude, n_magnitude % d_magnitude),
+    };
+    let (quotient, remainder) = (quotient as i64, remainder as i64);
+    match signed {
+        true => (
+            if (n < 0) != (d < 0) {
+                -quotient
+            } else {
+                quotient
+            },
+            if n < 0 { -remainder } else { remainder },
+        ),
+        false => (quotient, remainder),
+    }
+}
+
+/// Whether `label` is `stmt` or inside it, without looking into nested
+/// switches.
+fn contains_label(stmt: &Stmt, label: &Stmt) -> bool {
+    if std::ptr::eq(stmt, label) {
+        return true;
+    }
+    match stmt {
+        Stmt::If(_, then, otherwise) => {
+            contains_label(then, label)
+                || otherwise
+                    .as_ref()
+                    .is_some_and(|otherwise| contains_label(otherwise, label))
+        }
+        Stmt::While(_, body)
+        | Stmt::DoWhile(body, _)
+        | Stmt::For(_, _, _, body)
+        | Stmt::Case(_, body)
+        | Stmt::Default(_, body) => contains_label(body, label),
+        Stmt::Block(stmts) => stmts.iter().any(|stmt| contains_label(stmt, label)),
+        _ => false,
+    }
+}
+
+/// Whether an item of a list initializes a whole value of the type, rather
+/// than its first scalar.
+fn initializes_whole(item: &Initializer, var_type: &Type) -> bool {
+    match item {
+        Initializer::Expr(expr) => matches!(expr.kind, ExprKind::Str(_)) && is_char_array(var_type),
+        Initializer::List(_) => true,
+    }
+}
+
+/// Arrays of word sized or smaller integers, which strings can initialize.
+fn is_char_array(var_type: &Type) -> bool {
+    matches!(var_type, Type::Array(element, _) if element.is_integer() && !element.is_long())
+}
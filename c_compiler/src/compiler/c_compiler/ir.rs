@@ -0,0 +1,481 @@
+//! Three-address intermediate representation between the AST and BEPL code.
+//!
+//! Every value lives in a virtual register. Functions are control flow graphs
+//! of basic blocks. After lowering a virtual register may be assigned in
+//! several places; `ssa::construct` turns that into SSA form with a single
+//! definition per register and phi instructions at join points.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use super::asm::{Item, Reg};
+
+pub type VReg = usize;
+pub type BlockId = usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BinOp {
+    Add,
+    Sub,
+    And,
+    Or,
+    Xor,
+    Shl,
+    /// Arithmetic shift to the right.
+    Shr,
+    /// Logical shift to the right.
+    UShr,
+    Mul,
+    Div,
+    Mod,
+    UDiv,
+    UMod,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cond {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
+    Const(VReg, i16),
+    Copy(VReg, VReg),
+    Binary(BinOp, VReg, VReg, VReg),
+    Load(VReg, VReg),
+    /// Stores the first register at the address in the second.
+    Store(VReg, VReg),
+    /// Address of a word in the stack frame of the function.
+    FrameAddr(VReg, usize),
+    /// Reads an argument passed by the caller.
+    Param(VReg, usize),
+    /// Writes an argument for the next call.
+    Arg(VReg, usize),
+    /// Calls a function with the given number of argument words. A `long`
+    /// result comes back as two registers, the low word first.
+    Call(Vec<VReg>, String, usize),
+    /// Calls the function whose address is in the second register.
+    CallIndirect(Vec<VReg>, VReg, usize),
+    /// ROM address of a function.
+    FuncAddr(VReg, String),
+    In(VReg, u8),
+    Out(VReg, u8),
+    Phi(VReg, Vec<(BlockId, VReg)>),
+    /// Reload and spill of registers that did not fit into the hardware.
+    SpillLoad(VReg, usize),
+    SpillStore(VReg, usize),
+    Asm(Box<InlineAsm>),
+}
+
+/// An `asm` statement. The body refers to operand `n` as register `8 + n`,
+/// counting the outputs first and the inputs after them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InlineAsm {
+    pub body: Vec<Item>,
+    pub outputs: Vec<VReg>,
+    pub inputs: Vec<VReg>,
+    /// The input each output starts out with, for `+r` operands.
+    pub tied: Vec<Option<usize>>,
+    /// Registers the body overwrites besides its outputs.
+    pub clobbers: Vec<Reg>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Inst {
+    pub op: Op,
+    pub line: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Terminator {
+    Jump(BlockId),
+    Branch(VReg, Cond, VReg, BlockId, BlockId),
+    /// Jumps to the block at an index from 0 to the length of the table.
+    Table(VReg, Vec<BlockId>),
+    /// Returns no value, a word or the two words of a `long`.
+    Return(Vec<VReg>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub insts: Vec<Inst>,
+    pub terminator: Terminator,
+    pub line: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub name: String,
+    pub blocks: Vec<Block>,
+    pub vreg_count: usize,
+    /// Words of stack frame used by arrays and variables whose address is
+    /// taken. Spill slots are added behind them.
+    pub frame_size: usize,
+    /// Source variables held in virtual registers, with the word of a
+    /// `long` each one holds. Registers that take over the value of a
+    /// variable during optimization inherit its name, for debug information.
+    pub variables: HashMap<VReg, (String, Option<usize>)>,
+    /// Variables in the stack frame by name, word of a `long` and slot.
+    pub frame_variables: Vec<(String, Option<usize>, usize)>,
+    /// Spill slots holding a source variable while the register spilled
+    /// into them would have been live.
+    pub spilled_variables: HashMap<usize, (String, Option<usize>)>,
+    /// Source file index and line of the definition, passed on from the AST.
+    pub file: usize,
+    pub line: usize,
+}
+
+impl Cond {
+    pub fn negate(self) -> Cond {
+        match self {
+            Cond::Eq => Cond::Ne,
+            Cond::Ne => Cond::Eq,
+            Cond::Lt => Cond::Ge,
+            Cond::Le => Cond::Gt,
+            Cond::Gt => Cond::Le,
+            Cond::Ge => Cond::Lt,
+        }
+    }
+
+    pub fn evaluate(self, a: i16, b: i16) -> bool {
+        match self {
+            Cond::Eq => a == b,
+            Cond::Ne => a != b,
+            Cond::Lt => a < b,
+            Cond::Le => a <= b,
+            Cond::Gt => a > b,
+            Cond::Ge => a >= b,
+        }
+    }
+}
+
+impl BinOp {
+    pub fn is_commutative(self) -> bool {
+        matches!(
+            self,
+            BinOp::Add | BinOp::And | BinOp::Or | BinOp::Xor | BinOp::Mul
+        )
+    }
+
+    /// Evaluates the operation with the wrapping 16 bit semantics of the
+    /// hardware. Returns `None` where the result is not defined.
+    pub fn evaluate(self, a: i16, b: i16) -> Option<i16> {
+        Some(match self {
+            BinOp::Add => a.wrapping_add(b),
+            BinOp::Sub => a.wrapping_sub(b),
+            BinOp::And => a & b,
+            BinOp::Or => a | b,
+            BinOp::Xor => a ^ b,
+            BinOp::Shl if (0..16).contains(&b) => a << b,
+            BinOp::Shr if (0..16).contains(&b) => a >> b,
+            BinOp::UShr if (0..16).contains(&b) => ((a as u16) >> b) as i16,
+            BinOp::Mul => a.wrapping_mul(b),
+            BinOp::Div if b != 0 => a.wrapping_div(b),
+            BinOp::Mod if b != 0 => a.wrapping_rem(b),
+            BinOp::UDiv if b != 0 => ((a as u16) / (b as u16)) as i16,
+            BinOp::UMod if b != 0 => ((a as u16) % (b as u16)) as i16,
+            _ => return None,
+        })
+    }
+}
+
+impl Op {
+    pub fn defs(&self) -> Vec<VReg> {
+        match self {
+            Op::Const(d, _)
+            | Op::Copy(d, _)
+            | Op::Binary(_, d, _, _)
+            | Op::Load(d, _)
+            | Op::FrameAddr(d, _)
+            | Op::Param(d, _)
+            | Op::In(d, _)
+            | Op::Phi(d, _)
+            | Op::SpillLoad(d, _)
+            | Op::FuncAddr(d, _) => vec![*d],
+            Op::Call(results, _, _) | Op::CallIndirect(results, _, _) => results.clone(),
+            Op::Asm(asm) => asm.outputs.clone(),
+            Op::Store(..) | Op::Arg(..) | Op::Out(..) | Op::SpillStore(..) => vec![],
+        }
+    }
+
+    pub fn uses(&self) -> Vec<VReg> {
+        match self {
+            Op::Const(..) | Op::FrameAddr(..) | Op::Param(..) | Op::Call(..) => vec![],
+            Op::FuncAddr(..) => vec![],
+            Op::CallIndirect(_, s, _) => vec![*s],
+            Op::In(..) | Op::SpillLoad(..) => vec![],
+            Op::Copy(_, s) | Op::Load(_, s) => vec![*s],
+            Op::Binary(_, _, a, b) | Op::Store(a, b) => vec![*a, *b],
+            Op::Arg(s, _) | Op::Out(s, _) | Op::SpillStore(s, _) => vec![*s],
+            Op::Phi(_, sources) => sources.iter().map(|(_, v)| *v).collect(),
+            Op::Asm(asm) => asm.inputs.clone(),
+        }
+    }
+
+    pub fn map_uses(&mut self, mut f: impl FnMut(VReg) -> VReg) {
+        match self {
+            Op::Const(..) | Op::FrameAddr(..) | Op::Param(..) | Op::Call(..) => (),
+            Op::FuncAddr(..) => (),
+            Op::CallIndirect(_, s, _) => *s = f(*s),
+            Op::In(..) | Op::SpillLoad(..) => (),
+            Op::Copy(_, s) | Op::Load(_, s) => *s = f(*s),
+            Op::Binary(_, _, a, b) | Op::Store(a, b) => {
+                *a = f(*a);
+                *b = f(*b);
+            }
+            Op::Arg(s, _) | Op::Out(s, _) | Op::SpillStore(s, _) => *s = f(*s),
+            Op::Phi(_, sources) => {
+                for (_, v) in sources {
+                    *v = f(*v);
+                }
+            }
+            Op::Asm(asm) => {
+                for v in &mut asm.inputs {
+                    *v = f(*v);
+                }
+            }
+        }
+    }
+
+    pub fn map_defs(&mut self, mut f: impl FnMut(VReg) -> VReg) {
+        match self {
+            Op::Const(d, _)
+            | Op::Copy(d, _)
+            | Op::Binary(_, d, _, _)
+            | Op::Load(d, _)
+            | Op::FrameAddr(d, _)
+            | Op::Param(d, _)
+            | Op::In(d, _)
+            | Op::Phi(d, _)
+            | Op::SpillLoad(d, _)
+            | Op::FuncAddr(d, _) => *d = f(*d),
+            Op::Call(results, _, _) | Op::CallIndirect(results, _, _) => {
+                for v in results {
+                    *v = f(*v);
+                }
+            }
+            Op::Asm(asm) => {
+                for v in &mut asm.outputs {
+                    *v = f(*v);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Whether removing or moving the instruction could change behavior.
+    pub fn has_side_effects(&self) -> bool {
+        matches!(
+            self,
+            Op::Store(..)
+                | Op::Arg(..)
+                | Op::Call(..)
+                | Op::CallIndirect(..)
+                | Op::In(..)
+                | Op::Out(..)
+                | Op::SpillStore(..)
+                | Op::Asm(..)
+        )
+    }
+
+    pub fn is_call(&self) -> bool {
+        matches!(self, Op::Call(..) | Op::CallIndirect(..))
+    }
+}
+
+impl Terminator {
+    pub fn uses(&self) -> Vec<VReg> {
+        match self {
+            Terminator::Jump(_) => vec![],
+            Terminator::Branch(a, _, b, _, _) => vec![*a, *b],
+            Terminator::Table(index, _) => vec![*index],
+            Terminator::Return(values) => values.clone(),
+        }
+    }
+
+    pub fn map_uses(&mut self, mut f: impl FnMut(VReg) -> VReg) {
+        match self {
+            Terminator::Jump(_) => (),
+            Terminator::Branch(a, _, b, _, _) => {
+                *a = f(*a);
+                *b = f(*b);
+            }
+            Terminator::Table(v, _) => *v = f(*v),
+            Terminator::Return(values) => {
+                for v in values {
+                    *v = f(*v);
+                }
+            }
+        }
+    }
+
+    pub fn successors(&self) -> Vec<BlockId> {
+        match self {
+            Terminator::Jump(target) => vec![*target],
+            Terminator::Branch(_, _, _, then, otherwise) if then == otherwise => vec![*then],
+            Terminator::Branch(_, _, _, then, otherwise) => vec![*then, *otherwise],
+            Terminator::Table(_, targets) => {
+                let mut successors = targets.clone();
+                successors.sort();
+                successors.dedup();
+                successors
+            }
+            Terminator::Return(_) => vec![],
+        }
+    }
+
+    pub fn map_successors(&mut self, mut f: impl FnMut(BlockId) -> BlockId) {
+        match self {
+            Terminator::Jump(target) => *target = f(*target),
+            Terminator::Branch(_, _, _, then, otherwise) => {
+                *then = f(*then);
+                *otherwise = f(*otherwise);
+            }
+            Terminator::Table(_, targets) => {
+                for target in targets {
+                    *target = f(*target);
+                }
+            }
+            Terminator::Return(_) => (),
+        }
+    }
+}
+
+impl Function {
+    pub fn new_vreg(&mut self) -> VReg {
+        self.vreg_count += 1;
+        self.vreg_count - 1
+    }
+
+    pub fn predecessors(&self) -> Vec<Vec<BlockId>> {
+        let mut predecessors = vec![Vec::new(); self.blocks.len()];
+        for (id, block) in self.blocks.iter().enumerate() {
+            for successor in block.terminator.successors() {
+                predecessors[successor].push(id);
+            }
+        }
+        predecessors
+    }
+
+    /// Blocks in reverse postorder starting at the entry block. Unreachable
+    /// blocks are left out.
+    pub fn reverse_postorder(&self) -> Vec<BlockId> {
+        let mut visited = vec![false; self.blocks.len()];
+        let mut order = Vec::new();
+        let mut stack = vec![(0, 0)];
+        visited[0] = true;
+
+        while let Some((block, next)) = stack.pop() {
+            let successors = self.blocks[block].terminator.successors();
+            if next < successors.len() {
+                stack.push((block, next + 1));
+                let successor = successors[next];
+                if !visited[successor] {
+                    visited[successor] = true;
+                    stack.push((successor, 0));
+                }
+            } else {
+                order.push(block);
+            }
+        }
+
+        order.reverse();
+        order
+    }
+
+    /// Drops unreachable blocks and renumbers the rest in reverse postorder.
+    pub fn remove_unreachable_blocks(&mut self) {
+        let order = self.reverse_postorder();
+        let mut new_id = vec![usize::MAX; self.blocks.len()];
+        for (new, old) in order.iter().enumerate() {
+            new_id[*old] = new;
+        }
+
+        let mut old_blocks: Vec<Option<Block>> = self.blocks.drain(..).map(Some).collect();
+        for old in order {
+            let mut block = old_blocks[old].take().unwrap();
+            block.terminator.map_successors(|s| new_id[s]);
+            for inst in &mut block.insts {
+                if let Op::Phi(_, sources) = &mut inst.op {
+                    sources.retain(|(pred, _)| new_id[*pred] != usize::MAX);
+                    for (pred, _) in sources.iter_mut() {
+                        *pred = new_id[*pred];
+                    }
+                }
+            }
+            self.blocks.push(block);
+        }
+    }
+
+    /// Replaces multiplications, divisions and remainders by calls to the
+    /// runtime library, since the hardware has no instructions for them.
+    pub fn call_runtime_library(&mut self) {
+        for block in &mut self.blocks {
+            let insts = std::mem::take(&mut block.insts);
+            for inst in insts {
+                let name = match inst.op {
+                    Op::Binary(BinOp::Mul, ..) => "__mul",
+                    Op::Binary(BinOp::Div, ..) => "__div",
+                    Op::Binary(BinOp::Mod, ..) => "__mod",
+                    Op::Binary(BinOp::UDiv, ..) => "__udiv",
+                    Op::Binary(BinOp::UMod, ..) => "__umod",
+                    _ => {
+                        block.insts.push(inst);
+                        continue;
+                    }
+                };
+                let Op::Binary(_, target, a, b) = inst.op else {
+                    unreachable!();
+                };
+                let line = inst.line;
+                block.insts.extend([
+                    Inst {
+                        op: Op::Arg(a, 0),
+                        line,
+                    },
+                    Inst {
+                        op: Op::Arg(b, 1),
+                        line,
+                    },
+                    Inst {
+                        op: Op::Call(vec![target], name.to_string(), 2),
+                        line,
+                    },
+                ]);
+            }
+        }
+    }
+
+    /// Names of all functions this function calls or takes the address
+    /// of, with the line of the first use.
+    pub fn callees(&self) -> Vec<(String, usize)> {
+        let mut callees = Vec::new();
+        for block in &self.blocks {
+            for inst in &block.insts {
+                if let Op::Call(_, name, _) | Op::FuncAddr(_, name) = &inst.op {
+                    callees.push((name.clone(), inst.line));
+                }
+            }
+        }
+        callees
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "function {}:", self.name)?;
+        for (id, block) in self.blocks.iter().enumerate() {
+            writeln!(f, "  b{}:", id)?;
+            for inst in &block.insts {
+                writeln!(f, "    {:?}", inst.op)?;
+            }
+            writeln!(f, "    {:?}", block.terminator)?;
+        }
+        Ok(())
+    }
+}
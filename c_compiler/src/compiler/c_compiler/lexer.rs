@@ -0,0 +1,254 @@
+use std::ops::Range;
+
+use logos::Logos;
+
+#[derive(Logos, Debug, Clone, PartialEq)]
+#[logos(skip r"[ \t\r\n\f]+")]
+#[logos(skip r"\\\r?\n")]
+#[logos(skip r"//[^\n]*")]
+#[logos(skip r"/\*[^*]*\*+([^/*][^*]*\*+)*/")]
+pub enum Token {
+    // Keywords
+    #[token("int")]
+    Int,
+    #[token("char")]
+    Char,
+    #[token("short")]
+    Short,
+    #[token("long")]
+    Long,
+    #[token("signed")]
+    Signed,
+    #[token("unsigned")]
+    Unsigned,
+    #[token("void")]
+    Void,
+    #[token("struct")]
+    Struct,
+    #[token("typedef")]
+    Typedef,
+    #[token("static")]
+    Static,
+    #[token("const")]
+    Const,
+    #[token("sizeof")]
+    Sizeof,
+    #[token("asm")]
+    #[token("__asm__")]
+    Asm,
+    #[token("volatile")]
+    #[token("__volatile__")]
+    Volatile,
+    #[token("if")]
+    If,
+    #[token("else")]
+    Else,
+    #[token("while")]
+    While,
+    #[token("do")]
+    Do,
+    #[token("for")]
+    For,
+    #[token("switch")]
+    Switch,
+    #[token("case")]
+    Case,
+    #[token("default")]
+    Default,
+    #[token("return")]
+    Return,
+    #[token("break")]
+    Break,
+    #[token("continue")]
+    Continue,
+
+    // Literals
+    #[regex("[a-zA-Z_][a-zA-Z0-9_]*", |lex| lex.slice().to_string())]
+    Ident(String),
+    #[regex("[0-9]+", |lex| lex.slice().parse::<i64>().ok())]
+    Number(i64),
+    /// A hex number, which can be `unsigned` where a decimal one is `long`.
+    #[regex("0[xX][0-9a-fA-F]+", |lex| i64::from_str_radix(&lex.slice()[2..], 16).ok())]
+    HexNumber(i64),
+    /// A number with a suffix like `u` or `L`, which is kept in lowercase.
+    #[regex("([0-9]+|0[xX][0-9a-fA-F]+)[uUlL]+", suffixed_number)]
+    SuffixedNumber((i64, String)),
+    #[regex(r"'([^'\\\n]|\\.)'", |lex| unescape(&lex.slice()[1..lex.slice().len() - 1]).first().copied())]
+    CharLit(u8),
+    #[regex(r#""([^"\\\n]|\\.)*""#, |lex| unescape(&lex.slice()[1..lex.slice().len() - 1]))]
+    StrLit(Vec<u8>),
+
+    // Punctuation
+    #[token("(")]
+    LParen,
+    #[token(")")]
+    RParen,
+    #[token("{")]
+    LBrace,
+    #[token("}")]
+    RBrace,
+    #[token("[")]
+    LBracket,
+    #[token("]")]
+    RBracket,
+    #[token(";")]
+    Semicolon,
+    #[token(",")]
+    Comma,
+    #[token("?")]
+    Question,
+    #[token(":")]
+    Colon,
+    #[token(".")]
+    Dot,
+    #[token("->")]
+    Arrow,
+    /// Starts a preprocessor directive at the beginning of a line.
+    #[token("#")]
+    Hash,
+
+    // Operators
+    #[token("=")]
+    Assign,
+    #[token("+=")]
+    PlusAssign,
+    #[token("-=")]
+    MinusAssign,
+    #[token("*=")]
+    StarAssign,
+    #[token("/=")]
+    SlashAssign,
+    #[token("%=")]
+    PercentAssign,
+    #[token("&=")]
+    AmpAssign,
+    #[token("|=")]
+    PipeAssign,
+    #[token("^=")]
+    CaretAssign,
+    #[token("<<=")]
+    ShlAssign,
+    #[token(">>=")]
+    ShrAssign,
+    #[token("++")]
+    PlusPlus,
+    #[token("--")]
+    MinusMinus,
+    #[token("+")]
+    Plus,
+    #[token("-")]
+    Minus,
+    #[token("*")]
+    Star,
+    #[token("/")]
+    Slash,
+    #[token("%")]
+    Percent,
+    #[token("&")]
+    Amp,
+    #[token("|")]
+    Pipe,
+    #[token("^")]
+    Caret,
+    #[token("~")]
+    Tilde,
+    #[token("!")]
+    Bang,
+    #[token("<<")]
+    Shl,
+    #[token(">>")]
+    Shr,
+    #[token("&&")]
+    AndAnd,
+    #[token("||")]
+    OrOr,
+    #[token("==")]
+    Eq,
+    #[token("!=")]
+    Ne,
+    #[token("<")]
+    Lt,
+    #[token("<=")]
+    Le,
+    #[token(">")]
+    Gt,
+    #[token(">=")]
+    Ge,
+}
+
+/// A token together with where it was read from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lexeme {
+    pub token: Token,
+    pub line: usize,
+    /// Index of the source file.
+    pub file: usize,
+    /// Whether the token is the first on its line, which is where
+    /// preprocessor directives start.
+    pub line_start: bool,
+    /// Byte range in the source file.
+    pub span: Range<usize>,
+}
+
+pub fn tokenize(raw_code: &str, file: usize) -> Vec<Lexeme> {
+    let mut lexer = Token::lexer(raw_code);
+    let mut tokens = Vec::new();
+    let mut line = 1;
+    let mut position = 0;
+
+    while let Some(token) = lexer.next() {
+        let gap = &raw_code[position..lexer.span().start];
+        let newlines = gap.matches('\n').count();
+        // Escaped line breaks continue the line
+        let continued = gap.matches("\\\n").count() + gap.matches("\\\r\n").count();
+        line += newlines;
+        position = lexer.span().start;
+        match token {
+            Ok(token) => tokens.push(Lexeme {
+                token,
+                line,
+                file,
+                line_start: tokens.is_empty() || newlines > continued,
+                span: lexer.span(),
+            }),
+            Err(_) => panic!("Unexpected character: {} in line {}", lexer.slice(), line),
+        }
+    }
+
+    tokens
+}
+
+fn suffixed_number(lex: &mut logos::Lexer<Token>) -> Option<(i64, String)> {
+    let slice = lex.slice();
+    let end = slice.trim_end_matches(['u', 'U', 'l', 'L']).len();
+    let value = match slice[..end]
+        .strip_prefix("0x")
+        .or(slice[..end].strip_prefix("0X"))
+    {
+        Some(hex) => i64::from_str_radix(hex, 16).ok()?,
+        None => slice[..end].parse().ok()?,
+    };
+    Some((value, slice[end..].to_lowercase()))
+}
+
+fn unescape(raw: &str) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut chars = raw.bytes();
+
+    while let Some(c) = chars.next() {
+        if c != b'\\' {
+            bytes.push(c);
+            continue;
+        }
+        bytes.push(match chars.next() {
+            Some(b'n') => b'\n',
+            Some(b't') => b'\t',
+            Some(b'r') => b'\r',
+            Some(b'0') => 0,
+            Some(other) => other,
+            None => b'\\',
+        });
+    }
+
+    bytes
+}
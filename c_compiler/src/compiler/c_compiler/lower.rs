@@ -0,0 +1,1982 @@
+//! Lowers the AST into the IR and lays out the data image.
+
+mod long;
+
+use std::collections::{HashMap, HashSet};
+use std::iter::Peekable;
+use std::slice;
+
+use super::asm::{self, Item, Reg};
+use super::ast::*;
+use super::ir::{self, BinOp, Block, BlockId, Cond, Inst, Op, Terminator, VReg};
+use super::opt::OptLevel;
+
+#[derive(Debug, Clone, Copy)]
+enum Var {
+    /// Scalars whose address is never taken live in virtual registers.
+    Reg(VReg),
+    /// The low and high word of a `long` in registers.
+    Pair(VReg, VReg),
+    Frame(usize),
+    Global(usize),
+}
+
+pub struct Lowering<'a> {
+    functions: HashMap<String, &'a Function>,
+    structs: &'a Structs,
+    globals: HashMap<String, (usize, Type)>,
+    /// Globals declared `const`.
+    constants: HashSet<String>,
+    data: Vec<i16>,
+    /// Strings already in the data image, which identical literals share.
+    strings: HashMap<Vec<u8>, usize>,
+    function_addresses: Vec<(usize, String)>,
+    opt_level: OptLevel,
+}
+
+impl<'a> Lowering<'a> {
+    pub fn new(program: &'a Program, opt_level: OptLevel) -> Lowering<'a> {
+        let mut functions: HashMap<String, &Function> = HashMap::new();
+        for function in &program.functions {
+            let defined = functions
+                .get(&function.name)
+                .is_some_and(|f| f.body.is_some());
+            if defined && function.body.is_some() {
+                panic!(
+                    "Function {} defined twice in line {}",
+                    function.name, function.line
+                );
+            }
+            if !defined {
+                functions.insert(function.name.clone(), function);
+            }
+        }
+
+        let mut lowering = Lowering {
+            functions,
+            structs: &program.structs,
+            globals: HashMap::new(),
+            constants: HashSet::new(),
+            data: Vec::new(),
+            strings: HashMap::new(),
+            function_addresses: Vec::new(),
+            opt_level,
+        };
+        for global in &program.globals {
+            lowering.add_global(global);
+        }
+        lowering
+    }
+
+    /// The initial contents of data memory.
+    pub fn data(&self) -> &[i16] {
+        &self.data
+    }
+
+    /// Data words that start out holding the address of a function, which
+    /// only the assembler knows.
+    pub fn function_addresses(&self) -> &[(usize, String)] {
+        &self.function_addresses
+    }
+
+    /// The type of a function used by name.
+    fn function_type(&self, name: &str, line: usize) -> Type {
+        match self.functions.get(name) {
+            Some(function) => Type::Function(
+                Box::new(function.return_type.clone()),
+                function.params.iter().map(|p| p.0.clone()).collect(),
+            ),
+            None => panic!("Undefined variable: {} in line {}", name, line),
+        }
+    }
+
+    pub fn lower_function(&mut self, name: &str, line: usize) -> ir::Function {
+        let function = *self
+            .functions
+            .get(name)
+            .unwrap_or_else(|| panic!("Undefined function: {} in line {}", name, line));
+        let body = function.body.as_ref().unwrap_or_else(|| {
+            panic!(
+                "Function {} is declared but never defined in line {}",
+                name, line
+            )
+        });
+
+        for (param_type, param_name) in &function.params {
+            assert!(
+                !matches!(param_type, Type::Struct(_)),
+                "Struct parameter {} has to be passed as a pointer in line {}",
+                param_name,
+                function.line
+            );
+        }
+        assert!(
+            !matches!(function.return_type, Type::Struct(_)),
+            "Function {} cannot return a struct in line {}",
+            name,
+            function.line
+        );
+
+        let mut addressed = HashSet::new();
+        for stmt in body {
+            stmt.walk_exprs(&mut |expr| {
+                if let ExprKind::AddressOf(inner) = &expr.kind {
+                    if let ExprKind::Ident(name) = &inner.kind {
+                        addressed.insert(name.clone());
+                    }
+                }
+            });
+        }
+
+        let mut builder = Builder {
+            lowering: self,
+            function: ir::Function {
+                name: function.name.clone(),
+                blocks: Vec::new(),
+                vreg_count: 0,
+                frame_size: 0,
+                variables: HashMap::new(),
+                frame_variables: Vec::new(),
+                spilled_variables: HashMap::new(),
+                file: function.file,
+                line: function.line,
+            },
+            current: 0,
+            line: function.line,
+            scopes: vec![HashMap::new()],
+            addressed,
+            loops: Vec::new(),
+            switches: Vec::new(),
+            return_type: function.return_type.clone(),
+            asm_blocks: 0,
+        };
+        builder.current = builder.new_block();
+
+        // A `long` parameter takes two argument words
+        let mut index = 0;
+        for (param_type, param_name) in &function.params {
+            let size = words(param_type);
+            let values: Vec<VReg> = (index..index + size)
+                .map(|i| {
+                    let value = builder.vreg();
+                    builder.emit(Op::Param(value, i));
+                    value
+                })
+                .collect();
+            index += size;
+            builder.declare(param_name, param_type.clone(), size, false);
+            match builder.lookup(param_name).0 {
+                Var::Frame(slot) => {
+                    for (i, value) in values.into_iter().enumerate() {
+                        builder.store_frame(value, slot + i);
+                    }
+                }
+                Var::Pair(..) => builder.set_pair(param_name, (values[0], values[1])),
+                _ => builder.set_var(param_name, values[0]),
+            }
+        }
+
+        for stmt in body {
+            builder.stmt(stmt);
+        }
+
+        let value = (function.name == "main").then(|| builder.constant(0));
+        builder.terminate(Terminator::Return(value.into_iter().collect()));
+        builder.function.remove_unreachable_blocks();
+        builder.function
+    }
+
+    fn add_global(&mut self, global: &Declaration) {
+        assert!(
+            !self.globals.contains_key(&global.name),
+            "Global {} defined twice in line {}",
+            global.name,
+            global.line
+        );
+        let (address, var_type) = self.add_static(global);
+        self.globals
+            .insert(global.name.clone(), (address, var_type));
+        if global.constant {
+            self.constants.insert(global.name.clone());
+        }
+    }
+
+    /// Places a variable with its initial value into the data image, for
+    /// globals and static locals. Returns its address and complete type.
+    fn add_static(&mut self, global: &Declaration) -> (usize, Type) {
+        let var_type = self.complete_type(&global.var_type, &global.init, global.line);
+        let address = self.data.len();
+        self.data
+            .resize(address + var_type.size(self.structs, global.line), 0);
+
+        let Some(init) = &global.init else {
+            return (address, var_type);
+        };
+        for (offset, value_type, value) in self.flatten_initializer(&var_type, init, global.line) {
+            if let Some(name) = self.function_reference(&value) {
+                self.function_addresses.push((address + offset, name));
+                continue;
+            }
+            let value = value_type.convert_constant(self.constant_initializer(&value));
+            self.data[address + offset] = value as i16;
+            if value_type.is_long() {
+                self.data[address + offset + 1] = (value >> 16) as i16;
+            }
+        }
+        (address, var_type)
+    }
+
+    /// Fills in array lengths that are given by the initializer.
+    fn complete_type(&self, var_type: &Type, init: &Option<Initializer>, line: usize) -> Type {
+        match (var_type, init) {
+            (Type::Array(element, None), Some(Initializer::List(items))) => {
+                // Elements without braces of their own take several items
+                let mut items = items.iter().peekable();
+                let mut length = 0;
+                while items.peek().is_some() {
+                    self.fill_field(element, &mut items, 0, &mut Vec::new(), line);
+                    length += 1;
+                }
+                Type::Array(element.clone(), Some(length))
+            }
+            (
+                Type::Array(element, None),
+                Some(Initializer::Expr(Expr {
+                    kind: ExprKind::Str(bytes),
+                    ..
+                })),
+            ) => Type::Array(element.clone(), Some(bytes.len() + 1)),
+            _ => var_type.clone(),
+        }
+    }
+
+    /// Lists the scalars an initializer sets by their offset in words,
+    /// together with the type they are stored as. Strings initialize arrays
+    /// character by character.
+    fn flatten_initializer(
+        &self,
+        var_type: &Type,
+        init: &Initializer,
+        line: usize,
+    ) -> Vec<(usize, Type, Expr)> {
+        let mut values = Vec::new();
+        self.flatten(var_type, init, 0, &mut values, line);
+        values
+    }
+
+    fn flatten(
+        &self,
+        var_type: &Type,
+        init: &Initializer,
+        offset: usize,
+        values: &mut Vec<(usize, Type, Expr)>,
+        line: usize,
+    ) {
+        match init {
+            Initializer::Expr(Expr {
+                kind: ExprKind::Str(bytes),
+                line,
+            }) if is_string_array(var_type) => {
+                let Type::Array(element, Some(length)) = var_type else {
+                    panic!("Array has unknown size in line {}", line);
+                };
+                assert!(
+                    bytes.len() <= *length,
+                    "String does not fit into array in line {}",
+                    line
+                );
+                for (i, byte) in bytes.iter().chain([&0]).take(*length).enumerate() {
+                    let value = Expr::new(ExprKind::Number(*byte as i64), *line);
+                    values.push((offset + i, (**element).clone(), value));
+                }
+            }
+            Initializer::Expr(expr) if var_type.is_aggregate() => {
+                panic!("Expected initializer list in line {}", expr.line)
+            }
+            Initializer::Expr(expr) => values.push((offset, var_type.clone(), expr.clone())),
+            Initializer::List(items) => {
+                let mut items = items.iter().peekable();
+                self.fill(var_type, &mut items, offset, values, line);
+                assert!(
+                    items.peek().is_none(),
+                    "Too many initializers in line {}",
+                    line
+                );
+            }
+        }
+    }
+
+    /// Initializes the elements or members of an aggregate from the items of
+    /// a list. Nested aggregates without braces of their own take as many
+    /// items as they need.
+    fn fill(
+        &self,
+        var_type: &Type,
+        items: &mut Peekable<slice::Iter<Initializer>>,
+        offset: usize,
+        values: &mut Vec<(usize, Type, Expr)>,
+        line: usize,
+    ) {
+        let fields: Vec<(usize, Type)> = match var_type {
+            Type::Array(element, Some(length)) => {
+                let size = element.size(self.structs, line);
+                (0..*length)
+                    .map(|i| (i * size, (**element).clone()))
+                    .collect()
+            }
+            Type::Struct(tag) => self
+                .layout(tag, line)
+                .members
+                .iter()
+                .map(|m| (m.offset, m.member_type.clone()))
+                .collect(),
+            scalar => vec![(0, scalar.clone())],
+        };
+
+        for (field_offset, field_type) in fields {
+            if items.peek().is_none() {
+                break;
+            }
+            self.fill_field(&field_type, items, offset + field_offset, values, line);
+        }
+    }
+
+    /// Initializes one element or member from the next items.
+    fn fill_field(
+        &self,
+        field_type: &Type,
+        items: &mut Peekable<slice::Iter<Initializer>>,
+        offset: usize,
+        values: &mut Vec<(usize, Type, Expr)>,
+        line: usize,
+    ) {
+        let braced = match items.peek() {
+            Some(Initializer::Expr(expr)) => {
+                matches!(expr.kind, ExprKind::Str(_)) && is_string_array(field_type)
+            }
+            _ => true,
+        };
+        if field_type.is_aggregate() && !braced {
+            self.fill(field_type, items, offset, values, line);
+        } else if let Some(item) = items.next() {
+            self.flatten(field_type, item, offset, values, line);
+        }
+    }
+
+    fn layout(&self, tag: &str, line: usize) -> &Struct {
+        self.structs
+            .get(tag)
+            .unwrap_or_else(|| panic!("Struct {} is incomplete in line {}", tag, line))
+    }
+
+    /// Evaluates the initializer of a global, which may also be the address
+    /// of another global or a string literal.
+    fn constant_initializer(&mut self, expr: &Expr) -> i64 {
+        if let Some(value) = fold_constant(expr) {
+            return value;
+        }
+        match &expr.kind {
+            ExprKind::Str(bytes) => self.add_string(bytes) as i64,
+            ExprKind::AddressOf(inner) => match &inner.kind {
+                ExprKind::Ident(name) => match self.globals.get(name) {
+                    Some((address, _)) => *address as i64,
+                    None => panic!("Undefined variable: {} in line {}", name, expr.line),
+                },
+                _ => panic!("Global initializer must be constant in line {}", expr.line),
+            },
+            ExprKind::Ident(name) => match self.globals.get(name) {
+                Some((address, Type::Array(..))) => *address as i64,
+                _ => panic!("Global initializer must be constant in line {}", expr.line),
+            },
+            _ => panic!("Global initializer must be constant in line {}", expr.line),
+        }
+    }
+
+    /// The function named by `f` or `&f` in a global initializer.
+    fn function_reference(&self, expr: &Expr) -> Option<String> {
+        match &expr.kind {
+            ExprKind::Ident(name)
+                if !self.globals.contains_key(name) && self.functions.contains_key(name) =>
+            {
+                Some(name.clone())
+            }
+            ExprKind::AddressOf(inner) | ExprKind::Cast(_, inner) => self.function_reference(inner),
+            _ => None,
+        }
+    }
+
+    /// Places a zero terminated string into the data image, unless the
+    /// same string is there already.
+    fn add_string(&mut self, bytes: &[u8]) -> usize {
+        if let Some(address) = self.strings.get(bytes) {
+            return *address;
+        }
+        let address = self.data.len();
+        self.data.extend(bytes.iter().map(|b| *b as i8 as i16));
+        self.data.push(0);
+        self.strings.insert(bytes.to_vec(), address);
+        address
+    }
+}
+
+/// Switches with fewer cases always compare them one by one.
+const MIN_JUMP_TABLE_CASES: usize = 4;
+
+/// At `-Os` copying or zeroing this many words calls a loop in the runtime
+/// library instead of a load and store per word. From here on even a single
+/// call takes less ROM than the inline code, including the loop itself.
+const MIN_HELPER_WORDS: usize = 16;
+
+/// Blocks of the labels of a switch statement.
+struct CaseBlocks {
+    value_type: Type,
+    cases: HashMap<i16, BlockId>,
+    default: Option<BlockId>,
+}
+
+struct Builder<'l, 'a> {
+    lowering: &'l mut Lowering<'a>,
+    function: ir::Function,
+    current: BlockId,
+    line: usize,
+    /// Variables by name, with whether they are `const`.
+    scopes: Vec<HashMap<String, (Var, Type, bool)>>,
+    addressed: HashSet<String>,
+    /// Targets of `continue` and `break` in the enclosing loops and
+    /// switches. A switch passes on the `continue` target around it.
+    loops: Vec<(Option<BlockId>, BlockId)>,
+    switches: Vec<CaseBlocks>,
+    return_type: Type,
+    /// Number of `asm` statements so far, to keep their labels apart.
+    asm_blocks: usize,
+}
+
+impl Builder<'_, '_> {
+    // Statements
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Decl(decl) => self.local(decl),
+            Stmt::Expr(expr) => {
+                self.expr(expr);
+            }
+            Stmt::If(condition, then, otherwise) => {
+                let then_block = self.new_block();
+                let else_block = self.new_block();
+                self.branch(condition, then_block, else_block);
+
+                self.current = then_block;
+                self.stmt(then);
+                match otherwise {
+                    Some(otherwise) => {
+                        let end = self.new_block();
+                        self.terminate(Terminator::Jump(end));
+                        self.current = else_block;
+                        self.stmt(otherwise);
+                        self.terminate(Terminator::Jump(end));
+                        self.current = end;
+                    }
+                    None => {
+                        self.terminate(Terminator::Jump(else_block));
+                        self.current = else_block;
+                    }
+                }
+            }
+            Stmt::While(condition, body) => {
+                let top = self.new_block();
+                let body_block = self.new_block();
+                let end = self.new_block();
+                self.terminate(Terminator::Jump(top));
+                self.current = top;
+                self.branch(condition, body_block, end);
+                self.current = body_block;
+                self.loop_body(body, top, end);
+                self.terminate(Terminator::Jump(top));
+                self.current = end;
+            }
+            Stmt::DoWhile(body, condition) => {
+                let top = self.new_block();
+                let next = self.new_block();
+                let end = self.new_block();
+                self.terminate(Terminator::Jump(top));
+                self.current = top;
+                self.loop_body(body, next, end);
+                self.terminate(Terminator::Jump(next));
+                self.current = next;
+                self.branch(condition, top, end);
+                self.current = end;
+            }
+            Stmt::For(init, condition, step, body) => {
+                self.scopes.push(HashMap::new());
+                if let Some(init) = init {
+                    self.stmt(init);
+                }
+                let top = self.new_block();
+                let body_block = self.new_block();
+                let next = self.new_block();
+                let end = self.new_block();
+                self.terminate(Terminator::Jump(top));
+                self.current = top;
+                match condition {
+                    Some(condition) => self.branch(condition, body_block, end),
+                    None => self.terminate(Terminator::Jump(body_block)),
+                }
+                self.current = body_block;
+                self.loop_body(body, next, end);
+                self.terminate(Terminator::Jump(next));
+                self.current = next;
+                if let Some(step) = step {
+                    self.expr(step);
+                }
+                self.terminate(Terminator::Jump(top));
+                self.current = end;
+                self.scopes.pop();
+            }
+            Stmt::Return(value) => {
+                let return_type = self.return_type.clone();
+                let values = match value {
+                    Some(value) if return_type.is_long() => {
+                        let (low, high) = self.long(value);
+                        vec![low, high]
+                    }
+                    Some(value) => vec![self.converted(value, &return_type)],
+                    None => Vec::new(),
+                };
+                assert!(
+                    !values.is_empty() || self.return_type == Type::Void,
+                    "Missing return value in line {}",
+                    self.line
+                );
+                self.terminate(Terminator::Return(values));
+                self.current = self.new_block();
+            }
+            Stmt::Break(line) => {
+                let (_, end) = *self
+                    .loops
+                    .last()
+                    .unwrap_or_else(|| panic!("Break outside of loop or switch in line {}", line));
+                self.terminate(Terminator::Jump(end));
+                self.current = self.new_block();
+            }
+            Stmt::Continue(line) => {
+                let next = self
+                    .loops
+                    .last()
+                    .and_then(|(next, _)| *next)
+                    .unwrap_or_else(|| panic!("Continue outside of loop in line {}", line));
+                self.terminate(Terminator::Jump(next));
+                self.current = self.new_block();
+            }
+            Stmt::Block(stmts) => {
+                self.scopes.push(HashMap::new());
+                for stmt in stmts {
+                    self.stmt(stmt);
+                }
+                self.scopes.pop();
+            }
+            Stmt::Switch(value, body) => self.switch(value, body),
+            Stmt::Case(value, body) => {
+                let switch = self
+                    .switches
+                    .last()
+                    .unwrap_or_else(|| panic!("Case outside of switch in line {}", value.line));
+                let block = switch.cases[&case_value(value, &switch.value_type)];
+                self.terminate(Terminator::Jump(block));
+                self.current = block;
+                self.stmt(body);
+            }
+            Stmt::Default(line, body) => {
+                let block = self
+                    .switches
+                    .last()
+                    .and_then(|switch| switch.default)
+                    .unwrap_or_else(|| panic!("Default outside of switch in line {}", line));
+                self.terminate(Terminator::Jump(block));
+                self.current = block;
+                self.stmt(body);
+            }
+            Stmt::Asm(asm) => self.inline_asm(asm),
+        }
+    }
+
+    /// Creates a block for every label of the switch, dispatches to them
+    /// and lowers the body, whose labels jump to their block when reached.
+    fn switch(&mut self, value: &Expr, body: &Stmt) {
+        let value_type = self.type_of(value).promote();
+        assert!(
+            value_type.is_integer() && !value_type.is_long(),
+            "Cannot switch on {} in line {}",
+            value_type,
+            value.line
+        );
+        let value = self.expr(value);
+
+        let end = self.new_block();
+        let mut labels = CaseBlocks {
+            value_type,
+            cases: HashMap::new(),
+            default: None,
+        };
+        let mut cases = Vec::new();
+        for (label, line) in body.case_labels() {
+            let block = self.new_block();
+            match label {
+                Some(label) => {
+                    let case = case_value(label, &labels.value_type);
+                    assert!(
+                        labels.cases.insert(case, block).is_none(),
+                        "Duplicate case value {} in line {}",
+                        case,
+                        line
+                    );
+                    cases.push((case, block));
+                }
+                None => {
+                    assert!(
+                        labels.default.is_none(),
+                        "Multiple default labels in one switch in line {}",
+                        line
+                    );
+                    labels.default = Some(block);
+                }
+            }
+        }
+        cases.sort();
+        let default = labels.default.unwrap_or(end);
+        if self.use_jump_table(&cases) {
+            self.jump_table(value, &cases, default);
+        } else {
+            for (case, block) in cases {
+                let next = self.new_block();
+                let case = self.constant(case);
+                self.terminate(Terminator::Branch(value, Cond::Eq, case, block, next));
+                self.current = next;
+            }
+            self.terminate(Terminator::Jump(default));
+        }
+
+        let next = self.loops.last().and_then(|(next, _)| *next);
+        self.loops.push((next, end));
+        self.switches.push(labels);
+        self.stmt(body);
+        self.switches.pop();
+        self.loops.pop();
+        self.terminate(Terminator::Jump(end));
+        self.current = end;
+    }
+
+    /// Jump tables pay off from a few cases on, as long as they are not
+    /// mostly holes. The startup code writes every entry to data memory,
+    /// so at `-Os` the table also has to take less ROM than comparing the
+    /// cases one by one.
+    fn use_jump_table(&self, cases: &[(i16, BlockId)]) -> bool {
+        let (Some(first), Some(last)) = (cases.first(), cases.last()) else {
+            return false;
+        };
+        let range = (last.0 as i32 - first.0 as i32 + 1) as usize;
+        if cases.len() < MIN_JUMP_TABLE_CASES || range > 3 * cases.len() {
+            return false;
+        }
+        if self.lowering.opt_level != OptLevel::Os {
+            return true;
+        }
+        // Loading the label, addressing the entry and storing it for every
+        // entry; two range checks, the subtraction and the indirect jump.
+        let table_size = 3 * range + 14;
+        // Loading the case value, the label and a conditional jump for
+        // every case.
+        let compare_size: usize = cases
+            .iter()
+            .map(|(case, _)| asm::load_constant(0, *case).len() + 2)
+            .sum();
+        table_size <= compare_size
+    }
+
+    /// Jumps to `default` unless `value` is one of the table entries from
+    /// the first to the last case.
+    fn jump_table(&mut self, value: VReg, cases: &[(i16, BlockId)], default: BlockId) {
+        let (first, last) = (cases[0].0, cases[cases.len() - 1].0);
+        let above_first = self.new_block();
+        let in_range = self.new_block();
+        let low = self.constant(first);
+        self.terminate(Terminator::Branch(
+            value,
+            Cond::Lt,
+            low,
+            default,
+            above_first,
+        ));
+        self.current = above_first;
+        let high = self.constant(last);
+        self.terminate(Terminator::Branch(value, Cond::Gt, high, default, in_range));
+        self.current = in_range;
+
+        let index = self.binary(BinOp::Sub, value, low);
+        let mut table = vec![default; (last as i32 - first as i32 + 1) as usize];
+        for &(case, block) in cases {
+            table[(case as i32 - first as i32) as usize] = block;
+        }
+        self.terminate(Terminator::Table(index, table));
+    }
+
+    /// Binds the operands to virtual registers and checks the template.
+    /// Immediate operands are written into the template, register operands
+    /// become placeholders that the code generator replaces.
+    fn inline_asm(&mut self, stmt: &Asm) {
+        self.line = stmt.line;
+        let line = stmt.line;
+        let operands: Vec<&AsmOperand> = stmt.outputs.iter().chain(&stmt.inputs).collect();
+
+        let mut inputs = Vec::new();
+        let mut placeholders = Vec::new();
+        for (i, operand) in stmt.inputs.iter().enumerate() {
+            match operand.constraint.as_str() {
+                "r" => {
+                    placeholders.push(format!("%{}", stmt.outputs.len() + inputs.len()));
+                    let value = self.expr(&operand.expr);
+                    inputs.push(value);
+                }
+                "i" | "n" => {
+                    let value = fold_constant(&operand.expr).unwrap_or_else(|| {
+                        panic!(
+                            "Operand {} of asm must be a constant in line {}",
+                            stmt.outputs.len() + i,
+                            line
+                        )
+                    });
+                    placeholders.push(value.to_string());
+                }
+                constraint => panic!(
+                    "Unknown input constraint \"{}\" in line {}",
+                    constraint, line
+                ),
+            }
+        }
+        let mut outputs = Vec::new();
+        let mut tied = Vec::new();
+        for (i, operand) in stmt.outputs.iter().enumerate() {
+            match operand.constraint.as_str() {
+                "=r" => tied.push(None),
+                "+r" => {
+                    tied.push(Some(inputs.len()));
+                    let value = self.expr(&operand.expr);
+                    inputs.push(value);
+                }
+                constraint => panic!(
+                    "Unknown output constraint \"{}\" in line {}",
+                    constraint, line
+                ),
+            }
+            placeholders.insert(i, format!("%{}", i));
+            outputs.push(self.vreg());
+        }
+
+        let mut clobbers = Vec::new();
+        for clobber in &stmt.clobbers {
+            match clobber
+                .strip_prefix('x')
+                .and_then(|n| n.parse::<Reg>().ok())
+            {
+                Some(reg) if reg < 7 => clobbers.push(reg),
+                Some(7) => panic!("Asm cannot clobber the stack pointer x7 in line {}", line),
+                _ if clobber == "memory" => (),
+                _ => panic!("Unknown clobber \"{}\" in line {}", clobber, line),
+            }
+        }
+        let untied = inputs.len() - tied.iter().flatten().count();
+        let needed = outputs.len() + untied + clobbers.iter().filter(|r| **r < 5).count();
+        assert!(
+            needed <= 5,
+            "Asm needs {} of the 5 registers x0 to x4 in line {}",
+            needed,
+            line
+        );
+
+        let template = asm_template(&stmt.template, &operands, &placeholders, line);
+        let prefix = format!("{}.asm{}.", self.function.name, self.asm_blocks);
+        self.asm_blocks += 1;
+        let body = asm_body(&template, outputs.len() + untied, &prefix, line);
+
+        self.line = line;
+        self.emit(Op::Asm(Box::new(ir::InlineAsm {
+            body,
+            outputs: outputs.clone(),
+            inputs,
+            tied,
+            clobbers,
+        })));
+        for (operand, output) in stmt.outputs.iter().zip(outputs) {
+            let target_type = self.type_of(&operand.expr);
+            assert!(
+                target_type.is_integer() || matches!(target_type, Type::Pointer(_)),
+                "Cannot bind {} to a register in line {}",
+                target_type,
+                line
+            );
+            self.check_writable(&operand.expr);
+            let value = self.convert(output, &Type::INT, &target_type);
+            self.assign(&operand.expr, value);
+        }
+    }
+
+    fn loop_body(&mut self, body: &Stmt, next: BlockId, end: BlockId) {
+        self.loops.push((Some(next), end));
+        self.stmt(body);
+        self.loops.pop();
+    }
+
+    fn local(&mut self, decl: &Declaration) {
+        self.line = decl.line;
+        // Static locals are set up in the data image once and for all
+        if decl.is_static {
+            let (address, var_type) = self.lowering.add_static(decl);
+            self.scopes.last_mut().unwrap().insert(
+                decl.name.clone(),
+                (Var::Global(address), var_type, decl.constant),
+            );
+            return;
+        }
+        let var_type = self
+            .lowering
+            .complete_type(&decl.var_type, &decl.init, decl.line);
+        let size = var_type.size(self.lowering.structs, decl.line);
+        self.declare(&decl.name, var_type.clone(), size, decl.constant);
+        let Some(init) = &decl.init else {
+            return;
+        };
+
+        // Structs may also be initialized with a copy of another one
+        if let (Type::Struct(_), Initializer::Expr(expr)) = (&var_type, init) {
+            let source = self.struct_value(expr, &var_type);
+            let target = self.address(&Expr::new(ExprKind::Ident(decl.name.clone()), decl.line));
+            self.copy(target, source, size);
+            return;
+        }
+
+        let values = self
+            .lowering
+            .flatten_initializer(&var_type, init, decl.line);
+        let slot = match self.lookup(&decl.name).0 {
+            Var::Reg(_) => {
+                for (_, value_type, value) in values {
+                    let value = self.converted(&value, &value_type);
+                    self.set_var(&decl.name, value);
+                }
+                return;
+            }
+            Var::Pair(..) => {
+                for (_, _, value) in values {
+                    let value = self.long(&value);
+                    self.set_pair(&decl.name, value);
+                }
+                return;
+            }
+            Var::Frame(slot) => slot,
+            Var::Global(_) => unreachable!(),
+        };
+
+        // Whatever the initializer leaves out is 0
+        let mut initialized = vec![false; size];
+        for (offset, value_type, value) in values {
+            if value_type.is_long() {
+                let (low, high) = self.long(&value);
+                self.store_frame(low, slot + offset);
+                self.store_frame(high, slot + offset + 1);
+                initialized[offset + 1] = true;
+            } else {
+                let value = self.converted(&value, &value_type);
+                self.store_frame(value, slot + offset);
+            }
+            initialized[offset] = true;
+        }
+        let mut offset = 0;
+        while offset < size {
+            let end = (offset..size).find(|i| initialized[*i]).unwrap_or(size);
+            if end - offset >= MIN_HELPER_WORDS && self.lowering.opt_level == OptLevel::Os {
+                let address = self.vreg();
+                self.emit(Op::FrameAddr(address, slot + offset));
+                let words = self.constant((end - offset) as i16);
+                self.call_helper("__zero", &[address, words]);
+            } else {
+                for offset in offset..end {
+                    let zero = self.constant(0);
+                    self.store_frame(zero, slot + offset);
+                }
+            }
+            offset = end + 1;
+        }
+    }
+
+    fn store_frame(&mut self, value: VReg, slot: usize) {
+        let address = self.vreg();
+        self.emit(Op::FrameAddr(address, slot));
+        self.emit(Op::Store(value, address));
+    }
+
+    // Expressions
+
+    fn expr(&mut self, expr: &Expr) -> VReg {
+        self.line = expr.line;
+        let line = expr.line;
+
+        if let Some(value) = fold_constant(expr) {
+            return self.constant(value as i16);
+        }
+        if let ExprKind::Assign(_, target, _) | ExprKind::IncDec { target, .. } = &expr.kind {
+            self.check_writable(target);
+        }
+        // Where a word is expected a `long` is cut down to its low word
+        if self.type_of(expr).is_long() {
+            return self.long(expr).0;
+        }
+
+        match &expr.kind {
+            ExprKind::Number(value) => self.constant(*value as i16),
+            ExprKind::Str(bytes) => {
+                let address = self.lowering.add_string(bytes);
+                self.constant(address as i16)
+            }
+            ExprKind::Ident(name) if self.function_name(expr).is_some() => {
+                self.function_address(name, line)
+            }
+            ExprKind::Ident(name) => match self.lookup(name) {
+                (Var::Reg(value), _) => value,
+                (_, var_type) if var_type.is_aggregate() => self.address(expr),
+                _ => {
+                    let address = self.address(expr);
+                    self.load(address)
+                }
+            },
+            ExprKind::Deref(pointer) if matches!(self.type_of(expr), Type::Function(..)) => {
+                self.expr(pointer)
+            }
+            ExprKind::Index(..) | ExprKind::Deref(_) | ExprKind::Member(..) => {
+                let address = self.address(expr);
+                match self.type_of(expr).is_aggregate() {
+                    true => address,
+                    false => self.load(address),
+                }
+            }
+            ExprKind::Cast(target_type, operand) => {
+                let from = self.type_of(operand).decay();
+                let value = self.expr(operand);
+                assert!(
+                    !matches!(target_type, Type::Struct(_)),
+                    "Cannot cast to a struct in line {}",
+                    line
+                );
+                self.convert(value, &from, target_type)
+            }
+            ExprKind::SizeOf(operand) => {
+                let size = self.type_of(operand).size(self.lowering.structs, line);
+                self.constant(size as i16)
+            }
+            ExprKind::AddressOf(inner) => self.address(inner),
+            ExprKind::Unary(UnaryOp::Neg, operand) => {
+                let value = self.expr(operand);
+                let zero = self.constant(0);
+                self.binary(BinOp::Sub, zero, value)
+            }
+            ExprKind::Unary(UnaryOp::BitNot, operand) => {
+                let value = self.expr(operand);
+                let ones = self.constant(-1);
+                self.binary(BinOp::Xor, value, ones)
+            }
+            ExprKind::Unary(UnaryOp::Not, _) => self.bool_value(expr),
+            ExprKind::Binary(op, ..) if op.is_comparison() => self.bool_value(expr),
+            ExprKind::Binary(BinaryOp::LogicAnd | BinaryOp::LogicOr, ..) => self.bool_value(expr),
+            ExprKind::Binary(op, left, right) => {
+                let left_type = self.type_of(left).decay();
+                let right_type = self.type_of(right).decay();
+                let mut a = self.expr(left);
+                let mut b = self.expr(right);
+
+                match (op, left_type.target(), right_type.target()) {
+                    (BinaryOp::Add | BinaryOp::Sub, Some(pointee), None) => {
+                        b = self.scale(b, pointee.size(self.lowering.structs, line));
+                    }
+                    (BinaryOp::Add, None, Some(pointee)) => {
+                        a = self.scale(a, pointee.size(self.lowering.structs, line));
+                    }
+                    (BinaryOp::Sub, Some(pointee), Some(_)) => {
+                        let size = pointee.size(self.lowering.structs, line);
+                        let difference = self.binary(BinOp::Sub, a, b);
+                        return self.unscale(difference, size);
+                    }
+                    _ => (),
+                }
+                let op = arithmetic_op(*op, &left_type, &right_type);
+                self.arithmetic(op, a, b, fold_constant(right))
+            }
+            ExprKind::Assign(None, target, value) => {
+                let target_type = self.type_of(target);
+                if let Type::Struct(_) = target_type {
+                    let source = self.struct_value(value, &target_type);
+                    let address = self.address(target);
+                    let size = target_type.size(self.lowering.structs, line);
+                    self.copy(address, source, size);
+                    return address;
+                }
+                let value = self.converted(value, &target_type);
+                self.assign(target, value);
+                value
+            }
+            ExprKind::Assign(Some(op), target, value) => {
+                let target_type = self.type_of(target);
+                let value_type = self.type_of(value).decay();
+                let pointer =
+                    target_type.is_pointer_like() && matches!(op, BinaryOp::Add | BinaryOp::Sub);
+                let divisor = fold_constant(value);
+                let op = arithmetic_op(*op, &target_type, &value_type);
+
+                if let Some(var) = self.register_var(target) {
+                    let mut value = self.expr(value);
+                    if pointer {
+                        let size = target_type
+                            .target()
+                            .unwrap()
+                            .size(self.lowering.structs, line);
+                        value = self.scale(value, size);
+                    }
+                    self.line = line;
+                    let new = self.arithmetic(op, var, value, divisor);
+                    let new = self.convert(new, &Type::INT, &target_type);
+                    self.emit(Op::Copy(var, new));
+                    return var;
+                }
+
+                let address = self.address(target);
+                let old = self.load(address);
+                let mut value = self.expr(value);
+                if pointer {
+                    let size = target_type
+                        .target()
+                        .unwrap()
+                        .size(self.lowering.structs, line);
+                    value = self.scale(value, size);
+                }
+                self.line = line;
+                let new = self.arithmetic(op, old, value, divisor);
+                let new = self.convert(new, &Type::INT, &target_type);
+                self.emit(Op::Store(new, address));
+                new
+            }
+            ExprKind::IncDec {
+                increment,
+                prefix,
+                target,
+            } => {
+                let target_type = self.type_of(target);
+                let step = match &target_type {
+                    Type::Pointer(pointee) => pointee.size(self.lowering.structs, line) as i16,
+                    _ => 1,
+                };
+                let step = self.constant(if *increment { step } else { -step });
+
+                if let Some(var) = self.register_var(target) {
+                    let old = if *prefix {
+                        var
+                    } else {
+                        let old = self.vreg();
+                        self.emit(Op::Copy(old, var));
+                        old
+                    };
+                    self.emit(Op::Binary(BinOp::Add, var, var, step));
+                    let new = self.convert(var, &Type::INT, &target_type);
+                    if new != var {
+                        self.emit(Op::Copy(var, new));
+                    }
+                    return if *prefix { var } else { old };
+                }
+
+                let address = self.address(target);
+                let old = self.load(address);
+                let new = self.binary(BinOp::Add, old, step);
+                let new = self.convert(new, &Type::INT, &target_type);
+                self.emit(Op::Store(new, address));
+                if *prefix {
+                    new
+                } else {
+                    old
+                }
+            }
+            ExprKind::Conditional(condition, then, otherwise) => {
+                let result = self.vreg();
+                let then_block = self.new_block();
+                let else_block = self.new_block();
+                let end = self.new_block();
+                self.branch(condition, then_block, else_block);
+
+                for (block, value) in [(then_block, then), (else_block, otherwise)] {
+                    self.current = block;
+                    let value = self.expr(value);
+                    self.emit(Op::Copy(result, value));
+                    self.terminate(Terminator::Jump(end));
+                }
+                self.current = end;
+                result
+            }
+            ExprKind::Call(function, args) => match self.function_name(function) {
+                Some("__in") => {
+                    let device = io_device("__in", args, 1, line);
+                    let value = self.vreg();
+                    self.emit(Op::In(value, device));
+                    value
+                }
+                Some("__out") => {
+                    let device = io_device("__out", args, 2, line);
+                    let value = self.expr(&args[1]);
+                    self.emit(Op::Out(value, device));
+                    value
+                }
+                _ => self.call(function, args, line)[0],
+            },
+        }
+    }
+
+    /// Emits a call of a function or function pointer and returns the words
+    /// of its result. Void functions return 0.
+    fn call(&mut self, function: &Expr, args: &[Expr], line: usize) -> Vec<VReg> {
+        match self.function_name(function) {
+            Some(name) => {
+                let function = *self
+                    .lowering
+                    .functions
+                    .get(name)
+                    .unwrap_or_else(|| panic!("Undefined function: {} in line {}", name, line));
+                let params: Vec<Type> = function.params.iter().map(|p| p.0.clone()).collect();
+                let size = self.pass_args(&format!("Function {}", name), &params, args, line);
+                self.call_result(&function.return_type, |results| {
+                    Op::Call(results, name.to_string(), size)
+                })
+            }
+            None => {
+                let (return_type, params) = match self.type_of(function).decay().target() {
+                    Some(Type::Function(return_type, params)) => {
+                        (*return_type.clone(), params.clone())
+                    }
+                    _ => panic!("Called object is not a function in line {}", line),
+                };
+                let address = self.expr(function);
+                let size = self.pass_args("Function pointer", &params, args, line);
+                self.call_result(&return_type, |results| {
+                    Op::CallIndirect(results, address, size)
+                })
+            }
+        }
+    }
+
+    /// Converts the arguments to the parameter types and writes them for
+    /// the next call. Returns the number of words written.
+    fn pass_args(&mut self, callee: &str, params: &[Type], args: &[Expr], line: usize) -> usize {
+        assert!(
+            params.len() == args.len(),
+            "{} expects {} arguments but got {} in line {}",
+            callee,
+            params.len(),
+            args.len(),
+            line
+        );
+
+        // All arguments are evaluated before the first one is written, so
+        // nested calls cannot overwrite them.
+        let mut values = Vec::new();
+        for (arg, param_type) in args.iter().zip(params) {
+            if param_type.is_long() {
+                let (low, high) = self.long(arg);
+                values.extend([low, high]);
+            } else {
+                values.push(self.converted(arg, param_type));
+            }
+        }
+        self.line = line;
+        let size = values.len();
+        for (i, value) in values.into_iter().enumerate() {
+            self.emit(Op::Arg(value, i));
+        }
+        size
+    }
+
+    /// Emits a call and returns the words of its result, which is 0 for
+    /// void functions.
+    fn call_result(&mut self, return_type: &Type, call: impl FnOnce(Vec<VReg>) -> Op) -> Vec<VReg> {
+        let size = match return_type {
+            Type::Void => 0,
+            _ => words(return_type),
+        };
+        let results: Vec<VReg> = (0..size.max(1)).map(|_| self.vreg()).collect();
+        self.emit(call(results[..size].to_vec()));
+        if size == 0 {
+            self.emit(Op::Const(results[0], 0));
+        }
+        results
+    }
+
+    fn function_address(&mut self, name: &str, line: usize) -> VReg {
+        self.lowering.function_type(name, line);
+        let address = self.vreg();
+        self.emit(Op::FuncAddr(address, name.to_string()));
+        address
+    }
+
+    /// Computes the address of an lvalue.
+    fn address(&mut self, expr: &Expr) -> VReg {
+        self.line = expr.line;
+        match &expr.kind {
+            ExprKind::Ident(name) if self.function_name(expr).is_some() => {
+                self.function_address(name, expr.line)
+            }
+            ExprKind::Ident(name) => match self.lookup(name).0 {
+                Var::Frame(slot) => {
+                    let address = self.vreg();
+                    self.emit(Op::FrameAddr(address, slot));
+                    address
+                }
+                Var::Global(address) => self.constant(address as i16),
+                Var::Reg(_) | Var::Pair(..) => {
+                    panic!("Cannot take the address of {} in line {}", name, expr.line)
+                }
+            },
+            ExprKind::Deref(pointer) => self.expr(pointer),
+            ExprKind::Index(array, index) => {
+                let sum = Expr::new(
+                    ExprKind::Binary(BinaryOp::Add, array.clone(), index.clone()),
+                    expr.line,
+                );
+                self.expr(&sum)
+            }
+            ExprKind::Member(base, name) => {
+                let member = self.member(base, name, expr.line);
+                let address = self.address(base);
+                self.offset(address, member.offset)
+            }
+            ExprKind::Str(bytes) => {
+                let address = self.lowering.add_string(bytes);
+                self.constant(address as i16)
+            }
+            _ => panic!("Expression is not assignable in line {}", expr.line),
+        }
+    }
+
+    fn assign(&mut self, target: &Expr, value: VReg) {
+        if let Some(var) = self.register_var(target) {
+            self.emit(Op::Copy(var, value));
+            return;
+        }
+        if let ExprKind::Ident(name) = &target.kind {
+            assert!(
+                !matches!(self.lookup(name).1, Type::Array(..)),
+                "Cannot assign to array {} in line {}",
+                name,
+                target.line
+            );
+        }
+        let address = self.address(target);
+        self.emit(Op::Store(value, address));
+    }
+
+    /// The address of a struct that is copied into one of type `var_type`.
+    fn struct_value(&mut self, expr: &Expr, var_type: &Type) -> VReg {
+        let value_type = self.type_of(expr);
+        assert!(
+            value_type == *var_type,
+            "Cannot assign {} to {} in line {}",
+            value_type,
+            var_type,
+            expr.line
+        );
+        self.expr(expr)
+    }
+
+    /// Copies `size` words from `source` to `target`.
+    fn copy(&mut self, target: VReg, source: VReg, size: usize) {
+        if size >= MIN_HELPER_WORDS && self.lowering.opt_level == OptLevel::Os {
+            let words = self.constant(size as i16);
+            self.call_helper("__copy", &[target, source, words]);
+            return;
+        }
+        for i in 0..size {
+            let from = self.offset(source, i);
+            let value = self.load(from);
+            let to = self.offset(target, i);
+            self.emit(Op::Store(value, to));
+        }
+    }
+
+    /// Calls a function of the runtime library that returns nothing.
+    fn call_helper(&mut self, name: &str, args: &[VReg]) {
+        for (i, arg) in args.iter().enumerate() {
+            self.emit(Op::Arg(*arg, i));
+        }
+        self.emit(Op::Call(Vec::new(), name.to_string(), args.len()));
+    }
+
+    fn member(&self, base: &Expr, name: &str, line: usize) -> Member {
+        match self.type_of(base) {
+            Type::Struct(tag) => self
+                .lowering
+                .layout(&tag, line)
+                .member(name)
+                .cloned()
+                .unwrap_or_else(|| {
+                    panic!("Struct {} has no member {} in line {}", tag, name, line)
+                }),
+            other => panic!(
+                "Member {} of {}, which is no struct, in line {}",
+                name, other, line
+            ),
+        }
+    }
+
+    /// Evaluates `expr` and converts it to the type it is stored as.
+    fn converted(&mut self, expr: &Expr, to: &Type) -> VReg {
+        let from = self.type_of(expr).decay();
+        let value = self.expr(expr);
+        self.convert(value, &from, to)
+    }
+
+    /// Converts a value to another type. Only `char` takes code, which
+    /// keeps the low 8 bits and extends them to the word.
+    fn convert(&mut self, value: VReg, from: &Type, to: &Type) -> VReg {
+        match to {
+            Type::Integer { bits: 8, signed } if from != to => {
+                if *signed {
+                    let shift = self.constant(8);
+                    let shifted = self.binary(BinOp::Shl, value, shift);
+                    self.binary(BinOp::Shr, shifted, shift)
+                } else {
+                    let mask = self.constant(0xff);
+                    self.binary(BinOp::And, value, mask)
+                }
+            }
+            _ => value,
+        }
+    }
+
+    /// Emits `op`. Unsigned divisions by a constant power of two become
+    /// shifts and masks.
+    fn arithmetic(&mut self, op: BinOp, a: VReg, b: VReg, divisor: Option<i64>) -> VReg {
+        let power = divisor.map(|d| d as u16).filter(|d| d.is_power_of_two());
+        match (op, power) {
+            (BinOp::UDiv, Some(d)) => {
+                let shift = self.constant(d.trailing_zeros() as i16);
+                self.binary(BinOp::UShr, a, shift)
+            }
+            (BinOp::UMod, Some(d)) => {
+                let mask = self.constant(d.wrapping_sub(1) as i16);
+                self.binary(BinOp::And, a, mask)
+            }
+            _ => self.binary(op, a, b),
+        }
+    }
+
+    /// The address `offset` words after `address`.
+    fn offset(&mut self, address: VReg, offset: usize) -> VReg {
+        if offset == 0 {
+            return address;
+        }
+        let offset = self.constant(offset as i16);
+        self.binary(BinOp::Add, address, offset)
+    }
+
+    /// The virtual register of a variable that lives in one.
+    fn register_var(&self, expr: &Expr) -> Option<VReg> {
+        match &expr.kind {
+            ExprKind::Ident(name) => match self.find_var(name) {
+                Some((Var::Reg(var), _)) => Some(var),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Evaluates a condition to 0 or 1.
+    fn bool_value(&mut self, expr: &Expr) -> VReg {
+        let result = self.vreg();
+        let true_block = self.new_block();
+        let false_block = self.new_block();
+        let end = self.new_block();
+        self.branch(expr, true_block, false_block);
+
+        for (block, value) in [(true_block, 1), (false_block, 0)] {
+            self.current = block;
+            self.emit(Op::Const(result, value));
+            self.terminate(Terminator::Jump(end));
+        }
+        self.current = end;
+        result
+    }
+
+    /// Ends the current block with a jump to `then` if `expr` is true and to
+    /// `otherwise` if not.
+    fn branch(&mut self, expr: &Expr, then: BlockId, otherwise: BlockId) {
+        self.line = expr.line;
+        if let Some(value) = fold_constant(expr) {
+            let target = if value != 0 { then } else { otherwise };
+            self.terminate(Terminator::Jump(target));
+            return;
+        }
+
+        match &expr.kind {
+            ExprKind::Unary(UnaryOp::Not, operand) => self.branch(operand, otherwise, then),
+            ExprKind::Binary(BinaryOp::LogicAnd, left, right) => {
+                let middle = self.new_block();
+                self.branch(left, middle, otherwise);
+                self.current = middle;
+                self.branch(right, then, otherwise);
+            }
+            ExprKind::Binary(BinaryOp::LogicOr, left, right) => {
+                let middle = self.new_block();
+                self.branch(left, then, middle);
+                self.current = middle;
+                self.branch(right, then, otherwise);
+            }
+            ExprKind::Binary(op, left, right) if op.is_comparison() => {
+                let left_type = self.type_of(left).decay();
+                let right_type = self.type_of(right).decay();
+                let cond = match op {
+                    BinaryOp::Eq => Cond::Eq,
+                    BinaryOp::Ne => Cond::Ne,
+                    BinaryOp::Lt => Cond::Lt,
+                    BinaryOp::Le => Cond::Le,
+                    BinaryOp::Gt => Cond::Gt,
+                    _ => Cond::Ge,
+                };
+                if left_type.is_long() || right_type.is_long() {
+                    self.long_compare(cond, left, right, then, otherwise);
+                    return;
+                }
+                let mut a = self.expr(left);
+                let mut b = self.expr(right);
+                self.line = expr.line;
+                // Flipping the sign bit orders unsigned values the way the
+                // signed comparisons of the hardware expect
+                let unsigned = left_type.is_integer()
+                    && right_type.is_integer()
+                    && left_type.common(&right_type).is_unsigned();
+                if unsigned && !matches!(cond, Cond::Eq | Cond::Ne) {
+                    let sign = self.constant(i16::MIN);
+                    a = self.binary(BinOp::Xor, a, sign);
+                    b = self.binary(BinOp::Xor, b, sign);
+                }
+                self.terminate(Terminator::Branch(a, cond, b, then, otherwise));
+            }
+            _ if self.type_of(expr).is_long() => self.long_test(expr, then, otherwise),
+            _ => {
+                let value = self.expr(expr);
+                let zero = self.constant(0);
+                self.terminate(Terminator::Branch(value, Cond::Ne, zero, then, otherwise));
+            }
+        }
+    }
+
+    /// Multiplies an index by the size of the elements it steps over.
+    fn scale(&mut self, value: VReg, size: usize) -> VReg {
+        match size {
+            1 => value,
+            _ if size.is_power_of_two() => {
+                let shift = self.constant(size.trailing_zeros() as i16);
+                self.binary(BinOp::Shl, value, shift)
+            }
+            _ => {
+                let size = self.constant(size as i16);
+                self.binary(BinOp::Mul, value, size)
+            }
+        }
+    }
+
+    /// Divides a pointer difference by the element size.
+    fn unscale(&mut self, value: VReg, size: usize) -> VReg {
+        match size {
+            1 => value,
+            _ if size.is_power_of_two() => {
+                let shift = self.constant(size.trailing_zeros() as i16);
+                self.binary(BinOp::Shr, value, shift)
+            }
+            _ => {
+                let size = self.constant(size as i16);
+                self.binary(BinOp::Div, value, size)
+            }
+        }
+    }
+
+    // Variables and types
+
+    fn declare(&mut self, name: &str, var_type: Type, size: usize, constant: bool) {
+        let var = if var_type.is_aggregate() || self.addressed.contains(name) {
+            let slot = self.function.frame_size;
+            self.function.frame_size += size;
+            let words = self.word_names(name, &var_type).into_iter().enumerate();
+            let words = words.map(|(i, (name, word))| (name, word, slot + i));
+            self.function.frame_variables.extend(words);
+            Var::Frame(slot)
+        } else if var_type.is_long() {
+            let (low, high) = (self.vreg(), self.vreg());
+            let variables = &mut self.function.variables;
+            variables.insert(low, (name.to_string(), Some(0)));
+            variables.insert(high, (name.to_string(), Some(1)));
+            Var::Pair(low, high)
+        } else {
+            let var = self.vreg();
+            self.function
+                .variables
+                .insert(var, (name.to_string(), None));
+            Var::Reg(var)
+        };
+        self.scopes
+            .last_mut()
+            .unwrap()
+            .insert(name.to_string(), (var, var_type, constant));
+    }
+
+    /// Names for the words of a variable in memory, for debug information.
+    /// Elements and members are named on their own, the words of a `long`
+    /// share the name.
+    fn word_names(&self, name: &str, var_type: &Type) -> Vec<(String, Option<usize>)> {
+        match var_type {
+            Type::Array(element, Some(length)) => (0..*length)
+                .flat_map(|i| self.word_names(&format!("{}[{}]", name, i), element))
+                .collect(),
+            Type::Struct(tag) => self
+                .lowering
+                .layout(tag, self.line)
+                .members
+                .iter()
+                .flat_map(|m| self.word_names(&format!("{}.{}", name, m.name), &m.member_type))
+                .collect(),
+            _ if var_type.is_long() => {
+                vec![(name.to_string(), Some(0)), (name.to_string(), Some(1))]
+            }
+            _ => vec![(name.to_string(), None)],
+        }
+    }
+
+    fn set_var(&mut self, name: &str, value: VReg) {
+        if let (Var::Reg(var), _) = self.lookup(name) {
+            self.emit(Op::Copy(var, value));
+        }
+    }
+
+    fn lookup(&self, name: &str) -> (Var, Type) {
+        self.find_var(name)
+            .unwrap_or_else(|| panic!("Undefined variable: {} in line {}", name, self.line))
+    }
+
+    fn find_var(&self, name: &str) -> Option<(Var, Type)> {
+        for scope in self.scopes.iter().rev() {
+            if let Some((var, var_type, _)) = scope.get(name) {
+                return Some((*var, var_type.clone()));
+            }
+        }
+        self.lowering
+            .globals
+            .get(name)
+            .map(|(address, var_type)| (Var::Global(*address), var_type.clone()))
+    }
+
+    /// Rejects assigning to a `const` variable or an element or member of
+    /// one.
+    fn check_writable(&self, target: &Expr) {
+        let name = match &target.kind {
+            ExprKind::Ident(name) => name,
+            ExprKind::Index(array, _) if matches!(self.type_of(array), Type::Array(..)) => {
+                return self.check_writable(array);
+            }
+            ExprKind::Member(base, _) => return self.check_writable(base),
+            _ => return,
+        };
+        let constant = self
+            .scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name).map(|(_, _, constant)| *constant))
+            .unwrap_or_else(|| self.lowering.constants.contains(name));
+        assert!(
+            !constant,
+            "Cannot assign to const variable {} in line {}",
+            name, target.line
+        );
+    }
+
+    /// The function an expression names, unless a variable hides it.
+    fn function_name<'e>(&self, expr: &'e Expr) -> Option<&'e str> {
+        match &expr.kind {
+            ExprKind::Ident(name) if self.find_var(name).is_none() => Some(name),
+            _ => None,
+        }
+    }
+
+    fn type_of(&self, expr: &Expr) -> Type {
+        match &expr.kind {
+            ExprKind::Number(value) => literal_type(*value),
+            ExprKind::SizeOf(_) => Type::INT,
+            ExprKind::Str(bytes) => Type::Array(Box::new(Type::CHAR), Some(bytes.len() + 1)),
+            ExprKind::Ident(name) => match self.find_var(name) {
+                Some((_, var_type)) => var_type,
+                None => self.lowering.function_type(name, expr.line),
+            },
+            ExprKind::Unary(UnaryOp::Not, _) => Type::INT,
+            ExprKind::Unary(_, operand) => self.type_of(operand).promote(),
+            ExprKind::Binary(op, left, right) => {
+                let left = self.type_of(left).decay();
+                let right = self.type_of(right).decay();
+                match op {
+                    BinaryOp::Add if right.is_pointer_like() => right,
+                    BinaryOp::Add | BinaryOp::Sub
+                        if left.is_pointer_like() && !right.is_pointer_like() =>
+                    {
+                        left
+                    }
+                    BinaryOp::Shl | BinaryOp::Shr => left.promote(),
+                    _ if op.is_comparison() => Type::INT,
+                    BinaryOp::LogicAnd | BinaryOp::LogicOr => Type::INT,
+                    _ if left.is_integer() && right.is_integer() => left.common(&right),
+                    _ => Type::INT,
+                }
+            }
+            ExprKind::Assign(_, target, _) => self.type_of(target),
+            ExprKind::IncDec { target, .. } => self.type_of(target),
+            ExprKind::Conditional(_, then, otherwise) => {
+                let then = self.type_of(then).decay();
+                let otherwise = self.type_of(otherwise).decay();
+                match then.is_integer() && otherwise.is_integer() {
+                    true => then.common(&otherwise),
+                    false => then,
+                }
+            }
+            ExprKind::Call(function, _) => match self.function_name(function) {
+                Some(name) => self
+                    .lowering
+                    .functions
+                    .get(name)
+                    .map_or(Type::INT, |f| f.return_type.clone()),
+                None => match self.type_of(function).decay().target() {
+                    Some(Type::Function(return_type, _)) => *return_type.clone(),
+                    _ => panic!("Called object is not a function in line {}", expr.line),
+                },
+            },
+            ExprKind::Member(base, name) => self.member(base, name, expr.line).member_type,
+            ExprKind::Cast(target_type, _) => target_type.clone(),
+            ExprKind::Index(array, index) => {
+                let array_type = self.type_of(array);
+                let pointer = match array_type.target() {
+                    Some(_) => array_type,
+                    None => self.type_of(index),
+                };
+                pointer.target().cloned().unwrap_or_else(|| {
+                    panic!("Subscripted value is not an array in line {}", expr.line)
+                })
+            }
+            ExprKind::Deref(pointer) => {
+                self.type_of(pointer).target().cloned().unwrap_or_else(|| {
+                    panic!("Dereferenced value is not a pointer in line {}", expr.line)
+                })
+            }
+            ExprKind::AddressOf(inner) => Type::Pointer(Box::new(self.type_of(inner))),
+        }
+    }
+
+    // Instruction helpers
+
+    fn vreg(&mut self) -> VReg {
+        self.function.new_vreg()
+    }
+
+    fn new_block(&mut self) -> BlockId {
+        self.function.blocks.push(Block {
+            insts: Vec::new(),
+            terminator: Terminator::Return(Vec::new()),
+            line: self.line,
+        });
+        self.function.blocks.len() - 1
+    }
+
+    fn emit(&mut self, op: Op) {
+        let line = self.line;
+        self.function.blocks[self.current]
+            .insts
+            .push(Inst { op, line });
+    }
+
+    /// Ends the current block. Code that follows before the next block is
+    /// selected is unreachable and goes into a fresh block.
+    fn terminate(&mut self, terminator: Terminator) {
+        let block = &mut self.function.blocks[self.current];
+        block.terminator = terminator;
+        block.line = self.line;
+        self.current = self.new_block();
+    }
+
+    fn constant(&mut self, value: i16) -> VReg {
+        let target = self.vreg();
+        self.emit(Op::Const(target, value));
+        target
+    }
+
+    fn binary(&mut self, op: BinOp, a: VReg, b: VReg) -> VReg {
+        let target = self.vreg();
+        self.emit(Op::Binary(op, target, a, b));
+        target
+    }
+
+    fn load(&mut self, address: VReg) -> VReg {
+        let target = self.vreg();
+        self.emit(Op::Load(target, address));
+        target
+    }
+}
+
+/// The IR operation for an arithmetic operator on operands of the given
+/// types. Division, remainder and right shifts depend on the signedness.
+fn arithmetic_op(op: BinaryOp, left: &Type, right: &Type) -> BinOp {
+    let unsigned = match op {
+        BinaryOp::Shr => left.promote().is_unsigned(),
+        _ => left.is_integer() && right.is_integer() && left.common(right).is_unsigned(),
+    };
+    match (binary_op(op), unsigned) {
+        (BinOp::Div, true) => BinOp::UDiv,
+        (BinOp::Mod, true) => BinOp::UMod,
+        (BinOp::Shr, true) => BinOp::UShr,
+        (op, _) => op,
+    }
+}
+
+fn binary_op(op: BinaryOp) -> BinOp {
+    match op {
+        BinaryOp::Add => BinOp::Add,
+        BinaryOp::Sub => BinOp::Sub,
+        BinaryOp::Mul => BinOp::Mul,
+        BinaryOp::Div => BinOp::Div,
+        BinaryOp::Mod => BinOp::Mod,
+        BinaryOp::And => BinOp::And,
+        BinaryOp::Or => BinOp::Or,
+        BinaryOp::Xor => BinOp::Xor,
+        BinaryOp::Shl => BinOp::Shl,
+        BinaryOp::Shr => BinOp::Shr,
+        _ => unreachable!("{:?} is lowered to branches", op),
+    }
+}
+
+/// Checks the arguments of the `__in` and `__out` intrinsics. The device
+/// is encoded into the instruction and therefore has to be a constant.
+fn io_device(name: &str, args: &[Expr], arg_count: usize, line: usize) -> u8 {
+    assert!(
+        args.len() == arg_count,
+        "Function {} expects {} arguments but got {} in line {}",
+        name,
+        arg_count,
+        args.len(),
+        line
+    );
+    let device = fold_constant(&args[0])
+        .unwrap_or_else(|| panic!("Device of {} must be a constant in line {}", name, line));
+    assert!(
+        (0..8).contains(&device),
+        "Device out of range: {} in line {}. Must be 0 <= device <= 7",
+        device,
+        line
+    );
+    device as u8
+}
+
+/// The type of a number, the first of `int`, `long` and `unsigned long`
+/// it fits into. Hex numbers that only fit `unsigned` are cast to it by the
+/// parser.
+fn literal_type(value: i64) -> Type {
+    match value {
+        value if i16::try_from(value).is_ok() => Type::INT,
+        value if i32::try_from(value).is_ok() => Type::LONG,
+        _ => Type::ULONG,
+    }
+}
+
+/// The value of a constant expression, computed in the types of its
+/// operands like the code for it would. Divisions by 0 and shifts by more
+/// than the width are left to the code.
+fn fold_constant(expr: &Expr) -> Option<i64> {
+    fold(expr).map(|(value, _)| value)
+}
+
+/// The value and the type of a constant expression.
+fn fold(expr: &Expr) -> Option<(i64, Type)> {
+    match &expr.kind {
+        ExprKind::Number(value) => Some((*value, literal_type(*value))),
+        ExprKind::Unary(op, operand) => {
+            let (value, operand_type) = fold(operand)?;
+            let value_type = operand_type.promote();
+            let value = match op {
+                UnaryOp::Neg => -value,
+                UnaryOp::Not => return Some(((value == 0) as i64, Type::INT)),
+                UnaryOp::BitNot => !value,
+            };
+            Some((value_type.convert_constant(value), value_type))
+        }
+        ExprKind::Binary(op, left, right) => {
+            let (a, left_type) = fold(left)?;
+            let (b, right_type) = fold(right)?;
+            if !left_type.is_integer() || !right_type.is_integer() {
+                return None;
+            }
+            let value_type = match op {
+                BinaryOp::Shl | BinaryOp::Shr => left_type.promote(),
+                _ => left_type.common(&right_type),
+            };
+            let (a, b) = (
+                value_type.convert_constant(a),
+                value_type.convert_constant(b),
+            );
+            let truth = |value: bool| Some((value as i64, Type::INT));
+            let value = match op {
+                BinaryOp::Add => a.wrapping_add(b),
+                BinaryOp::Sub => a.wrapping_sub(b),
+                BinaryOp::Mul => a.wrapping_mul(b),
+                BinaryOp::Div => a.checked_div(b)?,
+                BinaryOp::Mod => a.checked_rem(b)?,
+                BinaryOp::And => a & b,
+                BinaryOp::Or => a | b,
+                BinaryOp::Xor => a ^ b,
+                BinaryOp::Shl | BinaryOp::Shr => {
+                    let bits = if value_type.is_long() { 32 } else { 16 };
+                    let count = right_type.promote().convert_constant(b);
+                    if !(0..bits).contains(&count) {
+                        return None;
+                    }
+                    match op {
+                        BinaryOp::Shl => a << count,
+                        _ => a >> count,
+                    }
+                }
+                BinaryOp::Eq => return truth(a == b),
+                BinaryOp::Ne => return truth(a != b),
+                BinaryOp::Lt => return truth(a < b),
+                BinaryOp::Le => return truth(a <= b),
+                BinaryOp::Gt => return truth(a > b),
+                BinaryOp::Ge => return truth(a >= b),
+                BinaryOp::LogicAnd => return truth(a != 0 && b != 0),
+                BinaryOp::LogicOr => return truth(a != 0 || b != 0),
+            };
+            Some((value_type.convert_constant(value), value_type))
+        }
+        ExprKind::Conditional(condition, then, otherwise) => {
+            let (condition, _) = fold(condition)?;
+            let (then, then_type) = fold(then)?;
+            let (otherwise, otherwise_type) = fold(otherwise)?;
+            let value = if condition != 0 { then } else { otherwise };
+            match then_type.is_integer() && otherwise_type.is_integer() {
+                true => {
+                    let value_type = then_type.common(&otherwise_type);
+                    Some((value_type.convert_constant(value), value_type))
+                }
+                false => Some((value, then_type)),
+            }
+        }
+        ExprKind::Cast(target, operand) if target.is_integer() => {
+            Some((target.convert_constant(fold(operand)?.0), target.clone()))
+        }
+        ExprKind::Cast(target @ Type::Pointer(_), operand) => {
+            Some((fold(operand)?.0, target.clone()))
+        }
+        _ => None,
+    }
+}
+
+/// The value of a case label, converted to the type switched on.
+fn case_value(label: &Expr, value_type: &Type) -> i16 {
+    let value = fold_constant(label)
+        .unwrap_or_else(|| panic!("Case value must be a constant in line {}", label.line));
+    value_type.convert_constant(value) as i16
+}
+
+/// Replaces `%n` and `%[name]` in an asm template by `placeholders[n]`.
+fn asm_template(
+    template: &str,
+    operands: &[&AsmOperand],
+    placeholders: &[String],
+    line: usize,
+) -> String {
+    let mut result = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('%') {
+        result += &rest[..start];
+        rest = &rest[start + 1..];
+        let digits = rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        let index = if digits > 0 {
+            let index: usize = rest[..digits].parse().unwrap();
+            rest = &rest[digits..];
+            index
+        } else if let Some(named) = rest.strip_prefix('[') {
+            let end = named
+                .find(']')
+                .unwrap_or_else(|| panic!("Unterminated operand name in asm in line {}", line));
+            let name = &named[..end];
+            rest = &named[end + 1..];
+            operands
+                .iter()
+                .position(|operand| operand.name.as_deref() == Some(name))
+                .unwrap_or_else(|| panic!("Unknown asm operand {} in line {}", name, line))
+        } else {
+            result.push('%');
+            continue;
+        };
+        let placeholder = placeholders
+            .get(index)
+            .unwrap_or_else(|| panic!("Asm operand {} does not exist in line {}", index, line));
+        result += placeholder;
+    }
+    result + rest
+}
+
+/// Parses the lines of an asm template, which may also be separated by
+/// `;`. Labels are renamed with `prefix` so that they stay local.
+fn asm_body(template: &str, operands: usize, prefix: &str, line: usize) -> Vec<Item> {
+    let mut body = Vec::new();
+    for text in template.split(['\n', ';']) {
+        match asm::parse_line(text, operands) {
+            Ok(Some(item)) => body.push(item),
+            Ok(None) => (),
+            Err(error) => panic!("{} in asm in line {}", error, line),
+        }
+    }
+
+    let mut labels = HashSet::new();
+    for item in &body {
+        if let Item::Label(label) = item {
+            assert!(
+                labels.insert(label.clone()),
+                "Label {} defined twice in asm in line {}",
+                label,
+                line
+            );
+        }
+    }
+    for item in &mut body {
+        match item {
+            Item::Label(label) => *label = format!("{}{}", prefix, label),
+            Item::SetLabel(_, label) => {
+                assert!(
+                    labels.contains(label),
+                    "Undefined label: {} in asm in line {}",
+                    label,
+                    line
+                );
+                *label = format!("{}{}", prefix, label);
+            }
+            _ => (),
+        }
+    }
+    body
+}
+
+/// Arrays that a string literal can initialize.
+/// Number of argument or return words a value of the type takes.
+fn words(value_type: &Type) -> usize {
+    if value_type.is_long() {
+        2
+    } else {
+        1
+    }
+}
+
+fn is_string_array(var_type: &Type) -> bool {
+    matches!(var_type, Type::Array(element, _) if element.is_integer() && !element.is_long())
+}
@@ -0,0 +1,415 @@
+//! Lowering of `long`, which takes a pair of words with the low word first.
+//!
+//! The hardware has no carry flag, so additions and subtractions work out
+//! the carry between the words from the sign bits of the operands and the
+//! low word of the result. Multiplication, division and shifts by a
+//! variable amount call the runtime library.
+
+use super::*;
+
+/// The low and the high word of a `long`.
+pub(super) type Pair = (VReg, VReg);
+
+impl Builder<'_, '_> {
+    /// Evaluates an integer expression as a `long`. Narrower values are
+    /// extended according to their own signedness.
+    pub(super) fn long(&mut self, expr: &Expr) -> Pair {
+        self.line = expr.line;
+        let line = expr.line;
+        let expr_type = self.type_of(expr).decay();
+        if !expr_type.is_long() {
+            let value = self.expr(expr);
+            return self.extend(value, &expr_type);
+        }
+        if let Some(value) = fold_constant(expr) {
+            return self.long_constant(expr_type.convert_constant(value));
+        }
+        if let ExprKind::Assign(_, target, _) | ExprKind::IncDec { target, .. } = &expr.kind {
+            self.check_writable(target);
+        }
+
+        match &expr.kind {
+            ExprKind::Ident(name) => match self.lookup(name).0 {
+                Var::Pair(low, high) => (low, high),
+                _ => {
+                    let address = self.address(expr);
+                    self.load_long(address)
+                }
+            },
+            ExprKind::Index(..) | ExprKind::Deref(_) | ExprKind::Member(..) => {
+                let address = self.address(expr);
+                self.load_long(address)
+            }
+            // Between `long` and `unsigned long` only the type changes
+            ExprKind::Cast(_, operand) => self.long(operand),
+            ExprKind::Unary(UnaryOp::Neg, operand) => {
+                let value = self.long(operand);
+                let zero = self.long_constant(0);
+                self.line = line;
+                self.long_sub(zero, value)
+            }
+            ExprKind::Unary(UnaryOp::BitNot, operand) => {
+                let (low, high) = self.long(operand);
+                let ones = self.constant(-1);
+                let low = self.binary(BinOp::Xor, low, ones);
+                (low, self.binary(BinOp::Xor, high, ones))
+            }
+            ExprKind::Binary(op @ (BinaryOp::Shl | BinaryOp::Shr), left, right) => {
+                let value = self.long(left);
+                self.long_shift(shift_op(*op, &expr_type), value, right)
+            }
+            ExprKind::Binary(op, left, right) => {
+                let a = self.long(left);
+                let b = self.long(right);
+                self.line = line;
+                self.long_arithmetic(*op, &expr_type, a, b)
+            }
+            ExprKind::Assign(None, target, value) => {
+                let value = self.long(value);
+                self.line = line;
+                self.store_target(target, None, value);
+                value
+            }
+            ExprKind::Assign(Some(op), target, value) => {
+                let (address, old) = self.load_target(target);
+                let new = match op {
+                    BinaryOp::Shl | BinaryOp::Shr => {
+                        self.long_shift(shift_op(*op, &expr_type), old, value)
+                    }
+                    _ => {
+                        let value_type = self.type_of(value).decay();
+                        let value = self.long(value);
+                        self.line = line;
+                        self.long_arithmetic(*op, &expr_type.common(&value_type), old, value)
+                    }
+                };
+                self.store_target(target, address, new);
+                new
+            }
+            ExprKind::IncDec {
+                increment,
+                prefix,
+                target,
+            } => {
+                let (address, old) = self.load_target(target);
+                // A variable in registers is overwritten by the new value
+                let old = match prefix {
+                    true => old,
+                    false => self.copy_long(old),
+                };
+                let step = self.long_constant(if *increment { 1 } else { -1 });
+                let new = self.long_add(old, step);
+                self.store_target(target, address, new);
+                if *prefix {
+                    new
+                } else {
+                    old
+                }
+            }
+            ExprKind::Conditional(condition, then, otherwise) => {
+                let result = (self.vreg(), self.vreg());
+                let then_block = self.new_block();
+                let else_block = self.new_block();
+                let end = self.new_block();
+                self.branch(condition, then_block, else_block);
+
+                for (block, value) in [(then_block, then), (else_block, otherwise)] {
+                    self.current = block;
+                    let (low, high) = self.long(value);
+                    self.emit(Op::Copy(result.0, low));
+                    self.emit(Op::Copy(result.1, high));
+                    self.terminate(Terminator::Jump(end));
+                }
+                self.current = end;
+                result
+            }
+            ExprKind::Call(function, args) => {
+                let results = self.call(function, args, line);
+                (results[0], results[1])
+            }
+            _ => unreachable!("{:?} has no long value in line {}", expr.kind, line),
+        }
+    }
+
+    /// Branches on a comparison where either operand is a `long`. The high
+    /// words decide unless they are equal, then the low words are compared
+    /// as unsigned.
+    pub(super) fn long_compare(
+        &mut self,
+        cond: Cond,
+        left: &Expr,
+        right: &Expr,
+        then: BlockId,
+        otherwise: BlockId,
+    ) {
+        let line = self.line;
+        let left_type = self.type_of(left).decay();
+        let right_type = self.type_of(right).decay();
+        let unsigned = left_type.common(&right_type).is_unsigned();
+        let (a_low, a_high) = self.long(left);
+        let (b_low, b_high) = self.long(right);
+        self.line = line;
+
+        let low_block = self.new_block();
+        if let Cond::Eq | Cond::Ne = cond {
+            let (equal, different) = match cond {
+                Cond::Eq => (then, otherwise),
+                _ => (otherwise, then),
+            };
+            self.terminate(Terminator::Branch(
+                a_high,
+                Cond::Ne,
+                b_high,
+                different,
+                low_block,
+            ));
+            self.current = low_block;
+            self.terminate(Terminator::Branch(a_low, Cond::Ne, b_low, different, equal));
+            return;
+        }
+
+        let (a_high, b_high) = match unsigned {
+            true => (self.flip_sign(a_high), self.flip_sign(b_high)),
+            false => (a_high, b_high),
+        };
+        let a_low = self.flip_sign(a_low);
+        let b_low = self.flip_sign(b_low);
+        let strict = match cond {
+            Cond::Lt | Cond::Le => Cond::Lt,
+            _ => Cond::Gt,
+        };
+        let high_differs = self.new_block();
+        self.terminate(Terminator::Branch(
+            a_high,
+            strict,
+            b_high,
+            then,
+            high_differs,
+        ));
+        self.current = high_differs;
+        self.terminate(Terminator::Branch(
+            a_high,
+            Cond::Ne,
+            b_high,
+            otherwise,
+            low_block,
+        ));
+        self.current = low_block;
+        self.terminate(Terminator::Branch(a_low, cond, b_low, then, otherwise));
+    }
+
+    /// Branches on whether a `long` is not 0.
+    pub(super) fn long_test(&mut self, expr: &Expr, then: BlockId, otherwise: BlockId) {
+        let (low, high) = self.long(expr);
+        let zero = self.constant(0);
+        let high_block = self.new_block();
+        self.terminate(Terminator::Branch(low, Cond::Ne, zero, then, high_block));
+        self.current = high_block;
+        self.terminate(Terminator::Branch(high, Cond::Ne, zero, then, otherwise));
+    }
+
+    fn long_arithmetic(&mut self, op: BinaryOp, value_type: &Type, a: Pair, b: Pair) -> Pair {
+        let unsigned = value_type.is_unsigned();
+        let name = match op {
+            BinaryOp::Add => return self.long_add(a, b),
+            BinaryOp::Sub => return self.long_sub(a, b),
+            BinaryOp::And | BinaryOp::Or | BinaryOp::Xor => {
+                let op = binary_op(op);
+                let low = self.binary(op, a.0, b.0);
+                return (low, self.binary(op, a.1, b.1));
+            }
+            BinaryOp::Mul => "__lmul",
+            BinaryOp::Div if unsigned => "__uldiv",
+            BinaryOp::Div => "__ldiv",
+            BinaryOp::Mod if unsigned => "__ulmod",
+            BinaryOp::Mod => "__lmod",
+            _ => unreachable!("{:?} is no arithmetic operator", op),
+        };
+        self.runtime_long(name, &[a.0, a.1, b.0, b.1])
+    }
+
+    fn long_add(&mut self, (a_low, a_high): Pair, (b_low, b_high): Pair) -> Pair {
+        // There is a carry if both sign bits are set, or one of them and
+        // not the one of the sum. The two cases never share a bit.
+        let low = self.binary(BinOp::Add, a_low, b_low);
+        let both = self.binary(BinOp::And, a_low, b_low);
+        let either = self.binary(BinOp::Xor, a_low, b_low);
+        let ones = self.constant(-1);
+        let not_low = self.binary(BinOp::Xor, low, ones);
+        let one_without_sum = self.binary(BinOp::And, either, not_low);
+        let carry = self.binary(BinOp::Xor, both, one_without_sum);
+        let carry = self.shift(BinOp::UShr, carry, 15);
+
+        let high = self.binary(BinOp::Add, a_high, b_high);
+        (low, self.binary(BinOp::Add, high, carry))
+    }
+
+    fn long_sub(&mut self, (a_low, a_high): Pair, (b_low, b_high): Pair) -> Pair {
+        // There is a borrow if only the subtrahend has the sign bit set, or
+        // both or neither have it and the difference has it.
+        let low = self.binary(BinOp::Sub, a_low, b_low);
+        let ones = self.constant(-1);
+        let not_a = self.binary(BinOp::Xor, a_low, ones);
+        let only_b = self.binary(BinOp::And, not_a, b_low);
+        let either = self.binary(BinOp::Xor, a_low, b_low);
+        let same = self.binary(BinOp::Xor, either, ones);
+        let same_with_low = self.binary(BinOp::And, same, low);
+        let borrow = self.binary(BinOp::Xor, only_b, same_with_low);
+        let borrow = self.shift(BinOp::UShr, borrow, 15);
+
+        let high = self.binary(BinOp::Sub, a_high, b_high);
+        (low, self.binary(BinOp::Sub, high, borrow))
+    }
+
+    /// Shifts by a constant move bits between the words in place, other
+    /// counts call the runtime library.
+    fn long_shift(&mut self, op: BinOp, (low, high): Pair, count: &Expr) -> Pair {
+        let line = self.line;
+        let Some(count) = fold_constant(count) else {
+            let count = self.expr(count);
+            self.line = line;
+            let name = match op {
+                BinOp::Shl => "__lshl",
+                BinOp::Shr => "__lshr",
+                _ => "__ulshr",
+            };
+            return self.runtime_long(name, &[low, high, count]);
+        };
+
+        // What is shifted in at the top of the high word
+        let fill = |builder: &mut Self| match op {
+            BinOp::Shr => builder.shift(BinOp::Shr, high, 15),
+            _ => builder.constant(0),
+        };
+        match (op, count) {
+            (_, ..=0) => (low, high),
+            (BinOp::Shl, 1..=15) => {
+                // The parts of the high word share no bits
+                let moved = self.shift(BinOp::UShr, low, 16 - count);
+                let kept = self.shift(BinOp::Shl, high, count);
+                let high = self.binary(BinOp::Xor, kept, moved);
+                (self.shift(BinOp::Shl, low, count), high)
+            }
+            (BinOp::Shl, 16..=31) => (self.constant(0), self.shift(BinOp::Shl, low, count - 16)),
+            (BinOp::Shl, _) => (self.constant(0), self.constant(0)),
+            (_, 1..=15) => {
+                let moved = self.shift(BinOp::Shl, high, 16 - count);
+                let kept = self.shift(BinOp::UShr, low, count);
+                let low = self.binary(BinOp::Xor, kept, moved);
+                (low, self.shift(op, high, count))
+            }
+            (_, 16..=31) => {
+                let low = self.shift(op, high, count - 16);
+                (low, fill(self))
+            }
+            _ => {
+                let high = fill(self);
+                (high, high)
+            }
+        }
+    }
+
+    /// Reads the variable or memory an assignment changes. The address is
+    /// returned so that it is only computed once.
+    fn load_target(&mut self, target: &Expr) -> (Option<VReg>, Pair) {
+        if let Some(pair) = self.register_pair(target) {
+            return (None, pair);
+        }
+        let address = self.address(target);
+        (Some(address), self.load_long(address))
+    }
+
+    /// Writes a `long` to a variable or memory, at `address` if it is known
+    /// already.
+    fn store_target(&mut self, target: &Expr, address: Option<VReg>, (low, high): Pair) {
+        if let Some((var_low, var_high)) = self.register_pair(target) {
+            self.emit(Op::Copy(var_low, low));
+            self.emit(Op::Copy(var_high, high));
+            return;
+        }
+        let address = address.unwrap_or_else(|| self.address(target));
+        self.emit(Op::Store(low, address));
+        let high_address = self.offset(address, 1);
+        self.emit(Op::Store(high, high_address));
+    }
+
+    fn register_pair(&self, expr: &Expr) -> Option<Pair> {
+        match &expr.kind {
+            ExprKind::Ident(name) => match self.find_var(name) {
+                Some((Var::Pair(low, high), _)) => Some((low, high)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub(super) fn set_pair(&mut self, name: &str, (low, high): Pair) {
+        if let (Var::Pair(var_low, var_high), _) = self.lookup(name) {
+            self.emit(Op::Copy(var_low, low));
+            self.emit(Op::Copy(var_high, high));
+        }
+    }
+
+    /// Calls a function of the runtime library that returns a `long`.
+    fn runtime_long(&mut self, name: &str, args: &[VReg]) -> Pair {
+        for (i, arg) in args.iter().enumerate() {
+            self.emit(Op::Arg(*arg, i));
+        }
+        let result = (self.vreg(), self.vreg());
+        self.emit(Op::Call(
+            vec![result.0, result.1],
+            name.to_string(),
+            args.len(),
+        ));
+        result
+    }
+
+    /// Extends a word to a `long`. Unsigned values and pointers get a high
+    /// word of 0, signed ones copies of their sign bit.
+    fn extend(&mut self, value: VReg, from: &Type) -> Pair {
+        let high = match from {
+            Type::Integer { signed: true, .. } => self.shift(BinOp::Shr, value, 15),
+            _ => self.constant(0),
+        };
+        (value, high)
+    }
+
+    fn long_constant(&mut self, value: i64) -> Pair {
+        let low = self.constant(value as i16);
+        (low, self.constant((value >> 16) as i16))
+    }
+
+    fn load_long(&mut self, address: VReg) -> Pair {
+        let low = self.load(address);
+        let high_address = self.offset(address, 1);
+        (low, self.load(high_address))
+    }
+
+    fn copy_long(&mut self, (low, high): Pair) -> Pair {
+        let copy = (self.vreg(), self.vreg());
+        self.emit(Op::Copy(copy.0, low));
+        self.emit(Op::Copy(copy.1, high));
+        copy
+    }
+
+    /// Flips the sign bit, which orders unsigned words the way the signed
+    /// comparisons of the hardware expect.
+    fn flip_sign(&mut self, value: VReg) -> VReg {
+        let sign = self.constant(i16::MIN);
+        self.binary(BinOp::Xor, value, sign)
+    }
+
+    fn shift(&mut self, op: BinOp, value: VReg, count: i64) -> VReg {
+        let count = self.constant(count as i16);
+        self.binary(op, value, count)
+    }
+}
+
+/// The shift a `<<` or `>>` on a value of the type does.
+fn shift_op(op: BinaryOp, value_type: &Type) -> BinOp {
+    match op {
+        BinaryOp::Shl => BinOp::Shl,
+        _ if value_type.is_unsigned() => BinOp::UShr,
+        _ => BinOp::Shr,
+    }
+}
@@ -0,0 +1,242 @@
+//! Compiles a subset of C to BEPL machine code.
+//!
+//! Globals, static locals, string literals and array initializers are laid
+//! out in a data image starting at memory address 0. Identical string
+//! literals share their copy, and `const` variables cannot be assigned.
+//! The startup code writes the image into memory, so the ROM is all the
+//! hardware needs. The `jal` return address stack follows the data image
+//! and grows upwards, while locals and arguments live on a software stack
+//! in x7 that grows down from the top of memory.
+//!
+//! Source files go through the preprocessor first. Functions are lowered
+//! into a three-address IR, optimized according to the `OptLevel`,
+//! assigned registers and only then turned into BEPL code.
+//!
+//! `compile_with_debug_info` also returns `DebugInfo` that maps every ROM
+//! address to its source line and tells where the variables are kept.
+//! `interpret` runs a program on a reference interpreter instead, to check
+//! the compiled code against.
+
+pub(super) mod asm;
+pub(super) mod ast;
+mod codegen;
+mod debug;
+mod interpreter;
+mod ir;
+mod lexer;
+mod lower;
+mod opt;
+mod parser;
+mod peephole;
+mod preprocessor;
+mod regalloc;
+mod ssa;
+
+use std::collections::{HashSet, VecDeque};
+use std::path::PathBuf;
+
+use crate::Compiler;
+
+use codegen::CodeGen;
+use interpreter::Interpreter;
+use lower::Lowering;
+use parser::Parser;
+use preprocessor::Preprocessor;
+
+pub use debug::{DebugInfo, Location};
+pub use interpreter::Outcome;
+pub use opt::OptLevel;
+
+const RUNTIME: &str = include_str!("runtime.c");
+
+pub struct CCompiler {
+    opt_level: OptLevel,
+    file_name: String,
+    include_paths: Vec<PathBuf>,
+}
+
+impl CCompiler {
+    pub fn new(opt_level: OptLevel) -> CCompiler {
+        CCompiler {
+            opt_level,
+            file_name: "main.c".to_string(),
+            include_paths: Vec::new(),
+        }
+    }
+
+    /// Adds a directory that `#include` searches, in the order added.
+    pub fn include_path(mut self, path: impl Into<PathBuf>) -> CCompiler {
+        self.include_paths.push(path.into());
+        self
+    }
+
+    /// Compiles like `compile` and describes the result for debugging.
+    pub fn compile_with_debug_info(&self, raw_code: &str) -> (Vec<u16>, DebugInfo) {
+        let (items, files) = self.generate(raw_code);
+        (asm::assemble(&items), DebugInfo::new(&items, &files))
+    }
+
+    /// Runs the program on the reference interpreter, which has the same
+    /// 16 bit semantics as the compiled code.
+    pub fn interpret(&self, raw_code: &str) -> Outcome {
+        let mut preprocessor = Preprocessor::new(&self.include_paths);
+        let program = self.parse_with_runtime(&mut preprocessor, raw_code);
+        Interpreter::new(&program).run()
+    }
+
+    /// Generates the code together with the name and text of every source
+    /// file involved.
+    fn generate(&self, raw_code: &str) -> (Vec<asm::Item>, Vec<(String, String)>) {
+        let mut preprocessor = Preprocessor::new(&self.include_paths);
+        let program = self.parse_with_runtime(&mut preprocessor, raw_code);
+        (self.generate_code(&program), preprocessor.files().to_vec())
+    }
+
+    /// Generates the code of a program that another front end built from
+    /// `files`, together with the runtime library it calls.
+    pub(super) fn generate_program(
+        &self,
+        mut program: ast::Program,
+        files: &[(String, String)],
+    ) -> (Vec<asm::Item>, Vec<(String, String)>) {
+        let mut preprocessor = Preprocessor::new(&self.include_paths);
+        let mut runtime = parse(preprocessor.preprocess("runtime.c", RUNTIME));
+        for function in &mut runtime.functions {
+            function.file += files.len();
+        }
+        add_runtime(&mut program, runtime);
+
+        let mut all_files = files.to_vec();
+        all_files.extend(preprocessor.files().iter().cloned());
+        (self.generate_code(&program), all_files)
+    }
+
+    fn generate_code(&self, program: &ast::Program) -> Vec<asm::Item> {
+        // Only functions reachable from main or the globals are compiled.
+        let mut lowering = Lowering::new(program, self.opt_level);
+        let mut functions = Vec::new();
+        let mut queued = HashSet::from(["main".to_string()]);
+        let mut queue = VecDeque::from([("main".to_string(), 0)]);
+        for (_, name) in lowering.function_addresses() {
+            if queued.insert(name.clone()) {
+                queue.push_back((name.clone(), 0));
+            }
+        }
+        while let Some((name, line)) = queue.pop_front() {
+            let mut function = self.optimize(lowering.lower_function(&name, line));
+            function.call_runtime_library();
+            for (callee, line) in function.callees() {
+                if queued.insert(callee.clone()) {
+                    queue.push_back((callee, line));
+                }
+            }
+            let registers = regalloc::allocate(&mut function);
+            functions.push((function, registers));
+        }
+
+        let mut items =
+            CodeGen::new().generate(&functions, lowering.data(), lowering.function_addresses());
+        if self.opt_level == OptLevel::Os {
+            peephole::optimize(&mut items);
+        }
+        items
+    }
+
+    /// Optimizes a function. Loop invariants are hoisted at `-O1`, and at
+    /// `-Os` if the function does not get larger for it.
+    fn optimize(&self, function: ir::Function) -> ir::Function {
+        let mut optimized = function.clone();
+        opt::optimize(
+            &mut optimized,
+            self.opt_level,
+            self.opt_level == OptLevel::O1,
+        );
+        if self.opt_level != OptLevel::Os {
+            return optimized;
+        }
+        let mut hoisted = function;
+        opt::optimize(&mut hoisted, self.opt_level, true);
+        match code_size(&hoisted) <= code_size(&optimized) {
+            true => hoisted,
+            false => optimized,
+        }
+    }
+
+    /// Parses the program together with the functions of the runtime
+    /// library it does not define itself.
+    fn parse_with_runtime(&self, preprocessor: &mut Preprocessor, raw_code: &str) -> ast::Program {
+        let mut program = parse(preprocessor.preprocess(&self.file_name, raw_code));
+        let runtime = parse(preprocessor.preprocess("runtime.c", RUNTIME));
+        add_runtime(&mut program, runtime);
+        program
+    }
+}
+
+impl Compiler for CCompiler {
+    fn compile(&self, raw_code: &str) -> Vec<u16> {
+        asm::assemble(&self.generate(raw_code).0)
+    }
+
+    fn compile_to_assembly(&self, raw_code: &str) -> String {
+        let (items, files) = self.generate(raw_code);
+        asm::to_text(&items, &files)
+    }
+
+    // Quoted includes are looked up relative to the file
+    fn file_name(mut self, file_name: &str) -> CCompiler {
+        self.file_name = file_name.to_string();
+        self
+    }
+}
+
+fn parse(tokens: Vec<lexer::Lexeme>) -> ast::Program {
+    Parser::new(tokens).parse_program()
+}
+
+/// The number of instructions a function takes at `-Os` when it is
+/// compiled on its own. Label loads count as short ones.
+fn code_size(function: &ir::Function) -> usize {
+    let mut function = function.clone();
+    function.call_runtime_library();
+    let registers = regalloc::allocate(&mut function);
+    let mut items = CodeGen::new().generate(&[(function, registers)], &[], &[]);
+    // Other functions only need a label to lay out the code
+    let defined: HashSet<String> = items
+        .iter()
+        .filter_map(|item| match item {
+            asm::Item::Label(name) => Some(name.clone()),
+            _ => None,
+        })
+        .collect();
+    let missing: HashSet<String> = items
+        .iter()
+        .filter_map(|item| match item {
+            asm::Item::SetLabel(_, name) if !defined.contains(name) => Some(name.clone()),
+            _ => None,
+        })
+        .collect();
+    items.extend(missing.into_iter().map(asm::Item::Label));
+    peephole::optimize(&mut items);
+    items
+        .iter()
+        .filter(|item| matches!(item, asm::Item::Inst(_) | asm::Item::SetLabel(..)))
+        .count()
+}
+
+/// Adds the functions of the runtime library the program does not define
+/// itself. Prototypes of them, like those in `bepl.h`, give way to the
+/// runtime definition.
+fn add_runtime(program: &mut ast::Program, runtime: ast::Program) {
+    for function in runtime.functions {
+        let same_name = |f: &ast::Function| f.name == function.name;
+        if program
+            .functions
+            .iter()
+            .any(|f| same_name(f) && f.body.is_some())
+        {
+            continue;
+        }
+        program.functions.retain(|f| !same_name(f));
+        program.functions.push(function);
+    }
+}
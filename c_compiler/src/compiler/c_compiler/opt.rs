@@ -0,0 +1,688 @@
+//! Optimization passes on the IR.
+//!
+//! Above `-O0` functions are converted into SSA form and a set of scalar
+//! passes runs until none of them finds anything left to do. Loop invariant
+//! code motion runs when asked to, which at `-Os` is only where it does not
+//! make the code larger.
+
+use std::collections::{HashMap, HashSet};
+
+use super::ir::{BinOp, Block, BlockId, Function, Inst, Op, Terminator, VReg};
+use super::ssa;
+
+/// Upper bound on rounds of the scalar passes, in case two of them keep
+/// undoing each other.
+const MAX_ROUNDS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OptLevel {
+    /// No optimization, every variable stays in its own register.
+    O0,
+    /// Optimize for speed.
+    O1,
+    /// Optimize for code size. Long copies call the runtime library and
+    /// the generated code goes through `peephole` as well.
+    Os,
+}
+
+impl OptLevel {
+    /// Parses the `-O0`, `-O1` and `-Os` command line flags.
+    pub fn from_flag(flag: &str) -> Option<OptLevel> {
+        match flag {
+            "-O0" => Some(OptLevel::O0),
+            "-O1" => Some(OptLevel::O1),
+            "-Os" => Some(OptLevel::Os),
+            _ => None,
+        }
+    }
+}
+
+/// Optimizes a function for `level`, hoisting loop invariants if `hoist`
+/// is set.
+pub fn optimize(function: &mut Function, level: OptLevel, hoist: bool) {
+    function.remove_unreachable_blocks();
+    if level == OptLevel::O0 {
+        return;
+    }
+
+    ssa::construct(function);
+    scalar_passes(function, level);
+    if hoist && hoist_loop_invariants(function) {
+        scalar_passes(function, level);
+    }
+    ssa::destruct(function);
+}
+
+fn scalar_passes(function: &mut Function, level: OptLevel) {
+    for _ in 0..MAX_ROUNDS {
+        let mut changed = fold_constants(function);
+        changed |= propagate_copies(function);
+        changed |= eliminate_common_subexpressions(function);
+        changed |= reduce_strength(function, level);
+        changed |= eliminate_dead_code(function);
+        changed |= simplify_cfg(function);
+        if !changed {
+            break;
+        }
+    }
+}
+
+fn constants(function: &Function) -> HashMap<VReg, i16> {
+    let mut constants = HashMap::new();
+    for block in &function.blocks {
+        for inst in &block.insts {
+            if let Op::Const(target, value) = inst.op {
+                constants.insert(target, value);
+            }
+        }
+    }
+    constants
+}
+
+/// Evaluates operations on constants, applies algebraic identities and
+/// resolves branches whose outcome is known.
+fn fold_constants(function: &mut Function) -> bool {
+    let constants = constants(function);
+    let mut changed = false;
+    let mut removed_edges = Vec::new();
+
+    for (id, block) in function.blocks.iter_mut().enumerate() {
+        for inst in &mut block.insts {
+            let folded = match &inst.op {
+                Op::Binary(op, target, a, b) => fold_binary(
+                    *op,
+                    *target,
+                    (*a, constants.get(a).copied()),
+                    (*b, constants.get(b).copied()),
+                ),
+                Op::Phi(target, sources) => {
+                    let mut values = sources.iter().map(|(_, v)| *v).filter(|v| v != target);
+                    let first = values.next();
+                    match first {
+                        Some(first) if values.all(|v| v == first) => Some(Op::Copy(*target, first)),
+                        _ => None,
+                    }
+                }
+                _ => None,
+            };
+            if let Some(op) = folded {
+                inst.op = op;
+                changed = true;
+            }
+        }
+
+        if let Terminator::Branch(a, cond, b, then, otherwise) = block.terminator {
+            let outcome = match (constants.get(&a), constants.get(&b)) {
+                _ if then == otherwise => Some(true),
+                _ if a == b => Some(cond.evaluate(0, 0)),
+                (Some(a), Some(b)) => Some(cond.evaluate(*a, *b)),
+                _ => None,
+            };
+            if let Some(outcome) = outcome {
+                let (taken, skipped) = if outcome {
+                    (then, otherwise)
+                } else {
+                    (otherwise, then)
+                };
+                block.terminator = Terminator::Jump(taken);
+                if taken != skipped {
+                    removed_edges.push((id, skipped));
+                }
+                changed = true;
+            }
+        }
+
+        if let Terminator::Table(index, targets) = &block.terminator {
+            let taken = constants
+                .get(index)
+                .and_then(|index| targets.get(*index as usize).copied());
+            if let Some(taken) = taken {
+                for skipped in block.terminator.successors() {
+                    if skipped != taken {
+                        removed_edges.push((id, skipped));
+                    }
+                }
+                block.terminator = Terminator::Jump(taken);
+                changed = true;
+            }
+        }
+    }
+
+    for (pred, block) in removed_edges {
+        remove_phi_sources(function, block, pred);
+    }
+    if changed {
+        function.remove_unreachable_blocks();
+    }
+    changed
+}
+
+fn fold_binary(
+    op: BinOp,
+    target: VReg,
+    a: (VReg, Option<i16>),
+    b: (VReg, Option<i16>),
+) -> Option<Op> {
+    let copy_a = Some(Op::Copy(target, a.0));
+    let copy_b = Some(Op::Copy(target, b.0));
+    let constant = |value| Some(Op::Const(target, value));
+
+    if let (Some(x), Some(y)) = (a.1, b.1) {
+        return op.evaluate(x, y).map(|value| Op::Const(target, value));
+    }
+    let same = a.0 == b.0;
+    match (op, a.1, b.1) {
+        (BinOp::Add | BinOp::Sub | BinOp::Or | BinOp::Xor, _, Some(0)) => copy_a,
+        (BinOp::Add | BinOp::Or | BinOp::Xor, Some(0), _) => copy_b,
+        (BinOp::Sub | BinOp::Xor, _, _) if same => constant(0),
+        (BinOp::And | BinOp::Mul, Some(0), _) | (BinOp::And | BinOp::Mul, _, Some(0)) => {
+            constant(0)
+        }
+        (BinOp::And, _, Some(-1)) | (BinOp::Mul | BinOp::Div | BinOp::UDiv, _, Some(1)) => copy_a,
+        (BinOp::And, Some(-1), _) | (BinOp::Mul, Some(1), _) => copy_b,
+        (BinOp::And | BinOp::Or, _, _) if same => copy_a,
+        (BinOp::Or, Some(-1), _) | (BinOp::Or, _, Some(-1)) => constant(-1),
+        (BinOp::Shl | BinOp::Shr | BinOp::UShr, _, Some(0)) => copy_a,
+        (BinOp::Shl | BinOp::Shr | BinOp::UShr, Some(0), _) => constant(0),
+        (BinOp::Mod | BinOp::UMod, _, Some(1)) => constant(0),
+        _ => None,
+    }
+}
+
+fn remove_phi_sources(function: &mut Function, block: BlockId, pred: BlockId) {
+    for inst in &mut function.blocks[block].insts {
+        if let Op::Phi(_, sources) = &mut inst.op {
+            sources.retain(|(p, _)| *p != pred);
+        }
+    }
+}
+
+/// Replaces every use of a copied register with the original.
+fn propagate_copies(function: &mut Function) -> bool {
+    let mut copies = HashMap::new();
+    for block in &function.blocks {
+        for inst in &block.insts {
+            if let Op::Copy(target, source) = inst.op {
+                copies.insert(target, source);
+            }
+        }
+    }
+
+    let resolve = |mut reg: VReg| {
+        while let Some(&source) = copies.get(&reg) {
+            if source == reg {
+                break;
+            }
+            reg = source;
+        }
+        reg
+    };
+
+    // A value copied into a variable stands for it once the copy is gone
+    for &target in copies.keys() {
+        let source = resolve(target);
+        if !function.variables.contains_key(&source) {
+            if let Some(variable) = function.variables.get(&target).cloned() {
+                function.variables.insert(source, variable);
+            }
+        }
+    }
+
+    let mut changed = false;
+    for block in &mut function.blocks {
+        for inst in &mut block.insts {
+            if matches!(inst.op, Op::Copy(..)) {
+                continue;
+            }
+            inst.op.map_uses(|u| {
+                let r = resolve(u);
+                changed |= r != u;
+                r
+            });
+        }
+        block.terminator.map_uses(|u| {
+            let r = resolve(u);
+            changed |= r != u;
+            r
+        });
+    }
+    changed
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Expression {
+    Binary(BinOp, VReg, VReg),
+    FrameAddr(usize),
+    Const(i16),
+}
+
+fn expression(op: &Op) -> Option<(Expression, VReg)> {
+    match *op {
+        Op::Binary(op, target, a, b) => {
+            let (a, b) = if op.is_commutative() && b < a {
+                (b, a)
+            } else {
+                (a, b)
+            };
+            Some((Expression::Binary(op, a, b), target))
+        }
+        Op::FrameAddr(target, slot) => Some((Expression::FrameAddr(slot), target)),
+        // Small constants are a single instruction, keeping them in a
+        // register for longer is not worth it.
+        Op::Const(target, value) if !(-128..=127).contains(&value) => {
+            Some((Expression::Const(value), target))
+        }
+        _ => None,
+    }
+}
+
+/// Reuses values that were already computed in a dominating block.
+fn eliminate_common_subexpressions(function: &mut Function) -> bool {
+    let idom = ssa::dominators(function);
+    let children = ssa::dominator_tree(&idom);
+    let mut available = HashMap::new();
+    cse_block(function, &children, 0, &mut available)
+}
+
+fn cse_block(
+    function: &mut Function,
+    children: &[Vec<BlockId>],
+    block: BlockId,
+    available: &mut HashMap<Expression, VReg>,
+) -> bool {
+    let mut changed = false;
+    let mut added = Vec::new();
+    for inst in &mut function.blocks[block].insts {
+        let Some((expression, target)) = expression(&inst.op) else {
+            continue;
+        };
+        match available.get(&expression) {
+            Some(&existing) => {
+                inst.op = Op::Copy(target, existing);
+                changed = true;
+            }
+            None => {
+                available.insert(expression.clone(), target);
+                added.push(expression);
+            }
+        }
+    }
+
+    for &child in &children[block] {
+        changed |= cse_block(function, children, child, available);
+    }
+    for expression in added {
+        available.remove(&expression);
+    }
+    changed
+}
+
+/// Replaces multiplications by constants with shifts and additions, using
+/// the non-adjacent form of the constant to keep the number of terms low.
+fn reduce_strength(function: &mut Function, level: OptLevel) -> bool {
+    // A call to `__mul` takes about six instructions plus the spills of
+    // values that live across it, so that is what the replacement may cost
+    // when optimizing for size.
+    let max_cost = match level {
+        OptLevel::Os => 8,
+        _ => 16,
+    };
+
+    let constants = constants(function);
+    let mut changed = false;
+    for id in 0..function.blocks.len() {
+        let insts = std::mem::take(&mut function.blocks[id].insts);
+        let mut new_insts = Vec::with_capacity(insts.len());
+        for inst in insts {
+            let Op::Binary(BinOp::Mul, target, a, b) = inst.op else {
+                new_insts.push(inst);
+                continue;
+            };
+            let (value, factor) = match (constants.get(&a), constants.get(&b)) {
+                (_, Some(factor)) => (a, *factor),
+                (Some(factor), _) => (b, *factor),
+                _ => {
+                    new_insts.push(inst);
+                    continue;
+                }
+            };
+
+            let terms = non_adjacent_form(factor);
+            if terms.is_empty() || strength_reduction_cost(&terms) > max_cost {
+                new_insts.push(inst);
+                continue;
+            }
+
+            let line = inst.line;
+            let mut emit = |op: Op| new_insts.push(Inst { op, line });
+            let mut sum: Option<VReg> = None;
+            for (shift, positive) in terms {
+                let term = if shift == 0 {
+                    value
+                } else {
+                    let amount = function.new_vreg();
+                    let shifted = function.new_vreg();
+                    emit(Op::Const(amount, shift as i16));
+                    emit(Op::Binary(BinOp::Shl, shifted, value, amount));
+                    shifted
+                };
+                let result = function.new_vreg();
+                match (sum, positive) {
+                    (None, true) => {
+                        sum = Some(term);
+                        continue;
+                    }
+                    (None, false) => {
+                        let zero = function.new_vreg();
+                        emit(Op::Const(zero, 0));
+                        emit(Op::Binary(BinOp::Sub, result, zero, term));
+                    }
+                    (Some(sum), true) => emit(Op::Binary(BinOp::Add, result, sum, term)),
+                    (Some(sum), false) => emit(Op::Binary(BinOp::Sub, result, sum, term)),
+                }
+                sum = Some(result);
+            }
+            emit(Op::Copy(target, sum.unwrap()));
+            changed = true;
+        }
+        function.blocks[id].insts = new_insts;
+    }
+    changed
+}
+
+/// Instructions needed to add up the terms.
+fn strength_reduction_cost(terms: &[(u32, bool)]) -> usize {
+    let shifts = terms.iter().filter(|(shift, _)| *shift != 0).count();
+    let negation = if terms[0].1 { 0 } else { 2 };
+    2 * shifts + terms.len() - 1 + negation
+}
+
+/// Signed powers of two that add up to `value` modulo 2^16, positive terms
+/// first.
+fn non_adjacent_form(value: i16) -> Vec<(u32, bool)> {
+    let mut terms = Vec::new();
+    let mut n = value as i32;
+    let mut shift = 0;
+    while n != 0 && shift < 16 {
+        if n & 1 == 1 {
+            let digit = 2 - n.rem_euclid(4);
+            terms.push((shift, digit > 0));
+            n -= digit;
+        }
+        n >>= 1;
+        shift += 1;
+    }
+    terms.sort_by_key(|(_, positive)| !positive);
+    terms
+}
+
+/// Removes instructions whose results are never used.
+fn eliminate_dead_code(function: &mut Function) -> bool {
+    let mut definitions = HashMap::new();
+    let mut live = HashSet::new();
+    let mut work = Vec::new();
+
+    for block in &function.blocks {
+        for inst in &block.insts {
+            for target in inst.op.defs() {
+                definitions.insert(target, &inst.op);
+            }
+            if inst.op.has_side_effects() {
+                work.extend(inst.op.uses());
+            }
+        }
+        work.extend(block.terminator.uses());
+    }
+    while let Some(reg) = work.pop() {
+        if live.insert(reg) {
+            if let Some(op) = definitions.get(&reg) {
+                work.extend(op.uses());
+            }
+        }
+    }
+
+    let mut changed = false;
+    for block in &mut function.blocks {
+        let before = block.insts.len();
+        block.insts.retain(|inst| {
+            inst.op.has_side_effects() || inst.op.defs().iter().any(|d| live.contains(d))
+        });
+        changed |= block.insts.len() != before;
+    }
+    changed
+}
+
+/// Skips empty blocks and merges blocks into their only predecessor.
+fn simplify_cfg(function: &mut Function) -> bool {
+    let mut changed = false;
+
+    loop {
+        let predecessors = function.predecessors();
+        let has_phis = |block: BlockId, function: &Function| {
+            function.blocks[block]
+                .insts
+                .iter()
+                .any(|inst| matches!(inst.op, Op::Phi(..)))
+        };
+
+        let forward = (1..function.blocks.len()).find_map(|block| {
+            let Terminator::Jump(target) = function.blocks[block].terminator else {
+                return None;
+            };
+            let skip = function.blocks[block].insts.is_empty()
+                && target != block
+                && !predecessors[block].is_empty()
+                && !has_phis(target, function);
+            skip.then_some((block, target))
+        });
+        if let Some((block, target)) = forward {
+            for &pred in &predecessors[block] {
+                function.blocks[pred].terminator.map_successors(|s| {
+                    if s == block {
+                        target
+                    } else {
+                        s
+                    }
+                });
+            }
+            function.remove_unreachable_blocks();
+            changed = true;
+            continue;
+        }
+
+        let merge = (0..function.blocks.len()).find_map(|block| {
+            let Terminator::Jump(next) = function.blocks[block].terminator else {
+                return None;
+            };
+            (next != 0 && next != block && predecessors[next] == [block]).then_some((block, next))
+        });
+        if let Some((block, next)) = merge {
+            let mut insts = std::mem::take(&mut function.blocks[next].insts);
+            for inst in &mut insts {
+                if let Op::Phi(target, sources) = &inst.op {
+                    inst.op = Op::Copy(*target, sources[0].1);
+                }
+            }
+            let terminator = function.blocks[next].terminator.clone();
+            for successor in terminator.successors() {
+                for inst in &mut function.blocks[successor].insts {
+                    if let Op::Phi(_, sources) = &mut inst.op {
+                        for (pred, _) in sources.iter_mut() {
+                            if *pred == next {
+                                *pred = block;
+                            }
+                        }
+                    }
+                }
+            }
+            function.blocks[block].insts.extend(insts);
+            function.blocks[block].terminator = terminator;
+            function.blocks[next].terminator = Terminator::Return(Vec::new());
+            function.remove_unreachable_blocks();
+            changed = true;
+            continue;
+        }
+
+        return changed;
+    }
+}
+
+/// Moves computations that give the same result in every iteration in
+/// front of the loop.
+fn hoist_loop_invariants(function: &mut Function) -> bool {
+    while insert_preheader(function) {}
+
+    let idom = ssa::dominators(function);
+    let loops = ssa::loops(function, &idom);
+    let predecessors = function.predecessors();
+
+    let mut defined_in = HashMap::new();
+    for (id, block) in function.blocks.iter().enumerate() {
+        for inst in &block.insts {
+            for target in inst.op.defs() {
+                defined_in.insert(target, id);
+            }
+        }
+    }
+    let mut constants = constants(function);
+
+    let mut changed = false;
+    for l in loops {
+        let outside: Vec<BlockId> = predecessors[l.header]
+            .iter()
+            .copied()
+            .filter(|p| !l.blocks.contains(p))
+            .collect();
+        let [preheader] = outside[..] else {
+            continue;
+        };
+        if function.blocks[preheader].terminator != Terminator::Jump(l.header) {
+            continue;
+        }
+
+        let mut blocks: Vec<BlockId> = l.blocks.iter().copied().collect();
+        blocks.sort();
+        for block in blocks {
+            let insts = std::mem::take(&mut function.blocks[block].insts);
+            let mut kept = Vec::with_capacity(insts.len());
+            for mut inst in insts {
+                let pure = matches!(
+                    inst.op,
+                    Op::Binary(..)
+                        | Op::Copy(..)
+                        | Op::FrameAddr(..)
+                        | Op::FuncAddr(..)
+                        | Op::Const(..)
+                );
+                let small_constant =
+                    |reg: &VReg| constants.get(reg).is_some_and(|v| (-128..=127).contains(v));
+                let in_loop =
+                    |reg: &VReg| defined_in.get(reg).is_some_and(|b| l.blocks.contains(b));
+                let invariant = inst
+                    .op
+                    .uses()
+                    .iter()
+                    .all(|u| !in_loop(u) || small_constant(u));
+                let lone_constant = matches!(inst.op, Op::Const(_, v) if (-128..=127).contains(&v));
+                if !pure || !invariant || lone_constant {
+                    kept.push(inst);
+                    continue;
+                }
+
+                // Small constants are rematerialized in the preheader
+                // instead of being hoisted on their own.
+                let mut new_constants = Vec::new();
+                inst.op.map_uses(|u| {
+                    if !in_loop(&u) {
+                        return u;
+                    }
+                    let copy = function.vreg_count + new_constants.len();
+                    new_constants.push(Inst {
+                        op: Op::Const(copy, constants[&u]),
+                        line: inst.line,
+                    });
+                    copy
+                });
+                function.vreg_count += new_constants.len();
+                for constant in &new_constants {
+                    if let Op::Const(reg, value) = constant.op {
+                        constants.insert(reg, value);
+                        defined_in.insert(reg, preheader);
+                    }
+                }
+                defined_in.insert(inst.op.defs()[0], preheader);
+                let insts = &mut function.blocks[preheader].insts;
+                insts.extend(new_constants);
+                insts.push(inst);
+                changed = true;
+            }
+            function.blocks[block].insts = kept;
+        }
+    }
+    changed
+}
+
+/// Gives the first loop that lacks one a preheader: a single predecessor
+/// outside the loop that only jumps to the header. Returns whether a block
+/// was inserted.
+fn insert_preheader(function: &mut Function) -> bool {
+    let idom = ssa::dominators(function);
+    let predecessors = function.predecessors();
+    let found = ssa::loops(function, &idom).into_iter().find_map(|l| {
+        let outside: Vec<BlockId> = predecessors[l.header]
+            .iter()
+            .copied()
+            .filter(|p| !l.blocks.contains(p))
+            .collect();
+        match outside[..] {
+            [] => None,
+            [pred] if function.blocks[pred].terminator == Terminator::Jump(l.header) => None,
+            _ => Some((l.header, outside)),
+        }
+    });
+    let Some((header, outside)) = found else {
+        return false;
+    };
+
+    let preheader = function.blocks.len();
+    let line = function.blocks[header].line;
+    let mut insts = Vec::new();
+    for i in 0..function.blocks[header].insts.len() {
+        let Op::Phi(_, sources) = &function.blocks[header].insts[i].op else {
+            continue;
+        };
+        let (entering, looping): (Vec<_>, Vec<_>) = sources
+            .iter()
+            .copied()
+            .partition(|(p, _)| outside.contains(p));
+        let first = entering[0].1;
+        let value = if entering.iter().all(|(_, v)| *v == first) {
+            first
+        } else {
+            let merged = function.new_vreg();
+            insts.push(Inst {
+                op: Op::Phi(merged, entering),
+                line,
+            });
+            merged
+        };
+        let mut sources = looping;
+        sources.push((preheader, value));
+        if let Op::Phi(_, old) = &mut function.blocks[header].insts[i].op {
+            *old = sources;
+        }
+    }
+
+    function.blocks.push(Block {
+        insts,
+        terminator: Terminator::Jump(header),
+        line,
+    });
+    for pred in outside {
+        function.blocks[pred]
+            .terminator
+            .map_successors(|s| if s == header { preheader } else { s });
+    }
+    function.remove_unreachable_blocks();
+    true
+}
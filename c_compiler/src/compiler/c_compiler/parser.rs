@@ -0,0 +1,1018 @@
+use std::collections::HashMap;
+
+use super::ast::*;
+use super::lexer::{Lexeme, Token};
+
+/// What a declarator makes of the type before it.
+#[derive(Clone)]
+enum Derived {
+    Pointer,
+    Array(Option<usize>),
+    Function(Vec<Type>),
+}
+
+/// The type a declaration starts with, and what comes with it.
+struct Specifiers {
+    base_type: Type,
+    is_static: bool,
+    constant: bool,
+}
+
+pub struct Parser {
+    tokens: Vec<Lexeme>,
+    position: usize,
+    structs: Structs,
+    typedefs: HashMap<String, Type>,
+    /// Number of structs without a tag so far, to name them.
+    anonymous_structs: usize,
+}
+
+impl Parser {
+    pub fn new(tokens: Vec<Lexeme>) -> Parser {
+        Parser {
+            tokens,
+            position: 0,
+            structs: HashMap::new(),
+            typedefs: HashMap::new(),
+            anonymous_structs: 0,
+        }
+    }
+
+    pub fn parse_program(&mut self) -> Program {
+        let mut program = Program {
+            globals: Vec::new(),
+            functions: Vec::new(),
+            structs: HashMap::new(),
+        };
+
+        while self.peek().is_some() {
+            let line = self.line();
+            if self.eat(&Token::Typedef) {
+                self.parse_typedef();
+                continue;
+            }
+            // Whether a function or global is static does not matter with
+            // a single translation unit
+            let specifiers = self.parse_specifiers();
+            // A struct definition without variables
+            if self.eat(&Token::Semicolon) {
+                continue;
+            }
+            if self.is_function_definition() {
+                let return_type = self.parse_pointers(specifiers.base_type);
+                let name = self.expect_ident();
+                program
+                    .functions
+                    .push(self.parse_function(return_type, name, line));
+                continue;
+            }
+            let (var_type, name) = self.parse_declarator(specifiers.base_type.clone());
+
+            program
+                .globals
+                .push(self.parse_declaration_rest(&specifiers, var_type, name, line));
+            while self.eat(&Token::Comma) {
+                let line = self.line();
+                let (var_type, name) = self.parse_declarator(specifiers.base_type.clone());
+                program.globals.push(self.parse_declaration_rest(
+                    &specifiers,
+                    var_type,
+                    name,
+                    line,
+                ));
+            }
+            self.expect(&Token::Semicolon);
+        }
+
+        program.structs = std::mem::take(&mut self.structs);
+        program
+    }
+
+    /// Parses the rest of a `typedef`, which declares names for types the
+    /// way a declaration declares variables.
+    fn parse_typedef(&mut self) {
+        let base_type = self.parse_base_type();
+        loop {
+            let (var_type, name) = self.parse_declarator(base_type.clone());
+            self.typedefs.insert(name, var_type);
+            if !self.eat(&Token::Comma) {
+                break;
+            }
+        }
+        self.expect(&Token::Semicolon);
+    }
+
+    /// Whether the declarator ahead is a name with parameters, as opposed
+    /// to a variable, including pointers to functions.
+    fn is_function_definition(&self) -> bool {
+        let mut offset = 0;
+        while self.peek_at(offset) == Some(&Token::Star) {
+            offset += 1;
+        }
+        matches!(self.peek_at(offset), Some(Token::Ident(_)))
+            && self.peek_at(offset + 1) == Some(&Token::LParen)
+    }
+
+    fn parse_pointers(&mut self, mut var_type: Type) -> Type {
+        while self.eat(&Token::Star) {
+            self.skip_qualifiers();
+            var_type = Type::Pointer(Box::new(var_type));
+        }
+        var_type
+    }
+
+    fn parse_function(&mut self, return_type: Type, name: String, line: usize) -> Function {
+        let file = self.tokens[self.position].file;
+        self.expect(&Token::LParen);
+        let mut params = Vec::new();
+
+        if self.peek() == Some(&Token::Void) && self.peek_at(1) == Some(&Token::RParen) {
+            self.advance();
+        }
+        if !self.eat(&Token::RParen) {
+            loop {
+                let base_type = self.parse_base_type();
+                let (param_type, param_name) = self.parse_declarator(base_type);
+                params.push((param_type.decay(), param_name));
+                if !self.eat(&Token::Comma) {
+                    break;
+                }
+            }
+            self.expect(&Token::RParen);
+        }
+
+        let body = if self.eat(&Token::Semicolon) {
+            None
+        } else {
+            Some(self.parse_block())
+        };
+
+        Function {
+            name,
+            return_type,
+            params,
+            body,
+            line,
+            file,
+        }
+    }
+
+    /// Parses a base type where no variable is declared, so that its
+    /// qualifiers do not matter.
+    fn parse_base_type(&mut self) -> Type {
+        let line = self.line();
+        let specifiers = self.parse_specifiers();
+        assert!(!specifiers.is_static, "Unexpected static in line {}", line);
+        specifiers.base_type
+    }
+
+    /// Parses a type with `static` and qualifiers around it, in any order
+    /// as in `static const int` or `char const`.
+    fn parse_specifiers(&mut self) -> Specifiers {
+        let mut is_static = false;
+        let mut constant = false;
+        let mut qualifiers = |parser: &mut Parser| loop {
+            match parser.peek() {
+                Some(Token::Static) => is_static = true,
+                Some(Token::Const) => constant = true,
+                Some(Token::Volatile) => (),
+                _ => break,
+            }
+            parser.advance();
+        };
+        qualifiers(self);
+        let base_type = self.parse_type_specifier();
+        qualifiers(self);
+        Specifiers {
+            base_type,
+            is_static,
+            constant,
+        }
+    }
+
+    /// Skips the qualifiers of a pointer, as in `char *const`. They are
+    /// accepted but not checked.
+    fn skip_qualifiers(&mut self) {
+        while self.eat(&Token::Const) || self.eat(&Token::Volatile) {}
+    }
+
+    fn parse_type_specifier(&mut self) -> Type {
+        let line = self.line();
+        match self.peek().cloned() {
+            Some(Token::Void) => {
+                self.advance();
+                return Type::Void;
+            }
+            Some(Token::Struct) => {
+                self.advance();
+                return self.parse_struct(line);
+            }
+            Some(Token::Ident(name)) if self.typedefs.contains_key(&name) => {
+                self.advance();
+                return self.typedefs[&name].clone();
+            }
+            _ => (),
+        }
+
+        // Integer specifiers come in any order, as in `unsigned short int`
+        let mut signed = None;
+        let mut bits = None;
+        let mut int = false;
+        loop {
+            match self.peek() {
+                Some(Token::Signed | Token::Unsigned) if signed.is_none() => {
+                    signed = Some(self.peek() == Some(&Token::Signed));
+                }
+                Some(Token::Char) if bits.is_none() && !int => bits = Some(8),
+                Some(Token::Short) if bits.is_none() => bits = Some(16),
+                Some(Token::Long) if bits == Some(32) => {
+                    panic!("long long is not supported in line {}", line)
+                }
+                Some(Token::Long) if bits.is_none() => bits = Some(32),
+                Some(Token::Int) if !int && bits != Some(8) => int = true,
+                _ => break,
+            }
+            self.advance();
+        }
+        if signed.is_none() && bits.is_none() && !int {
+            panic!("Expected type but found {:?} in line {}", self.peek(), line);
+        }
+        Type::Integer {
+            bits: bits.unwrap_or(16),
+            signed: signed.unwrap_or(true),
+        }
+    }
+
+    /// Parses a struct type after the `struct` keyword. A body defines the
+    /// struct and lays out its members one after another.
+    fn parse_struct(&mut self, line: usize) -> Type {
+        let tag = match self.peek() {
+            Some(Token::Ident(_)) => self.expect_ident(),
+            Some(Token::LBrace) => {
+                self.anonymous_structs += 1;
+                format!("<anonymous {}>", self.anonymous_structs)
+            }
+            token => panic!("Expected struct tag but found {:?} in line {}", token, line),
+        };
+        if !self.eat(&Token::LBrace) {
+            return Type::Struct(tag);
+        }
+        assert!(
+            !self.structs.contains_key(&tag),
+            "Struct {} defined twice in line {}",
+            tag,
+            line
+        );
+
+        let mut members: Vec<Member> = Vec::new();
+        let mut size = 0;
+        while !self.eat(&Token::RBrace) {
+            let base_type = self.parse_base_type();
+            loop {
+                let line = self.line();
+                let (member_type, name) = self.parse_declarator(base_type.clone());
+                assert!(
+                    members.iter().all(|m| m.name != name),
+                    "Duplicate member {} in line {}",
+                    name,
+                    line
+                );
+                let member_size = member_type.size(&self.structs, line);
+                members.push(Member {
+                    name,
+                    member_type,
+                    offset: size,
+                });
+                size += member_size;
+                if !self.eat(&Token::Comma) {
+                    break;
+                }
+            }
+            self.expect(&Token::Semicolon);
+        }
+        assert!(
+            !members.is_empty(),
+            "Struct {} has no members in line {}",
+            tag,
+            line
+        );
+
+        self.structs.insert(tag.clone(), Struct { members, size });
+        Type::Struct(tag)
+    }
+
+    fn is_type_start(&self) -> bool {
+        self.is_type_start_at(0)
+    }
+
+    fn is_type_start_at(&self, offset: usize) -> bool {
+        match self.peek_at(offset) {
+            Some(
+                Token::Int
+                | Token::Char
+                | Token::Short
+                | Token::Long
+                | Token::Signed
+                | Token::Unsigned
+                | Token::Void
+                | Token::Struct
+                | Token::Static
+                | Token::Const
+                | Token::Volatile,
+            ) => true,
+            Some(Token::Ident(name)) => self.typedefs.contains_key(name),
+            _ => false,
+        }
+    }
+
+    fn parse_declarator(&mut self, base_type: Type) -> (Type, String) {
+        let line = self.line();
+        match self.parse_any_declarator(base_type) {
+            (var_type, Some(name)) => (var_type, name),
+            (_, None) => panic!(
+                "Expected identifier but found {:?} in line {}",
+                self.peek(),
+                line
+            ),
+        }
+    }
+
+    /// Parses a type without a name, as in casts and `sizeof`.
+    fn parse_type_name(&mut self) -> Type {
+        let line = self.line();
+        let base_type = self.parse_base_type();
+        match self.parse_any_declarator(base_type) {
+            (var_type, None) => var_type,
+            (_, Some(name)) => panic!("Unexpected name {} in type in line {}", name, line),
+        }
+    }
+
+    /// Parses a declarator whose name may be left out.
+    fn parse_any_declarator(&mut self, base_type: Type) -> (Type, Option<String>) {
+        let (name, derived) = self.parse_derived_types();
+        let var_type = derived
+            .into_iter()
+            .fold(base_type, |var_type, derived| match derived {
+                Derived::Pointer => Type::Pointer(Box::new(var_type)),
+                Derived::Array(length) => Type::Array(Box::new(var_type), length),
+                Derived::Function(params) => Type::Function(Box::new(var_type), params),
+            });
+        (var_type, name)
+    }
+
+    /// Parses the pointers, the name and the suffixes of a declarator, as
+    /// in `*(*name[2])(int)`. Returns what they make of the base type in
+    /// the order they apply to it.
+    fn parse_derived_types(&mut self) -> (Option<String>, Vec<Derived>) {
+        let mut pointers = 0;
+        while self.eat(&Token::Star) {
+            pointers += 1;
+            self.skip_qualifiers();
+        }
+
+        // Parentheses group a declarator unless they hold parameters
+        let grouped = self.peek() == Some(&Token::LParen)
+            && matches!(
+                self.peek_at(1),
+                Some(Token::Star | Token::LParen | Token::LBracket)
+            );
+        let (name, inner) = if grouped {
+            self.advance();
+            let inner = self.parse_derived_types();
+            self.expect(&Token::RParen);
+            inner
+        } else if let Some(Token::Ident(name)) = self.peek().cloned() {
+            self.advance();
+            (Some(name), Vec::new())
+        } else {
+            (None, Vec::new())
+        };
+
+        let mut suffixes = Vec::new();
+        loop {
+            if self.eat(&Token::LBracket) {
+                suffixes.push(Derived::Array(self.parse_dimension()));
+            } else if self.eat(&Token::LParen) {
+                suffixes.push(Derived::Function(self.parse_parameter_types()));
+            } else {
+                break;
+            }
+        }
+
+        let mut derived = vec![Derived::Pointer; pointers];
+        derived.extend(suffixes.into_iter().rev());
+        derived.extend(inner);
+        (name, derived)
+    }
+
+    /// Parses the length of an array after `[`, which may be left out.
+    fn parse_dimension(&mut self) -> Option<usize> {
+        if self.eat(&Token::RBracket) {
+            return None;
+        }
+        let size = self.parse_expr();
+        let value = size
+            .constant_value()
+            .unwrap_or_else(|| panic!("Array size must be constant in line {}", size.line));
+        assert!(
+            value > 0,
+            "Array size must be positive in line {}",
+            size.line
+        );
+        self.expect(&Token::RBracket);
+        Some(value as usize)
+    }
+
+    /// Parses the parameters of a function type after `(`. Their names
+    /// are optional and do not matter.
+    fn parse_parameter_types(&mut self) -> Vec<Type> {
+        let mut params = Vec::new();
+        if self.peek() == Some(&Token::Void) && self.peek_at(1) == Some(&Token::RParen) {
+            self.advance();
+        }
+        if self.eat(&Token::RParen) {
+            return params;
+        }
+        loop {
+            let base_type = self.parse_base_type();
+            params.push(self.parse_any_declarator(base_type).0.decay());
+            if !self.eat(&Token::Comma) {
+                break;
+            }
+        }
+        self.expect(&Token::RParen);
+        params
+    }
+
+    fn parse_declaration_rest(
+        &mut self,
+        specifiers: &Specifiers,
+        var_type: Type,
+        name: String,
+        line: usize,
+    ) -> Declaration {
+        let init = if self.eat(&Token::Assign) {
+            Some(self.parse_initializer())
+        } else {
+            None
+        };
+
+        // A `const` before the type of a pointer is about what it points to
+        let mut element = &var_type;
+        while let Type::Array(inner, _) = element {
+            element = inner;
+        }
+        let constant = specifiers.constant && !matches!(element, Type::Pointer(_));
+        Declaration {
+            name,
+            var_type,
+            init,
+            line,
+            is_static: specifiers.is_static,
+            constant,
+        }
+    }
+
+    fn parse_initializer(&mut self) -> Initializer {
+        if !self.eat(&Token::LBrace) {
+            return Initializer::Expr(self.parse_assignment());
+        }
+
+        let mut values = Vec::new();
+        while !self.eat(&Token::RBrace) {
+            values.push(self.parse_initializer());
+            if !self.eat(&Token::Comma) {
+                self.expect(&Token::RBrace);
+                break;
+            }
+        }
+        Initializer::List(values)
+    }
+
+    fn parse_block(&mut self) -> Vec<Stmt> {
+        self.expect(&Token::LBrace);
+        let mut statements = Vec::new();
+        while !self.eat(&Token::RBrace) {
+            self.parse_statement_into(&mut statements);
+        }
+        statements
+    }
+
+    /// Parses one statement. Declarations of several variables expand into
+    /// several statements.
+    fn parse_statement_into(&mut self, statements: &mut Vec<Stmt>) {
+        if self.eat(&Token::Typedef) {
+            self.parse_typedef();
+            return;
+        }
+        if !self.is_type_start() {
+            statements.push(self.parse_statement());
+            return;
+        }
+
+        let specifiers = self.parse_specifiers();
+        if self.eat(&Token::Semicolon) {
+            return;
+        }
+        loop {
+            let line = self.line();
+            let (var_type, name) = self.parse_declarator(specifiers.base_type.clone());
+            statements.push(Stmt::Decl(self.parse_declaration_rest(
+                &specifiers,
+                var_type,
+                name,
+                line,
+            )));
+            if !self.eat(&Token::Comma) {
+                break;
+            }
+        }
+        self.expect(&Token::Semicolon);
+    }
+
+    fn parse_statement(&mut self) -> Stmt {
+        let line = self.line();
+        match self.peek() {
+            Some(Token::LBrace) => Stmt::Block(self.parse_block()),
+            Some(Token::If) => {
+                self.advance();
+                self.expect(&Token::LParen);
+                let condition = self.parse_expr();
+                self.expect(&Token::RParen);
+                let then = Box::new(self.parse_statement());
+                let otherwise = if self.eat(&Token::Else) {
+                    Some(Box::new(self.parse_statement()))
+                } else {
+                    None
+                };
+                Stmt::If(condition, then, otherwise)
+            }
+            Some(Token::While) => {
+                self.advance();
+                self.expect(&Token::LParen);
+                let condition = self.parse_expr();
+                self.expect(&Token::RParen);
+                Stmt::While(condition, Box::new(self.parse_statement()))
+            }
+            Some(Token::Do) => {
+                self.advance();
+                let body = Box::new(self.parse_statement());
+                self.expect(&Token::While);
+                self.expect(&Token::LParen);
+                let condition = self.parse_expr();
+                self.expect(&Token::RParen);
+                self.expect(&Token::Semicolon);
+                Stmt::DoWhile(body, condition)
+            }
+            Some(Token::For) => {
+                self.advance();
+                self.expect(&Token::LParen);
+                let init = if self.eat(&Token::Semicolon) {
+                    None
+                } else {
+                    let mut init = Vec::new();
+                    self.parse_statement_into(&mut init);
+                    Some(Box::new(match init.len() {
+                        1 => init.pop().unwrap(),
+                        _ => Stmt::Block(init),
+                    }))
+                };
+                let condition = self.parse_optional_expr(&Token::Semicolon);
+                let step = self.parse_optional_expr(&Token::RParen);
+                Stmt::For(init, condition, step, Box::new(self.parse_statement()))
+            }
+            Some(Token::Switch) => {
+                self.advance();
+                self.expect(&Token::LParen);
+                let value = self.parse_expr();
+                self.expect(&Token::RParen);
+                Stmt::Switch(value, Box::new(self.parse_statement()))
+            }
+            Some(Token::Case) => {
+                self.advance();
+                let value = self.parse_expr();
+                self.expect(&Token::Colon);
+                Stmt::Case(value, Box::new(self.parse_labeled_statement()))
+            }
+            Some(Token::Default) => {
+                self.advance();
+                self.expect(&Token::Colon);
+                Stmt::Default(line, Box::new(self.parse_labeled_statement()))
+            }
+            Some(Token::Return) => {
+                self.advance();
+                Stmt::Return(self.parse_optional_expr(&Token::Semicolon))
+            }
+            Some(Token::Break) => {
+                self.advance();
+                self.expect(&Token::Semicolon);
+                Stmt::Break(line)
+            }
+            Some(Token::Continue) => {
+                self.advance();
+                self.expect(&Token::Semicolon);
+                Stmt::Continue(line)
+            }
+            Some(Token::Semicolon) => {
+                self.advance();
+                Stmt::Block(Vec::new())
+            }
+            Some(Token::Asm) => self.parse_asm(),
+            _ => {
+                let expr = self.parse_expr();
+                self.expect(&Token::Semicolon);
+                Stmt::Expr(expr)
+            }
+        }
+    }
+
+    /// The statement after a label, which may be left out at the end of a
+    /// block.
+    fn parse_labeled_statement(&mut self) -> Stmt {
+        match self.peek() {
+            Some(Token::RBrace) => Stmt::Block(Vec::new()),
+            _ => self.parse_statement(),
+        }
+    }
+
+    fn parse_asm(&mut self) -> Stmt {
+        let line = self.line();
+        self.advance();
+        self.eat(&Token::Volatile);
+        self.expect(&Token::LParen);
+        let template = self.parse_string();
+        let mut asm = Asm {
+            template,
+            outputs: Vec::new(),
+            inputs: Vec::new(),
+            clobbers: Vec::new(),
+            line,
+        };
+        if self.eat(&Token::Colon) {
+            asm.outputs = self.parse_asm_operands();
+            if self.eat(&Token::Colon) {
+                asm.inputs = self.parse_asm_operands();
+                if self.eat(&Token::Colon) && self.peek() != Some(&Token::RParen) {
+                    loop {
+                        asm.clobbers.push(self.parse_string());
+                        if !self.eat(&Token::Comma) {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        self.expect(&Token::RParen);
+        self.expect(&Token::Semicolon);
+        Stmt::Asm(asm)
+    }
+
+    fn parse_asm_operands(&mut self) -> Vec<AsmOperand> {
+        let mut operands = Vec::new();
+        if matches!(self.peek(), Some(Token::Colon | Token::RParen)) {
+            return operands;
+        }
+        loop {
+            let name = if self.eat(&Token::LBracket) {
+                let name = self.expect_ident();
+                self.expect(&Token::RBracket);
+                Some(name)
+            } else {
+                None
+            };
+            let constraint = self.parse_string();
+            self.expect(&Token::LParen);
+            let expr = self.parse_expr();
+            self.expect(&Token::RParen);
+            operands.push(AsmOperand {
+                name,
+                constraint,
+                expr,
+            });
+            if !self.eat(&Token::Comma) {
+                return operands;
+            }
+        }
+    }
+
+    /// One or more adjacent string literals.
+    fn parse_string(&mut self) -> String {
+        let line = self.line();
+        let mut value = match self.advance() {
+            Some(Token::StrLit(value)) => value,
+            token => panic!("Expected string but found {:?} in line {}", token, line),
+        };
+        while let Some(Token::StrLit(next)) = self.peek() {
+            value.extend(next);
+            self.advance();
+        }
+        String::from_utf8_lossy(&value).into_owned()
+    }
+
+    fn parse_optional_expr(&mut self, terminator: &Token) -> Option<Expr> {
+        if self.eat(terminator) {
+            return None;
+        }
+        let expr = self.parse_expr();
+        self.expect(terminator);
+        Some(expr)
+    }
+
+    pub fn parse_expr(&mut self) -> Expr {
+        self.parse_assignment()
+    }
+
+    fn parse_assignment(&mut self) -> Expr {
+        let target = self.parse_conditional();
+        let line = self.line();
+
+        let op = match self.peek() {
+            Some(Token::Assign) => None,
+            Some(Token::PlusAssign) => Some(BinaryOp::Add),
+            Some(Token::MinusAssign) => Some(BinaryOp::Sub),
+            Some(Token::StarAssign) => Some(BinaryOp::Mul),
+            Some(Token::SlashAssign) => Some(BinaryOp::Div),
+            Some(Token::PercentAssign) => Some(BinaryOp::Mod),
+            Some(Token::AmpAssign) => Some(BinaryOp::And),
+            Some(Token::PipeAssign) => Some(BinaryOp::Or),
+            Some(Token::CaretAssign) => Some(BinaryOp::Xor),
+            Some(Token::ShlAssign) => Some(BinaryOp::Shl),
+            Some(Token::ShrAssign) => Some(BinaryOp::Shr),
+            _ => return target,
+        };
+        self.advance();
+
+        let value = self.parse_assignment();
+        Expr::new(
+            ExprKind::Assign(op, Box::new(target), Box::new(value)),
+            line,
+        )
+    }
+
+    fn parse_conditional(&mut self) -> Expr {
+        let condition = self.parse_binary(0);
+        let line = self.line();
+        if !self.eat(&Token::Question) {
+            return condition;
+        }
+
+        let then = self.parse_expr();
+        self.expect(&Token::Colon);
+        let otherwise = self.parse_conditional();
+        Expr::new(
+            ExprKind::Conditional(Box::new(condition), Box::new(then), Box::new(otherwise)),
+            line,
+        )
+    }
+
+    /// Precedence climbing over all left associative binary operators.
+    fn parse_binary(&mut self, min_precedence: u8) -> Expr {
+        let mut left = self.parse_unary();
+
+        while let Some((op, precedence)) = self.peek().and_then(binary_operator) {
+            if precedence < min_precedence {
+                break;
+            }
+            let line = self.line();
+            self.advance();
+            let right = self.parse_binary(precedence + 1);
+            left = Expr::new(ExprKind::Binary(op, Box::new(left), Box::new(right)), line);
+        }
+
+        left
+    }
+
+    fn parse_unary(&mut self) -> Expr {
+        let line = self.line();
+        let kind = match self.peek() {
+            Some(Token::Minus) => {
+                self.advance();
+                ExprKind::Unary(UnaryOp::Neg, Box::new(self.parse_unary()))
+            }
+            Some(Token::Plus) => {
+                self.advance();
+                return self.parse_unary();
+            }
+            Some(Token::Bang) => {
+                self.advance();
+                ExprKind::Unary(UnaryOp::Not, Box::new(self.parse_unary()))
+            }
+            Some(Token::Tilde) => {
+                self.advance();
+                ExprKind::Unary(UnaryOp::BitNot, Box::new(self.parse_unary()))
+            }
+            Some(Token::Star) => {
+                self.advance();
+                ExprKind::Deref(Box::new(self.parse_unary()))
+            }
+            Some(Token::Amp) => {
+                self.advance();
+                ExprKind::AddressOf(Box::new(self.parse_unary()))
+            }
+            Some(Token::PlusPlus | Token::MinusMinus) => {
+                let increment = self.advance() == Some(Token::PlusPlus);
+                ExprKind::IncDec {
+                    increment,
+                    prefix: true,
+                    target: Box::new(self.parse_unary()),
+                }
+            }
+            // The size of a type is known right away, that of an expression
+            // only once its type is
+            Some(Token::Sizeof) => {
+                self.advance();
+                if self.peek() == Some(&Token::LParen) && self.is_type_start_at(1) {
+                    self.advance();
+                    let var_type = self.parse_type_name();
+                    self.expect(&Token::RParen);
+                    ExprKind::Number(var_type.size(&self.structs, line) as i64)
+                } else {
+                    ExprKind::SizeOf(Box::new(self.parse_unary()))
+                }
+            }
+            Some(Token::LParen) if self.is_type_start_at(1) => {
+                self.advance();
+                let var_type = self.parse_type_name();
+                self.expect(&Token::RParen);
+                ExprKind::Cast(var_type, Box::new(self.parse_unary()))
+            }
+            _ => return self.parse_postfix(),
+        };
+        Expr::new(kind, line)
+    }
+
+    fn parse_postfix(&mut self) -> Expr {
+        let mut expr = self.parse_primary();
+
+        loop {
+            let line = self.line();
+            match self.peek() {
+                Some(Token::LBracket) => {
+                    self.advance();
+                    let index = self.parse_expr();
+                    self.expect(&Token::RBracket);
+                    expr = Expr::new(ExprKind::Index(Box::new(expr), Box::new(index)), line);
+                }
+                Some(Token::LParen) => {
+                    self.advance();
+                    let mut args = Vec::new();
+                    if !self.eat(&Token::RParen) {
+                        loop {
+                            args.push(self.parse_assignment());
+                            if !self.eat(&Token::Comma) {
+                                break;
+                            }
+                        }
+                        self.expect(&Token::RParen);
+                    }
+                    expr = Expr::new(ExprKind::Call(Box::new(expr), args), line);
+                }
+                Some(Token::Dot) => {
+                    self.advance();
+                    let member = self.expect_ident();
+                    expr = Expr::new(ExprKind::Member(Box::new(expr), member), line);
+                }
+                Some(Token::Arrow) => {
+                    self.advance();
+                    let member = self.expect_ident();
+                    let target = Expr::new(ExprKind::Deref(Box::new(expr)), line);
+                    expr = Expr::new(ExprKind::Member(Box::new(target), member), line);
+                }
+                Some(Token::PlusPlus | Token::MinusMinus) => {
+                    let increment = self.advance() == Some(Token::PlusPlus);
+                    expr = Expr::new(
+                        ExprKind::IncDec {
+                            increment,
+                            prefix: false,
+                            target: Box::new(expr),
+                        },
+                        line,
+                    );
+                }
+                _ => return expr,
+            }
+        }
+    }
+
+    fn parse_primary(&mut self) -> Expr {
+        let line = self.line();
+        match self.advance() {
+            Some(Token::Number(value)) => Expr::new(ExprKind::Number(value), line),
+            Some(Token::HexNumber(value)) => {
+                let number = Expr::new(ExprKind::Number(value), line);
+                match i16::try_from(value).is_err() && u16::try_from(value).is_ok() {
+                    true => Expr::new(ExprKind::Cast(Type::UNSIGNED, Box::new(number)), line),
+                    false => number,
+                }
+            }
+            Some(Token::SuffixedNumber((value, suffix))) => {
+                let number_type = match suffix.as_str() {
+                    "u" => Type::UNSIGNED,
+                    "l" => Type::LONG,
+                    "ul" | "lu" => Type::ULONG,
+                    _ => panic!("Invalid number suffix {} in line {}", suffix, line),
+                };
+                let number = Expr::new(ExprKind::Number(value), line);
+                Expr::new(ExprKind::Cast(number_type, Box::new(number)), line)
+            }
+            Some(Token::CharLit(value)) => Expr::new(ExprKind::Number(value as i64), line),
+            Some(Token::StrLit(mut value)) => {
+                while let Some(Token::StrLit(next)) = self.peek() {
+                    value.extend(next);
+                    self.advance();
+                }
+                Expr::new(ExprKind::Str(value), line)
+            }
+            Some(Token::Ident(name)) => Expr::new(ExprKind::Ident(name), line),
+            Some(Token::LParen) => {
+                let expr = self.parse_expr();
+                self.expect(&Token::RParen);
+                expr
+            }
+            token => panic!("Expected expression but found {:?} in line {}", token, line),
+        }
+    }
+
+    pub fn at_end(&self) -> bool {
+        self.position >= self.tokens.len()
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.peek_at(0)
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<&Token> {
+        self.tokens
+            .get(self.position + offset)
+            .map(|lexeme| &lexeme.token)
+    }
+
+    fn line(&self) -> usize {
+        self.tokens
+            .get(self.position)
+            .or(self.tokens.last())
+            .map_or(1, |lexeme| lexeme.line)
+    }
+
+    fn advance(&mut self) -> Option<Token> {
+        let token = self
+            .tokens
+            .get(self.position)
+            .map(|lexeme| lexeme.token.clone());
+        self.position += 1;
+        token
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        if self.peek() == Some(token) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, token: &Token) {
+        let line = self.line();
+        let found = self.advance();
+        assert!(
+            found.as_ref() == Some(token),
+            "Expected {:?} but found {:?} in line {}",
+            token,
+            found,
+            line
+        );
+    }
+
+    fn expect_ident(&mut self) -> String {
+        let line = self.line();
+        match self.advance() {
+            Some(Token::Ident(name)) => name,
+            token => panic!("Expected identifier but found {:?} in line {}", token, line),
+        }
+    }
+}
+
+fn binary_operator(token: &Token) -> Option<(BinaryOp, u8)> {
+    Some(match token {
+        Token::OrOr => (BinaryOp::LogicOr, 0),
+        Token::AndAnd => (BinaryOp::LogicAnd, 1),
+        Token::Pipe => (BinaryOp::Or, 2),
+        Token::Caret => (BinaryOp::Xor, 3),
+        Token::Amp => (BinaryOp::And, 4),
+        Token::Eq => (BinaryOp::Eq, 5),
+        Token::Ne => (BinaryOp::Ne, 5),
+        Token::Lt => (BinaryOp::Lt, 6),
+        Token::Le => (BinaryOp::Le, 6),
+        Token::Gt => (BinaryOp::Gt, 6),
+        Token::Ge => (BinaryOp::Ge, 6),
+        Token::Shl => (BinaryOp::Shl, 7),
+        Token::Shr => (BinaryOp::Shr, 7),
+        Token::Plus => (BinaryOp::Add, 8),
+        Token::Minus => (BinaryOp::Sub, 8),
+        Token::Star => (BinaryOp::Mul, 9),
+        Token::Slash => (BinaryOp::Div, 9),
+        Token::Percent => (BinaryOp::Mod, 9),
+        _ => return None,
+    })
+}
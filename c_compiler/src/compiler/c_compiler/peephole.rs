@@ -0,0 +1,365 @@
+//! Shrinks the generated code at `-Os`, where every instruction takes ROM.
+//! Wide constants are copied from registers that hold them already, and
+//! blocks that end the same way share their common tail.
+//!
+//! Both rely on the code generator never keeping `ADDRESS` or `SCRATCH`
+//! live across a label or jump.
+
+use std::collections::HashSet;
+
+use super::asm::{self, Inst, Item, Reg, ShiftOp, SCRATCH};
+use super::codegen::ADDRESS;
+
+/// Values of the registers where they are known.
+type Known = [Option<i16>; 8];
+
+pub fn optimize(items: &mut Vec<Item>) {
+    reuse_constants(items);
+    let mut merged = 0;
+    while merge_tails(items, merged) {
+        merged += 1;
+    }
+}
+
+// Constants
+
+/// Replaces the loads of wide constants with a copy of a register that
+/// holds the value or one within reach of `addi`. Register contents are
+/// only followed from one label or call to the next.
+fn reuse_constants(items: &mut Vec<Item>) {
+    let mut known: Known = [None; 8];
+    let mut i = 0;
+    while i < items.len() {
+        let Some((target, value, length)) = wide_constant(&items[i..]) else {
+            track(&mut known, &items[i]);
+            i += 1;
+            continue;
+        };
+        // The load leaves 8 in `SCRATCH`, which a copy does not
+        let copy = cheapest_copy(&known, target, value)
+            .filter(|insts| insts.len() < length && !reads_scratch(&items[i + length..]));
+        match copy {
+            Some(insts) => {
+                let copied = insts.len();
+                items.splice(i..i + length, insts.into_iter().map(Item::Inst));
+                i += copied;
+            }
+            None => {
+                known[SCRATCH as usize] = Some(8);
+                i += length;
+            }
+        }
+        known[target as usize] = Some(value);
+    }
+}
+
+/// The target, value and length of the `load_constant` sequence for a
+/// value that does not fit into `set`, if the items start with one.
+fn wide_constant(items: &[Item]) -> Option<(Reg, i16, usize)> {
+    let [Item::Inst(set), Item::Inst(Inst::Set(SCRATCH, 8)), Item::Inst(shift), ..] = items else {
+        return None;
+    };
+    let (Inst::Set(target, high), Inst::Sft(shifted, source, ShiftOp::Left, SCRATCH)) =
+        (set, shift)
+    else {
+        return None;
+    };
+    if shifted != target || source != target || *target == SCRATCH {
+        return None;
+    }
+    let value = (*high as i16) << 8;
+    match items.get(3) {
+        Some(Item::Inst(Inst::Addi(added, low))) if added == target => {
+            Some((*target, value.wrapping_add(*low as i16), 4))
+        }
+        _ => Some((*target, value, 3)),
+    }
+}
+
+/// The shortest way to get `value` into `target` from a known register.
+fn cheapest_copy(known: &Known, target: Reg, value: i16) -> Option<Vec<Inst>> {
+    (0..8)
+        .filter_map(|reg| {
+            let delta = i8::try_from(value.wrapping_sub(known[reg as usize]?)).ok()?;
+            let mut insts = Vec::new();
+            if reg != target {
+                insts.push(Inst::And(target, reg, reg));
+            }
+            if delta != 0 {
+                insts.push(Inst::Addi(target, delta));
+            }
+            Some(insts)
+        })
+        .min_by_key(Vec::len)
+}
+
+/// Whether `SCRATCH` is read before it is written again.
+fn reads_scratch(items: &[Item]) -> bool {
+    for item in items {
+        match item {
+            Item::Inst(inst) => {
+                if reads(inst).contains(&SCRATCH) {
+                    return true;
+                }
+                if writes(inst) == Some(SCRATCH) || ends_block(inst) {
+                    return false;
+                }
+            }
+            Item::SetLabel(target, _) if *target == SCRATCH => return false,
+            Item::Label(_) => return false,
+            _ => (),
+        }
+    }
+    false
+}
+
+/// Follows what an item does to the known register values.
+fn track(known: &mut Known, item: &Item) {
+    let inst = match item {
+        Item::Inst(inst) => inst,
+        Item::Label(_) => return *known = [None; 8],
+        // Far label loads go through `SCRATCH`
+        Item::SetLabel(target, _) => {
+            known[*target as usize] = None;
+            known[SCRATCH as usize] = None;
+            return;
+        }
+        Item::Source { .. } | Item::Variables(_) => return,
+    };
+    match *inst {
+        Inst::Set(target, value) => known[target as usize] = Some(value as i16),
+        Inst::Addi(target, value) => {
+            known[target as usize] = known[target as usize].map(|v| v.wrapping_add(value as i16));
+        }
+        Inst::And(target, a, b) if a == b => known[target as usize] = known[a as usize],
+        Inst::Jal(..) | Inst::Ret | Inst::Halt => *known = [None; 8],
+        _ => {
+            if let Some(target) = writes(inst) {
+                known[target as usize] = None;
+            }
+        }
+    }
+}
+
+// Tails
+
+/// Where a block goes on: by returning, or at a label it jumps or falls
+/// through to.
+#[derive(Clone, PartialEq)]
+enum Exit {
+    Return,
+    Goto(String),
+}
+
+/// The end of a block. `start..end` are the items of the return or jump,
+/// which are empty when the block falls through.
+struct Tail {
+    function: String,
+    exit: Exit,
+    start: usize,
+    end: usize,
+}
+
+/// Items two tails have in common, as pairs of indices, and the tail that
+/// gives them up for a jump to the other.
+struct Merge<'t> {
+    saved: usize,
+    common: Vec<(usize, usize)>,
+    remove: &'t Tail,
+}
+
+/// Finds two blocks of a function that end in the same instructions and
+/// go on at the same place, and replaces the end of one with a jump to the
+/// same instructions in the other. Takes the pair that saves the most ROM.
+/// Returns whether it found one.
+fn merge_tails(items: &mut Vec<Item>, merged: usize) -> bool {
+    let sizes = asm::sizes(items);
+    let addresses = asm::addresses(items);
+    let tails = tails(items);
+
+    let mut best: Option<Merge> = None;
+    for (i, keep) in tails.iter().enumerate() {
+        for remove in &tails[i + 1..] {
+            if keep.function != remove.function || keep.exit != remove.exit {
+                continue;
+            }
+            let mut common = common_suffix(items, keep.start, remove.start);
+            // The jump must not clobber what the shared instructions read
+            while !common.is_empty() && !keeps_jump_registers(items, &common) {
+                common.remove(0);
+            }
+            let Some(&(first, _)) = common.first() else {
+                continue;
+            };
+            let jump = 1 + if addresses[first] > 127 { 4 } else { 1 };
+            let removed: usize = common.iter().map(|(_, r)| sizes[*r]).sum::<usize>()
+                + sizes[remove.start..remove.end].iter().sum::<usize>();
+            let saved = removed.saturating_sub(jump);
+            if saved > best.as_ref().map_or(0, |merge| merge.saved) {
+                best = Some(Merge {
+                    saved,
+                    common,
+                    remove,
+                });
+            }
+        }
+    }
+    let Some(Merge { common, remove, .. }) = best else {
+        return false;
+    };
+
+    let label = format!("{}.tail{}", remove.function, merged);
+    let keep_start = common[0].0;
+    let remove_start = common[0].1;
+    let removed: HashSet<usize> = common
+        .iter()
+        .map(|(_, r)| *r)
+        .chain(remove.start..remove.end)
+        .filter(|r| !is_marker(&items[*r]))
+        .collect();
+    let mut result = Vec::with_capacity(items.len());
+    for (i, item) in items.drain(..).enumerate() {
+        if i == keep_start {
+            result.push(Item::Label(label.clone()));
+        }
+        if i == remove_start {
+            result.push(Item::SetLabel(ADDRESS, label.clone()));
+            result.push(Item::Inst(Inst::J(ADDRESS, None)));
+        }
+        if !removed.contains(&i) {
+            result.push(item);
+        }
+    }
+    *items = result;
+    true
+}
+
+/// The ends of all blocks in the order of the items.
+fn tails(items: &[Item]) -> Vec<Tail> {
+    let mut tails = Vec::new();
+    let mut function = String::new();
+    let mut previous: Option<usize> = None;
+    for (i, item) in items.iter().enumerate() {
+        let exit = match item {
+            // Block labels are prefixed with the name of their function
+            Item::Label(name) if !name.contains('.') => {
+                function = name.clone();
+                None
+            }
+            Item::Label(name) => match previous.map(|p| &items[p]) {
+                Some(Item::Inst(inst)) if ends_block(inst) => None,
+                _ => Some((Exit::Goto(name.clone()), i)),
+            },
+            Item::Inst(Inst::Ret) => Some((Exit::Return, i)),
+            Item::Inst(Inst::J(ADDRESS, None)) => match previous.map(|p| (p, &items[p])) {
+                Some((p, Item::SetLabel(ADDRESS, name))) => Some((Exit::Goto(name.clone()), p)),
+                _ => None,
+            },
+            _ => None,
+        };
+        if let Some((exit, start)) = exit {
+            let end = if start == i && matches!(item, Item::Label(_)) {
+                i
+            } else {
+                i + 1
+            };
+            tails.push(Tail {
+                function: function.clone(),
+                exit,
+                start,
+                end,
+            });
+        }
+        if !is_marker(item) {
+            previous = Some(i);
+        }
+    }
+    tails
+}
+
+/// Pairs of identical items that end right before `a` and `b`, first to
+/// last. Stops at labels, which may be jumped to from elsewhere.
+fn common_suffix(items: &[Item], mut a: usize, mut b: usize) -> Vec<(usize, usize)> {
+    let mut common = Vec::new();
+    while let (Some(x), Some(y)) = (previous_code(items, a), previous_code(items, b)) {
+        let ends = matches!(&items[x], Item::Inst(inst) if ends_block(inst));
+        if x == y || matches!(items[x], Item::Label(_)) || ends || items[x] != items[y] {
+            break;
+        }
+        common.push((x, y));
+        a = x;
+        b = y;
+    }
+    common.reverse();
+    common
+}
+
+fn previous_code(items: &[Item], index: usize) -> Option<usize> {
+    (0..index).rev().find(|i| !is_marker(&items[*i]))
+}
+
+/// Whether the shared instructions write `ADDRESS` and `SCRATCH` before
+/// reading them, so that jumping to them may clobber both.
+fn keeps_jump_registers(items: &[Item], common: &[(usize, usize)]) -> bool {
+    let mut written = HashSet::new();
+    for (i, _) in common {
+        match &items[*i] {
+            Item::Inst(inst) => {
+                let clobbered = |reg: &Reg| *reg == ADDRESS || *reg == SCRATCH;
+                if reads(inst)
+                    .iter()
+                    .any(|reg| clobbered(reg) && !written.contains(reg))
+                {
+                    return false;
+                }
+                written.extend(writes(inst));
+            }
+            Item::SetLabel(target, _) => {
+                written.insert(*target);
+            }
+            _ => (),
+        }
+    }
+    true
+}
+
+fn is_marker(item: &Item) -> bool {
+    matches!(item, Item::Source { .. } | Item::Variables(_))
+}
+
+/// Whether execution never goes on with the next instruction.
+fn ends_block(inst: &Inst) -> bool {
+    matches!(inst, Inst::J(_, None) | Inst::Ret | Inst::Halt)
+}
+
+fn reads(inst: &Inst) -> Vec<Reg> {
+    match *inst {
+        Inst::Nop | Inst::Set(..) | Inst::In(..) | Inst::Ret | Inst::Halt => vec![],
+        Inst::Load(_, address) => vec![address],
+        Inst::Store(value, address) => vec![value, address],
+        Inst::Add(_, a, b) | Inst::Sub(_, a, b) | Inst::And(_, a, b) | Inst::Xor(_, a, b) => {
+            vec![a, b]
+        }
+        Inst::Sft(_, a, _, steps) => vec![a, steps],
+        Inst::Addi(reg, _) | Inst::Ssp(reg) | Inst::Out(reg, _) => vec![reg],
+        Inst::J(target, condition) | Inst::Jal(target, condition) => match condition {
+            Some((a, _, b)) => vec![target, a, b],
+            None => vec![target],
+        },
+    }
+}
+
+fn writes(inst: &Inst) -> Option<Reg> {
+    match *inst {
+        Inst::Load(target, _)
+        | Inst::Add(target, ..)
+        | Inst::Addi(target, _)
+        | Inst::Sub(target, ..)
+        | Inst::And(target, ..)
+        | Inst::Xor(target, ..)
+        | Inst::Set(target, _)
+        | Inst::Sft(target, ..)
+        | Inst::In(target, _) => Some(target),
+        _ => None,
+    }
+}
@@ -0,0 +1,467 @@
+//! Handles `#define`, `#undef`, `#include`, conditional sections and
+//! `#pragma once` on the tokens of a file, before the parser sees them.
+//!
+//! Directives start with a `#` at the beginning of a line and end with the
+//! line. Expanded macros take the line of the place they are used, so errors
+//! point at the code that uses them.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::lexer::{self, Lexeme, Token};
+use super::parser::Parser;
+
+/// Headers that are found even without include paths.
+const BUILTIN_HEADERS: &[(&str, &str)] = &[("bepl.h", include_str!("../../../include/bepl.h"))];
+
+/// Guards against files that include themselves without `#pragma once`.
+const MAX_INCLUDE_DEPTH: usize = 64;
+
+struct Macro {
+    /// Parameter names of function-like macros.
+    params: Option<Vec<String>>,
+    body: Vec<Lexeme>,
+}
+
+/// One level of `#if` nesting.
+struct Conditional {
+    /// Whether the tokens in the current branch are compiled.
+    active: bool,
+    /// Whether one of the branches was already taken. Always set when the
+    /// surrounding section is inactive.
+    taken: bool,
+}
+
+pub struct Preprocessor<'a> {
+    include_paths: &'a [PathBuf],
+    /// Name and text of every file read, indexed by `Lexeme::file`.
+    files: Vec<(String, String)>,
+    macros: HashMap<String, Macro>,
+    /// Paths of the files marked with `#pragma once`.
+    once: HashSet<String>,
+    depth: usize,
+}
+
+impl<'a> Preprocessor<'a> {
+    pub fn new(include_paths: &'a [PathBuf]) -> Preprocessor<'a> {
+        Preprocessor {
+            include_paths,
+            files: Vec::new(),
+            macros: HashMap::new(),
+            once: HashSet::new(),
+            depth: 0,
+        }
+    }
+
+    /// Preprocesses a translation unit. Macros and `#pragma once` do not
+    /// carry over between translation units, only the list of files does.
+    pub fn preprocess(&mut self, name: &str, text: &str) -> Vec<Lexeme> {
+        self.macros.clear();
+        self.once.clear();
+        self.process_file(name.to_string(), text.to_string())
+    }
+
+    /// Name and text of every file read so far.
+    pub fn files(&self) -> &[(String, String)] {
+        &self.files
+    }
+
+    fn process_file(&mut self, name: String, text: String) -> Vec<Lexeme> {
+        let file = self.files.len();
+        let tokens = lexer::tokenize(&text, file);
+        self.files.push((name, text));
+
+        let mut output = Vec::new();
+        let mut conditionals: Vec<Conditional> = Vec::new();
+        let mut position = 0;
+        while position < tokens.len() {
+            let end = next_line(&tokens, position);
+            let active = conditionals.last().is_none_or(|c| c.active);
+            let first = &tokens[position];
+            if first.token == Token::Hash && first.line_start {
+                let directive = &tokens[position..end];
+                if !self.conditional(directive, &mut conditionals) && active {
+                    output.extend(self.directive(directive, file));
+                }
+                position = end;
+                continue;
+            }
+
+            // Function-like macro invocations may span lines, so everything
+            // up to the next directive is expanded together.
+            let end = (position..tokens.len())
+                .find(|&i| i > position && tokens[i].token == Token::Hash && tokens[i].line_start)
+                .unwrap_or(tokens.len());
+            if active {
+                output.extend(self.expand(&tokens[position..end], &mut Vec::new()));
+            }
+            position = end;
+        }
+
+        if !conditionals.is_empty() {
+            panic!("Unterminated #if in file {}", self.files[file].0);
+        }
+        output
+    }
+
+    /// Handles `#if` and friends, which are looked at even in inactive
+    /// sections. Returns whether the directive was one of them.
+    fn conditional(&mut self, directive: &[Lexeme], conditionals: &mut Vec<Conditional>) -> bool {
+        let line = directive[0].line;
+        match directive_name(directive).as_deref() {
+            Some(name @ ("if" | "ifdef" | "ifndef")) => {
+                let outer = conditionals.last().is_none_or(|c| c.active);
+                let condition = outer
+                    && match name {
+                        "if" => self.evaluate(&directive[2..], line),
+                        "ifdef" => self.macros.contains_key(&macro_name(directive, line)),
+                        _ => !self.macros.contains_key(&macro_name(directive, line)),
+                    };
+                conditionals.push(Conditional {
+                    active: condition,
+                    taken: condition || !outer,
+                });
+            }
+            Some("elif") => {
+                let taken = match conditionals.last() {
+                    Some(c) => c.taken,
+                    None => panic!("#elif without #if in line {}", line),
+                };
+                let condition = !taken && self.evaluate(&directive[2..], line);
+                let current = conditionals.last_mut().unwrap();
+                current.active = condition;
+                current.taken |= condition;
+            }
+            Some("else") => {
+                let Some(current) = conditionals.last_mut() else {
+                    panic!("#else without #if in line {}", line);
+                };
+                current.active = !current.taken;
+                current.taken = true;
+            }
+            Some("endif") => {
+                if conditionals.pop().is_none() {
+                    panic!("#endif without #if in line {}", line);
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// Runs a directive in an active section and returns the tokens it
+    /// produces.
+    fn directive(&mut self, directive: &[Lexeme], file: usize) -> Vec<Lexeme> {
+        let line = directive[0].line;
+        let Some(name) = directive_name(directive) else {
+            // A lone # does nothing
+            return Vec::new();
+        };
+        match name.as_str() {
+            "define" => self.define(directive, line),
+            "undef" => {
+                self.macros.remove(&macro_name(directive, line));
+            }
+            "include" => return self.include(directive, file),
+            "pragma" => {
+                if matches!(directive.get(2).map(|l| &l.token), Some(Token::Ident(n)) if n == "once")
+                {
+                    self.once.insert(self.files[file].0.clone());
+                }
+                // Other pragmas are ignored
+            }
+            "error" => {
+                let message = directive.get(2).map_or("", |first| {
+                    self.source(file, first.span.start, directive.last().unwrap().span.end)
+                });
+                panic!("#error {} in line {}", message, line);
+            }
+            _ => panic!("Unknown preprocessor directive: {} in line {}", name, line),
+        }
+        Vec::new()
+    }
+
+    fn define(&mut self, directive: &[Lexeme], line: usize) {
+        let name = macro_name(directive, line);
+        // Only a parenthesis right after the name starts a parameter list
+        let function_like = directive
+            .get(3)
+            .is_some_and(|l| l.token == Token::LParen && l.span.start == directive[2].span.end);
+
+        let (params, body) = if function_like {
+            let mut params = Vec::new();
+            let mut position = 4;
+            loop {
+                match directive.get(position).map(|l| &l.token) {
+                    Some(Token::RParen) if params.is_empty() => break,
+                    Some(Token::Ident(param)) => params.push(param.clone()),
+                    _ => panic!("Expected parameter name of macro {} in line {}", name, line),
+                }
+                position += 1;
+                match directive.get(position).map(|l| &l.token) {
+                    Some(Token::Comma) => position += 1,
+                    Some(Token::RParen) => break,
+                    _ => panic!(
+                        "Expected , or ) in parameters of macro {} in line {}",
+                        name, line
+                    ),
+                }
+            }
+            (Some(params), &directive[position + 1..])
+        } else {
+            (None, &directive[3.min(directive.len())..])
+        };
+
+        self.macros.insert(
+            name,
+            Macro {
+                params,
+                body: body.to_vec(),
+            },
+        );
+    }
+
+    fn include(&mut self, directive: &[Lexeme], file: usize) -> Vec<Lexeme> {
+        let line = directive[0].line;
+        let (header, quoted) = match directive.get(2).map(|l| &l.token) {
+            Some(Token::StrLit(bytes)) => (String::from_utf8_lossy(bytes).to_string(), true),
+            Some(Token::Lt) => {
+                let close = directive
+                    .iter()
+                    .position(|l| l.token == Token::Gt)
+                    .unwrap_or_else(|| {
+                        panic!("Expected > after include file name in line {}", line)
+                    });
+                let header = self.source(file, directive[2].span.end, directive[close].span.start);
+                (header.to_string(), false)
+            }
+            _ => panic!(
+                "Expected \"file\" or <file> after #include in line {}",
+                line
+            ),
+        };
+
+        let (path, text) = self
+            .find_header(&header, quoted.then_some(file))
+            .unwrap_or_else(|| panic!("Could not find include file {} in line {}", header, line));
+        if self.once.contains(&path) {
+            return Vec::new();
+        }
+        if self.depth == MAX_INCLUDE_DEPTH {
+            panic!("#include nested too deeply in line {}", line);
+        }
+        self.depth += 1;
+        let tokens = self.process_file(path, text);
+        self.depth -= 1;
+        tokens
+    }
+
+    /// Looks for a header next to the including file for quoted names, then
+    /// in the include paths and finally among the built-in headers.
+    fn find_header(&self, header: &str, including: Option<usize>) -> Option<(String, String)> {
+        let directory = including.map(|file| {
+            Path::new(&self.files[file].0)
+                .parent()
+                .unwrap_or(Path::new(""))
+                .to_path_buf()
+        });
+        for directory in directory.iter().chain(self.include_paths) {
+            let path = directory.join(header);
+            if let Ok(text) = fs::read_to_string(&path) {
+                return Some((path.to_string_lossy().to_string(), text));
+            }
+        }
+        BUILTIN_HEADERS
+            .iter()
+            .find(|(name, _)| *name == header)
+            .map(|(name, text)| (format!("<{}>", name), text.to_string()))
+    }
+
+    /// Evaluates the condition of `#if` or `#elif`. Identifiers that are no
+    /// macros count as 0.
+    fn evaluate(&self, tokens: &[Lexeme], line: usize) -> bool {
+        let mut resolved = Vec::new();
+        let mut position = 0;
+        while position < tokens.len() {
+            if tokens[position].token != Token::Ident("defined".to_string()) {
+                resolved.push(tokens[position].clone());
+                position += 1;
+                continue;
+            }
+            let parenthesized = tokens.get(position + 1).map(|l| &l.token) == Some(&Token::LParen);
+            let name_at = position + 1 + parenthesized as usize;
+            let defined = match tokens.get(name_at).map(|l| &l.token) {
+                Some(Token::Ident(name)) => self.macros.contains_key(name),
+                _ => panic!("Expected macro name after defined in line {}", line),
+            };
+            if parenthesized && tokens.get(name_at + 1).map(|l| &l.token) != Some(&Token::RParen) {
+                panic!("Expected ) after defined in line {}", line);
+            }
+            resolved.push(Lexeme {
+                token: Token::Number(defined as i64),
+                ..tokens[position].clone()
+            });
+            position = name_at + 1 + parenthesized as usize;
+        }
+
+        let expanded = self
+            .expand(&resolved, &mut Vec::new())
+            .into_iter()
+            .map(|lexeme| match lexeme.token {
+                Token::Ident(_) => Lexeme {
+                    token: Token::Number(0),
+                    ..lexeme
+                },
+                _ => lexeme,
+            })
+            .collect::<Vec<_>>();
+        if expanded.is_empty() {
+            panic!("Expected expression after #if in line {}", line);
+        }
+
+        let mut parser = Parser::new(expanded);
+        let expr = parser.parse_expr();
+        if !parser.at_end() {
+            panic!("Unexpected tokens after #if expression in line {}", line);
+        }
+        expr.constant_value()
+            .unwrap_or_else(|| panic!("#if expression is not constant in line {}", line))
+            != 0
+    }
+
+    /// Replaces macros in `tokens`. Macros in `disabled` are being expanded
+    /// already and stay as they are, which stops recursion.
+    fn expand(&self, tokens: &[Lexeme], disabled: &mut Vec<String>) -> Vec<Lexeme> {
+        let mut output = Vec::new();
+        let mut position = 0;
+        while position < tokens.len() {
+            let lexeme = &tokens[position];
+            position += 1;
+            let definition = match &lexeme.token {
+                Token::Ident(name) if !disabled.contains(name) => {
+                    self.macros.get(name).map(|m| (name, m))
+                }
+                _ => None,
+            };
+            let Some((name, definition)) = definition else {
+                output.push(lexeme.clone());
+                continue;
+            };
+
+            let body = match &definition.params {
+                None => definition.body.clone(),
+                // Without arguments the name of a function-like macro is a
+                // plain identifier
+                Some(_) if tokens.get(position).map(|l| &l.token) != Some(&Token::LParen) => {
+                    output.push(lexeme.clone());
+                    continue;
+                }
+                Some(params) => {
+                    let (args, end) = collect_args(tokens, position, name, lexeme.line);
+                    position = end;
+                    let args: Vec<Vec<Lexeme>> = match (params.len(), args.as_slice()) {
+                        (0, [arg]) if arg.is_empty() => Vec::new(),
+                        _ => args,
+                    };
+                    if args.len() != params.len() {
+                        panic!(
+                            "Macro {} expects {} arguments but got {} in line {}",
+                            name,
+                            params.len(),
+                            args.len(),
+                            lexeme.line
+                        );
+                    }
+                    let args: Vec<Vec<Lexeme>> =
+                        args.iter().map(|arg| self.expand(arg, disabled)).collect();
+                    definition
+                        .body
+                        .iter()
+                        .flat_map(|l| match &l.token {
+                            Token::Ident(ident) => match params.iter().position(|p| p == ident) {
+                                Some(index) => args[index].clone(),
+                                None => vec![l.clone()],
+                            },
+                            _ => vec![l.clone()],
+                        })
+                        .collect()
+                }
+            };
+
+            // The expansion appears where the macro is used
+            let body: Vec<Lexeme> = body
+                .into_iter()
+                .map(|l| Lexeme {
+                    line: lexeme.line,
+                    file: lexeme.file,
+                    line_start: false,
+                    span: lexeme.span.clone(),
+                    ..l
+                })
+                .collect();
+            disabled.push(name.clone());
+            output.extend(self.expand(&body, disabled));
+            disabled.pop();
+        }
+        output
+    }
+
+    fn source(&self, file: usize, start: usize, end: usize) -> &str {
+        self.files[file].1[start..end].trim()
+    }
+}
+
+/// Index of the first token on the next line.
+fn next_line(tokens: &[Lexeme], position: usize) -> usize {
+    (position + 1..tokens.len())
+        .find(|&i| tokens[i].line_start)
+        .unwrap_or(tokens.len())
+}
+
+fn directive_name(directive: &[Lexeme]) -> Option<String> {
+    match directive.get(1).map(|l| &l.token) {
+        Some(Token::Ident(name)) => Some(name.clone()),
+        Some(Token::If) => Some("if".to_string()),
+        Some(Token::Else) => Some("else".to_string()),
+        None => None,
+        Some(other) => panic!(
+            "Unknown preprocessor directive: {:?} in line {}",
+            other, directive[0].line
+        ),
+    }
+}
+
+fn macro_name(directive: &[Lexeme], line: usize) -> String {
+    match directive.get(2).map(|l| &l.token) {
+        Some(Token::Ident(name)) => name.clone(),
+        _ => panic!("Expected macro name in line {}", line),
+    }
+}
+
+/// Splits the arguments of a macro invocation at the commas outside of
+/// nested parentheses. `position` is at the opening parenthesis. Returns the
+/// arguments and the position after the closing parenthesis.
+fn collect_args(
+    tokens: &[Lexeme],
+    position: usize,
+    name: &str,
+    line: usize,
+) -> (Vec<Vec<Lexeme>>, usize) {
+    let mut args = vec![Vec::new()];
+    let mut depth = 0;
+    for (i, lexeme) in tokens.iter().enumerate().skip(position + 1) {
+        match lexeme.token {
+            Token::RParen if depth == 0 => return (args, i + 1),
+            Token::Comma if depth == 0 => {
+                args.push(Vec::new());
+                continue;
+            }
+            Token::LParen => depth += 1,
+            Token::RParen => depth -= 1,
+            _ => {}
+        }
+        args.last_mut().unwrap().push(lexeme.clone());
+    }
+    panic!("Unterminated arguments of macro {} in line {}", name, line);
+}
@@ -0,0 +1,277 @@
+//! Graph coloring register allocation in the style of Chaitin and Briggs.
+//!
+//! Calls do not preserve any registers, so values that are live across a
+//! call are kept in stack slots. Everything else competes for x0 to x4 and
+//! what does not fit is spilled as well. Registers clobbered by inline
+//! assembly are kept free of the values live across it.
+
+use std::collections::{BTreeSet, HashSet};
+
+use super::asm::Reg;
+use super::ir::{Function, Inst, Op, VReg};
+use super::ssa;
+
+/// x0 to x4 hold values, x5 to x7 are reserved by the code generator.
+const REGISTER_COUNT: usize = 5;
+
+/// Assigns a register to every virtual register, adding spill code to the
+/// function where needed.
+pub fn allocate(function: &mut Function) -> Vec<Reg> {
+    let mut unspillable = HashSet::new();
+    for reg in live_across_calls(function) {
+        spill(function, reg, &mut unspillable);
+    }
+
+    loop {
+        match color(function, &unspillable) {
+            Ok(colors) => return colors,
+            Err(spills) => {
+                assert!(
+                    spills.iter().all(|reg| !unspillable.contains(reg)),
+                    "Register allocation failed in function {}",
+                    function.name
+                );
+                for reg in spills {
+                    spill(function, reg, &mut unspillable);
+                }
+            }
+        }
+    }
+}
+
+/// Registers live at the end of every block.
+fn live_out(function: &Function) -> Vec<BTreeSet<VReg>> {
+    let mut live_in = vec![BTreeSet::new(); function.blocks.len()];
+    let mut live_out = vec![BTreeSet::new(); function.blocks.len()];
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for id in (0..function.blocks.len()).rev() {
+            let block = &function.blocks[id];
+            let mut live: BTreeSet<VReg> = block
+                .terminator
+                .successors()
+                .iter()
+                .flat_map(|s| live_in[*s].iter().copied())
+                .collect();
+            live_out[id] = live.clone();
+            live.extend(block.terminator.uses());
+            for inst in block.insts.iter().rev() {
+                for target in inst.op.defs() {
+                    live.remove(&target);
+                }
+                live.extend(inst.op.uses());
+            }
+            if live != live_in[id] {
+                live_in[id] = live;
+                changed = true;
+            }
+        }
+    }
+    live_out
+}
+
+/// Walks every instruction backwards, passing the registers that are live
+/// right after it.
+fn for_each_live(function: &Function, mut f: impl FnMut(&Inst, &BTreeSet<VReg>)) {
+    let live_out = live_out(function);
+    for (id, block) in function.blocks.iter().enumerate() {
+        let mut live = live_out[id].clone();
+        live.extend(block.terminator.uses());
+        for inst in block.insts.iter().rev() {
+            f(inst, &live);
+            for target in inst.op.defs() {
+                live.remove(&target);
+            }
+            live.extend(inst.op.uses());
+        }
+    }
+}
+
+fn live_across_calls(function: &Function) -> BTreeSet<VReg> {
+    let mut across = BTreeSet::new();
+    for_each_live(function, |inst, live| {
+        if inst.op.is_call() {
+            across.extend(live.iter().filter(|r| !inst.op.defs().contains(r)));
+        }
+    });
+    across
+}
+
+/// Moves a register into a new stack slot. Every definition is followed by
+/// a store and every use preceded by a load into a short lived register.
+fn spill(function: &mut Function, reg: VReg, unspillable: &mut HashSet<VReg>) {
+    let slot = function.frame_size;
+    function.frame_size += 1;
+    if let Some(variable) = function.variables.get(&reg).cloned() {
+        function.spilled_variables.insert(slot, variable);
+    }
+
+    for id in 0..function.blocks.len() {
+        let insts = std::mem::take(&mut function.blocks[id].insts);
+        let mut new_insts = Vec::with_capacity(insts.len());
+        for mut inst in insts {
+            let line = inst.line;
+            if inst.op.uses().contains(&reg) {
+                let temp = function.new_vreg();
+                unspillable.insert(temp);
+                new_insts.push(Inst {
+                    op: Op::SpillLoad(temp, slot),
+                    line,
+                });
+                inst.op.map_uses(|u| if u == reg { temp } else { u });
+            }
+            if inst.op.defs().contains(&reg) {
+                let temp = function.new_vreg();
+                unspillable.insert(temp);
+                inst.op.map_defs(|d| if d == reg { temp } else { d });
+                new_insts.push(inst);
+                new_insts.push(Inst {
+                    op: Op::SpillStore(temp, slot),
+                    line,
+                });
+            } else {
+                new_insts.push(inst);
+            }
+        }
+
+        let block = &mut function.blocks[id];
+        if block.terminator.uses().contains(&reg) {
+            let temp = function.vreg_count;
+            function.vreg_count += 1;
+            unspillable.insert(temp);
+            new_insts.push(Inst {
+                op: Op::SpillLoad(temp, slot),
+                line: block.line,
+            });
+            block
+                .terminator
+                .map_uses(|u| if u == reg { temp } else { u });
+        }
+        block.insts = new_insts;
+    }
+}
+
+/// Colors the interference graph, or returns the registers that have to be
+/// spilled first.
+fn color(function: &Function, unspillable: &HashSet<VReg>) -> Result<Vec<Reg>, Vec<VReg>> {
+    let count = function.vreg_count;
+    let mut neighbors = vec![BTreeSet::new(); count];
+    let mut moves = vec![Vec::new(); count];
+    let mut forbidden = vec![HashSet::new(); count];
+    let mut present = BTreeSet::new();
+
+    for_each_live(function, |inst, live| {
+        present.extend(inst.op.uses());
+        present.extend(inst.op.defs());
+        let source = match inst.op {
+            Op::Copy(target, source) => {
+                moves[target].push(source);
+                moves[source].push(target);
+                Some(source)
+            }
+            _ => None,
+        };
+        if let Op::Asm(asm) = &inst.op {
+            // Operands and values live across the block stay out of the
+            // clobbered registers. Outputs may be written before all inputs
+            // are read, so they get registers of their own. Only a `+r`
+            // output starts out as its input and may share with it.
+            for &reg in live.iter().chain(&asm.inputs).chain(&asm.outputs) {
+                forbidden[reg].extend(asm.clobbers.iter().copied());
+            }
+            for (&output, tied) in asm.outputs.iter().zip(&asm.tied) {
+                let inputs = asm.inputs.iter().enumerate();
+                let others = inputs.filter(|(i, _)| Some(*i) != *tied).map(|(_, r)| r);
+                for &other in others.chain(&asm.outputs) {
+                    if other != output {
+                        neighbors[output].insert(other);
+                        neighbors[other].insert(output);
+                    }
+                }
+                if let Some(input) = tied {
+                    moves[output].push(asm.inputs[*input]);
+                    moves[asm.inputs[*input]].push(output);
+                }
+            }
+        }
+        for target in inst.op.defs() {
+            for &other in live {
+                if other != target && Some(other) != source {
+                    neighbors[target].insert(other);
+                    neighbors[other].insert(target);
+                }
+            }
+        }
+    });
+    for block in &function.blocks {
+        present.extend(block.terminator.uses());
+    }
+
+    // Spilling registers used in loops costs more.
+    let depths = ssa::loop_depths(function);
+    let mut costs = vec![0.0f64; count];
+    for (id, block) in function.blocks.iter().enumerate() {
+        let weight = 10f64.powi(depths[id].min(4) as i32);
+        for inst in &block.insts {
+            for reg in inst.op.uses().into_iter().chain(inst.op.defs()) {
+                costs[reg] += weight;
+            }
+        }
+        for reg in block.terminator.uses() {
+            costs[reg] += weight;
+        }
+    }
+
+    // Simplify: take out registers with few neighbors, the rest
+    // optimistically in order of spill cost.
+    let mut degrees: Vec<usize> = neighbors.iter().map(|n| n.len()).collect();
+    let mut remaining = present.clone();
+    let mut stack = Vec::new();
+    while !remaining.is_empty() {
+        let next = remaining
+            .iter()
+            .copied()
+            .find(|r| degrees[*r] + forbidden[*r].len() < REGISTER_COUNT)
+            .unwrap_or_else(|| {
+                *remaining
+                    .iter()
+                    .min_by(|a, b| {
+                        let cost = |r: VReg| match unspillable.contains(&r) {
+                            true => f64::INFINITY,
+                            false => costs[r] / degrees[r] as f64,
+                        };
+                        cost(**a).total_cmp(&cost(**b))
+                    })
+                    .unwrap()
+            });
+        remaining.remove(&next);
+        for &neighbor in &neighbors[next] {
+            degrees[neighbor] = degrees[neighbor].saturating_sub(1);
+        }
+        stack.push(next);
+    }
+
+    // Select: prefer the color of registers this one is copied from or to.
+    let mut colors: Vec<Option<Reg>> = vec![None; count];
+    let mut spills = Vec::new();
+    while let Some(reg) = stack.pop() {
+        let taken: HashSet<Reg> = neighbors[reg].iter().filter_map(|n| colors[*n]).collect();
+        let free = |c: &Reg| !taken.contains(c) && !forbidden[reg].contains(c);
+        let preferred = moves[reg]
+            .iter()
+            .filter_map(|m| colors[*m])
+            .find(|c| free(c));
+        match preferred.or_else(|| (0..REGISTER_COUNT as Reg).find(|c| free(c))) {
+            Some(c) => colors[reg] = Some(c),
+            None => spills.push(reg),
+        }
+    }
+
+    if spills.is_empty() {
+        Ok(colors.into_iter().map(|c| c.unwrap_or(0)).collect())
+    } else {
+        Err(spills)
+    }
+}
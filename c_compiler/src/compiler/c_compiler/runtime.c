@@ -0,0 +1,231 @@
+// Runtime library linked into every C program. Only functions that are
+// actually called end up in the ROM.
+
+int __mul(int a, int b) {
+    int result = 0;
+    while (b != 0) {
+        if (b & 1) {
+            result += a;
+        }
+        a = a << 1;
+        b = (b >> 1) & 32767;
+    }
+    return result;
+}
+
+// Shift and subtract division.
+unsigned __udivmod(unsigned n, unsigned d, int want_remainder) {
+    unsigned quotient = 0;
+    unsigned remainder = 0;
+    for (int i = 0; i < 16; i++) {
+        remainder = (remainder << 1) | (n >> 15);
+        n = n << 1;
+        quotient = quotient << 1;
+        if (remainder >= d) {
+            remainder -= d;
+            quotient = quotient | 1;
+        }
+    }
+    return want_remainder ? remainder : quotient;
+}
+
+unsigned __udiv(unsigned a, unsigned b) {
+    return __udivmod(a, b, 0);
+}
+
+unsigned __umod(unsigned a, unsigned b) {
+    return __udivmod(a, b, 1);
+}
+
+// Signed division works on the magnitudes. The quotient is negative if the
+// signs differ, the remainder takes the sign of the dividend.
+int __divmod(int n, int d, int want_remainder) {
+    int negate_quotient = 0;
+    int negate_remainder = 0;
+    if (n < 0) {
+        n = -n;
+        negate_quotient = 1;
+        negate_remainder = 1;
+    }
+    if (d < 0) {
+        d = -d;
+        negate_quotient = !negate_quotient;
+    }
+
+    int result = __udivmod(n, d, want_remainder);
+    if (want_remainder) {
+        return negate_remainder ? -result : result;
+    }
+    return negate_quotient ? -result : result;
+}
+
+int __div(int a, int b) {
+    return __divmod(a, b, 0);
+}
+
+int __mod(int a, int b) {
+    return __divmod(a, b, 1);
+}
+
+// A long is a pair of words. Multiplying works the same for signed and
+// unsigned values.
+unsigned long __lmul(unsigned long a, unsigned long b) {
+    unsigned long result = 0;
+    while (b != 0) {
+        if (b & 1) {
+            result += a;
+        }
+        a = a << 1;
+        b = b >> 1;
+    }
+    return result;
+}
+
+unsigned long __uldivmod(unsigned long n, unsigned long d, int want_remainder) {
+    unsigned long quotient = 0;
+    unsigned long remainder = 0;
+    for (int i = 0; i < 32; i++) {
+        remainder = (remainder << 1) | (n >> 31);
+        n = n << 1;
+        quotient = quotient << 1;
+        if (remainder >= d) {
+            remainder -= d;
+            quotient = quotient | 1;
+        }
+    }
+    return want_remainder ? remainder : quotient;
+}
+
+unsigned long __uldiv(unsigned long a, unsigned long b) {
+    return __uldivmod(a, b, 0);
+}
+
+unsigned long __ulmod(unsigned long a, unsigned long b) {
+    return __uldivmod(a, b, 1);
+}
+
+long __ldivmod(long n, long d, int want_remainder) {
+    int negate_quotient = 0;
+    int negate_remainder = 0;
+    if (n < 0) {
+        n = -n;
+        negate_quotient = 1;
+        negate_remainder = 1;
+    }
+    if (d < 0) {
+        d = -d;
+        negate_quotient = !negate_quotient;
+    }
+
+    long result = __uldivmod(n, d, want_remainder);
+    if (want_remainder) {
+        return negate_remainder ? -result : result;
+    }
+    return negate_quotient ? -result : result;
+}
+
+long __ldiv(long a, long b) {
+    return __ldivmod(a, b, 0);
+}
+
+long __lmod(long a, long b) {
+    return __ldivmod(a, b, 1);
+}
+
+// Shifts by a variable count move whole words first.
+long __lshl(long a, int n) {
+    if (n >= 16) {
+        a = a << 16;
+        n -= 16;
+    }
+    for (; n > 0; n--) {
+        a = a << 1;
+    }
+    return a;
+}
+
+long __lshr(long a, int n) {
+    if (n >= 16) {
+        a = a >> 16;
+        n -= 16;
+    }
+    for (; n > 0; n--) {
+        a = a >> 1;
+    }
+    return a;
+}
+
+unsigned long __ulshr(unsigned long a, int n) {
+    if (n >= 16) {
+        a = a >> 16;
+        n -= 16;
+    }
+    for (; n > 0; n--) {
+        a = a >> 1;
+    }
+    return a;
+}
+
+// Struct copies and zero filled locals share these loops at -Os instead of
+// a load and store for every word.
+void __copy(int *target, const int *source, int words) {
+    for (; words > 0; words--) {
+        *target++ = *source++;
+    }
+}
+
+void __zero(int *target, int words) {
+    for (; words > 0; words--) {
+        *target++ = 0;
+    }
+}
+
+// The device of an OUT instruction is a constant, so every device needs
+// its own.
+void __puts(int device, const char *text) {
+    for (; *text; text++) {
+        switch (device) {
+        case 0:
+            __out(0, *text);
+            break;
+        case 1:
+            __out(1, *text);
+            break;
+        case 2:
+            __out(2, *text);
+            break;
+        case 3:
+            __out(3, *text);
+            break;
+        case 4:
+            __out(4, *text);
+            break;
+        case 5:
+            __out(5, *text);
+            break;
+        case 6:
+            __out(6, *text);
+            break;
+        case 7:
+            __out(7, *text);
+            break;
+        }
+    }
+}
+
+void __putn(int device, int value) {
+    char digits[7];
+    char *text = digits + 6;
+    unsigned magnitude = value < 0 ? -value : value;
+    *text = 0;
+    do {
+        text--;
+        *text = '0' + magnitude % 10;
+        magnitude /= 10;
+    } while (magnitude);
+    if (value < 0) {
+        text--;
+        *text = '-';
+    }
+    __puts(device, text);
+}
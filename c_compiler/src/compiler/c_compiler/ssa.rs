@@ -0,0 +1,293 @@
+//! Conversion into and out of SSA form, plus the dominator and loop
+//! analyses the optimizer builds on.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use super::ir::{BlockId, Function, Inst, Op, VReg};
+
+/// Immediate dominators, computed with the algorithm of Cooper, Harvey and
+/// Kennedy. Blocks have to be numbered in reverse postorder, which
+/// `Function::remove_unreachable_blocks` ensures. The entry block is its own
+/// immediate dominator.
+pub fn dominators(function: &Function) -> Vec<BlockId> {
+    let predecessors = function.predecessors();
+    let mut idom = vec![usize::MAX; function.blocks.len()];
+    idom[0] = 0;
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for block in 1..function.blocks.len() {
+            let mut new_idom = usize::MAX;
+            for &pred in &predecessors[block] {
+                if idom[pred] == usize::MAX {
+                    continue;
+                }
+                new_idom = match new_idom {
+                    usize::MAX => pred,
+                    other => intersect(&idom, pred, other),
+                };
+            }
+            if idom[block] != new_idom {
+                idom[block] = new_idom;
+                changed = true;
+            }
+        }
+    }
+    idom
+}
+
+fn intersect(idom: &[BlockId], mut a: BlockId, mut b: BlockId) -> BlockId {
+    while a != b {
+        while a > b {
+            a = idom[a];
+        }
+        while b > a {
+            b = idom[b];
+        }
+    }
+    a
+}
+
+pub fn dominates(idom: &[BlockId], a: BlockId, mut b: BlockId) -> bool {
+    loop {
+        if a == b {
+            return true;
+        }
+        if b == 0 {
+            return false;
+        }
+        b = idom[b];
+    }
+}
+
+/// Children of every block in the dominator tree.
+pub fn dominator_tree(idom: &[BlockId]) -> Vec<Vec<BlockId>> {
+    let mut children = vec![Vec::new(); idom.len()];
+    for (block, &parent) in idom.iter().enumerate().skip(1) {
+        children[parent].push(block);
+    }
+    children
+}
+
+pub struct Loop {
+    pub header: BlockId,
+    pub blocks: HashSet<BlockId>,
+}
+
+/// Natural loops, one per loop header, innermost first.
+pub fn loops(function: &Function, idom: &[BlockId]) -> Vec<Loop> {
+    let predecessors = function.predecessors();
+    let mut loops: Vec<Loop> = Vec::new();
+
+    for (block, successors) in function
+        .blocks
+        .iter()
+        .map(|b| b.terminator.successors())
+        .enumerate()
+    {
+        for header in successors {
+            if !dominates(idom, header, block) {
+                continue;
+            }
+            let mut blocks = HashSet::from([header]);
+            let mut stack = vec![block];
+            while let Some(b) = stack.pop() {
+                if blocks.insert(b) {
+                    stack.extend(&predecessors[b]);
+                }
+            }
+            match loops.iter_mut().find(|l| l.header == header) {
+                Some(existing) => existing.blocks.extend(blocks),
+                None => loops.push(Loop { header, blocks }),
+            }
+        }
+    }
+
+    loops.sort_by_key(|l| l.blocks.len());
+    loops
+}
+
+/// How many loops each block is nested in.
+pub fn loop_depths(function: &Function) -> Vec<usize> {
+    let idom = dominators(function);
+    let mut depths = vec![0; function.blocks.len()];
+    for l in loops(function, &idom) {
+        for block in l.blocks {
+            depths[block] += 1;
+        }
+    }
+    depths
+}
+
+/// Renames every virtual register so that it has exactly one definition and
+/// inserts phi instructions where definitions meet. Variables that are read
+/// before they are written start out as 0.
+pub fn construct(function: &mut Function) {
+    function.remove_unreachable_blocks();
+    let idom = dominators(function);
+    let children = dominator_tree(&idom);
+    let predecessors = function.predecessors();
+
+    let mut frontiers: Vec<BTreeSet<BlockId>> = vec![BTreeSet::new(); function.blocks.len()];
+    for (block, preds) in predecessors.iter().enumerate() {
+        if preds.len() < 2 {
+            continue;
+        }
+        for &pred in preds {
+            let mut runner = pred;
+            while runner != idom[block] {
+                frontiers[runner].insert(block);
+                runner = idom[runner];
+            }
+        }
+    }
+
+    // Only variables that are live across blocks need phis.
+    let mut global = BTreeSet::new();
+    let mut definitions: HashMap<VReg, BTreeSet<BlockId>> = HashMap::new();
+    for (id, block) in function.blocks.iter().enumerate() {
+        let mut defined = HashSet::new();
+        for inst in &block.insts {
+            for used in inst.op.uses() {
+                if !defined.contains(&used) {
+                    global.insert(used);
+                }
+            }
+            for def in inst.op.defs() {
+                defined.insert(def);
+                definitions.entry(def).or_default().insert(id);
+            }
+        }
+        for used in block.terminator.uses() {
+            if !defined.contains(&used) {
+                global.insert(used);
+            }
+        }
+    }
+
+    let mut phis: Vec<Vec<VReg>> = vec![Vec::new(); function.blocks.len()];
+    for &var in &global {
+        let mut work: Vec<BlockId> = definitions
+            .get(&var)
+            .map(|d| d.iter().copied().collect())
+            .unwrap_or_default();
+        work.push(0);
+        let mut placed = HashSet::new();
+        while let Some(block) = work.pop() {
+            for &frontier in &frontiers[block] {
+                if placed.insert(frontier) {
+                    phis[frontier].push(var);
+                    work.push(frontier);
+                }
+            }
+        }
+    }
+
+    for (block, vars) in phis.iter().enumerate() {
+        let line = function.blocks[block].line;
+        let phi_insts = vars.iter().map(|&var| Inst {
+            op: Op::Phi(var, Vec::new()),
+            line,
+        });
+        function.blocks[block].insts.splice(0..0, phi_insts);
+    }
+
+    // Uninitialized variables read 0.
+    let line = function.blocks[0].line;
+    function.blocks[0].insts.splice(
+        0..0,
+        global.iter().map(|&var| Inst {
+            op: Op::Const(var, 0),
+            line,
+        }),
+    );
+
+    let mut renamer = Renamer {
+        stacks: HashMap::new(),
+        phi_vars: phis,
+    };
+    renamer.rename(function, &children, 0);
+}
+
+struct Renamer {
+    stacks: HashMap<VReg, Vec<VReg>>,
+    phi_vars: Vec<Vec<VReg>>,
+}
+
+impl Renamer {
+    fn current(&self, var: VReg) -> VReg {
+        self.stacks
+            .get(&var)
+            .and_then(|s| s.last().copied())
+            .unwrap_or(var)
+    }
+
+    fn rename(&mut self, function: &mut Function, children: &[Vec<BlockId>], block: BlockId) {
+        let mut pushed = Vec::new();
+
+        let mut insts = std::mem::take(&mut function.blocks[block].insts);
+        for inst in &mut insts {
+            if !matches!(inst.op, Op::Phi(..)) {
+                inst.op.map_uses(|u| self.current(u));
+            }
+            inst.op.map_defs(|def| {
+                let new = function.new_vreg();
+                if let Some(variable) = function.variables.get(&def) {
+                    function.variables.insert(new, variable.clone());
+                }
+                self.stacks.entry(def).or_default().push(new);
+                pushed.push(def);
+                new
+            });
+        }
+        function.blocks[block].insts = insts;
+        let mut terminator = function.blocks[block].terminator.clone();
+        terminator.map_uses(|u| self.current(u));
+        function.blocks[block].terminator = terminator.clone();
+
+        for successor in terminator.successors() {
+            let vars = self.phi_vars[successor].clone();
+            for (i, var) in vars.into_iter().enumerate() {
+                let value = self.current(var);
+                if let Op::Phi(_, sources) = &mut function.blocks[successor].insts[i].op {
+                    sources.push((block, value));
+                }
+            }
+        }
+
+        for &child in &children[block] {
+            self.rename(function, children, child);
+        }
+
+        for var in pushed {
+            self.stacks.get_mut(&var).unwrap().pop();
+        }
+    }
+}
+
+/// Replaces phi instructions with copies at the end of the predecessors.
+/// Each phi goes through a fresh register, so parallel phis in one block
+/// cannot overwrite each other's sources.
+pub fn destruct(function: &mut Function) {
+    for block in 0..function.blocks.len() {
+        let mut copies = Vec::new();
+        for inst in &mut function.blocks[block].insts {
+            let Op::Phi(target, sources) = &inst.op else {
+                continue;
+            };
+            let temp = function.vreg_count;
+            function.vreg_count += 1;
+            for &(pred, value) in sources {
+                copies.push((pred, temp, value, inst.line));
+            }
+            inst.op = Op::Copy(*target, temp);
+        }
+        for (pred, temp, value, line) in copies {
+            function.blocks[pred].insts.push(Inst {
+                op: Op::Copy(temp, value),
+                line,
+            });
+        }
+    }
+}
@@ -0,0 +1,809 @@
+//! Compiles a small Forth dialect to BEPL machine code.
+//!
+//! Code outside of colon definitions runs in order as the main program,
+//! which halts with the top of the stack in x0. Words have to be defined
+//! before they are used, and `RECURSE` calls the word being defined.
+//!
+//! The top of the data stack is kept in x1 and the rest grows down from the
+//! top of memory, with x7 pointing at the second item. Colon definitions are
+//! called with `jal` and return with `ret` on the hardware stack, which
+//! grows up from address 0. `DO` loops keep their index and limit on a
+//! third stack in x4 that grows down from `LOOP_STACK`.
+//!
+//! Numbers are 16 bit, and flags are -1 for true and 0 for false. `*`, `/`
+//! and `MOD` call shared subroutines that are only included when used;
+//! division truncates towards 0. `.` writes a number to the console, `EMIT`,
+//! `CR` and `."` write characters to the display and `KEY` reads a number
+//! from the console. Comments are `( ... )` and `\` to the end of the line.
+
+use std::collections::HashMap;
+
+use super::c_compiler::asm::{self, load_constant, Flag, Inst, Item, Reg, ShiftOp};
+use super::{Compiler, CONSOLE, DISPLAY};
+
+/// Always 0, to compare against.
+const ZERO: Reg = 0;
+/// The top of the data stack.
+const TOP: Reg = 1;
+/// Temporaries within a word.
+const A: Reg = 2;
+const B: Reg = 3;
+/// Points at the index of the innermost `DO` loop, with its limit above.
+const LOOPS: Reg = 4;
+const SCRATCH: Reg = asm::SCRATCH;
+/// Holds jump targets.
+const ADDRESS: Reg = 6;
+/// Points at the second item of the data stack.
+const STACK: Reg = 7;
+
+/// Start of the `DO` loop stack, which leaves the return stack 48K words
+/// and the data stack 16K words.
+const LOOP_STACK: i16 = -0x4000;
+
+/// `.` and `KEY` use the console, `EMIT`, `CR` and `."` the display.
+const NUMBER_DEVICE: u8 = CONSOLE;
+const CHARACTER_DEVICE: u8 = DISPLAY;
+
+pub struct ForthCompiler {
+    file_name: String,
+}
+
+impl ForthCompiler {
+    pub fn new() -> ForthCompiler {
+        ForthCompiler {
+            file_name: "main.fs".to_string(),
+        }
+    }
+}
+
+impl Compiler for ForthCompiler {
+    fn compile(&self, raw_code: &str) -> Vec<u16> {
+        asm::assemble(&Generator::new().generate(&tokenize(raw_code)))
+    }
+
+    fn compile_to_assembly(&self, raw_code: &str) -> String {
+        let files = [(self.file_name.clone(), raw_code.to_string())];
+        asm::to_text(&Generator::new().generate(&tokenize(raw_code)), &files)
+    }
+
+    fn file_name(mut self, file_name: &str) -> ForthCompiler {
+        self.file_name = file_name.to_string();
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    /// A word or number, in upper case.
+    Word(String),
+    /// The text of `." text"`.
+    Text(String),
+}
+
+/// Splits the source into words with their line, dropping comments.
+fn tokenize(raw_code: &str) -> Vec<(Token, usize)> {
+    let mut tokens = Vec::new();
+    let mut comment_line = None;
+    for (i, text) in raw_code.lines().enumerate() {
+        let line = i + 1;
+        let mut rest = text;
+        loop {
+            // Inside a comment, everything up to `)` is skipped
+            if comment_line.is_some() {
+                match rest.find(')') {
+                    Some(end) => {
+                        rest = &rest[end + 1..];
+                        comment_line = None;
+                    }
+                    None => break,
+                }
+            }
+            rest = rest.trim_start();
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let word = &rest[..end];
+            rest = &rest[end..];
+            match word {
+                "" | "\\" => break,
+                "(" => comment_line = Some(line),
+                ".\"" => {
+                    let Some(end) = rest.get(1..).and_then(|text| text.find('"')) else {
+                        panic!("Unterminated .\" in line {}", line);
+                    };
+                    tokens.push((Token::Text(rest[1..end + 1].to_string()), line));
+                    rest = &rest[end + 2..];
+                }
+                _ => tokens.push((Token::Word(word.to_ascii_uppercase()), line)),
+            }
+        }
+    }
+    if let Some(line) = comment_line {
+        panic!("Unterminated comment in line {}", line);
+    }
+    tokens
+}
+
+/// An open control structure, with the labels it still needs.
+enum Control {
+    If { otherwise: String },
+    Else { end: String },
+    Begin { start: String },
+    While { start: String, end: String },
+    Do { start: String },
+}
+
+/// The colon definition being compiled.
+struct Definition {
+    name: String,
+    label: String,
+    line: usize,
+}
+
+struct Generator {
+    main: Vec<Item>,
+    definitions: Vec<Item>,
+    /// Labels of the defined words.
+    words: HashMap<String, String>,
+    current: Option<Definition>,
+    control: Vec<(Control, usize)>,
+    /// The last source line of `main` and of `definitions`.
+    lines: [Option<usize>; 2],
+    label_count: usize,
+    uses_multiply: bool,
+    uses_divide: bool,
+}
+
+impl Generator {
+    fn new() -> Generator {
+        Generator {
+            main: Vec::new(),
+            definitions: Vec::new(),
+            words: HashMap::new(),
+            current: None,
+            control: Vec::new(),
+            lines: [None; 2],
+            label_count: 0,
+            uses_multiply: false,
+            uses_divide: false,
+        }
+    }
+
+    fn generate(mut self, tokens: &[(Token, usize)]) -> Vec<Item> {
+        let mut tokens = tokens.iter();
+        while let Some((token, line)) = tokens.next() {
+            let line = *line;
+            self.source(line);
+            let word = match token {
+                Token::Word(word) => word,
+                Token::Text(text) => {
+                    for c in text.bytes() {
+                        self.emit(Inst::Set(B, c as i8));
+                        self.emit(Inst::Out(B, CHARACTER_DEVICE));
+                    }
+                    continue;
+                }
+            };
+            match word.as_str() {
+                ":" => {
+                    let Some((Token::Word(name), _)) = tokens.next() else {
+                        panic!("Expected a name after : in line {}", line);
+                    };
+                    self.start_definition(name, line);
+                }
+                ";" => self.end_definition(line),
+                _ => self.word(word, line),
+            }
+        }
+        if let Some(definition) = &self.current {
+            panic!(
+                "Definition of {} is not ended in line {}",
+                definition.name, definition.line
+            );
+        }
+        self.check_control_closed();
+
+        let mut items: Vec<Item> =
+            vec![Item::Inst(Inst::Set(ZERO, 0)), Item::Inst(Inst::Ssp(ZERO))];
+        items.push(Item::Inst(Inst::Set(STACK, 0)));
+        items.extend(load_constant(LOOPS, LOOP_STACK).into_iter().map(Item::Inst));
+        items.append(&mut self.main);
+        items.push(Item::Inst(Inst::And(ZERO, TOP, TOP)));
+        items.push(Item::Inst(Inst::Halt));
+        items.append(&mut self.definitions);
+        if self.uses_multiply {
+            items.extend(multiply());
+        }
+        if self.uses_divide {
+            items.extend(divide());
+        }
+        items
+    }
+
+    fn start_definition(&mut self, name: &str, line: usize) {
+        if let Some(definition) = &self.current {
+            panic!(
+                "Definition of {} inside of {} in line {}",
+                name, definition.name, line
+            );
+        }
+        self.check_control_closed();
+        // Words may be defined again, so their labels are numbered
+        self.label_count += 1;
+        let label = format!("word{}", self.label_count);
+        self.definitions.push(Item::Label(label.clone()));
+        self.current = Some(Definition {
+            name: name.to_string(),
+            label,
+            line,
+        });
+        self.lines[1] = None;
+        self.source(line);
+    }
+
+    fn end_definition(&mut self, line: usize) {
+        if self.current.is_none() {
+            panic!("; without : in line {}", line);
+        }
+        // Control structures cannot span definitions
+        self.check_control_closed();
+        let definition = self.current.take().unwrap();
+        self.definitions.push(Item::Inst(Inst::Ret));
+        self.words.insert(definition.name, definition.label);
+    }
+
+    fn check_control_closed(&self) {
+        if let Some((control, line)) = self.control.last() {
+            let name = match control {
+                Control::If { .. } | Control::Else { .. } => "IF",
+                Control::Begin { .. } | Control::While { .. } => "BEGIN",
+                Control::Do { .. } => "DO",
+            };
+            panic!("{} is not closed in line {}", name, line);
+        }
+    }
+
+    fn word(&mut self, word: &str, line: usize) {
+        match word {
+            // Stack
+            "DUP" => self.push(TOP),
+            "DROP" => self.pop(TOP),
+            "SWAP" => {
+                self.emit(Inst::Load(A, STACK));
+                self.emit(Inst::Store(TOP, STACK));
+                self.emit(Inst::And(TOP, A, A));
+            }
+            "OVER" => {
+                self.emit(Inst::Load(A, STACK));
+                self.push(TOP);
+                self.emit(Inst::And(TOP, A, A));
+            }
+            "ROT" => {
+                self.emit(Inst::And(B, STACK, STACK));
+                self.emit(Inst::Addi(B, 1));
+                self.emit(Inst::Load(A, B));
+                self.emit(Inst::Load(SCRATCH, STACK));
+                self.emit(Inst::Store(SCRATCH, B));
+                self.emit(Inst::Store(TOP, STACK));
+                self.emit(Inst::And(TOP, A, A));
+            }
+
+            // Arithmetic
+            "+" => self.binary(|a, b| vec![Inst::Add(TOP, a, b)]),
+            "-" => self.binary(|a, b| vec![Inst::Sub(TOP, a, b)]),
+            "AND" => self.binary(|a, b| vec![Inst::And(TOP, a, b)]),
+            "XOR" => self.binary(|a, b| vec![Inst::Xor(TOP, a, b)]),
+            // The bits of a ^ b and a & b never overlap
+            "OR" => self.binary(|a, b| {
+                vec![
+                    Inst::Xor(B, a, b),
+                    Inst::And(TOP, a, b),
+                    Inst::Xor(TOP, TOP, B),
+                ]
+            }),
+            "*" => {
+                self.pop(A);
+                self.call("multiply");
+                self.uses_multiply = true;
+            }
+            "/" | "MOD" | "/MOD" => {
+                self.pop(A);
+                self.call("divide");
+                self.uses_divide = true;
+                // `/MOD` leaves the remainder below the quotient
+                match word {
+                    "MOD" => self.emit(Inst::And(TOP, A, A)),
+                    "/MOD" => {
+                        self.emit(Inst::Addi(STACK, -1));
+                        self.emit(Inst::Store(A, STACK));
+                    }
+                    _ => (),
+                }
+            }
+            "NEGATE" => self.emit(Inst::Sub(TOP, ZERO, TOP)),
+            "ABS" => {
+                self.emit(Inst::Set(B, 15));
+                self.emit(Inst::Sft(B, TOP, ShiftOp::ArithmeticRight, B));
+                self.emit(Inst::Xor(TOP, TOP, B));
+                self.emit(Inst::Sub(TOP, TOP, B));
+            }
+            "INVERT" => {
+                self.emit(Inst::Set(B, -1));
+                self.emit(Inst::Xor(TOP, TOP, B));
+            }
+            "1+" => self.emit(Inst::Addi(TOP, 1)),
+            "1-" => self.emit(Inst::Addi(TOP, -1)),
+            "2*" => self.emit(Inst::Add(TOP, TOP, TOP)),
+            "2/" => {
+                self.emit(Inst::Set(B, 1));
+                self.emit(Inst::Sft(TOP, TOP, ShiftOp::ArithmeticRight, B));
+            }
+
+            // Comparisons
+            "=" => self.compare(Flag::Equal, false),
+            "<" => self.compare(Flag::Less, false),
+            ">" => self.compare(Flag::Greater, false),
+            "<>" => {
+                self.compare(Flag::Equal, false);
+                self.emit(Inst::Set(B, -1));
+                self.emit(Inst::Xor(TOP, TOP, B));
+            }
+            "0=" => self.compare(Flag::Equal, true),
+            "0<" => self.compare(Flag::Less, true),
+            "0>" => self.compare(Flag::Greater, true),
+
+            // Input and output
+            "." => {
+                self.emit(Inst::Out(TOP, NUMBER_DEVICE));
+                self.pop(TOP);
+            }
+            "EMIT" => {
+                self.emit(Inst::Out(TOP, CHARACTER_DEVICE));
+                self.pop(TOP);
+            }
+            "CR" => {
+                self.emit(Inst::Set(B, b'\n' as i8));
+                self.emit(Inst::Out(B, CHARACTER_DEVICE));
+            }
+            "KEY" => {
+                self.push(TOP);
+                self.emit(Inst::In(TOP, NUMBER_DEVICE));
+            }
+
+            // Control
+            "IF" => {
+                let otherwise = self.new_label();
+                self.branch_if_zero(&otherwise);
+                self.control.push((Control::If { otherwise }, line));
+            }
+            "ELSE" => match self.control.pop() {
+                Some((Control::If { otherwise }, start)) => {
+                    let end = self.new_label();
+                    self.jump(&end, None);
+                    self.label(otherwise);
+                    self.control.push((Control::Else { end }, start));
+                }
+                _ => panic!("ELSE without IF in line {}", line),
+            },
+            "THEN" => match self.control.pop() {
+                Some((Control::If { otherwise: end } | Control::Else { end }, _)) => {
+                    self.label(end)
+                }
+                _ => panic!("THEN without IF in line {}", line),
+            },
+            "BEGIN" => {
+                let start = self.new_label();
+                self.label(start.clone());
+                self.control.push((Control::Begin { start }, line));
+            }
+            "UNTIL" => match self.control.pop() {
+                Some((Control::Begin { start }, _)) => self.branch_if_zero(&start),
+                _ => panic!("UNTIL without BEGIN in line {}", line),
+            },
+            "AGAIN" => match self.control.pop() {
+                Some((Control::Begin { start }, _)) => self.jump(&start, None),
+                _ => panic!("AGAIN without BEGIN in line {}", line),
+            },
+            "WHILE" => match self.control.pop() {
+                Some((Control::Begin { start }, begin)) => {
+                    let end = self.new_label();
+                    self.branch_if_zero(&end);
+                    self.control.push((Control::While { start, end }, begin));
+                }
+                _ => panic!("WHILE without BEGIN in line {}", line),
+            },
+            "REPEAT" => match self.control.pop() {
+                Some((Control::While { start, end }, _)) => {
+                    self.jump(&start, None);
+                    self.label(end);
+                }
+                _ => panic!("REPEAT without WHILE in line {}", line),
+            },
+            "DO" => {
+                // The start is on top, the limit below it
+                self.pop(A);
+                self.emit(Inst::Addi(LOOPS, -2));
+                self.emit(Inst::Store(TOP, LOOPS));
+                self.emit(Inst::And(B, LOOPS, LOOPS));
+                self.emit(Inst::Addi(B, 1));
+                self.emit(Inst::Store(A, B));
+                self.pop(TOP);
+                let start = self.new_label();
+                self.label(start.clone());
+                self.control.push((Control::Do { start }, line));
+            }
+            "LOOP" | "+LOOP" => {
+                let Some((Control::Do { start }, _)) = self.control.pop() else {
+                    panic!("{} without DO in line {}", word, line);
+                };
+                self.emit(Inst::Load(A, LOOPS));
+                match word {
+                    "LOOP" => self.emit(Inst::Addi(A, 1)),
+                    _ => self.emit(Inst::Add(A, A, TOP)),
+                }
+                self.emit(Inst::Store(A, LOOPS));
+                self.emit(Inst::And(B, LOOPS, LOOPS));
+                self.emit(Inst::Addi(B, 1));
+                self.emit(Inst::Load(B, B));
+                // Counting down goes on while the index is not below the
+                // limit, which is the same as -index - 1 < -limit
+                if word == "+LOOP" {
+                    self.emit(Inst::Set(SCRATCH, 15));
+                    self.emit(Inst::Sft(SCRATCH, TOP, ShiftOp::ArithmeticRight, SCRATCH));
+                    self.emit(Inst::Xor(A, A, SCRATCH));
+                    self.emit(Inst::Xor(B, B, SCRATCH));
+                    self.emit(Inst::Sub(B, B, SCRATCH));
+                    self.pop(TOP);
+                }
+                self.jump(&start, Some((A, Flag::Less, B)));
+                self.emit(Inst::Addi(LOOPS, 2));
+            }
+            "I" | "J" => {
+                let depth = self
+                    .control
+                    .iter()
+                    .filter(|(c, _)| matches!(c, Control::Do { .. }))
+                    .count();
+                // `J` is the index of the loop around the innermost
+                let outer = word == "J";
+                if depth < 1 + outer as usize {
+                    panic!("{} outside of a DO loop in line {}", word, line);
+                }
+                self.push(TOP);
+                match outer {
+                    true => {
+                        self.emit(Inst::And(B, LOOPS, LOOPS));
+                        self.emit(Inst::Addi(B, 2));
+                        self.emit(Inst::Load(TOP, B));
+                    }
+                    false => self.emit(Inst::Load(TOP, LOOPS)),
+                }
+            }
+            "RECURSE" => match &self.current {
+                Some(definition) => {
+                    let label = definition.label.clone();
+                    self.call(&label);
+                }
+                None => panic!("RECURSE outside of a definition in line {}", line),
+            },
+
+            _ => match self.words.get(word) {
+                Some(label) => {
+                    let label = label.clone();
+                    self.call(&label);
+                }
+                None => self.number(word, line),
+            },
+        }
+    }
+
+    fn number(&mut self, word: &str, line: usize) {
+        let Some(value) = word
+            .parse::<i32>()
+            .ok()
+            .filter(|value| (i16::MIN as i32..=u16::MAX as i32).contains(value))
+        else {
+            panic!("Unknown word {} in line {}", word, line);
+        };
+        self.push(TOP);
+        for inst in load_constant(TOP, value as i16) {
+            self.emit(inst);
+        }
+    }
+
+    /// Applies an operation to the second item and the top, which it
+    /// replaces both with.
+    fn binary(&mut self, op: impl Fn(Reg, Reg) -> Vec<Inst>) {
+        self.pop(A);
+        for inst in op(A, TOP) {
+            self.emit(inst);
+        }
+    }
+
+    /// Replaces the top two items, or the top and 0, with whether the
+    /// comparison holds.
+    fn compare(&mut self, flag: Flag, with_zero: bool) {
+        let condition = match with_zero {
+            true => (B, flag, ZERO),
+            false => {
+                self.pop(A);
+                (A, flag, B)
+            }
+        };
+        self.emit(Inst::And(B, TOP, TOP));
+        self.emit(Inst::Set(TOP, -1));
+        let end = self.new_label();
+        self.jump(&end, Some(condition));
+        self.emit(Inst::Set(TOP, 0));
+        self.label(end);
+    }
+
+    /// Pops a flag and jumps to the label if it is 0.
+    fn branch_if_zero(&mut self, label: &str) {
+        self.emit(Inst::And(B, TOP, TOP));
+        self.pop(TOP);
+        self.jump(label, Some((B, Flag::Equal, ZERO)));
+    }
+
+    /// Pushes a register onto the data stack, after which it is the top.
+    fn push(&mut self, reg: Reg) {
+        self.emit(Inst::Addi(STACK, -1));
+        self.emit(Inst::Store(TOP, STACK));
+        if reg != TOP {
+            self.emit(Inst::And(TOP, reg, reg));
+        }
+    }
+
+    /// Moves the second item into a register. Popping into `TOP` drops the
+    /// top.
+    fn pop(&mut self, reg: Reg) {
+        self.emit(Inst::Load(reg, STACK));
+        self.emit(Inst::Addi(STACK, 1));
+    }
+
+    fn call(&mut self, label: &str) {
+        self.item(Item::SetLabel(ADDRESS, label.to_string()));
+        self.emit(Inst::Jal(ADDRESS, None));
+    }
+
+    fn jump(&mut self, label: &str, condition: Option<(Reg, Flag, Reg)>) {
+        self.item(Item::SetLabel(ADDRESS, label.to_string()));
+        self.emit(Inst::J(ADDRESS, condition));
+    }
+
+    fn new_label(&mut self) -> String {
+        self.label_count += 1;
+        format!("forth.{}", self.label_count)
+    }
+
+    fn label(&mut self, label: String) {
+        self.item(Item::Label(label));
+    }
+
+    fn emit(&mut self, inst: Inst) {
+        self.item(Item::Inst(inst));
+    }
+
+    fn item(&mut self, item: Item) {
+        match self.current {
+            Some(_) => self.definitions.push(item),
+            None => self.main.push(item),
+        }
+    }
+
+    /// Marks the code that follows as coming from this line.
+    fn source(&mut self, line: usize) {
+        let index = self.current.is_some() as usize;
+        if self.lines[index] != Some(line) {
+            self.lines[index] = Some(line);
+            self.item(Item::Source { file: 0, line });
+        }
+    }
+}
+
+/// `A * TOP` into `TOP` by shift and add, clobbering `A`, `B` and
+/// `SCRATCH`.
+fn multiply() -> Vec<Item> {
+    let mut items = vec![Item::Label("multiply".to_string())];
+    items.extend(save_loops(1));
+    items.extend([Inst::Set(B, 0), Inst::Set(LOOPS, 1)].map(Item::Inst));
+    items.push(Item::Label("multiply.loop".to_string()));
+    // Adds A if the lowest bit is set, without a jump
+    items.extend(
+        [
+            Inst::And(SCRATCH, TOP, LOOPS),
+            Inst::Sub(SCRATCH, ZERO, SCRATCH),
+            Inst::And(SCRATCH, SCRATCH, A),
+            Inst::Add(B, B, SCRATCH),
+            Inst::Sft(A, A, ShiftOp::Left, LOOPS),
+            Inst::Sft(TOP, TOP, ShiftOp::LogicalRight, LOOPS),
+        ]
+        .map(Item::Inst),
+    );
+    items.push(Item::SetLabel(ADDRESS, "multiply.loop".to_string()));
+    items.push(Item::Inst(Inst::J(
+        ADDRESS,
+        Some((TOP, Flag::Greater, ZERO)),
+    )));
+    items.push(Item::Inst(Inst::And(TOP, B, B)));
+    items.extend(restore_loops(1));
+    items.push(Item::Inst(Inst::Ret));
+    items
+}
+
+/// `A / TOP` into `TOP` and the remainder into `A`, both truncated
+/// towards 0, clobbering `B` and `SCRATCH`. Works on the magnitudes, which
+/// -32768 does not have.
+fn divide() -> Vec<Item> {
+    let mut items = vec![Item::Label("divide".to_string())];
+    // Keeps the signs of the remainder and the quotient above the saved
+    // loop stack pointer
+    items.extend(save_loops(3));
+    items.extend(
+        [
+            Inst::And(B, STACK, STACK),
+            Inst::Addi(B, 1),
+            Inst::Store(A, B),
+            Inst::Xor(LOOPS, A, TOP),
+            Inst::Addi(B, 1),
+            Inst::Store(LOOPS, B),
+            Inst::Set(LOOPS, 15),
+        ]
+        .map(Item::Inst),
+    );
+    items.extend(magnitude(A).map(Item::Inst));
+    items.extend(magnitude(TOP).map(Item::Inst));
+    items.extend([Inst::Set(B, 0), Inst::Set(LOOPS, 14)].map(Item::Inst));
+
+    // Subtracts the divisor shifted by LOOPS bits where it fits
+    items.push(Item::Label("divide.loop".to_string()));
+    items.push(Item::SetLabel(ADDRESS, "divide.skip".to_string()));
+    items.extend(
+        [
+            Inst::Sft(SCRATCH, A, ShiftOp::LogicalRight, LOOPS),
+            Inst::J(ADDRESS, Some((SCRATCH, Flag::Less, TOP))),
+            Inst::Sft(SCRATCH, TOP, ShiftOp::Left, LOOPS),
+            Inst::Sub(A, A, SCRATCH),
+            Inst::Set(SCRATCH, 1),
+            Inst::Sft(SCRATCH, SCRATCH, ShiftOp::Left, LOOPS),
+            Inst::Xor(B, B, SCRATCH),
+        ]
+        .map(Item::Inst),
+    );
+    items.push(Item::Label("divide.skip".to_string()));
+    items.push(Item::Inst(Inst::Addi(LOOPS, -1)));
+    items.push(Item::SetLabel(ADDRESS, "divide.loop".to_string()));
+    items.extend(
+        [
+            Inst::J(ADDRESS, Some((LOOPS, Flag::Greater, ZERO))),
+            Inst::J(ADDRESS, Some((LOOPS, Flag::Equal, ZERO))),
+        ]
+        .map(Item::Inst),
+    );
+
+    // The quotient is negative if the signs differ, the remainder has the
+    // sign of the dividend
+    items.extend(
+        [
+            Inst::Set(LOOPS, 15),
+            Inst::And(ADDRESS, STACK, STACK),
+            Inst::Addi(ADDRESS, 2),
+            Inst::Load(SCRATCH, ADDRESS),
+            Inst::Sft(SCRATCH, SCRATCH, ShiftOp::ArithmeticRight, LOOPS),
+            Inst::Xor(B, B, SCRATCH),
+            Inst::Sub(TOP, B, SCRATCH),
+            Inst::Addi(ADDRESS, -1),
+            Inst::Load(SCRATCH, ADDRESS),
+            Inst::Sft(SCRATCH, SCRATCH, ShiftOp::ArithmeticRight, LOOPS),
+            Inst::Xor(A, A, SCRATCH),
+            Inst::Sub(A, A, SCRATCH),
+        ]
+        .map(Item::Inst),
+    );
+    items.extend(restore_loops(3));
+    items.push(Item::Inst(Inst::Ret));
+    items
+}
+
+/// Replaces a register with its magnitude, with 15 in `LOOPS`.
+fn magnitude(reg: Reg) -> [Inst; 3] {
+    [
+        Inst::Sft(SCRATCH, reg, ShiftOp::ArithmeticRight, LOOPS),
+        Inst::Xor(reg, reg, SCRATCH),
+        Inst::Sub(reg, reg, SCRATCH),
+    ]
+}
+
+/// Saves the loop stack pointer below the data stack, in the first of
+/// `words` words the subroutine reserves there.
+fn save_loops(words: i8) -> Vec<Item> {
+    vec![
+        Item::Inst(Inst::Addi(STACK, -words)),
+        Item::Inst(Inst::Store(LOOPS, STACK)),
+    ]
+}
+
+fn restore_loops(words: i8) -> Vec<Item> {
+    vec![
+        Item::Inst(Inst::Load(LOOPS, STACK)),
+        Item::Inst(Inst::Addi(STACK, words)),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::{Bus, InputQueue, OnEmpty, Source};
+    use crate::simulator::{Cpu, Status};
+
+    /// What ran: the numbers written to the console, the text written to
+    /// the display and the top of the stack at the end.
+    #[derive(Debug, PartialEq)]
+    struct Run {
+        numbers: Vec<i16>,
+        text: String,
+        top: i16,
+    }
+
+    fn run(code: &str, input: Vec<i16>) -> Run {
+        let console = InputQueue::new(CONSOLE as usize, Source::Values(input), OnEmpty::Fault);
+        let bus = Bus::default().attach(CONSOLE as usize, Box::new(console));
+        let mut cpu = Cpu::with_bus(&ForthCompiler::new().compile(code), bus);
+        assert_eq!(cpu.run(1_000_000), Ok(Status::Halted));
+        let mut run = Run {
+            numbers: Vec::new(),
+            text: String::new(),
+            top: cpu.reg(0),
+        };
+        for &(device, value) in cpu.outputs() {
+            match device {
+                NUMBER_DEVICE => run.numbers.push(value),
+                CHARACTER_DEVICE => run.text.push(value as u8 as char),
+                _ => panic!("Output to device {}", device),
+            }
+        }
+        run
+    }
+
+    fn numbers(code: &str) -> Vec<i16> {
+        run(code, vec![]).numbers
+    }
+
+    #[test]
+    fn words_call_each_other_and_recurse() {
+        let code = "
+            : SQUARE ( n -- n*n ) DUP * ;
+            : FACT ( n -- n! ) DUP 1 > IF DUP 1- RECURSE * THEN ;
+            7 SQUARE . 5 FACT . 3 SQUARE FACT
+        ";
+        let run = run(code, vec![]);
+        assert_eq!((run.numbers, run.top), (vec![49, 120], 362880_i32 as i16));
+    }
+
+    #[test]
+    fn loops_count_and_test() {
+        assert_eq!(numbers("4 0 DO I . LOOP"), [0, 1, 2, 3]);
+        assert_eq!(numbers("10 0 DO I . 3 +LOOP"), [0, 3, 6, 9]);
+        assert_eq!(numbers("3 1 DO 2 0 DO J I + . LOOP LOOP"), [1, 2, 2, 3]);
+        assert_eq!(numbers("3 BEGIN DUP . 1- DUP 0= UNTIL"), [3, 2, 1]);
+        let run = run("0 BEGIN DUP 3 < WHILE DUP . 1+ REPEAT", vec![]);
+        assert_eq!((run.numbers, run.top), (vec![0, 1, 2], 3));
+    }
+
+    #[test]
+    fn multiplication_wraps_and_division_truncates_towards_0() {
+        assert_eq!(
+            numbers("-7 2 / . -7 2 MOD . 7 -2 /MOD . ."),
+            [-3, -1, -3, 1]
+        );
+        assert_eq!(
+            numbers("-32768 -1 * . 300 -12 * . -1 0< ."),
+            [-32768, -3600, -1]
+        );
+    }
+
+    #[test]
+    fn text_goes_to_the_display_and_key_reads_the_console() {
+        let run = run(".\" HI\" KEY EMIT CR KEY KEY + .", vec![65, 2, 3]);
+        assert_eq!(run.text, "HIA\n");
+        assert_eq!(run.numbers, [5]);
+    }
+}
@@ -1,8 +1,32 @@
 pub mod assembly_compiler;
 pub use assembly_compiler::AssemblyCompiler;
+pub mod basic_compiler;
+pub use basic_compiler::BasicCompiler;
+pub mod brainfuck_compiler;
+pub use brainfuck_compiler::BrainfuckCompiler;
 pub mod c_compiler;
-pub use c_compiler::CCompiler;
+pub use c_compiler::{CCompiler, DebugInfo, Location, OptLevel, Outcome};
+pub mod forth_compiler;
+pub use forth_compiler::ForthCompiler;
 
+/// The console, which prints the numbers written to it. The Brainfuck,
+/// BASIC and Forth front ends also read their input from it.
+pub const CONSOLE: u8 = 0;
+/// The character display, which shows the characters written to it. C
+/// programs find both devices under these names in `Programs/devices.h`.
+pub const DISPLAY: u8 = 2;
+
+/// A front end that turns source code into the words of the ROM.
 pub trait Compiler {
     fn compile(&self, raw_code: &str) -> Vec<u16>;
+
+    /// Compiles to assembly text that `AssemblyCompiler` turns into the same
+    /// words as `compile`. Comments name the file and line each piece of
+    /// code comes from.
+    fn compile_to_assembly(&self, raw_code: &str) -> String;
+
+    /// Sets the name of the compiled file, which the assembly quotes.
+    fn file_name(self, file_name: &str) -> Self
+    where
+        Self: Sized;
 }
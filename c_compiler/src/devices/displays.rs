@@ -0,0 +1,268 @@
+//! The number and text displays, which print what they show after every
+//! write.
+
+use super::{IoDevice, Latch, NoInput};
+
+/// How a seven-segment display shows the 16 bits written to it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Decoding {
+    /// In decimal, with a minus in front of negative values.
+    Signed,
+    /// In decimal, reading negative values as the ones from 32768 on.
+    Unsigned,
+    /// In hex with leading zeros.
+    Hex,
+}
+
+impl Decoding {
+    pub fn from_name(name: &str) -> Option<Decoding> {
+        match name {
+            "signed" => Some(Decoding::Signed),
+            "unsigned" => Some(Decoding::Unsigned),
+            "hex" => Some(Decoding::Hex),
+            _ => None,
+        }
+    }
+}
+
+/// Segments a to g of the digits 0 to F, a in the lowest bit.
+const DIGIT_SEGMENTS: [u8; 16] = [
+    0x3F, 0x06, 0x5B, 0x4F, 0x66, 0x6D, 0x7D, 0x07, 0x7F, 0x6F, 0x77, 0x7C, 0x39, 0x5E, 0x79, 0x71,
+];
+/// Only segment g, for a minus.
+const MINUS_SEGMENTS: u8 = 0x40;
+
+/// A row of seven-segment digits. Decimal values that do not fit show a
+/// minus on every digit, as their last digits would be a different number,
+/// and hex values show their last digits.
+pub struct SevenSegment {
+    digits: usize,
+    decoding: Decoding,
+    value: Latch,
+}
+
+impl SevenSegment {
+    pub fn new(digits: usize, decoding: Decoding) -> SevenSegment {
+        SevenSegment {
+            digits,
+            decoding,
+            value: Latch::default(),
+        }
+    }
+
+    /// The lit segments of every digit from the left.
+    fn segments(&self, value: i16) -> Vec<u8> {
+        let text = match self.decoding {
+            Decoding::Signed => value.to_string(),
+            Decoding::Unsigned => (value as u16).to_string(),
+            Decoding::Hex => format!("{:0width$X}", value as u16, width = self.digits),
+        };
+        let mut segments: Vec<u8> = text
+            .chars()
+            .map(|c| match c.to_digit(16) {
+                Some(digit) => DIGIT_SEGMENTS[digit as usize],
+                None => MINUS_SEGMENTS,
+            })
+            .collect();
+        if segments.len() > self.digits {
+            match self.decoding {
+                Decoding::Hex => drop(segments.drain(..segments.len() - self.digits)),
+                _ => return vec![MINUS_SEGMENTS; self.digits],
+            }
+        }
+        let blank = self.digits - segments.len();
+        [vec![0; blank], segments].concat()
+    }
+
+    /// Three lines of `_` and `|` drawing the lit segments.
+    fn to_text(&self, value: i16) -> String {
+        let mut lines = [String::new(), String::new(), String::new()];
+        for segments in self.segments(value) {
+            let on = |segment: u8, c: char| {
+                if segments & (1 << segment) != 0 {
+                    c
+                } else {
+                    ' '
+                }
+            };
+            for line in &mut lines {
+                if !line.is_empty() {
+                    line.push(' ');
+                }
+            }
+            lines[0].extend([' ', on(0, '_'), ' ']);
+            lines[1].extend([on(5, '|'), on(6, '_'), on(1, '|')]);
+            lines[2].extend([on(4, '|'), on(3, '_'), on(2, '|')]);
+        }
+        lines.join("\n") + "\n"
+    }
+}
+
+impl IoDevice for SevenSegment {
+    fn read(&mut self) -> Result<i16, NoInput> {
+        self.value.read()
+    }
+
+    fn write(&mut self, value: i16) {
+        print!("{}", self.to_text(value));
+        self.value.write(value);
+    }
+
+    fn reset(&mut self) {
+        self.value.reset();
+    }
+}
+
+/// A character LCD that takes text one ASCII character per write. Text
+/// wraps at the end of a line, a newline starts the next line and the
+/// display scrolls up once the last line is full. A form feed, 12, clears
+/// it. Other values show as `?`.
+pub struct Lcd {
+    columns: usize,
+    rows: usize,
+    /// The visible lines, the last one being written.
+    lines: Vec<String>,
+    value: Latch,
+}
+
+impl Lcd {
+    pub fn new(columns: usize, rows: usize) -> Lcd {
+        Lcd {
+            columns,
+            rows,
+            lines: vec![String::new()],
+            value: Latch::default(),
+        }
+    }
+
+    fn new_line(&mut self) {
+        if self.lines.len() == self.rows {
+            self.lines.remove(0);
+        }
+        self.lines.push(String::new());
+    }
+
+    /// The display in a frame of `+`, `-` and `|`.
+    fn to_text(&self) -> String {
+        let border = format!("+{}+\n", "-".repeat(self.columns));
+        let mut text = border.clone();
+        for row in 0..self.rows {
+            let line = self.lines.get(row).map_or("", |line| line.as_str());
+            text += &format!("|{:width$}|\n", line, width = self.columns);
+        }
+        text + &border
+    }
+}
+
+impl IoDevice for Lcd {
+    fn read(&mut self) -> Result<i16, NoInput> {
+        self.value.read()
+    }
+
+    fn write(&mut self, value: i16) {
+        match value {
+            10 => self.new_line(),
+            12 => self.lines = vec![String::new()],
+            _ => {
+                if self.lines.last().unwrap().len() == self.columns {
+                    self.new_line();
+                }
+                let c = match u8::try_from(value) {
+                    Ok(byte) if byte.is_ascii_graphic() || byte == b' ' => byte as char,
+                    _ => '?',
+                };
+                self.lines.last_mut().unwrap().push(c);
+            }
+        }
+        print!("{}", self.to_text());
+        self.value.write(value);
+    }
+
+    fn reset(&mut self) {
+        self.lines = vec![String::new()];
+        self.value.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What the digits show, with `-` for a minus and ` ` for a blank digit.
+    fn shown(digits: usize, decoding: Decoding, value: i16) -> String {
+        SevenSegment::new(digits, decoding)
+            .segments(value)
+            .iter()
+            .map(|&segments| match segments {
+                0 => ' ',
+                MINUS_SEGMENTS => '-',
+                _ => {
+                    let digit = DIGIT_SEGMENTS.iter().position(|&s| s == segments).unwrap();
+                    char::from_digit(digit as u32, 16)
+                        .unwrap()
+                        .to_ascii_uppercase()
+                }
+            })
+            .collect()
+    }
+
+    fn lcd(columns: usize, rows: usize, text: &str) -> Lcd {
+        let mut lcd = Lcd::new(columns, rows);
+        for c in text.chars() {
+            lcd.write(c as i16);
+        }
+        lcd
+    }
+
+    #[test]
+    fn decodings_show_values_right_aligned() {
+        assert_eq!(shown(6, Decoding::Signed, 1234), "  1234");
+        assert_eq!(shown(6, Decoding::Signed, -32768), "-32768");
+        assert_eq!(shown(6, Decoding::Unsigned, -1), " 65535");
+        assert_eq!(shown(4, Decoding::Hex, -2), "FFFE");
+        assert_eq!(shown(6, Decoding::Hex, 0x2A), "00002A");
+        assert_eq!(shown(1, Decoding::Signed, 0), "0");
+    }
+
+    #[test]
+    fn decimal_values_that_do_not_fit_show_minuses() {
+        assert_eq!(shown(4, Decoding::Signed, 12345), "----");
+        assert_eq!(shown(4, Decoding::Signed, -1234), "----");
+        assert_eq!(shown(4, Decoding::Signed, -123), "-123");
+        assert_eq!(shown(2, Decoding::Unsigned, 100), "--");
+        assert_eq!(shown(2, Decoding::Hex, 0x1234), "34");
+    }
+
+    #[test]
+    fn segments_are_drawn_in_three_lines() {
+        let display = SevenSegment::new(3, Decoding::Signed);
+        assert_eq!(
+            display.to_text(-8),
+            "         _ \n     _  |_|\n        |_|\n"
+        );
+        assert_eq!(
+            display.to_text(17),
+            "         _ \n      |   |\n      |   |\n"
+        );
+    }
+
+    #[test]
+    fn lcd_wraps_breaks_and_scrolls_lines() {
+        assert_eq!(lcd(4, 2, "ABCDEF").lines, ["ABCD", "EF"]);
+        assert_eq!(lcd(4, 2, "AB\nC").lines, ["AB", "C"]);
+        assert_eq!(lcd(4, 2, "AB\nCD\nE").lines, ["CD", "E"]);
+        assert_eq!(lcd(3, 2, "ABCDEFG").lines, ["DEF", "G"]);
+        assert_eq!(lcd(4, 1, "ABCD").lines, ["ABCD"]);
+        assert_eq!(lcd(4, 2, "AB\u{c}C").lines, ["C"]);
+        assert_eq!(lcd(4, 2, "A\u{7}\u{e9}").lines, ["A??"]);
+    }
+
+    #[test]
+    fn lcd_is_framed() {
+        let mut display = lcd(3, 2, "HI");
+        assert_eq!(display.to_text(), "+---+\n|HI |\n|   |\n+---+\n");
+        assert_eq!(display.read(), Ok('I' as i16));
+        display.reset();
+        assert_eq!(display.to_text(), "+---+\n|   |\n|   |\n+---+\n");
+    }
+}
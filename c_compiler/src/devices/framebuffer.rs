@@ -0,0 +1,337 @@
+//! The lamp screen, a grid of pixels that are either on or off.
+//!
+//! A value written to the screen is a command in its top 4 bits and an
+//! argument in its lower 12:
+//!
+//! | Value    | Command                                             |
+//! |----------|-----------------------------------------------------|
+//! | `0x0nnn` | Sets x to n                                         |
+//! | `0x1nnn` | Sets y to n                                         |
+//! | `0x2nnn` | Turns the pixel at x, y on, or off if n is 0        |
+//! | `0x3000` | Turns all pixels off                                |
+//!
+//! x counts from the left and y from the top, and pixels outside the
+//! screen are not drawn. Reading the screen gives 1 if the pixel at x, y
+//! is on and 0 if not.
+//!
+//! A frame is what the screen shows before it is cleared and when the
+//! program stops. Every frame with something drawn since the last one is
+//! printed as text and can be written to numbered PNG or PPM files.
+
+use std::{fs, io::Write, path::Path};
+
+use flate2::{write::ZlibEncoder, Compression};
+
+use super::{IoDevice, NoInput};
+
+const SET_X: u16 = 0x0;
+const SET_Y: u16 = 0x1;
+const DRAW: u16 = 0x2;
+const CLEAR: u16 = 0x3;
+
+/// Image pixels per side of a screen pixel, so small screens are visible.
+const IMAGE_SCALE: usize = 8;
+/// Colors of a lit and a dark lamp.
+const ON_COLOR: [u8; 3] = [255, 196, 92];
+const OFF_COLOR: [u8; 3] = [48, 32, 24];
+
+pub struct Framebuffer {
+    width: usize,
+    height: usize,
+    pixels: Vec<bool>,
+    x: usize,
+    y: usize,
+    /// Whether anything was drawn since the last frame.
+    changed: bool,
+    frames: usize,
+    /// Path of the image files, which get the frame number before the
+    /// extension. `.png` or `.ppm` picks the format.
+    images: Option<String>,
+}
+
+impl Framebuffer {
+    pub fn new(width: usize, height: usize) -> Framebuffer {
+        Framebuffer {
+            width,
+            height,
+            pixels: vec![false; width * height],
+            x: 0,
+            y: 0,
+            changed: false,
+            frames: 0,
+            images: None,
+        }
+    }
+
+    /// Writes every frame to an image file as well.
+    pub fn images(mut self, path: &str) -> Framebuffer {
+        let extension = Path::new(path).extension().and_then(|e| e.to_str());
+        if !matches!(extension, Some("png" | "ppm")) {
+            panic!(
+                "Frames can only be written to .png or .ppm files, not {}",
+                path
+            );
+        }
+        self.images = Some(path.to_string());
+        self
+    }
+
+    /// Where the pixel at x, y is in `pixels`, if it is on the screen.
+    fn index(&self) -> Option<usize> {
+        (self.x < self.width && self.y < self.height).then(|| self.y * self.width + self.x)
+    }
+
+    /// Prints the frame and writes its image if it shows something new.
+    fn end_frame(&mut self) {
+        if !self.changed {
+            return;
+        }
+        self.changed = false;
+        println!("Frame {}:", self.frames);
+        print!("{}", self.to_text());
+        if let Some(path) = &self.images {
+            let path = numbered(path, self.frames);
+            let image = match path.ends_with(".png") {
+                true => self.to_png(),
+                false => self.to_ppm(),
+            };
+            fs::write(&path, image).unwrap_or_else(|_| panic!("Could not write {}", path));
+        }
+        self.frames += 1;
+    }
+
+    /// The screen with `#` for lit and `.` for dark pixels.
+    fn to_text(&self) -> String {
+        let mut text = String::new();
+        for row in self.pixels.chunks(self.width) {
+            text.extend(row.iter().map(|&on| if on { '#' } else { '.' }));
+            text.push('\n');
+        }
+        text
+    }
+
+    /// The rows of the scaled image as RGB bytes.
+    fn image_rows(&self) -> Vec<Vec<u8>> {
+        let mut rows = Vec::new();
+        for row in self.pixels.chunks(self.width) {
+            let line: Vec<u8> = row
+                .iter()
+                .flat_map(|&on| {
+                    let color = if on { ON_COLOR } else { OFF_COLOR };
+                    color.repeat(IMAGE_SCALE)
+                })
+                .collect();
+            rows.extend(std::iter::repeat_n(line, IMAGE_SCALE));
+        }
+        rows
+    }
+
+    fn to_ppm(&self) -> Vec<u8> {
+        let (width, height) = (self.width * IMAGE_SCALE, self.height * IMAGE_SCALE);
+        let mut ppm = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+        ppm.extend(self.image_rows().concat());
+        ppm
+    }
+
+    fn to_png(&self) -> Vec<u8> {
+        let (width, height) = (self.width * IMAGE_SCALE, self.height * IMAGE_SCALE);
+        let mut header = Vec::new();
+        header.extend((width as u32).to_be_bytes());
+        header.extend((height as u32).to_be_bytes());
+        // 8 bit RGB, no interlacing
+        header.extend([8, 2, 0, 0, 0]);
+
+        // Every row starts with the filter type, 0 for none
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        for row in self.image_rows() {
+            encoder.write_all(&[0]).unwrap();
+            encoder.write_all(&row).unwrap();
+        }
+        let data = encoder.finish().unwrap();
+
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        png_chunk(&mut png, b"IHDR", &header);
+        png_chunk(&mut png, b"IDAT", &data);
+        png_chunk(&mut png, b"IEND", &[]);
+        png
+    }
+}
+
+fn png_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend((data.len() as u32).to_be_bytes());
+    png.extend(kind);
+    png.extend(data);
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(kind);
+    crc.update(data);
+    png.extend(crc.finalize().to_be_bytes());
+}
+
+/// Puts the frame number before the extension, as in `frame-003.png`.
+fn numbered(path: &str, frame: usize) -> String {
+    let (stem, extension) = path.rsplit_once('.').unwrap();
+    format!("{}-{:03}.{}", stem, frame, extension)
+}
+
+impl IoDevice for Framebuffer {
+    fn read(&mut self) -> Result<i16, NoInput> {
+        let on = self.index().is_some_and(|i| self.pixels[i]);
+        Ok(on as i16)
+    }
+
+    fn write(&mut self, value: i16) {
+        let argument = (value as u16 & 0x0FFF) as usize;
+        match value as u16 >> 12 {
+            SET_X => self.x = argument,
+            SET_Y => self.y = argument,
+            DRAW => {
+                if let Some(i) = self.index() {
+                    self.pixels[i] = argument != 0;
+                    self.changed = true;
+                }
+            }
+            CLEAR => {
+                self.end_frame();
+                self.pixels.fill(false);
+            }
+            _ => {}
+        }
+    }
+
+    fn reset(&mut self) {
+        self.pixels.fill(false);
+        self.x = 0;
+        self.y = 0;
+        self.changed = false;
+        self.frames = 0;
+    }
+
+    fn finish(&mut self) {
+        self.end_frame();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::ZlibDecoder;
+
+    use super::*;
+
+    /// A screen with the pixels at `points` lit through the commands.
+    fn draw(width: usize, height: usize, points: &[(u16, u16)]) -> Framebuffer {
+        let mut screen = Framebuffer::new(width, height);
+        for &(x, y) in points {
+            screen.write((SET_X << 12 | x) as i16);
+            screen.write((SET_Y << 12 | y) as i16);
+            screen.write((DRAW << 12 | 1) as i16);
+        }
+        screen
+    }
+
+    /// The RGB color at x, y of the scaled image in `pixels`.
+    fn color(pixels: &[u8], width: usize, x: usize, y: usize) -> [u8; 3] {
+        let i = (y * width * IMAGE_SCALE + x) * 3;
+        pixels[i..i + 3].try_into().unwrap()
+    }
+
+    #[test]
+    fn commands_draw_and_read_pixels() {
+        let mut screen = draw(4, 3, &[(2, 1), (0, 2), (4, 0), (0, 3)]);
+        assert_eq!(screen.to_text(), "....\n..#.\n#...\n");
+        assert_eq!(screen.read(), Ok(0));
+        screen.write((SET_X << 12 | 2) as i16);
+        screen.write((SET_Y << 12 | 1) as i16);
+        assert_eq!(screen.read(), Ok(1));
+        screen.write((DRAW << 12) as i16);
+        screen.write(0x7123);
+        assert_eq!(screen.read(), Ok(0));
+        assert_eq!(screen.to_text(), "....\n....\n#...\n");
+
+        screen.write((CLEAR << 12) as i16);
+        assert_eq!(screen.to_text(), "....\n....\n....\n");
+        assert_eq!(screen.frames, 1);
+        screen.write((CLEAR << 12) as i16);
+        assert_eq!(screen.frames, 1);
+    }
+
+    #[test]
+    fn ppm_scales_the_pixels() {
+        let screen = draw(2, 1, &[(1, 0)]);
+        let ppm = screen.to_ppm();
+        let header = format!("P6\n{} {}\n255\n", 2 * IMAGE_SCALE, IMAGE_SCALE);
+        assert!(ppm.starts_with(header.as_bytes()));
+        let pixels = &ppm[header.len()..];
+        assert_eq!(pixels.len(), 2 * IMAGE_SCALE * IMAGE_SCALE * 3);
+        let last = IMAGE_SCALE - 1;
+        assert_eq!(color(pixels, 2, 0, 0), OFF_COLOR);
+        assert_eq!(color(pixels, 2, last, last), OFF_COLOR);
+        assert_eq!(color(pixels, 2, IMAGE_SCALE, 0), ON_COLOR);
+        assert_eq!(color(pixels, 2, 2 * IMAGE_SCALE - 1, last), ON_COLOR);
+    }
+
+    #[test]
+    fn png_chunks_check_out_and_hold_the_image() {
+        let screen = draw(3, 2, &[(0, 0), (2, 1)]);
+        let png = screen.to_png();
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+
+        let mut chunks = Vec::new();
+        let mut rest = &png[8..];
+        while !rest.is_empty() {
+            let length = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            let (kind, data) = (&rest[4..8], &rest[8..8 + length]);
+            let crc = u32::from_be_bytes(rest[8 + length..12 + length].try_into().unwrap());
+            let mut hasher = crc32fast::Hasher::new();
+            hasher.update(kind);
+            hasher.update(data);
+            assert_eq!(hasher.finalize(), crc, "CRC of {:?}", kind);
+            chunks.push((kind.to_vec(), data.to_vec()));
+            rest = &rest[12 + length..];
+        }
+        let kinds: Vec<&[u8]> = chunks.iter().map(|(kind, _)| &kind[..]).collect();
+        assert_eq!(kinds, [&b"IHDR"[..], b"IDAT", b"IEND"]);
+
+        let (width, height) = (3 * IMAGE_SCALE, 2 * IMAGE_SCALE);
+        let mut header = (width as u32).to_be_bytes().to_vec();
+        header.extend((height as u32).to_be_bytes());
+        header.extend([8, 2, 0, 0, 0]);
+        assert_eq!(chunks[0].1, header);
+        assert!(chunks[2].1.is_empty());
+
+        let mut data = Vec::new();
+        ZlibDecoder::new(&chunks[1].1[..])
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(data.len(), height * (1 + width * 3));
+        let rows: Vec<&[u8]> = data.chunks(1 + width * 3).collect();
+        assert!(rows.iter().all(|row| row[0] == 0));
+        let pixels: Vec<u8> = rows.iter().flat_map(|row| &row[1..]).copied().collect();
+        assert_eq!(pixels, screen.image_rows().concat());
+        assert_eq!(color(&pixels, 3, 0, 0), ON_COLOR);
+        assert_eq!(color(&pixels, 3, IMAGE_SCALE, 0), OFF_COLOR);
+        assert_eq!(color(&pixels, 3, width - 1, height - 1), ON_COLOR);
+    }
+
+    #[test]
+    fn frames_go_to_numbered_files() {
+        let dir = std::env::temp_dir().join(format!("framebuffer-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("frame.ppm").to_string_lossy().into_owned();
+        let mut screen = draw(2, 2, &[(0, 0)]).images(&path);
+        screen.write((CLEAR << 12) as i16);
+        screen.finish();
+        screen.write((SET_X << 12 | 1) as i16);
+        screen.write((DRAW << 12 | 1) as i16);
+        screen.finish();
+
+        let first = fs::read(numbered(&path, 0)).unwrap();
+        let second = fs::read(numbered(&path, 1)).unwrap();
+        assert!(!dir.join("frame-002.ppm").exists());
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(first, draw(2, 2, &[(0, 0)]).to_ppm());
+        assert_eq!(second, draw(2, 2, &[(1, 0)]).to_ppm());
+        assert_eq!(numbered("out/frame.png", 12), "out/frame-012.png");
+    }
+}
@@ -0,0 +1,137 @@
+//! Input devices, which queue the values IN reads.
+
+use std::{
+    collections::VecDeque,
+    fs,
+    io::{self, BufRead},
+};
+
+use super::{IoDevice, NoInput};
+
+/// What an IN gets once a queue has run out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OnEmpty {
+    /// The CPU waits at the IN, and the simulator asks stdin for more
+    /// values before it runs the IN again.
+    Block,
+    /// The IN reads 0.
+    Zero,
+    /// The IN faults.
+    Fault,
+}
+
+impl OnEmpty {
+    pub fn from_name(name: &str) -> Option<OnEmpty> {
+        match name {
+            "block" => Some(OnEmpty::Block),
+            "zero" => Some(OnEmpty::Zero),
+            "fault" => Some(OnEmpty::Fault),
+            _ => None,
+        }
+    }
+}
+
+/// Where the values of a queue come from.
+pub enum Source {
+    /// Values given up front, like from the command line or a file.
+    Values(Vec<i16>),
+    /// Lines typed on stdin after a prompt with the device number. A line
+    /// can hold several values.
+    Stdin,
+}
+
+/// Reads values from its source one after another. Writes are dropped.
+pub struct InputQueue {
+    number: usize,
+    source: Source,
+    on_empty: OnEmpty,
+    queue: VecDeque<i16>,
+}
+
+impl InputQueue {
+    /// A queue for the device number `number`.
+    pub fn new(number: usize, source: Source, on_empty: OnEmpty) -> InputQueue {
+        let mut input = InputQueue {
+            number,
+            source,
+            on_empty,
+            queue: VecDeque::new(),
+        };
+        input.reset();
+        input
+    }
+
+    /// The values in a file, separated by whitespace or commas. Everything
+    /// after a `#` on a line is a comment.
+    pub fn file_values(path: &str) -> Vec<i16> {
+        let text = fs::read_to_string(path)
+            .unwrap_or_else(|_| panic!("Could not read the input file {}", path));
+        let mut values = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap();
+            for value in line
+                .split([',', ' ', '\t'])
+                .filter(|value| !value.is_empty())
+            {
+                match value.parse() {
+                    Ok(value) => values.push(value),
+                    Err(_) => panic!("Invalid input value {} in line {}", value, i + 1),
+                }
+            }
+        }
+        values
+    }
+
+    /// Asks stdin for a line of values until one parses and queues them.
+    /// Returns false at the end of stdin.
+    pub fn prompt(&mut self) -> bool {
+        loop {
+            eprint!("device {}> ", self.number);
+            let mut line = String::new();
+            match io::stdin().lock().read_line(&mut line) {
+                Ok(0) | Err(_) => return false,
+                Ok(_) => {}
+            }
+            let values: Result<Vec<i16>, _> = line
+                .split([',', ' ', '\t', '\n', '\r'])
+                .filter(|value| !value.is_empty())
+                .map(str::parse)
+                .collect();
+            match values {
+                Ok(values) if !values.is_empty() => {
+                    self.queue.extend(values);
+                    return true;
+                }
+                Ok(_) => {}
+                Err(_) => eprintln!("Expected numbers from {} to {}", i16::MIN, i16::MAX),
+            }
+        }
+    }
+}
+
+impl IoDevice for InputQueue {
+    // A blocking input leaves asking stdin to the simulator
+    fn read(&mut self) -> Result<i16, NoInput> {
+        let stdin = matches!(self.source, Source::Stdin);
+        if self.queue.is_empty() && stdin && self.on_empty != OnEmpty::Block {
+            self.prompt();
+        }
+        match self.queue.pop_front() {
+            Some(value) => Ok(value),
+            None => match self.on_empty {
+                OnEmpty::Block => Err(NoInput::Blocked),
+                OnEmpty::Zero => Ok(0),
+                OnEmpty::Fault => Err(NoInput::Exhausted),
+            },
+        }
+    }
+
+    fn write(&mut self, _: i16) {}
+
+    fn reset(&mut self) {
+        self.queue.clear();
+        if let Source::Values(values) = &self.source {
+            self.queue.extend(values);
+        }
+    }
+}
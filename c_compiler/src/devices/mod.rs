@@ -0,0 +1,475 @@
+//! IO devices behind the IN and OUT instructions of the simulator.
+//!
+//! Each of the eight device numbers is connected to an `IoDevice` on a
+//! `Bus`. Devices are picked on the command line with specs like
+//! `terminal` or `constant:5`, either one by one as `--device 2=terminal`
+//! or from a file given with `--devices`, which has a device number and a
+//! spec on each line:
+//!
+//! ```text
+//! # number printer, text display and three inputs
+//! 0 console
+//! 2 terminal
+//! 3 input:1,2,3
+//! 4 file:values.txt:fault
+//! 5 stdin
+//! ```
+//!
+//! `screen` is the lamp screen of `framebuffer.rs`, 32 by 16 pixels unless
+//! a size follows as in `screen:64x32`, and a file name after that like
+//! `screen:64x32:frames/frame.png` writes every frame to an image.
+//!
+//! The displays of `displays.rs` are `segments`, 6 seven-segment digits
+//! showing signed values unless told otherwise as in `segments:4:hex` or
+//! `segments:5:unsigned`, and `lcd`, a character LCD of 16 by 2
+//! characters unless a size follows as in `lcd:20x4`.
+//!
+//! The inputs `input` with a list of values, `file` with a file of values
+//! and `stdin` read 0 once they run out. A last `:block` makes the CPU
+//! wait for the simulator to ask stdin for more instead and `:fault` makes
+//! it fault.
+
+mod displays;
+mod framebuffer;
+mod input;
+
+use std::any::Any;
+use std::io::{self, Write};
+
+pub use displays::{Decoding, Lcd, SevenSegment};
+pub use framebuffer::Framebuffer;
+pub use input::{InputQueue, OnEmpty, Source};
+
+/// Number of device numbers an IO instruction can address.
+pub const DEVICE_COUNT: usize = 8;
+
+/// Why a device has no value for an IN.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NoInput {
+    /// The CPU has to wait at the IN.
+    Blocked,
+    /// The IN faults.
+    Exhausted,
+}
+
+/// Something connected to a device number, which IN reads and OUT writes.
+pub trait IoDevice: Any {
+    /// The value for an IN from the device.
+    fn read(&mut self) -> Result<i16, NoInput>;
+
+    /// Takes the value of an OUT to the device.
+    fn write(&mut self, value: i16);
+
+    /// Goes back to the state the device started in.
+    fn reset(&mut self) {}
+
+    /// Shows what the device has not shown yet, once the program stopped.
+    fn finish(&mut self) {}
+}
+
+/// Connects the device numbers to their devices.
+pub struct Bus {
+    devices: Vec<Box<dyn IoDevice>>,
+}
+
+/// A `Latch` on every device number, as in the hardware.
+impl Default for Bus {
+    fn default() -> Bus {
+        Bus::new(|| Box::new(Latch::default()))
+    }
+}
+
+impl Bus {
+    /// A bus with a device from `device` on every device number.
+    pub fn new(device: impl Fn() -> Box<dyn IoDevice>) -> Bus {
+        Bus {
+            devices: (0..DEVICE_COUNT).map(|_| device()).collect(),
+        }
+    }
+
+    /// Connects `device` to the device number `number`.
+    pub fn attach(mut self, number: usize, device: Box<dyn IoDevice>) -> Bus {
+        assert!(number < DEVICE_COUNT, "There is no device {}", number);
+        self.devices[number] = device;
+        self
+    }
+
+    /// The device on `number` if it is a `T`, to reach it while the CPU
+    /// runs, like to give a blocked input more values.
+    pub fn device_mut<T: IoDevice>(&mut self, number: usize) -> Option<&mut T> {
+        let device: &mut dyn Any = self.devices[number].as_mut();
+        device.downcast_mut()
+    }
+
+    pub fn read(&mut self, number: usize) -> Result<i16, NoInput> {
+        self.devices[number].read()
+    }
+
+    pub fn write(&mut self, number: usize, value: i16) {
+        self.devices[number].write(value);
+    }
+
+    pub fn reset(&mut self) {
+        for device in &mut self.devices {
+            device.reset();
+        }
+    }
+
+    pub fn finish(&mut self) {
+        for device in &mut self.devices {
+            device.finish();
+        }
+    }
+
+    /// Connects the devices of `--device number=spec` options and of the
+    /// file of a `--devices` option.
+    pub fn configure(self, args: &[String]) -> Bus {
+        let mut bus = self;
+        for (i, arg) in args.iter().enumerate() {
+            let value = || {
+                args.get(i + 1)
+                    .unwrap_or_else(|| panic!("No value after {}", arg))
+            };
+            match arg.as_str() {
+                "--device" => {
+                    let (number, spec) = value()
+                        .split_once('=')
+                        .unwrap_or_else(|| panic!("Expected number=device after --device"));
+                    let number = device_number(number);
+                    bus = bus.attach(number, device(number, spec));
+                }
+                "--devices" => {
+                    let text =
+                        std::fs::read_to_string(value()).expect("Could not read the device file");
+                    for (number, spec) in parse_config(&text) {
+                        bus = bus.attach(number, device(number, &spec));
+                    }
+                }
+                _ => {}
+            }
+        }
+        bus
+    }
+}
+
+/// Reads a device file into device numbers and specs. Everything after a
+/// `#` is a comment.
+fn parse_config(text: &str) -> Vec<(usize, String)> {
+    let mut devices = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line_number = i + 1;
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        match line.split_once(char::is_whitespace) {
+            Some((number, spec)) => devices.push((device_number(number), spec.trim().to_string())),
+            None => panic!(
+                "Expected a device number and a device in line {}",
+                line_number
+            ),
+        }
+    }
+    devices
+}
+
+fn device_number(text: &str) -> usize {
+    match text.trim().parse() {
+        Ok(number) if number < DEVICE_COUNT => number,
+        _ => panic!("Invalid device number: {}", text),
+    }
+}
+
+/// Makes the device a spec like `console` or `constant:5` describes for the
+/// device number `number`.
+pub fn device(number: usize, spec: &str) -> Box<dyn IoDevice> {
+    let (kind, argument) = spec.split_once(':').unwrap_or((spec, ""));
+    if matches!(kind, "input" | "file" | "stdin") {
+        return input_device(number, kind, argument);
+    }
+    match kind {
+        "latch" => Box::new(Latch::default()),
+        "null" => Box::new(Null),
+        "constant" => Box::new(Constant(parse_value(argument))),
+        "console" => Box::new(Console::default()),
+        "terminal" => Box::new(Terminal::default()),
+        "screen" => Box::new(screen(argument)),
+        "segments" => Box::new(segments(argument)),
+        "lcd" => {
+            let (columns, rows) = parse_size(argument, (16, 2));
+            Box::new(Lcd::new(columns, rows))
+        }
+        _ => panic!("Unknown device: {}", spec),
+    }
+}
+
+/// Makes an input with the policy of a last `:block`, `:zero` or `:fault`.
+fn input_device(number: usize, kind: &str, argument: &str) -> Box<dyn IoDevice> {
+    let (argument, on_empty) = match argument.rsplit_once(':') {
+        Some((rest, name)) if OnEmpty::from_name(name).is_some() => {
+            (rest, OnEmpty::from_name(name))
+        }
+        _ => match OnEmpty::from_name(argument) {
+            Some(on_empty) => ("", Some(on_empty)),
+            None => (argument, None),
+        },
+    };
+    let source = match kind {
+        "input" => Source::Values(
+            argument
+                .split(',')
+                .filter(|value| !value.is_empty())
+                .map(parse_value)
+                .collect(),
+        ),
+        "file" => Source::Values(InputQueue::file_values(argument)),
+        _ => Source::Stdin,
+    };
+    Box::new(InputQueue::new(
+        number,
+        source,
+        on_empty.unwrap_or(OnEmpty::Zero),
+    ))
+}
+
+/// Makes a lamp screen from a size and an image path, both optional.
+fn screen(argument: &str) -> Framebuffer {
+    let (size, images) = argument.split_once(':').unwrap_or((argument, ""));
+    let (width, height) = parse_size(size, (32, 16));
+    let screen = Framebuffer::new(width, height);
+    match images {
+        "" => screen,
+        path => screen.images(path),
+    }
+}
+
+/// Makes a seven-segment display from a digit count and a decoding, both
+/// optional.
+fn segments(argument: &str) -> SevenSegment {
+    let (digits, decoding) = argument.split_once(':').unwrap_or((argument, ""));
+    let digits = match digits {
+        "" => 6,
+        _ => digits
+            .parse()
+            .ok()
+            .filter(|&digits| digits > 0)
+            .unwrap_or_else(|| panic!("Invalid digit count: {}", digits)),
+    };
+    let decoding = match decoding {
+        "" => Decoding::Signed,
+        _ => Decoding::from_name(decoding)
+            .unwrap_or_else(|| panic!("Unknown decoding: {}", decoding)),
+    };
+    SevenSegment::new(digits, decoding)
+}
+
+/// Reads a size like `32x16`, which is `default` if it is empty.
+fn parse_size(size: &str, default: (usize, usize)) -> (usize, usize) {
+    match size {
+        "" => default,
+        _ => size
+            .split_once('x')
+            .and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?)))
+            .filter(|&(width, height)| width > 0 && height > 0)
+            .unwrap_or_else(|| panic!("Invalid size: {}", size)),
+    }
+}
+
+fn parse_value(text: &str) -> i16 {
+    text.trim()
+        .parse()
+        .unwrap_or_else(|_| panic!("Invalid device value: {}", text))
+}
+
+/// Holds the last value written, like the plain IO port of the hardware.
+#[derive(Default)]
+pub struct Latch(i16);
+
+impl IoDevice for Latch {
+    fn read(&mut self) -> Result<i16, NoInput> {
+        Ok(self.0)
+    }
+
+    fn write(&mut self, value: i16) {
+        self.0 = value;
+    }
+
+    fn reset(&mut self) {
+        self.0 = 0;
+    }
+}
+
+/// Reads 0 and drops what is written.
+pub struct Null;
+
+impl IoDevice for Null {
+    fn read(&mut self) -> Result<i16, NoInput> {
+        Ok(0)
+    }
+
+    fn write(&mut self, _: i16) {}
+}
+
+/// Always reads the same value and drops what is written.
+pub struct Constant(pub i16);
+
+impl IoDevice for Constant {
+    fn read(&mut self) -> Result<i16, NoInput> {
+        Ok(self.0)
+    }
+
+    fn write(&mut self, _: i16) {}
+}
+
+/// Prints every value written in decimal on a line of its own. Reads give
+/// back the last value written.
+#[derive(Default)]
+pub struct Console(Latch);
+
+impl IoDevice for Console {
+    fn read(&mut self) -> Result<i16, NoInput> {
+        self.0.read()
+    }
+
+    fn write(&mut self, value: i16) {
+        println!("{}", value);
+        self.0.write(value);
+    }
+
+    fn reset(&mut self) {
+        self.0.reset();
+    }
+}
+
+/// Prints every value written as an ASCII character, so text shows up as
+/// it is written. Other values print as `?`. Reads give back the last value
+/// written.
+#[derive(Default)]
+pub struct Terminal(Latch);
+
+impl IoDevice for Terminal {
+    fn read(&mut self) -> Result<i16, NoInput> {
+        self.0.read()
+    }
+
+    fn write(&mut self, value: i16) {
+        let c = match u8::try_from(value) {
+            Ok(byte) if byte.is_ascii() => byte as char,
+            _ => '?',
+        };
+        print!("{}", c);
+        io::stdout()
+            .flush()
+            .expect("Could not write to the terminal");
+        self.0.write(value);
+    }
+
+    fn reset(&mut self) {
+        self.0.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is<T: IoDevice>(device: Box<dyn IoDevice>) -> bool {
+        let device: &dyn Any = device.as_ref();
+        device.is::<T>()
+    }
+
+    /// What the first `count` INs from the device of `spec` read.
+    fn reads(spec: &str, count: usize) -> Vec<Result<i16, NoInput>> {
+        let mut device = device(1, spec);
+        (0..count).map(|_| device.read()).collect()
+    }
+
+    #[test]
+    fn config_files_give_numbers_and_specs() {
+        let text = "# Devices\n0 console\n\n  3   screen:8x4  # small\n5\tinput:-1,2:fault\n";
+        let expected = [(0, "console"), (3, "screen:8x4"), (5, "input:-1,2:fault")];
+        let expected: Vec<(usize, String)> = expected
+            .iter()
+            .map(|&(number, spec)| (number, spec.to_string()))
+            .collect();
+        assert_eq!(parse_config(text), expected);
+        assert!(parse_config("# nothing\n\n").is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid device number: 8")]
+    fn config_files_only_have_devices_0_to_7() {
+        parse_config("8 console\n");
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected a device number and a device in line 2")]
+    fn config_lines_need_a_spec() {
+        parse_config("0 console\n2\n");
+    }
+
+    #[test]
+    fn inputs_read_their_values_and_then_follow_their_policy() {
+        use NoInput::{Blocked, Exhausted};
+        assert_eq!(
+            reads("input:-1,2:fault", 3),
+            [Ok(-1), Ok(2), Err(Exhausted)]
+        );
+        assert_eq!(reads("input:7", 2), [Ok(7), Ok(0)]);
+        assert_eq!(reads("input:3,:zero", 2), [Ok(3), Ok(0)]);
+        assert_eq!(reads("input:3:block", 2), [Ok(3), Err(Blocked)]);
+        assert_eq!(reads("input:block", 1), [Err(Blocked)]);
+        assert_eq!(reads("input", 1), [Ok(0)]);
+
+        let path = std::env::temp_dir().join(format!("devices-{}.txt", std::process::id()));
+        std::fs::write(&path, "1, 2 # the first two\n\n-3\n").unwrap();
+        let spec = format!("file:{}:fault", path.display());
+        let values = reads(&spec, 4);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(values, [Ok(1), Ok(2), Ok(-3), Err(Exhausted)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid device value: 40000")]
+    fn input_values_have_16_bits() {
+        device(0, "input:1,40000");
+    }
+
+    #[test]
+    fn specs_pick_the_device() {
+        assert!(is::<Latch>(device(0, "latch")));
+        assert!(is::<Null>(device(0, "null")));
+        assert!(is::<Console>(device(0, "console")));
+        assert!(is::<Terminal>(device(0, "terminal")));
+        assert!(is::<Framebuffer>(device(0, "screen:8x4")));
+        assert!(is::<SevenSegment>(device(0, "segments:4:hex")));
+        assert!(is::<Lcd>(device(0, "lcd")));
+        assert!(is::<InputQueue>(device(0, "stdin:zero")));
+        assert_eq!(device(0, "constant:-5").read(), Ok(-5));
+    }
+
+    #[test]
+    fn sizes_are_width_x_height() {
+        assert_eq!(parse_size("20x4", (16, 2)), (20, 4));
+        assert_eq!(parse_size("", (16, 2)), (16, 2));
+        assert_eq!(parse_size("1x1", (16, 2)), (1, 1));
+    }
+
+    #[test]
+    fn bad_specs_are_rejected() {
+        let specs = [
+            "lcd:20x0",
+            "lcd:20",
+            "screen:x4",
+            "screen:8x8:frame.gif",
+            "segments:0",
+            "segments:4:octal",
+            "constant:x",
+            "blinkenlights",
+        ];
+        for spec in specs {
+            let result = std::panic::catch_unwind(|| device(0, spec));
+            assert!(result.is_err(), "{} was accepted", spec);
+        }
+    }
+}
@@ -0,0 +1,153 @@
+//! Differential testing of the C compiler. Every program is compiled at
+//! each optimization level and run on the simulator, and its outputs and
+//! the return value of main have to match what the reference interpreter
+//! computes.
+
+use std::{
+    any::Any,
+    fs,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use crate::compiler::{CCompiler, Compiler, OptLevel, Outcome};
+use crate::simulator::{self, Limits};
+
+const OPT_FLAGS: [&str; 3] = ["-O0", "-O1", "-Os"];
+
+/// Keeps a program that never halts from holding up the whole run.
+const LIMITS: Limits = Limits {
+    steps: Some(10_000_000),
+    timeout: Some(Duration::from_secs(10)),
+    detect_stuck: true,
+};
+
+/// Tests the given C files and the C files in the given directories and
+/// prints a line for each. Returns whether all of them passed.
+pub fn run(paths: &[String], include_paths: &[String]) -> bool {
+    let mut files = Vec::new();
+    for path in paths {
+        let path = PathBuf::from(path);
+        if path.is_dir() {
+            let entries = fs::read_dir(&path).expect("Could not read directory");
+            let mut sources: Vec<PathBuf> = entries
+                .map(|entry| entry.expect("Could not read directory").path())
+                .filter(|file| file.extension().is_some_and(|e| e == "c"))
+                .collect();
+            sources.sort();
+            files.extend(sources);
+        } else {
+            files.push(path);
+        }
+    }
+
+    let mut failed = 0;
+    for file in &files {
+        match test(file, include_paths) {
+            Ok(()) => println!("ok   {}", file.display()),
+            Err(error) => {
+                println!("FAIL {}: {}", file.display(), error);
+                failed += 1;
+            }
+        }
+    }
+
+    println!("{} passed, {} failed", files.len() - failed, failed);
+    failed == 0
+}
+
+fn test(file: &Path, include_paths: &[String]) -> Result<(), String> {
+    let source = fs::read_to_string(file).map_err(|error| error.to_string())?;
+    let compiler = |opt_level| {
+        let mut compiler = CCompiler::new(opt_level).file_name(&file.to_string_lossy());
+        for path in include_paths {
+            compiler = compiler.include_path(path);
+        }
+        compiler
+    };
+
+    let expected = catch(|| compiler(OptLevel::O0).interpret(&source))
+        .map_err(|error| format!("interpreter: {}", error))?;
+    for flag in OPT_FLAGS {
+        let opt_level = OptLevel::from_flag(flag).unwrap();
+        let run = catch(|| simulator::run(&compiler(opt_level).compile(&source), LIMITS))
+            .and_then(|run| run.map_err(|stop| stop.to_string()))
+            .map_err(|error| format!("{}: {}", flag, error))?;
+        let actual = Outcome {
+            outputs: run.outputs,
+            result: run.result,
+        };
+        if let Some(difference) = difference(&expected, &actual) {
+            return Err(format!("{}: {}", flag, difference));
+        }
+    }
+    Ok(())
+}
+
+/// Describes the first way the simulated run differs from the expected one.
+fn difference(expected: &Outcome, actual: &Outcome) -> Option<String> {
+    let outputs = expected.outputs.iter().zip(&actual.outputs).enumerate();
+    for (i, ((expected_device, expected), (device, value))) in outputs {
+        if (expected_device, expected) != (device, value) {
+            return Some(format!(
+                "output {} is {} on device {} instead of {} on device {}",
+                i, value, device, expected, expected_device
+            ));
+        }
+    }
+    if expected.outputs.len() != actual.outputs.len() {
+        return Some(format!(
+            "{} outputs instead of {}",
+            actual.outputs.len(),
+            expected.outputs.len()
+        ));
+    }
+    if expected.result != actual.result {
+        return Some(format!(
+            "returned {} instead of {}",
+            actual.result, expected.result
+        ));
+    }
+    None
+}
+
+/// Runs `f`, turning a panic into its message, so that a program the
+/// compiler or the interpreter rejects fails its test instead of ending the
+/// run. Only the panic of `f` goes unreported; other threads, like other
+/// tests under `cargo test`, still get the hook they had.
+fn catch<T>(f: impl FnOnce() -> T) -> Result<T, String> {
+    let previous = Arc::new(panic::take_hook());
+    let hook = Arc::clone(&previous);
+    let quiet = thread::current().id();
+    panic::set_hook(Box::new(move |info| {
+        if thread::current().id() != quiet {
+            hook(info);
+        }
+    }));
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    // Dropping the quiet hook leaves `previous` with a single owner again
+    drop(panic::take_hook());
+    panic::set_hook(Arc::into_inner(previous).expect("The quiet hook is gone"));
+    result.map_err(|payload| panic_message(&*payload))
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    match payload.downcast_ref::<String>() {
+        Some(message) => message.clone(),
+        None => payload
+            .downcast_ref::<&str>()
+            .map_or("panic".to_string(), |message| message.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_programs_match_the_interpreter() {
+        let tests = concat!(env!("CARGO_MANIFEST_DIR"), "/Programs/tests");
+        assert!(super::run(&[tests.to_string()], &[]));
+    }
+}
@@ -1,31 +1,139 @@
-use std::{env, fs::read_to_string};
+use std::{
+    env,
+    fs::{read_to_string, write},
+    process,
+    time::Duration,
+};
 
-use compiler::Compiler;
+use compiler::{Compiler, OptLevel};
 
 mod compiler;
+mod devices;
+mod differential;
+#[allow(dead_code)]
 mod schematic;
 mod simulator;
 
 fn main() {
-    let compiler: Box<dyn Compiler> = match env::args().any(|arg| arg == "--asm") {
-        true => Box::new(compiler::AssemblyCompiler),
-        false => Box::new(compiler::CCompiler),
+    let args: Vec<String> = env::args().collect();
+
+    // --diff runs the C files and directories after it on the simulator and
+    // the reference interpreter and fails if they disagree
+    if let Some(i) = args.iter().position(|arg| arg == "--diff") {
+        let passed = differential::run(&args[i + 1..], &include_paths(&args[..i]));
+        process::exit(if passed { 0 } else { 1 });
+    }
+
+    let opt_level = env::args()
+        .filter_map(|arg| OptLevel::from_flag(&arg))
+        .next_back()
+        .unwrap_or(OptLevel::O1);
+
+    let source_file = env::args().next_back().expect("No source file specified");
+    let raw_assembly = read_to_string(&source_file).expect("Could not read file");
+
+    let mut c_compiler = compiler::CCompiler::new(opt_level).file_name(&source_file);
+    for path in include_paths(&args) {
+        c_compiler = c_compiler.include_path(path);
+    }
+
+    // --asm assembles, --bf compiles Brainfuck, --basic Tiny BASIC and
+    // --forth Forth instead of C
+    let flag = |flag: &str| env::args().any(|arg| arg == flag);
+    let front_end: Option<Box<dyn Compiler>> = if flag("--asm") {
+        Some(Box::new(compiler::AssemblyCompiler))
+    } else if flag("--bf") {
+        Some(Box::new(compiler::BrainfuckCompiler::new().file_name(&source_file)))
+    } else if flag("--basic") {
+        Some(Box::new(compiler::BasicCompiler::new(opt_level).file_name(&source_file)))
+    } else if flag("--forth") {
+        Some(Box::new(compiler::ForthCompiler::new().file_name(&source_file)))
+    } else {
+        None
     };
 
-    let source_file = env::args().last().expect("No source file specified");
-    let raw_assembly = read_to_string(source_file).expect("Could not read file");
+    // -S prints the assembly generated from the file instead of running it
+    if flag("-S") {
+        let compiler: &dyn Compiler = front_end.as_deref().unwrap_or(&c_compiler);
+        print!("{}", compiler.compile_to_assembly(&raw_assembly));
+        return;
+    }
+
+    // C code is compiled with debug information, which -g also writes to
+    // a .dbg file next to the source
+    let (hex_code, debug_info) = if let Some(front_end) = &front_end {
+        (front_end.compile(&raw_assembly), None)
+    } else {
+        let (hex_code, debug_info) = c_compiler.compile_with_debug_info(&raw_assembly);
+        if flag("-g") {
+            write(format!("{}.dbg", source_file), debug_info.to_string())
+                .expect("Could not write debug information");
+        }
+        (hex_code, Some(debug_info))
+    };
 
-    let hex_code = compiler.compile(&raw_assembly);
+    // Code size matters most at -Os, which is for fitting programs into
+    // the ROM. --rom-size gives the number of instructions it holds
+    let rom_size = option_value(&args, "--rom-size");
+    if opt_level == OptLevel::Os || rom_size.is_some() {
+        let capacity = rom_size.unwrap_or(schematic::DEFAULT_ROM_CAPACITY);
+        eprintln!("{}", schematic::rom_usage(hex_code.len(), capacity));
+    }
 
     // let binary = hex_code_to_binary(&hex_code);
     // println!("{}", binary);
 
     // schematic::create_rom_schematic(&hex_code);
 
-    simulator::simulate(&hex_code);
+    // --max-steps and --timeout (in seconds) stop programs that run too
+    // long, and --no-stuck keeps endless loops running
+    let limits = simulator::Limits {
+        steps: option_value(&args, "--max-steps"),
+        timeout: option_value(&args, "--timeout").map(Duration::from_secs_f64),
+        detect_stuck: !flag("--no-stuck"),
+    };
+
+    // Every device prints the numbers written to it unless --device or
+    // --devices connects another one
+    let bus = devices::Bus::new(|| Box::new(devices::Console::default())).configure(&args);
+
+    if let Err(stop) = simulator::simulate(&hex_code, bus, debug_info.as_ref(), limits) {
+        eprintln!("{}", stop);
+        process::exit(1);
+    }
+}
+
+/// The value after an option like --max-steps, which has to parse.
+fn option_value<T: std::str::FromStr>(args: &[String], option: &str) -> Option<T> {
+    let i = args.iter().position(|arg| arg == option)?;
+    let value = args
+        .get(i + 1)
+        .unwrap_or_else(|| panic!("No value after {}", option));
+    Some(
+        value
+            .parse()
+            .unwrap_or_else(|_| panic!("Invalid value for {}: {}", option, value)),
+    )
+}
+
+/// Include paths are given as -Ipath or -I path.
+fn include_paths(args: &[String]) -> Vec<String> {
+    let mut paths = Vec::new();
+    for (i, arg) in args.iter().enumerate() {
+        match arg.strip_prefix("-I") {
+            Some("") => {
+                let path = args.get(i + 1).expect("No include path after -I");
+                paths.push(path.clone());
+            }
+            Some(path) => paths.push(path.to_string()),
+            None => {}
+        }
+    }
+    paths
 }
 
-fn hex_code_to_binary(hex_code: &Vec<u16>) -> String {
+#[allow(dead_code)]
+fn hex_code_to_binary(hex_code: &[u16]) -> String {
     hex_code
         .iter()
         .map(|code| format!("{:#018b}", code))
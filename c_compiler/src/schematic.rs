@@ -9,6 +9,28 @@ const BARREL_OFFSET_X: i16 = 4;
 const BARREL_OFFSET_Y: i16 = 2;
 const BARREL_OFFSET_Z: i16 = 3;
 
+/// Every instruction takes one barrel per hex digit.
+pub const BARRELS_PER_INSTRUCTION: usize = 4;
+/// Instructions the ROM is assumed to hold when `--rom-size` does not give
+/// the size of the one built in the world.
+pub const DEFAULT_ROM_CAPACITY: usize = 1024;
+
+/// Tells how much of a ROM for `capacity` instructions a program of this
+/// many instructions takes.
+pub fn rom_usage(instructions: usize, capacity: usize) -> String {
+    let mut usage = format!(
+        "{} of {} instructions in ROM ({:.1}%), {} barrels",
+        instructions,
+        capacity,
+        100.0 * instructions as f64 / capacity as f64,
+        instructions * BARRELS_PER_INSTRUCTION
+    );
+    if instructions > capacity {
+        usage += &format!(", {} instructions too many", instructions - capacity);
+    }
+    usage
+}
+
 pub fn create_rom_schematic(hex_code: &Vec<u16>) {
     let lines: i16 = hex_code.len() as i16;
 
@@ -16,11 +38,11 @@ pub fn create_rom_schematic(hex_code: &Vec<u16>) {
     let length: i16 = (lines / 16 + 1) * BARREL_OFFSET_Z - BARREL_OFFSET_Z + 1;
     let height: i16 = 4 * BARREL_OFFSET_Y - BARREL_OFFSET_Y + 1;
 
-    let mut block_entities = NbtList::with_capacity(lines as usize * 4);
+    let mut block_entities = NbtList::with_capacity(lines as usize * BARRELS_PER_INSTRUCTION);
     let mut data = vec![1i8; (width * height * length) as usize];
 
     let mut y: i16 = 0;
-    for part in 0..4 {
+    for part in 0..BARRELS_PER_INSTRUCTION {
         let mut x: i16 = 0;
         let mut z: i16 = 0;
         for instruction in hex_code {
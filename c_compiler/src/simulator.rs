@@ -1,3 +1,11 @@
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+use crate::compiler::{DebugInfo, Location};
+use crate::devices::{Bus, InputQueue, NoInput};
+
 // OP Codes
 const NOP  : u16 = 0x0 << 12;
 const LOAD : u16 = 0x1 << 12;
@@ -20,7 +28,6 @@ const HALT : u16 = 0xF << 12;
 const ROM_SIZE: usize = 1 << 16;
 const MEMORY_SIZE: usize = 1 << 16;
 const REG_COUNT: usize = 8;
-const IO_DEVICES: usize = 8;
 
 // Instruction parts
 const TARGET_MSK: u16 = 0b1110_0000_0000;
@@ -47,132 +54,674 @@ const DATA_POS  : u8 = 9;
 const DEV_POS   : u8 = 6;
 const R_W_POS   : u8 = 5;
 
-pub fn simulate(hex_code: &Vec<u16>) {
+/// What a program did until it halted.
+pub struct Run {
+    /// Device and value of every OUT instruction, in order.
+    pub outputs: Vec<(u8, i16)>,
+    /// x0, which holds the return value of main for C programs.
+    pub result: i16,
+}
 
-    let mut rom: [u16; ROM_SIZE] = [0; ROM_SIZE];
-    let mut memory: [i16; MEMORY_SIZE] = [0; MEMORY_SIZE];
-    let mut reg: [i16; REG_COUNT] = [0; REG_COUNT];
-    let mut io: [i16; IO_DEVICES] = [0; IO_DEVICES];
+/// Whether the CPU can go on after a step.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    Running,
+    Halted,
+    /// Waiting at an IN for a device that has no input yet.
+    Blocked,
+}
 
-    let mut pc: usize = 0;
-    let mut sp: usize = 0;
+/// What made the CPU stop before a HALT.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FaultKind {
+    /// `ret` with nothing on the return stack.
+    StackUnderflow,
+    /// `jal` with the return stack at the end of the memory.
+    StackOverflow,
+    /// The next instruction would be past the end of the ROM.
+    InvalidPc,
+    /// `sft` with the unused shift op 3.
+    InvalidShiftOp,
+    /// `in` from a device that has run out of input.
+    InputExhausted,
+}
 
-    for (i, line) in hex_code.iter().enumerate() {
-        rom[i] = *line;
+/// A fault with the address and word of the instruction that caused it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fault {
+    pub kind: FaultKind,
+    pub pc: usize,
+    pub instr: u16,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            FaultKind::StackUnderflow => "Stack underflow",
+            FaultKind::StackOverflow => "Stack overflow",
+            FaultKind::InvalidPc => "Invalid PC",
+            FaultKind::InvalidShiftOp => "Invalid shift op",
+            FaultKind::InputExhausted => "Input exhausted",
+        };
+        write!(f, "{} at pc {} ({:#06x})", kind, self.pc, self.instr)
     }
-    let rom = rom;
+}
 
-    loop {
-        let instr = rom[pc];
+/// How long a program may run before it is stopped.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Instructions to run at most.
+    pub steps: Option<u64>,
+    /// Wall-clock time to run at most. It is checked between steps, so an
+    /// IN waiting for a line on stdin is not cut short.
+    pub timeout: Option<Duration>,
+    /// Whether to stop a program that is back in a state it was in before
+    /// without any IO in between, so it would loop forever.
+    pub detect_stuck: bool,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            steps: None,
+            timeout: None,
+            detect_stuck: true,
+        }
+    }
+}
+
+/// Why a program stopped without a HALT.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stop {
+    Fault(Fault),
+    /// The step limit ran out before the instruction at `pc`.
+    StepLimit { pc: usize },
+    /// The timeout ran out before the instruction at `pc`.
+    Timeout { pc: usize },
+    /// The program would loop forever from the instruction at `pc` on.
+    Stuck { pc: usize },
+    /// The IN at `pc` waits for input that cannot come.
+    Blocked { pc: usize },
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stop::Fault(fault) => write!(f, "{}", fault),
+            Stop::StepLimit { pc } => write!(f, "Step limit reached at pc {}", pc),
+            Stop::Timeout { pc } => write!(f, "Timed out at pc {}", pc),
+            Stop::Stuck { pc } => write!(f, "Stuck in an endless loop at pc {}", pc),
+            Stop::Blocked { pc } => write!(f, "Waiting for input at pc {}", pc),
+        }
+    }
+}
+
+/// Checks a CPU against `Limits` before each step.
+///
+/// Endless loops are found by saving the machine state after 1, 2, 4, 8 ...
+/// steps without IO and comparing the state before every step against the
+/// last one saved, which finds a loop of any length within twice the steps
+/// it takes to enter and go around it. A jump to itself is found on the
+/// step after the next save.
+pub struct Watchdog {
+    limits: Limits,
+    start: Instant,
+    /// Steps since the last IO instruction.
+    quiet_steps: u64,
+    saved: Option<State>,
+}
+
+#[derive(PartialEq)]
+struct State {
+    pc: usize,
+    sp: usize,
+    reg: [i16; REG_COUNT],
+    memory: Vec<i16>,
+}
+
+/// Steps between looking at the clock, which is slow compared to a step.
+const CLOCK_INTERVAL: u64 = 1 << 12;
+
+impl Watchdog {
+    pub fn new(limits: Limits) -> Watchdog {
+        Watchdog {
+            limits,
+            start: Instant::now(),
+            quiet_steps: 0,
+            saved: None,
+        }
+    }
+
+    /// Returns why the CPU has to stop before its next step, if it has to.
+    pub fn check(&mut self, cpu: &Cpu) -> Option<Stop> {
+        let pc = cpu.pc;
+        if self.limits.steps.is_some_and(|steps| cpu.steps >= steps) {
+            return Some(Stop::StepLimit { pc });
+        }
+        if let Some(timeout) = self.limits.timeout {
+            if cpu.steps.is_multiple_of(CLOCK_INTERVAL) && self.start.elapsed() >= timeout {
+                return Some(Stop::Timeout { pc });
+            }
+        }
+        if self.limits.detect_stuck && !cpu.halted {
+            if cpu.rom[pc] & 0xF000 == IO {
+                self.quiet_steps = 0;
+                self.saved = None;
+                return None;
+            }
+            // Registers differ between most states, so the memory is only
+            // compared when everything else is the same
+            if let Some(saved) = &self.saved {
+                if saved.pc == pc
+                    && saved.sp == cpu.sp
+                    && saved.reg == cpu.reg
+                    && saved.memory == cpu.memory
+                {
+                    return Some(Stop::Stuck { pc });
+                }
+            }
+            self.quiet_steps += 1;
+            if self.quiet_steps.is_power_of_two() {
+                self.saved = Some(State {
+                    pc,
+                    sp: cpu.sp,
+                    reg: cpu.reg,
+                    memory: cpu.memory.clone(),
+                });
+            }
+        }
+        None
+    }
+}
+
+/// A BEPL-T3X16 CPU with its ROM, data memory and the bus of its IO
+/// devices, which runs one instruction per `step`.
+pub struct Cpu {
+    rom: Vec<u16>,
+    memory: Vec<i16>,
+    reg: [i16; REG_COUNT],
+    bus: Bus,
+    pc: usize,
+    /// Where `jal` stores the next return address.
+    sp: usize,
+    halted: bool,
+    /// Whether the last step waited at an IN.
+    blocked: bool,
+    steps: u64,
+    /// Device and value of every OUT instruction, in order.
+    outputs: Vec<(u8, i16)>,
+}
+
+// Front ends use the accessors the simulator itself does not need, and the
+// tests make sure each of them is used
+#[cfg_attr(not(test), allow(dead_code))]
+impl Cpu {
+    /// A CPU with the code at the start of its ROM, about to run it, and
+    /// a `Latch` on every device number.
+    pub fn new(hex_code: &[u16]) -> Cpu {
+        Cpu::with_bus(hex_code, Bus::default())
+    }
+
+    /// A CPU with the code at the start of its ROM and the given devices.
+    pub fn with_bus(hex_code: &[u16], bus: Bus) -> Cpu {
+        let mut rom = vec![0; ROM_SIZE];
+        rom[..hex_code.len()].copy_from_slice(hex_code);
+        Cpu {
+            rom,
+            memory: vec![0; MEMORY_SIZE],
+            reg: [0; REG_COUNT],
+            bus,
+            pc: 0,
+            sp: 0,
+            halted: false,
+            blocked: false,
+            steps: 0,
+            outputs: Vec::new(),
+        }
+    }
+
+    /// Clears everything but the ROM, as after powering on.
+    pub fn reset(&mut self) {
+        self.memory.fill(0);
+        self.reg = [0; REG_COUNT];
+        self.bus.reset();
+        self.pc = 0;
+        self.sp = 0;
+        self.halted = false;
+        self.blocked = false;
+        self.steps = 0;
+        self.outputs.clear();
+    }
+
+    /// Runs until the CPU halts, blocks, faults or has taken `limit` more
+    /// steps.
+    pub fn run(&mut self, limit: u64) -> Result<Status, Fault> {
+        for _ in 0..limit {
+            let status = self.step()?;
+            if status != Status::Running {
+                return Ok(status);
+            }
+        }
+        Ok(self.status())
+    }
+
+    /// Runs the instruction at `pc`. A halted CPU stays at its HALT, and an
+    /// IN that blocks or an instruction that faults leaves `pc` at it.
+    /// Arithmetic wraps around and addresses are taken modulo 2^16, as in
+    /// the hardware.
+    pub fn step(&mut self) -> Result<Status, Fault> {
+        if self.halted {
+            return Ok(Status::Halted);
+        }
+        self.steps += 1;
+        self.blocked = false;
+        let instr = self.rom[self.pc];
+        let fault = |kind| Fault {
+            kind,
+            pc: self.pc,
+            instr,
+        };
+        let mut next_pc = self.pc + 1;
         match instr & 0xF000 {
             NOP => (),
+            // The address is the value of the register, read as unsigned
             LOAD => {
-                reg[((instr & TARGET_MSK) >> TARGET_POS) as usize] = memory[((instr & ADDR_MSK) >> ADDR_POS) as usize];
+                self.reg[((instr & TARGET_MSK) >> TARGET_POS) as usize] = self.memory[self.reg[((instr & ADDR_MSK) >> ADDR_POS) as usize] as u16 as usize];
             },
             STORE => {
-                memory[((instr & ADDR_MSK) >> ADDR_POS) as usize] = reg[((instr & REG_A_MSK) >> REG_A_POS) as usize];
+                self.memory[self.reg[((instr & ADDR_MSK) >> ADDR_POS) as usize] as u16 as usize] = self.reg[((instr & REG_A_MSK) >> REG_A_POS) as usize];
             },
+            // Arithmetic wraps around like in the hardware
             ADD => {
-                let sum = reg[((instr & REG_A_MSK) >> REG_A_POS) as usize] + reg[((instr & REG_B_MSK) >> REG_B_POS) as usize];
-                reg[((instr & TARGET_MSK) >> TARGET_POS) as usize] = sum;
+                let sum = self.reg[((instr & REG_A_MSK) >> REG_A_POS) as usize].wrapping_add(self.reg[((instr & REG_B_MSK) >> REG_B_POS) as usize]);
+                self.reg[((instr & TARGET_MSK) >> TARGET_POS) as usize] = sum;
             },
             ADDI => {
                 let imm = ((instr & IMM_MSK) >> IMM_POS) as i8;
-                reg[((instr & TARGET_MSK) >> TARGET_POS) as usize] += imm as i16;
+                let target = ((instr & TARGET_MSK) >> TARGET_POS) as usize;
+                self.reg[target] = self.reg[target].wrapping_add(imm as i16);
             },
             SUB => {
-                let dif = reg[((instr & REG_A_MSK) >> REG_A_POS) as usize] - reg[((instr & REG_B_MSK) >> REG_B_POS) as usize];
-                reg[((instr & TARGET_MSK) >> TARGET_POS) as usize] = dif;
+                let dif = self.reg[((instr & REG_A_MSK) >> REG_A_POS) as usize].wrapping_sub(self.reg[((instr & REG_B_MSK) >> REG_B_POS) as usize]);
+                self.reg[((instr & TARGET_MSK) >> TARGET_POS) as usize] = dif;
             },
             AND => {
-                let and = reg[((instr & REG_A_MSK) >> REG_A_POS) as usize] & reg[((instr & REG_B_MSK) >> REG_B_POS) as usize];
-                reg[((instr & TARGET_MSK) >> TARGET_POS) as usize] = and;
+                let and = self.reg[((instr & REG_A_MSK) >> REG_A_POS) as usize] & self.reg[((instr & REG_B_MSK) >> REG_B_POS) as usize];
+                self.reg[((instr & TARGET_MSK) >> TARGET_POS) as usize] = and;
             },
             XOR => {
-                let xor = reg[((instr & REG_A_MSK) >> REG_A_POS) as usize] ^ reg[((instr & REG_B_MSK) >> REG_B_POS) as usize];
-                reg[((instr & TARGET_MSK) >> TARGET_POS) as usize] = xor;
+                let xor = self.reg[((instr & REG_A_MSK) >> REG_A_POS) as usize] ^ self.reg[((instr & REG_B_MSK) >> REG_B_POS) as usize];
+                self.reg[((instr & TARGET_MSK) >> TARGET_POS) as usize] = xor;
             },
             J => {
                 let flag = (instr & FLAG_MSK) >> FLAG_POS;
                 if flag == 0 {
-                    pc = (reg[((instr & TARGET_MSK) >> TARGET_POS) as usize] - 1) as usize;
+                    next_pc = self.reg[((instr & TARGET_MSK) >> TARGET_POS) as usize] as u16 as usize;
                 } else {
-                    let a = reg[((instr & REG_A_MSK) >> REG_A_POS) as usize];
-                    let b = reg[((instr & REG_B_MSK) >> REG_B_POS) as usize];
-                    if flag == 2 && a == b {
-                        pc = (reg[((instr & TARGET_MSK) >> TARGET_POS) as usize] - 1) as usize;
-                    } else if flag == 1 && a < b {
-                        pc = (reg[((instr & TARGET_MSK) >> TARGET_POS) as usize] - 1) as usize;
-                    } else if flag == 3 && a > b {
-                        pc = (reg[((instr & TARGET_MSK) >> TARGET_POS) as usize] - 1) as usize;
+                    let a = self.reg[((instr & REG_A_MSK) >> REG_A_POS) as usize];
+                    let b = self.reg[((instr & REG_B_MSK) >> REG_B_POS) as usize];
+                    if (flag == 2 && a == b) || (flag == 1 && a < b) || (flag == 3 && a > b) {
+                        next_pc = self.reg[((instr & TARGET_MSK) >> TARGET_POS) as usize] as u16 as usize;
                     }
                 }
             },
             JAL => {
                 let flag = (instr & FLAG_MSK) >> FLAG_POS;
                 if flag == 0 {
-                    memory[sp] = pc as i16;
-                    sp += 1;
-                    pc = (reg[((instr & TARGET_MSK) >> TARGET_POS) as usize] - 1) as usize;
+                    if self.sp >= MEMORY_SIZE {
+                        return Err(fault(FaultKind::StackOverflow));
+                    }
+                    self.memory[self.sp] = self.pc as i16;
+                    self.sp += 1;
+                    next_pc = self.reg[((instr & TARGET_MSK) >> TARGET_POS) as usize] as u16 as usize;
                 } else {
-                    let a = reg[((instr & REG_A_MSK) >> REG_A_POS) as usize];
-                    let b = reg[((instr & REG_B_MSK) >> REG_B_POS) as usize];
-                    if flag == 2 && a == b {
-                        memory[sp] = pc as i16;
-                        sp += 1;
-                        pc = (reg[((instr & TARGET_MSK) >> TARGET_POS) as usize] - 1) as usize;
-                    } else if flag == 1 && a < b {
-                        memory[sp] = pc as i16;
-                        sp += 1;
-                        pc = (reg[((instr & TARGET_MSK) >> TARGET_POS) as usize] - 1) as usize;
-                    } else if flag == 3 && a > b {
-                        memory[sp] = pc as i16;
-                        sp += 1;
-                        pc = (reg[((instr & TARGET_MSK) >> TARGET_POS) as usize] - 1) as usize;
+                    let a = self.reg[((instr & REG_A_MSK) >> REG_A_POS) as usize];
+                    let b = self.reg[((instr & REG_B_MSK) >> REG_B_POS) as usize];
+                    if (flag == 2 && a == b) || (flag == 1 && a < b) || (flag == 3 && a > b) {
+                        if self.sp >= MEMORY_SIZE {
+                            return Err(fault(FaultKind::StackOverflow));
+                        }
+                        self.memory[self.sp] = self.pc as i16;
+                        self.sp += 1;
+                        next_pc = self.reg[((instr & TARGET_MSK) >> TARGET_POS) as usize] as u16 as usize;
                     }
                 }
             },
             SSP => {
-                sp = reg[((instr & REG_A_MSK) >> REG_A_POS) as usize] as usize;
+                self.sp = self.reg[((instr & REG_A_MSK) >> REG_A_POS) as usize] as u16 as usize;
             },
             SET => {
                 let imm = ((instr & IMM_MSK) >> IMM_POS) as i8;
-                reg[((instr & TARGET_MSK) >> TARGET_POS) as usize] = imm as i16;
+                self.reg[((instr & TARGET_MSK) >> TARGET_POS) as usize] = imm as i16;
             },
             RET => {
-                sp -= 1;
-                pc = memory[sp] as usize;
+                if self.sp == 0 {
+                    return Err(fault(FaultKind::StackUnderflow));
+                }
+                self.sp -= 1;
+                next_pc = self.memory[self.sp] as u16 as usize + 1;
             },
             SFT => {
-                let a = reg[((instr & REG_A_MSK) >> REG_A_POS) as usize];
-                let steps = reg[((instr & STEPS_MSK) >> STEPS_POS) as usize];
+                let a = self.reg[((instr & REG_A_MSK) >> REG_A_POS) as usize];
+                // Shifting by 16 or more leaves only zeros or sign bits
+                let steps = self.reg[((instr & STEPS_MSK) >> STEPS_POS) as usize] as u16 as u32;
                 match (instr & SFT_OP_MSK) >> SFT_OP_POS {
                     0 => {
-                        reg[((instr & TARGET_MSK) >> TARGET_POS) as usize] = a << steps;
+                        self.reg[((instr & TARGET_MSK) >> TARGET_POS) as usize] = (a as u16).checked_shl(steps).unwrap_or(0) as i16;
                     },
                     1 => {
-                        reg[((instr & TARGET_MSK) >> TARGET_POS) as usize] = ((a as u16) >> steps) as i16;
+                        self.reg[((instr & TARGET_MSK) >> TARGET_POS) as usize] = (a as u16).checked_shr(steps).unwrap_or(0) as i16;
                     },
                     2 => {
-                        reg[((instr & TARGET_MSK) >> TARGET_POS) as usize] = a >> steps;
+                        self.reg[((instr & TARGET_MSK) >> TARGET_POS) as usize] = a >> steps.min(15);
                     },
-                    _ => panic!("Invalid Shift Op")
+                    _ => return Err(fault(FaultKind::InvalidShiftOp))
                 }
             },
             IO => {
                 if (instr & R_W_MSK) >> R_W_POS == 0 {
-                    reg[((instr & DATA_MSK) >> DATA_POS) as usize] = io[((instr & DEV_MSK) >> DEV_POS) as usize];
+                    match self.bus.read(((instr & DEV_MSK) >> DEV_POS) as usize) {
+                        Ok(value) => self.reg[((instr & DATA_MSK) >> DATA_POS) as usize] = value,
+                        Err(NoInput::Blocked) => {
+                            self.blocked = true;
+                            return Ok(Status::Blocked);
+                        },
+                        Err(NoInput::Exhausted) => return Err(fault(FaultKind::InputExhausted)),
+                    }
                 } else {
-                    io[((instr & DEV_MSK) >> DEV_POS) as usize] = reg[((instr & DATA_MSK) >> DATA_POS) as usize];
-                    println!("{}", io[((instr & DEV_MSK) >> DEV_POS) as usize]);
+                    let value = self.reg[((instr & DATA_MSK) >> DATA_POS) as usize];
+                    self.bus.write(((instr & DEV_MSK) >> DEV_POS) as usize, value);
+                    self.outputs.push((((instr & DEV_MSK) >> DEV_POS) as u8, value));
                 }
             },
             HALT => {
-                break;
+                self.halted = true;
+                return Ok(Status::Halted);
             },
-            _ => panic!("Invalid Op Code")
+            _ => unreachable!()
+        }
+
+        if next_pc >= ROM_SIZE {
+            return Err(fault(FaultKind::InvalidPc));
+        }
+        self.pc = next_pc;
+        Ok(Status::Running)
+    }
+
+    pub fn status(&self) -> Status {
+        match (self.halted, self.blocked) {
+            (true, _) => Status::Halted,
+            (false, true) => Status::Blocked,
+            (false, false) => Status::Running,
         }
+    }
+
+    /// Instructions run since the start or the last reset.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn set_pc(&mut self, pc: usize) {
+        assert!(pc < ROM_SIZE, "PC {} is outside the ROM", pc);
+        self.pc = pc;
+    }
+
+    pub fn sp(&self) -> usize {
+        self.sp
+    }
+
+    pub fn set_sp(&mut self, sp: usize) {
+        self.sp = sp;
+    }
+
+    pub fn registers(&self) -> &[i16; REG_COUNT] {
+        &self.reg
+    }
+
+    pub fn reg(&self, reg: usize) -> i16 {
+        self.reg[reg]
+    }
+
+    pub fn set_reg(&mut self, reg: usize, value: i16) {
+        self.reg[reg] = value;
+    }
+
+    pub fn memory(&self) -> &[i16] {
+        &self.memory
+    }
+
+    pub fn read_memory(&self, address: usize) -> i16 {
+        self.memory[address]
+    }
+
+    pub fn write_memory(&mut self, address: usize, value: i16) {
+        self.memory[address] = value;
+    }
+
+    pub fn read_rom(&self, address: usize) -> u16 {
+        self.rom[address]
+    }
+
+    pub fn write_rom(&mut self, address: usize, word: u16) {
+        self.rom[address] = word;
+    }
+
+    pub fn bus_mut(&mut self) -> &mut Bus {
+        &mut self.bus
+    }
+
+    /// Device and value of every OUT instruction, in order.
+    pub fn outputs(&self) -> &[(u8, i16)] {
+        &self.outputs
+    }
+}
+
+/// Runs the code on the devices of `bus` until it halts or is stopped. With
+/// `debug_info` the source line it stopped at and the values of the live
+/// variables are printed too.
+pub fn simulate(hex_code: &[u16], bus: Bus, debug_info: Option<&DebugInfo>, limits: Limits) -> Result<(), Stop> {
+    let mut cpu = Cpu::with_bus(hex_code, bus);
+    let mut watchdog = Watchdog::new(limits);
+
+    // Code without a source line, like the startup code, reports the last line run
+    let mut line_pc: Option<usize> = None;
+
+    let (result, stopped) = loop {
+        if debug_info.is_some_and(|info| info.line(cpu.pc()).is_some()) {
+            line_pc = Some(cpu.pc());
+        }
+        if let Some(stop) = watchdog.check(&cpu) {
+            break (Err(stop), "Stopped");
+        }
+        match cpu.step() {
+            Ok(Status::Running) => (),
+            Ok(Status::Halted) => break (Ok(()), "Halted"),
+            // An input that ran out gets more values from stdin, and the IN
+            // runs again with them
+            Ok(Status::Blocked) => {
+                let device = ((cpu.rom[cpu.pc] & DEV_MSK) >> DEV_POS) as usize;
+                let refilled = cpu.bus.device_mut::<InputQueue>(device).is_some_and(|input| input.prompt());
+                if !refilled {
+                    break (Err(Stop::Blocked { pc: cpu.pc() }), "Stopped");
+                }
+            },
+            Err(fault) => break (Err(Stop::Fault(fault)), "Faulted"),
+        }
+    };
+
+    cpu.bus_mut().finish();
+    if let Some(info) = debug_info {
+        report_halt(info, line_pc, &cpu, stopped);
+    }
+    result
+}
+
+/// Runs the code until it halts or is stopped, with a `Latch` on every
+/// device number.
+pub fn run(hex_code: &[u16], limits: Limits) -> Result<Run, Stop> {
+    let mut cpu = Cpu::new(hex_code);
+    let mut watchdog = Watchdog::new(limits);
+    loop {
+        if let Some(stop) = watchdog.check(&cpu) {
+            return Err(stop);
+        }
+        match cpu.step().map_err(Stop::Fault)? {
+            Status::Running => (),
+            Status::Halted => break,
+            Status::Blocked => return Err(Stop::Blocked { pc: cpu.pc() }),
+        }
+    }
+    Ok(Run {
+        outputs: cpu.outputs,
+        result: cpu.reg[0],
+    })
+}
+
+/// Prints the source line the CPU `stopped` at and the live variables.
+fn report_halt(info: &DebugInfo, line_pc: Option<usize>, cpu: &Cpu, stopped: &str) {
+    if let Some((file, line)) = line_pc.and_then(|line_pc| info.line(line_pc)) {
+        println!("{} at {}:{}", stopped, file, line);
+    }
+    let read = |location: Location| match location {
+        Location::Register(r) => cpu.reg(r as usize),
+        Location::Stack(offset) => cpu.read_memory((cpu.reg(7) as u16 as usize + offset) % MEMORY_SIZE),
+    };
+    for (name, value) in info.variable_values(cpu.pc(), read) {
+        println!("  {} = {}", name, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::{AssemblyCompiler, Compiler};
+    use crate::devices::{InputQueue, IoDevice, Latch, OnEmpty, Source};
+
+    fn assemble(code: &str) -> Vec<u16> {
+        AssemblyCompiler.compile(code)
+    }
+
+    #[test]
+    fn step_runs_one_instruction() {
+        let mut cpu = Cpu::new(&assemble("set x1 5\naddi x1 3\nout x1 2\nhalt\n"));
+        assert_eq!(cpu.step(), Ok(Status::Running));
+        assert_eq!((cpu.pc(), cpu.reg(1), cpu.steps()), (1, 5, 1));
+        assert_eq!(cpu.step(), Ok(Status::Running));
+        assert_eq!(cpu.reg(1), 8);
+        assert_eq!(cpu.step(), Ok(Status::Running));
+        assert_eq!(cpu.outputs(), &[(2, 8)]);
+        assert_eq!(cpu.status(), Status::Running);
+        assert_eq!(cpu.step(), Ok(Status::Halted));
+        assert_eq!(cpu.step(), Ok(Status::Halted));
+        assert_eq!((cpu.pc(), cpu.steps(), cpu.status()), (3, 4, Status::Halted));
+    }
+
+    #[test]
+    fn run_stops_at_the_limit() {
+        let mut cpu = Cpu::new(&assemble("set x1 1\nset x0 2\nadd x2 x2 x1\nj x0\n"));
+        assert_eq!(cpu.run(10), Ok(Status::Running));
+        assert_eq!((cpu.steps(), cpu.reg(2)), (10, 4));
+        assert_eq!(cpu.run(1000), Ok(Status::Running));
+        assert_eq!(cpu.steps(), 1010);
+
+        let mut cpu = Cpu::new(&assemble("set x0 -2\nset x1 0\nj x0 x1 < x1\nhalt\n"));
+        assert_eq!(cpu.run(100), Ok(Status::Halted));
+        assert_eq!(cpu.steps(), 4);
+    }
+
+    #[test]
+    fn reset_keeps_only_the_rom() {
+        let code = assemble("set x0 7\nset x1 3\nstore x0 x1\nout x0 1\nhalt\n");
+        let mut cpu = Cpu::new(&code);
+        assert_eq!(cpu.run(100), Ok(Status::Halted));
+        assert_eq!((cpu.read_memory(3), cpu.outputs().len()), (7, 1));
+
+        cpu.reset();
+        assert_eq!((cpu.pc(), cpu.sp(), cpu.steps()), (0, 0, 0));
+        assert_eq!(cpu.status(), Status::Running);
+        assert_eq!(cpu.registers(), &[0; REG_COUNT]);
+        assert!(cpu.memory().iter().all(|&word| word == 0));
+        assert!(cpu.outputs().is_empty());
+        assert_eq!(cpu.read_rom(0), code[0]);
+        assert_eq!(cpu.run(100), Ok(Status::Halted));
+        assert_eq!(cpu.outputs(), &[(1, 7)]);
+    }
+
+    #[test]
+    fn accessors_change_what_runs() {
+        let mut cpu = Cpu::new(&[]);
+        let ret = assemble("ret\n")[0];
+        cpu.write_rom(100, ret);
+        cpu.write_rom(21, assemble("halt\n")[0]);
+        cpu.write_memory(4, 20);
+        cpu.set_sp(5);
+        cpu.set_pc(100);
+        assert_eq!(cpu.step(), Ok(Status::Running));
+        assert_eq!((cpu.pc(), cpu.sp()), (21, 4));
+        assert_eq!(cpu.step(), Ok(Status::Halted));
+
+        cpu.reset();
+        cpu.write_rom(0, assemble("in x2 5\n")[0]);
+        cpu.bus_mut().write(5, -3);
+        cpu.set_reg(2, 9);
+        assert_eq!(cpu.reg(2), 9);
+        assert_eq!(cpu.step(), Ok(Status::Running));
+        assert_eq!(cpu.reg(2), -3);
+    }
+
+    #[test]
+    fn load_and_store_address_memory_through_registers() {
+        let code = assemble(
+            "set x0 10\nset x1 4\nstore x1 x0\nset x3 -1\nset x1 -5\nstore x1 x3\n\
+             set x5 10\nload x2 x5\nload x4 x3\nhalt\n",
+        );
+        let mut cpu = Cpu::new(&code);
+        assert_eq!(cpu.run(100), Ok(Status::Halted));
+        assert_eq!((cpu.read_memory(10), cpu.read_memory(MEMORY_SIZE - 1)), (4, -5));
+        assert_eq!((cpu.read_memory(0), cpu.read_memory(1), cpu.read_memory(3)), (0, 0, 0));
+        assert_eq!((cpu.reg(2), cpu.reg(4)), (4, -5));
+    }
+
+    /// Has no input for the first IN, and then counts up from 1.
+    #[derive(Default)]
+    struct Late {
+        reads: i16,
+    }
+
+    impl IoDevice for Late {
+        fn read(&mut self) -> Result<i16, NoInput> {
+            self.reads += 1;
+            match self.reads {
+                1 => Err(NoInput::Blocked),
+                reads => Ok(reads - 1),
+            }
+        }
+
+        fn write(&mut self, _: i16) {}
+    }
+
+    #[test]
+    fn blocked_in_runs_again_once_there_is_input() {
+        let input = InputQueue::new(1, Source::Values(vec![5]), OnEmpty::Block);
+        let bus = Bus::default().attach(1, Box::new(input)).attach(2, Box::<Late>::default());
+        let code = assemble("in x1 1\nin x1 1\nhalt\n");
+        let mut cpu = Cpu::with_bus(&code, bus);
+        assert_eq!(cpu.run(100), Ok(Status::Blocked));
+        assert_eq!((cpu.pc(), cpu.reg(1), cpu.status()), (1, 5, Status::Blocked));
+        assert_eq!(cpu.step(), Ok(Status::Blocked));
+        assert!(cpu.bus_mut().device_mut::<Latch>(1).is_none());
+        assert!(cpu.bus_mut().device_mut::<InputQueue>(1).is_some());
 
-        pc += 1;
+        cpu.reset();
+        cpu.write_rom(1, assemble("in x1 2\n")[0]);
+        assert_eq!(cpu.run(100), Ok(Status::Blocked));
+        assert_eq!(cpu.bus_mut().device_mut::<Late>(2).map(|late| late.reads), Some(1));
+        assert_eq!(cpu.step(), Ok(Status::Running));
+        assert_eq!((cpu.pc(), cpu.reg(1), cpu.status()), (2, 1, Status::Running));
+        assert_eq!(cpu.run(100), Ok(Status::Halted));
     }
 }